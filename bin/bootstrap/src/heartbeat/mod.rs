@@ -15,12 +15,12 @@ pub async fn check_servers_heartbeat(
 
         if let Some(ref join) = join {
             if !join.is_finished() {
-                println!("[WARNING] check_servers_heartbeat: still running, sleeping...");
+                tracing::warn!("previous heartbeat job is still running, sleeping");
                 continue;
             }
         }
 
-        println!("[INFO] check_servers_heartbeat: starting new job");
+        tracing::info!("starting new heartbeat job");
         join = Some(tokio::spawn(heartbeat_task(app_state.clone())));
     }
 }
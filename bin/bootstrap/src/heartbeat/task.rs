@@ -34,7 +34,7 @@ async fn ping(
                 _ => Err("Expected Pong on Ping message".to_string()),
             }
         },
-        _ = tokio::time::sleep(Duration::from_secs(1 * 60)) => {
+        _ = tokio::time::sleep(Duration::from_secs(60)) => {
             Err("Failed to receive timeout in 1 minute".to_string())
         }
     };
@@ -45,6 +45,7 @@ async fn ping(
     res
 }
 
+#[tracing::instrument(name = "heartbeat", skip_all)]
 pub async fn heartbeat_task(
     app_state: AppStateRc,
 ) {
@@ -59,7 +60,7 @@ pub async fn heartbeat_task(
     let mut dead_servers = vec![];
     for server in servers {
         if let Err(e) = ping(&server).await {
-            println!("[INFO] Server {server} is dead - {e}");
+            tracing::info!(%server, error = %e, "server is dead");
             dead_servers.push(server);
         }
     }
@@ -69,7 +70,9 @@ pub async fn heartbeat_task(
             .write()
             .expect("Failed to get write lock");
         for server in dead_servers {
-            lock.servers.remove(&server);
+            if lock.servers.remove(&server).is_some() {
+                lock.metrics.evictions.inc();
+            }
         }
     }
 }
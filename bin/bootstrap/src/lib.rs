@@ -1,4 +1,5 @@
 pub mod heartbeat;
+pub mod metrics;
 pub mod routers;
 pub mod types;
 pub mod utils;
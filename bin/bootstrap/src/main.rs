@@ -7,6 +7,7 @@ use axum::http::Uri;
 use tokio::join;
 use tokio::net::TcpListener;
 use bootstrap::heartbeat::check_servers_heartbeat;
+use bootstrap::metrics::BootstrapMetrics;
 use bootstrap::routers::get_router;
 use bootstrap::types::{ApiResponse, AppState, AppStateRc, NodeConfig};
 
@@ -22,6 +23,8 @@ async fn handle_404(
 
 #[tokio::main]
 async fn main() {
+    tracing_subscriber::fmt::init();
+
     let config = NodeConfig {
         addr: SocketAddr::from_str("127.0.0.1:6000").expect("Failed to parse SocketAddr"),
         network_name: "test".to_string(),
@@ -30,6 +33,7 @@ async fn main() {
     let app_state: AppStateRc = Arc::new(RwLock::new(AppState {
         network_name: config.network_name,
         servers: HashMap::new(),
+        metrics: BootstrapMetrics::new(),
     }));
 
     let heartbeat_task = tokio::spawn(
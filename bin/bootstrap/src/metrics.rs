@@ -0,0 +1,40 @@
+use prometheus::{Encoder, IntCounter, IntGauge, Registry, TextEncoder};
+
+/// Counters of the bootstrap node, served at `/metrics`
+pub struct BootstrapMetrics {
+    registry: Registry,
+    pub listings: IntGauge, // taken from the state on every scrape
+    pub evictions: IntCounter, // listed servers the heartbeat found dead
+}
+
+impl BootstrapMetrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("bootstrap".to_string()), None).expect("valid prefix");
+        let listings = IntGauge::new("listings", "Listed servers").expect("valid metric");
+        let evictions = IntCounter::new("heartbeat_evictions_total", "Servers removed by the heartbeat").expect("valid metric");
+
+        registry.register(Box::new(listings.clone())).expect("metric names are unique");
+        registry.register(Box::new(evictions.clone())).expect("metric names are unique");
+
+        Self {
+            registry,
+            listings,
+            evictions,
+        }
+    }
+
+    /// Metrics in Prometheus text format
+    pub fn encode(&self) -> String {
+        let mut buf = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .expect("text encoding doesn't fail");
+        String::from_utf8(buf).expect("text encoding is utf-8")
+    }
+}
+
+impl Default for BootstrapMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -38,6 +38,7 @@ fn exec(
 
         lock.servers.insert(addr, ());
     };
+    tracing::info!(%addr, "server listed");
 
     Ok(CreateListingRes {
     })
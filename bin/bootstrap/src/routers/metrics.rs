@@ -0,0 +1,19 @@
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use crate::types::AppStateRc;
+
+pub async fn get_metrics(
+    State(state): State<AppStateRc>,
+) -> impl IntoResponse {
+    let lock = match state.read() {
+        Ok(lock) => lock,
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to get read lock: {e}")).into_response(),
+    };
+    lock.metrics.listings.set(lock.servers.len() as i64);
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        lock.metrics.encode(),
+    ).into_response()
+}
@@ -1,9 +1,12 @@
 pub mod listings;
+pub mod metrics;
 
 use axum::Router;
+use axum::routing::get;
 use crate::types::AppStateRc;
 
 pub fn get_router() -> Router<AppStateRc> {
     Router::new()
         .nest("/listings", listings::get_router())
+        .route("/metrics", get(metrics::get_metrics))
 }
@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
 use serde::Serialize;
+use crate::metrics::BootstrapMetrics;
 
 #[derive(Serialize)]
 pub struct ApiResponse<Success, Error> {
@@ -20,6 +21,7 @@ pub struct NodeConfig {
 pub struct AppState {
     pub network_name: String,
     pub servers: HashMap<SocketAddr, ()>,
+    pub metrics: BootstrapMetrics,
 }
 
 pub type AppStateRc = Arc<RwLock<AppState>>;
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use axum::Router;
+use tokio::net::TcpListener;
+use protocol::core::bootstrap::client::BootstrapClient;
+use protocol::types::{
+    builder::ProtocolBuilder,
+    event::ProtocolEvent,
+    state::StreamDirection,
+};
+use bootstrap::metrics::BootstrapMetrics;
+use bootstrap::routers::get_router;
+use bootstrap::types::{AppState, AppStateRc};
+
+fn localhost(port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+}
+
+/// Serves the bootstrap api on a random port, returns its url
+async fn serve() -> String {
+    let app_state: AppStateRc = Arc::new(RwLock::new(AppState {
+        network_name: "test".to_string(),
+        servers: HashMap::new(),
+        metrics: BootstrapMetrics::new(),
+    }));
+    let app = Router::new()
+        .nest("/", get_router())
+        .with_state(app_state);
+
+    let listener = TcpListener::bind(localhost(0)).await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    url
+}
+
+#[tokio::test]
+async fn client_registers_and_fetches_listings() {
+    let client = BootstrapClient::new(&serve().await);
+
+    assert!(client.listings().await.unwrap().is_empty());
+    client.register(localhost(1000)).await.unwrap();
+    client.register(localhost(1001)).await.unwrap();
+    // listing the same address again isn't an error
+    client.register(localhost(1000)).await.unwrap();
+
+    let mut listings = client.listings().await.unwrap();
+    listings.sort();
+    assert_eq!(listings, [localhost(1000), localhost(1001)]);
+}
+
+#[tokio::test]
+async fn node_dials_listed_node() {
+    let url = serve().await;
+    let (a_addr, b_addr) = (localhost(17547), localhost(17548));
+
+    let node = |addr| {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1000);
+        tokio::spawn(async move { while receiver.recv().await.is_some() {} });
+        let mut builder = ProtocolBuilder::new(addr, sender);
+        builder.with_bootstrap(vec![url.clone()]);
+        builder
+    };
+
+    let _a = node(a_addr).build().await.unwrap().0;
+    let b = node(b_addr);
+    let mut events = b.subscribe();
+    let _b = b.build().await.unwrap().0;
+
+    let connected = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let ProtocolEvent::PeerConnected { addr, direction: StreamDirection::Outbound, .. } = events.recv().await.unwrap() {
+                return addr;
+            }
+        }
+    }).await.expect("node didn't dial the listed node");
+    assert_eq!(connected, a_addr);
+
+    let mut listings = BootstrapClient::new(&url).listings().await.unwrap();
+    listings.sort();
+    assert_eq!(listings, [a_addr, b_addr]);
+}
+
+#[tokio::test]
+async fn unreachable_bootstrap_is_reported_and_skipped() {
+    let url = serve().await;
+    let dead_url = {
+        let listener = TcpListener::bind(localhost(0)).await.unwrap();
+        format!("http://{}", listener.local_addr().unwrap())
+    };
+    let (a_addr, b_addr) = (localhost(17549), localhost(17550));
+
+    let node = |addr| {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1000);
+        tokio::spawn(async move { while receiver.recv().await.is_some() {} });
+        let mut builder = ProtocolBuilder::new(addr, sender);
+        builder.with_bootstrap(vec![dead_url.clone(), url.clone()]);
+        builder
+    };
+
+    let _a = node(a_addr).build().await.unwrap().0;
+    let b = node(b_addr);
+    let mut events = b.subscribe();
+    let _b = b.build().await.unwrap().0;
+
+    let mut failed = vec![];
+    let connected = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match events.recv().await.unwrap() {
+                ProtocolEvent::BootstrapFailed { url, .. } => failed.push(url),
+                ProtocolEvent::SuspectBootstrap { url, reason } => panic!("{} suspected: {}", url, reason),
+                ProtocolEvent::PeerConnected { addr, direction: StreamDirection::Outbound, .. } => return addr,
+                _ => {},
+            }
+        }
+    }).await.expect("node didn't dial the listed node");
+    assert_eq!(connected, a_addr);
+    assert_eq!(failed, [dead_url]);
+}
@@ -1,5 +1,6 @@
 use std::io::{stdout, Write};
 use crate::frontend::send_message::send_message;
+use crate::frontend::show_peers::show_peers;
 use crate::frontend::state::AppState;
 use crate::types::ui::V100;
 
@@ -10,7 +11,7 @@ pub async fn handle_input(
     if str.trim() == "" {
         let mut stdout = stdout().lock();
         stdout
-            .write(format!(
+            .write_all(format!(
                 "{}{}{}---\n\r>{}",
                 V100::GoLineUp(2),
                 V100::ClearLine,
@@ -19,10 +20,12 @@ pub async fn handle_input(
             ).as_bytes())
             .expect("Failed to write");
         stdout.flush().expect("failed to flush");
+    } else if str.trim() == "/peers" {
+        show_peers(app_state).await;
     } else {
         send_message(
             app_state,
-            &str,
+            str,
         ).await;
     }
 }
@@ -1,17 +1,23 @@
 use tokio::io::{self, AsyncBufReadExt, BufReader};
 use tokio::select;
+use tokio::sync::broadcast::{self, error::RecvError};
 use tokio::sync::mpsc::Receiver;
 use crate::frontend::handle_input::handle_input;
 use crate::frontend::state::AppState;
-use protocol::types::package::{AlertPackage, AlertPackageLevel, AppPackage};
+use protocol::types::{
+    event::{EventLevel, ProtocolEvent},
+    package::AppPackage,
+};
 
 mod handle_input;
 mod send_message;
+mod show_peers;
 pub mod state;
 
 pub async fn setup_frontend(
     app_state: AppState,
     mut package_receiver: Receiver<AppPackage>,
+    mut events: broadcast::Receiver<ProtocolEvent>,
 ) {
     let stdin = io::stdin();
     let mut reader = BufReader::new(stdin);
@@ -24,10 +30,7 @@ pub async fn setup_frontend(
                         handle_input(&app_state, &line).await;
                     },
                     Err(e) => {
-                        app_state.new_package(AppPackage::Alert(AlertPackage {
-                            level: AlertPackageLevel::ERROR,
-                            msg: format!("Failed to read_line {}", e),
-                        }));
+                        app_state.ui.new_message(&format!("System: {:?}", EventLevel::Error), &format!("Failed to read_line {}", e));
                     }
                 }
             },
@@ -35,10 +38,17 @@ pub async fn setup_frontend(
                 if let Some(package) = package {
                     app_state.new_package(package);
                 } else {
-                    app_state.ui.new_message(&format!("System: {}", AlertPackageLevel::INFO), "channel hangup");
+                    app_state.ui.new_message(&format!("System: {:?}", EventLevel::Info), "channel hangup");
                     break;
                 }
             }
+            event = events.recv() => {
+                match event {
+                    Ok(event) => app_state.new_event(event),
+                    Err(RecvError::Lagged(_)) => {}, // missed some, the next ones are still worth showing
+                    Err(RecvError::Closed) => {},
+                }
+            }
         }
     }
 }
@@ -9,7 +9,7 @@ pub async fn send_message(
     let index = {
         let mut stdout = stdout().lock();
         stdout
-            .write(format!(
+            .write_all(format!(
                 "{}",
                 V100::GoLineUp(1),
             ).as_bytes())
@@ -18,7 +18,7 @@ pub async fn send_message(
         let index = app_state.ui.new_message("YOU", message);
 
         stdout
-            .write(format!(
+            .write_all(format!(
                 "{}{}>",
                 V100::GoLineDown(1),
                 V100::ClearLineRight,
@@ -34,5 +34,5 @@ pub async fn send_message(
         .protocol_state
         .broadcast_data(message.as_bytes().to_vec())
         .await
-        .expect(&format!("Failed to broadcast message #{}", index));
+        .unwrap_or_else(|_| panic!("Failed to broadcast message #{}", index));
 }
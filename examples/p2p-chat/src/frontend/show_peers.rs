@@ -0,0 +1,43 @@
+use std::io::{stdout, Write};
+use crate::frontend::state::AppState;
+use crate::types::ui::V100;
+
+pub async fn show_peers(
+    app_state: &AppState,
+) {
+    let peers = app_state.protocol_state.peers().await;
+
+    let mut stdout = stdout().lock();
+    stdout
+        .write_all(format!(
+            "{}",
+            V100::GoLineUp(1),
+        ).as_bytes())
+        .expect("Failed to write");
+
+    app_state.ui.new_message("System", &format!("{} peers connected", peers.len()));
+    for peer in peers {
+        let id = peer.node_id.map(|id| id.to_string()).unwrap_or_else(|| "unknown".to_string());
+        app_state.ui.new_message("System", &format!(
+            "{} {:?} over {:?}, id {}, rtt {:?}, up {}s, {}B sent, {}B received",
+            peer.addr,
+            peer.direction,
+            peer.transport,
+            id,
+            peer.rtt.srtt,
+            peer.connected_since.elapsed().as_secs(),
+            peer.bytes_sent,
+            peer.bytes_received,
+        ));
+    }
+
+    stdout
+        .write_all(format!(
+            "{}{}>",
+            V100::GoLineDown(1),
+            V100::ClearLineRight,
+        ).as_bytes())
+        .expect("Failed to write");
+
+    stdout.flush().expect("failed to flush");
+}
@@ -1,6 +1,7 @@
 use std::sync::Arc;
 use protocol::types::{
     state::ProtocolState,
+    event::{EventLevel, ProtocolEvent},
     package::AppPackage,
 };
 use crate::utils::ui::UITerminal;
@@ -17,11 +18,35 @@ impl AppStateInner {
                 let msg = String::from_utf8_lossy(&message.msg).to_string();
                 self.ui.new_message(&format!("User: {}", message.from), &msg);
             }
-            AppPackage::Alert(_alert) => {
-                // self.ui.new_message(&format!("System: {}", alert.level), &alert.msg);
-                // todo: write macro to wrap sending packages and ignore `level: DEBUG` in release mode
-                return;
+            AppPackage::Routed(message) => {
+                let msg = String::from_utf8_lossy(&message.msg).to_string();
+                self.ui.new_message(&format!("User: {}", message.from), &msg);
+            }
+            AppPackage::Anonymous(message) => {
+                let msg = String::from_utf8_lossy(&message.msg).to_string();
+                self.ui.new_message("Anonymous", &msg);
+            }
+        }
+    }
+
+    pub fn new_event(&self, event: ProtocolEvent) {
+        match event {
+            ProtocolEvent::PeerConnected { addr, .. } => {
+                self.ui.new_message("System", &format!("{} joined", addr));
+            }
+            ProtocolEvent::PeerDisconnected { addr, reason } => {
+                self.ui.new_message("System", &format!("{} left - {:?}", addr, reason));
+            }
+            ProtocolEvent::PeerDead { addr, .. } => {
+                self.ui.new_message("System", &format!("{} stopped responding", addr));
+            }
+            ProtocolEvent::SuspectBootstrap { url, reason } => {
+                self.ui.new_message("System", &format!("Bootstrap node {} looks suspicious: {}", url, reason));
+            }
+            event if event.level() >= EventLevel::Warning => {
+                self.ui.new_message(&format!("System: {:?}", event.level()), &format!("{:?}", event));
             }
+            _ => {}
         }
     }
 }
@@ -10,18 +10,22 @@ use crate::frontend::setup_frontend;
 use crate::frontend::state::{AppState, AppStateInner};
 use crate::utils::ui::UITerminal;
 
+use protocol::core::peer_store::json::JsonPeerStore;
 use protocol::types::{
     builder::ProtocolBuilder,
-    package::{AlertPackage, AlertPackageLevel, AppPackage},
+    config::ProtocolConfig,
+    event::EventLevel,
 };
 
 #[tokio::main]
 async fn main() {
-    let (server_addr, client_addr) = {
+    let (server_addr, client_addr, peers_path, config_path) = {
         let mut args = args().skip(1);
 
         let mut server_addr: Option<SocketAddr> = None;
         let mut client_addr: Option<SocketAddr> = None;
+        let mut peers_path: Option<String> = None;
+        let mut config_path: Option<String> = None;
         while let Some(arg) = args.next() {
             match arg.as_ref() {
                 "-s" => {
@@ -34,6 +38,14 @@ async fn main() {
                     let addr = SocketAddr::from_str(&addr).expect("Invalid address for a client");
                     client_addr = Some(addr)
                 }
+                "-p" => {
+                    let path = args.next().expect("Missing a path to the file with known peers");
+                    peers_path = Some(path)
+                }
+                "-f" => {
+                    let path = args.next().expect("Missing a path to the config file");
+                    config_path = Some(path)
+                }
                 _ => {
                     panic!("Unknown argument {}", arg);
                 }
@@ -43,21 +55,43 @@ async fn main() {
         (
             server_addr.expect("Server is required to run node"),
             client_addr,
+            peers_path,
+            config_path,
         )
     };
 
+    // env vars override the file, see `ProtocolConfig::with_env`
+    let config = match config_path {
+        Some(path) => ProtocolConfig::from_file(path).expect("Invalid config file"),
+        None => ProtocolConfig::default(),
+    };
+    let config = config.with_env().expect("Invalid config in env vars");
 
     let (package_sender, package_receiver) = channel(10);
     let mut protocol_builder = ProtocolBuilder::new(
         server_addr,
         package_sender,
-        1234567, // todo: get seed randomly
     );
+    protocol_builder.set_config(config);
     // this will be removed with ui commands like `/connect`
     if let Some(client_addr) = client_addr {
-        protocol_builder.set_client(client_addr).await
+        protocol_builder.set_client(client_addr)
+    }
+    if let Some(peers_path) = peers_path {
+        protocol_builder.set_peer_store(JsonPeerStore::new(peers_path))
+    }
+    let events = protocol_builder.subscribe();
+    let (protocol_state, protocol_handles) = protocol_builder.build().await.expect("Invalid protocol config");
+
+    {
+        let protocol_state = protocol_state.clone();
+        tokio::spawn(async move {
+            // peers learned since the last periodic save would be lost otherwise
+            let _ = tokio::signal::ctrl_c().await;
+            protocol_state.save_peers().await;
+            std::process::exit(0);
+        });
     }
-    let (protocol_state, protocol_handles) = protocol_builder.build().await;
 
     let app_state = AppState::new(AppStateInner {
         protocol_state,
@@ -65,16 +99,14 @@ async fn main() {
     });
     let mut handles = vec![];
 
-    app_state.new_package(AppPackage::Alert(AlertPackage {
-        level: AlertPackageLevel::INFO,
-        msg: "Init threads".to_string(),
-    }));
+    app_state.ui.new_message(&format!("System: {:?}", EventLevel::Info), "Init threads");
 
     handles.extend(protocol_handles);
 
     handles.push(tokio::spawn(setup_frontend(
         app_state.clone(),
         package_receiver, // this is a bridge from protocol to application
+        events,
     )));
     for join in handles {
         join
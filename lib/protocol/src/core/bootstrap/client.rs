@@ -0,0 +1,97 @@
+use std::net::SocketAddr;
+use std::str::FromStr;
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+
+// mirrors types of the `bootstrap` binary
+
+#[derive(Deserialize)]
+struct ApiResponse<T> {
+    success: bool,
+    data: Option<T>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GetListingsRes {
+    servers: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct CreateListingReq {
+    server_addr: String,
+}
+
+#[derive(Deserialize)]
+struct CreateListingRes {
+}
+
+/// Talks to a single bootstrap node over its http api
+pub struct BootstrapClient {
+    http: reqwest::Client,
+    url: String,
+}
+
+impl BootstrapClient {
+    pub fn new(url: &str) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub async fn register(&self, server_addr: SocketAddr) -> Result<()> {
+        let res = self.http
+            .post(format!("{}/listings", self.url))
+            .json(&CreateListingReq {
+                server_addr: server_addr.to_string(),
+            })
+            .send()
+            .await
+            .context("Failed to send listing request")?;
+
+        // bootstrap node responds with bad request only if address is already listed
+        if res.status() == StatusCode::BAD_REQUEST {
+            return Ok(());
+        }
+
+        let status = res.status();
+        let body = res
+            .json::<ApiResponse<CreateListingRes>>()
+            .await
+            .context("Failed to parse listing response")?;
+        if !body.success {
+            bail!("Bootstrap node refused listing ({}): {}", status, body.error.unwrap_or_default());
+        }
+
+        Ok(())
+    }
+
+    pub async fn listings(&self) -> Result<Vec<SocketAddr>> {
+        let body = self.http
+            .get(format!("{}/listings", self.url))
+            .send()
+            .await
+            .context("Failed to request listings")?
+            .json::<ApiResponse<GetListingsRes>>()
+            .await
+            .context("Failed to parse listings")?;
+
+        let data = match (body.success, body.data) {
+            (true, Some(data)) => data,
+            _ => return Err(anyhow!("Bootstrap node failed to return listings: {}", body.error.unwrap_or_default())),
+        };
+
+        // skip what we can't parse instead of throwing away whole list
+        Ok(data
+            .servers
+            .iter()
+            .filter_map(|addr| SocketAddr::from_str(addr).ok())
+            .collect())
+    }
+}
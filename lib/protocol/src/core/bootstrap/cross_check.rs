@@ -0,0 +1,89 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use crate::types::event::SuspectReason;
+
+/// Listings returned by each of the bootstrap nodes that responded
+pub struct Listings {
+    sources: Vec<(String, HashSet<SocketAddr>)>,
+}
+
+impl Listings {
+    pub fn new() -> Self {
+        Self {
+            sources: vec![],
+        }
+    }
+
+    pub fn add(&mut self, url: String, addrs: Vec<SocketAddr>) {
+        self.sources.push((url, addrs.into_iter().collect()));
+    }
+
+    /// Bootstrap nodes that didn't list this node or whose listings mostly aren't confirmed
+    /// by any other bootstrap node. Only bootstrap nodes that listed this node are trusted
+    /// to confirm listings of others.
+    pub fn suspects(
+        &self,
+        server_addr: SocketAddr,
+        min_overlap: f64,
+    ) -> Vec<(String, SuspectReason)> {
+        let mut suspects = vec![];
+
+        let (listed, not_listed): (Vec<_>, Vec<_>) = self.sources
+            .iter()
+            .partition(|(_, addrs)| addrs.contains(&server_addr));
+        for (url, _) in not_listed {
+            suspects.push((url.clone(), SuspectReason::NotListed));
+        }
+
+        for (i, (url, addrs)) in listed.iter().enumerate() {
+            let others = listed
+                .iter()
+                .enumerate()
+                .filter(|(j, _)| *j != i)
+                .flat_map(|(_, (_, addrs))| addrs.iter())
+                .filter(|addr| **addr != server_addr)
+                .collect::<HashSet<_>>();
+            let own = addrs
+                .iter()
+                .filter(|addr| **addr != server_addr)
+                .collect::<HashSet<_>>();
+            // nothing to compare with
+            if own.is_empty() || others.is_empty() {
+                continue;
+            }
+
+            let overlap = own.intersection(&others).count() as f64 / own.len() as f64;
+            if overlap < min_overlap {
+                suspects.push((url.clone(), SuspectReason::Disjoint { overlap }));
+            }
+        }
+
+        suspects
+    }
+
+    /// How many bootstrap nodes listed each address. Addresses known only from
+    /// the `excluded` bootstrap nodes are left out, unless there is nothing else.
+    pub fn confirmations(
+        &self,
+        excluded: &HashSet<String>,
+    ) -> HashMap<SocketAddr, usize> {
+        let exclude = self.sources.iter().any(|(url, _)| !excluded.contains(url));
+
+        let mut confirmations = HashMap::new();
+        for (url, addrs) in &self.sources {
+            if exclude && excluded.contains(url) {
+                continue;
+            }
+            for addr in addrs {
+                *confirmations.entry(*addr).or_insert(0) += 1;
+            }
+        }
+        confirmations
+    }
+}
+
+impl Default for Listings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use crate::core::bootstrap::{client::BootstrapClient, cross_check::Listings};
+use crate::core::client::{reconnect::schedule_reconnect, start_client};
+use crate::types::{
+    state::ProtocolState,
+    event::{DialSource, ProtocolEvent},
+};
+
+pub mod client;
+pub mod cross_check;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BootstrapConfig {
+    pub urls: Vec<String>,
+    #[serde(with = "crate::utils::duration_ms")]
+    pub register_interval: Duration, // has to be shorter than heartbeat of bootstrap nodes
+    pub dial_count: usize, // how many listed nodes to connect to
+    pub min_overlap: f64, // share of listings that other bootstrap nodes have to confirm
+}
+
+impl BootstrapConfig {
+    pub fn new(urls: Vec<String>) -> Self {
+        Self {
+            urls,
+            register_interval: Duration::from_secs(5 * 60),
+            dial_count: 4,
+            min_overlap: 0.5,
+        }
+    }
+}
+
+impl Default for BootstrapConfig {
+    fn default() -> Self {
+        Self::new(vec![])
+    }
+}
+
+/// Picks up to `count` addresses without repeats. The more bootstrap nodes confirmed
+/// the address, the more likely it is picked.
+pub fn weighted_subset(
+    mut addrs: Vec<(SocketAddr, usize)>,
+    random: &mut impl FnMut() -> u64,
+    count: usize,
+) -> Vec<SocketAddr> {
+    let mut picked = Vec::with_capacity(count);
+    while picked.len() < count && !addrs.is_empty() {
+        let total = addrs.iter().map(|(_, weight)| *weight as u64).sum::<u64>();
+        let mut r = random() % total;
+
+        let mut i = 0;
+        while r >= addrs[i].1 as u64 {
+            r -= addrs[i].1 as u64;
+            i += 1;
+        }
+        picked.push(addrs.swap_remove(i).0);
+    }
+    picked
+}
+
+async fn register_and_dial(
+    protocol_state: &ProtocolState,
+    clients: &[BootstrapClient],
+    suspected: &mut HashSet<String>,
+    dial: bool,
+) {
+    let config = protocol_state.read().bootstrap.as_ref().expect("bootstrap is configured");
+    let server_addr = protocol_state.read().server_addr;
+
+    let mut listings = Listings::new();
+    for client in clients {
+        if let Err(e) = client.register(server_addr).await {
+            protocol_state.emit(ProtocolEvent::BootstrapFailed {
+                url: client.url().to_string(),
+                error: format!("failed to register - {}", e),
+            });
+            continue;
+        }
+
+        match client.listings().await {
+            Ok(addrs) => listings.add(client.url().to_string(), addrs),
+            Err(e) => {
+                protocol_state.emit(ProtocolEvent::BootstrapFailed {
+                    url: client.url().to_string(),
+                    error: format!("failed to get listings - {}", e),
+                });
+            }
+        }
+    }
+
+    let suspects = listings.suspects(server_addr, config.min_overlap);
+    let current = suspects.iter().map(|(url, _)| url.clone()).collect::<HashSet<_>>();
+    for (url, reason) in suspects {
+        // report only once until bootstrap node starts to behave
+        if suspected.contains(&url) {
+            continue;
+        }
+        protocol_state.emit(ProtocolEvent::SuspectBootstrap { url, reason });
+    }
+    *suspected = current;
+
+    if !dial {
+        return;
+    }
+
+    let picked = {
+        let lock = &mut *protocol_state.lock().await;
+        let addrs = listings
+            .confirmations(suspected)
+            .into_iter()
+            .filter(|(addr, _)| {
+                *addr != server_addr
+                    && !lock.streams.contains_key(addr)
+                    && !lock.scores.is_banned(&addr.ip())
+            })
+            .collect::<Vec<_>>();
+
+        let state = &mut lock.state;
+        weighted_subset(addrs, &mut || state.next(), config.dial_count)
+    };
+
+    for addr in picked {
+        protocol_state.emit(ProtocolEvent::Dialing {
+            addr,
+            source: DialSource::Bootstrap,
+        });
+
+        if start_client(protocol_state.clone(), addr, None).await.is_err() {
+            schedule_reconnect(protocol_state, addr).await;
+        }
+    }
+}
+
+/// Lists the node at bootstrap nodes, connects to some of the listed nodes and keeps
+/// the listing alive. Listings of all bootstrap nodes are cross-checked on every round,
+/// but nodes are dialed again only if this node lost all its connections.
+pub fn bootstrap(
+    protocol_state: ProtocolState,
+) -> [JoinHandle<()>; 1] {
+    let handle = tokio::spawn(async move {
+        let config = protocol_state.read().bootstrap.as_ref().expect("bootstrap is configured");
+        let clients = config
+            .urls
+            .iter()
+            .map(|url| BootstrapClient::new(url))
+            .collect::<Vec<_>>();
+
+        let mut suspected = HashSet::new();
+
+        register_and_dial(&protocol_state, &clients, &mut suspected, true).await;
+
+        loop {
+            tokio::time::sleep(config.register_interval).await;
+
+            let lonely = protocol_state.lock().await.streams.is_empty();
+            register_and_dial(&protocol_state, &clients, &mut suspected, lonely).await;
+        }
+    });
+
+    [handle]
+}
@@ -1,112 +1,156 @@
 use std::net::SocketAddr;
-use std::time::SystemTime;
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
 use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
 use tokio::task::JoinHandle;
+use tokio::time::{timeout, Instant};
+use crate::core::dht;
 use crate::core::frames::ProtocolMessage;
+use crate::core::pex;
+use crate::core::handshake::HandshakeError;
+use crate::core::peer_store;
 use crate::core::stream::protocol_handle_stream;
+use crate::core::transport::{self, BoxedStream, TransportKind};
+use crate::core::vivaldi::Coordinate;
 use crate::types::{
-    state::{ProtocolState, StreamMetadata},
-    package::{AlertPackage, AlertPackageLevel, AppPackage}
+    state::{ProtocolState, StreamDirection, StreamMetadata},
+    event::ProtocolEvent,
 };
-use crate::utils::sss_triangle::sss_triangle;
 
+pub mod reconnect;
+
+/// Connects to the node and sends `CONN_INIT`, returning established stream and measured ping
+async fn connect(
+    protocol_state: &ProtocolState,
+    addr: SocketAddr,
+) -> Result<(BoxedStream, Duration)> {
+    let timeouts = &protocol_state.read().timeouts;
+
+    let started_at = Instant::now();
+    let mut stream = timeout(timeouts.connect, transport::connect(protocol_state, addr))
+        .await
+        .map_err(|_| HandshakeError::ConnectTimeout)?
+        .context("---Failed to connect")?;
+    let rtt = started_at.elapsed(); // tcp handshake takes a single round trip
+
+    protocol_state.lock().await.state.next();
+    protocol_state.send_message(
+        &mut stream,
+        ProtocolMessage::ConnInit {
+            server_addr: protocol_state.read().server_addr,
+        },
+    )
+        .await
+        .context("---Failed to write to stream")?;
+
+    Ok((stream, rtt))
+}
+
+/// Returns `Err` if connection couldn't be established, so caller can decide whether to retry,
+/// and `Ok(None)` if connection was established but then dropped on purpose.
+#[tracing::instrument(name = "dial", skip(protocol_state, src_info))]
 pub async fn start_client(
     protocol_state: ProtocolState,
     addr: SocketAddr,
-    src_info: Option<(SocketAddr, u16)>,
-) -> Option<JoinHandle<()>> {
-    let ping = SystemTime::now();
-    let mut stream = TcpStream::connect(addr).await.expect("---Failed to connect");
-    let ping = SystemTime::now().duration_since(ping).expect("Failed to calculate ping").as_millis();
+    src_info: Option<(SocketAddr, Option<Coordinate>)>,
+) -> Result<Option<JoinHandle<()>>> {
+    let handshake_timeout = protocol_state.read().timeouts.handshake;
+    let res = timeout(handshake_timeout, connect(&protocol_state, addr))
+        .await
+        .unwrap_or(Err(HandshakeError::HandshakeTimeout.into()));
+
+    let (stream, rtt) = match res {
+        Ok(res) => res,
+        Err(e) => {
+            protocol_state.emit(ProtocolEvent::HandshakeFailed {
+                addr,
+                direction: StreamDirection::Outbound,
+                error: e.to_string(),
+            });
+            return Err(e);
+        }
+    };
+
+    run_client(protocol_state, addr, addr, TransportKind::Tcp, stream, Some(rtt), src_info).await
+}
+
+/// Reaches the node that doesn't accept connections - through a circuit at the relay it was advertised with,
+/// otherwise by punching a hole with help of `src_addr`, node that told us about it
+pub(crate) async fn connect_indirectly(
+    protocol_state: &ProtocolState,
+    addr: SocketAddr,
+    src_addr: Option<SocketAddr>,
+) -> Result<Option<JoinHandle<()>>> {
+    let relay = protocol_state.lock().await.relay.relay_of(&addr);
+    let res = match relay {
+        Some(relay) => protocol_state.connect_relayed(relay, addr).await,
+        None => Err(anyhow!("{} isn't advertised with a relay", addr)),
+    };
 
+    match (res, src_addr) {
+        (Err(_), Some(src_addr)) if protocol_state.read().udp.is_some() => protocol_state.punch(src_addr, addr).await,
+        (res, _) => res,
+    }
+}
+
+/// Registers the stream that already sent `CONN_INIT` and starts handling it.
+/// `remote_addr` is where the stream actually goes, it differs from `addr` for udp links.
+/// `rtt` is `None` if connecting didn't measure it, then it's known after the first pong.
+pub(crate) async fn run_client(
+    protocol_state: ProtocolState,
+    addr: SocketAddr,
+    remote_addr: SocketAddr,
+    transport: TransportKind,
+    mut stream: BoxedStream,
+    rtt: Option<Duration>,
+    src_info: Option<(SocketAddr, Option<Coordinate>)>,
+) -> Result<Option<JoinHandle<()>>> {
     let stream_request_receiver;
     {
-        let server_addr = protocol_state.read().server_addr;
         let mut lock = protocol_state.lock().await;
 
-        lock.state.next();
-        ProtocolState::send_message(
-            &mut stream,
-            ProtocolMessage::ConnInit {
-                server_addr,
-            },
-        )
-            .await
-            .expect("---Failed to write to stream");
-
-        protocol_state
-            .read()
-            .package_sender
-            .send(AppPackage::Alert(AlertPackage {
-                level: AlertPackageLevel::DEBUG,
-                msg: format!("Sent init message to {} with server_addr {}", addr, server_addr),
-            }))
-            .await
-            .expect("---Failed to send app package");
-
-        // u16 < 2^24 => save to convert to f32
-        // src - https://stackoverflow.com/a/41651053
-        if ping > 60_000 { // todo: move to constant
-            protocol_state
-                .read()
-                .package_sender
-                .send(AppPackage::Alert(AlertPackage {
-                    level: AlertPackageLevel::WARNING,
-                    msg: format!("Ping with host {} is too big ({}). Disconnecting", addr, ping),
-                }))
-                .await
-                .expect("---Failed to send app package");
-            stream.shutdown().await.expect("---Failed to shutdown stream");
-            return None;
+        if let Some(rtt) = rtt.filter(|rtt| *rtt > protocol_state.read().ping.max_rtt) {
+            protocol_state.emit(ProtocolEvent::PingTooHigh { addr, rtt });
+            stream.shutdown().await.context("---Failed to shutdown stream")?;
+            return Ok(None);
+        }
+        let mut predicted = None;
+
+        let mut targ_metadata = StreamMetadata::new(StreamDirection::Outbound, remote_addr, transport);
+        if let Some(rtt) = rtt {
+            targ_metadata.rtt.sample(rtt);
         }
-        let ping = ping as u16;
-
-        protocol_state
-            .read()
-            .package_sender
-            .send(AppPackage::Alert(AlertPackage {
-                level: AlertPackageLevel::INFO,
-                msg: format!("You joined to {}", addr),
-            }))
-            .await
-            .expect("---Failed to send app package");
-
-        let mut targ_metadata = StreamMetadata::new();
-        targ_metadata.ping = ping;
-
-        if let Some((src_addr, src_to_targ_ping)) = src_info {
-            let src_ping = lock.streams.get_mut(&src_addr).expect("src_addr should exist").1.ping;
-
-            let angle = sss_triangle(src_ping, ping, src_to_targ_ping);
-
-            protocol_state
-                .read()
-                .package_sender
-                .send(AppPackage::Alert(AlertPackage {
-                    level: AlertPackageLevel::DEBUG,
-                    msg: format!("Calculated angle of {} for {}", angle, src_addr),
-                }))
-                .await
-                .expect("---Failed to send app package");
-
-            targ_metadata.topology_rad = angle;
-            targ_metadata.knows_about.push(src_addr.clone());
+
+        if let Some((src_addr, targ_coordinate)) = src_info {
+            if let Some(targ_coordinate) = targ_coordinate {
+                predicted = Some(lock.coordinate.predict(&targ_coordinate));
+                targ_metadata.coordinate = Some(targ_coordinate);
+            }
+            // src could have already disconnected while we were connecting
+            if lock.streams.contains_key(&src_addr) {
+                targ_metadata.knows_about.push(src_addr);
+            }
         }
 
-        let channels = tokio::sync::mpsc::channel(100);
+        let channels = tokio::sync::mpsc::channel(protocol_state.read().buffers.stream);
         stream_request_receiver = channels.1;
-        lock.streams.insert(addr.clone(), (channels.0, targ_metadata));
-
-        protocol_state
-            .read()
-            .package_sender
-            .send(AppPackage::Alert(AlertPackage {
-                level: AlertPackageLevel::DEBUG,
-                msg: format!("Connected with delay of {}", ping),
-            }))
-            .await
-            .expect("---Failed to send app package");
+        lock.streams.insert(addr, (channels.0, targ_metadata));
+        lock.pex.remove(&addr);
+
+        // learn about its neighbours right away, sent once the stream starts
+        pex::request(&protocol_state, &mut lock, addr).await;
+
+        let record = peer_store::remember(&protocol_state.read().scoring, &mut lock, addr);
+
+        protocol_state.emit(ProtocolEvent::PeerConnected {
+            addr,
+            direction: StreamDirection::Outbound,
+            transport,
+        });
+        if let Some(rtt) = rtt {
+            record.latency = Some(rtt.as_millis() as u16);
+            protocol_state.emit(ProtocolEvent::PingMeasured { addr, rtt, predicted });
+        }
     }
 
     let read_handle = {
@@ -119,5 +163,8 @@ pub async fn start_client(
         ))
     };
 
-    Some(read_handle)
+    reconnect::record_success(&protocol_state, addr).await;
+    dht::introduce(&protocol_state, addr);
+
+    Ok(Some(read_handle))
 }
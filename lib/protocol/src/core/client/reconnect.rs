@@ -0,0 +1,139 @@
+use std::cmp::min;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+use serde::{Deserialize, Serialize};
+use crate::core::commands::ProtocolCommand;
+use crate::types::{
+    state::ProtocolState,
+    event::ProtocolEvent,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    #[serde(with = "crate::utils::duration_ms")]
+    pub base_delay: Duration, // delay before the first retry, doubled on every next failure
+    #[serde(with = "crate::utils::duration_ms")]
+    pub max_delay: Duration,
+    pub max_failures: u32, // after that many failures in a row non-sticky address is marked dead
+    #[serde(with = "crate::utils::duration_ms")]
+    pub min_uptime: Duration, // connection has to stay up that long to reset failures, so flapping peers back off
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5 * 60),
+            max_failures: 8,
+            min_uptime: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ReconnectConfig {
+    /// Exponential delay for the given number of failures in a row, with up to 50% of jitter
+    /// so that peers dropped at the same moment don't come back at the same moment.
+    pub fn delay(&self, failures: u32, random: u64) -> Duration {
+        let exp = self.base_delay.saturating_mul(1_u32 << min(failures, 16));
+        let delay = min(exp, self.max_delay).as_millis() as u64;
+
+        let half = delay / 2;
+        let jitter = if half == 0 { 0 } else { random % half };
+        Duration::from_millis(half + jitter)
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionHistory {
+    pub failures: u32, // failures in a row, reset once connection stays up for `min_uptime`
+    pub total_failures: u64,
+    pub last_failure: Option<Instant>,
+    pub last_success: Option<Instant>,
+    pub dead: bool,
+}
+
+impl ConnectionHistory {
+    pub fn new() -> Self {
+        Self {
+            failures: 0,
+            total_failures: 0,
+            last_failure: None,
+            last_success: None,
+            dead: false,
+        }
+    }
+}
+
+impl Default for ConnectionHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub async fn record_success(
+    protocol_state: &ProtocolState,
+    addr: SocketAddr,
+) {
+    let mut lock = protocol_state.lock().await;
+    let history = lock.history.entry(addr).or_default();
+
+    history.last_success = Some(Instant::now());
+    history.dead = false;
+}
+
+/// Records failure of the outbound connection and, unless address is considered dead,
+/// schedules next attempt to connect after a backoff delay.
+pub async fn schedule_reconnect(
+    protocol_state: &ProtocolState,
+    addr: SocketAddr,
+) {
+    let sticky = protocol_state.read().sticky_peers.contains(&addr);
+    let config = &protocol_state.read().reconnect;
+
+    let delay = {
+        let lock = &mut *protocol_state.lock().await;
+        let random = lock.state.next();
+        let history = lock.history.entry(addr).or_default();
+
+        // connection that was up long enough ends the streak, one that was dropped right away continues it
+        let stayed_up = match (history.last_success, history.last_failure) {
+            (Some(success), Some(failure)) if failure > success => false,
+            (Some(success), _) => success.elapsed() >= config.min_uptime,
+            (None, _) => false,
+        };
+        if stayed_up {
+            history.failures = 0;
+        }
+        history.failures = history.failures.saturating_add(1);
+        history.total_failures += 1;
+        history.last_failure = Some(Instant::now());
+
+        if !sticky && history.failures > config.max_failures {
+            history.dead = true;
+            None
+        } else {
+            Some(config.delay(history.failures - 1, random))
+        }
+    };
+
+    let delay = match delay {
+        Some(d) => d,
+        None => {
+            protocol_state.emit(ProtocolEvent::ReconnectGaveUp { addr });
+            return;
+        }
+    };
+
+    protocol_state.emit(ProtocolEvent::Reconnecting { addr, delay });
+
+    let protocol_state = protocol_state.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(delay).await;
+
+        let command_sender = protocol_state.lock().await.command_sender.clone();
+        // processor is gone only when protocol is shutting down, nothing to retry then
+        let _ = command_sender.send(ProtocolCommand::ClientReconnect(addr)).await;
+    });
+}
@@ -1,16 +1,19 @@
 use std::net::{SocketAddr};
 use tokio::sync::mpsc::Receiver;
 use tokio::task::JoinHandle;
-use crate::core::client::start_client;
+use crate::core::client::{connect_indirectly, reconnect::schedule_reconnect, start_client};
+use crate::core::frames::CloseReason;
 use crate::core::stream::types::StreamAction;
+use crate::core::vivaldi::Coordinate;
 use crate::types::state::ProtocolState;
 
 pub enum ProtocolCommand {
     ClientConnect {
         targ_addr: SocketAddr,
-        src_to_targ_ping: u16,
+        targ_coordinate: Option<Coordinate>, // as `src_addr` told about it
         src_addr: SocketAddr,
     },
+    ClientReconnect(SocketAddr), // scheduled by backoff, see `reconnect::schedule_reconnect`
     #[allow(unused)]
     ClientDisconnect(SocketAddr),
 }
@@ -23,18 +26,50 @@ async fn process_command(
 
     while let Some(command) = command_receiver.recv().await {
         match command {
-            ProtocolCommand::ClientConnect { targ_addr, src_addr, src_to_targ_ping } => {
-                let h = start_client(
+            ProtocolCommand::ClientConnect { targ_addr, src_addr, targ_coordinate } => {
+                {
+                    let lock = protocol_state.lock().await;
+                    let is_dead = lock.history.get(&targ_addr).map(|h| h.dead).unwrap_or(false);
+                    if is_dead || lock.scores.is_banned(&targ_addr.ip()) || lock.streams.contains_key(&targ_addr) {
+                        continue;
+                    }
+                }
+
+                match start_client(
                     protocol_state.clone(),
                     targ_addr,
-                    Some((src_addr, src_to_targ_ping)),
-                ).await;
-                handles.extend(h);
+                    Some((src_addr, targ_coordinate)),
+                ).await {
+                    Ok(h) => handles.extend(h),
+                    // node could be behind NAT, it's still reachable through a relay or a punched hole
+                    Err(_) => {
+                        let protocol_state = protocol_state.clone();
+                        handles.push(tokio::spawn(async move {
+                            if connect_indirectly(&protocol_state, targ_addr, Some(src_addr)).await.is_err() {
+                                schedule_reconnect(&protocol_state, targ_addr).await;
+                            }
+                        }));
+                    }
+                }
+            }
+            ProtocolCommand::ClientReconnect(addr) => {
+                {
+                    let lock = protocol_state.lock().await;
+                    let is_dead = lock.history.get(&addr).map(|h| h.dead).unwrap_or(false);
+                    if is_dead || lock.scores.is_banned(&addr.ip()) || lock.streams.contains_key(&addr) {
+                        continue;
+                    }
+                }
+
+                match start_client(protocol_state.clone(), addr, None).await {
+                    Ok(h) => handles.extend(h),
+                    Err(_) => schedule_reconnect(&protocol_state, addr).await,
+                }
             }
             ProtocolCommand::ClientDisconnect(addr) => {
                 if let Some((channel, _)) = protocol_state.lock().await.streams.get(&addr) {
                     channel
-                        .send(StreamAction::InitiateDisconnect)
+                        .send(StreamAction::InitiateDisconnect(CloseReason::Unspecified))
                         .await
                         .expect("--Failed to send request to disconnect")
                 }
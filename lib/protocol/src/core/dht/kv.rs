@@ -0,0 +1,220 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::Instant;
+use anyhow::{bail, Result};
+use tokio::task::JoinSet;
+use crate::core::dht::{
+    closest_for, heard_from, local_contact, lookup, request,
+    message::{Contact, DhtMessage},
+    record::{merge_records, Record, StoreError},
+};
+use crate::core::identity::NodeId;
+use crate::core::peer_store::unix_now;
+use crate::core::scoring::{penalize, Misbehaviour};
+use crate::types::{
+    state::{ProtocolState, ProtocolStateInnerMut},
+    event::ProtocolEvent,
+};
+
+/// Record published by this node, kept to be stored again before the closest nodes forget it
+pub struct Published {
+    pub record: Record,
+    pub published_at: Instant,
+}
+
+pub(crate) fn answer_find_value(
+    protocol_state: &ProtocolState,
+    lock: &mut ProtocolStateInnerMut,
+    remote_addr: SocketAddr,
+    sender: Contact,
+    request_id: u64,
+    key: NodeId,
+) -> DhtMessage {
+    if sender.addr.ip() == remote_addr.ip() {
+        heard_from(protocol_state, lock, sender);
+    }
+
+    DhtMessage::Value {
+        sender: local_contact(protocol_state),
+        request_id,
+        records: lock.records.get(&key, unix_now()),
+        nodes: closest_for(protocol_state, lock, &key, &sender.id),
+    }
+}
+
+pub(crate) async fn answer_store(
+    protocol_state: &ProtocolState,
+    lock: &mut ProtocolStateInnerMut,
+    remote_addr: SocketAddr,
+    sender: Contact,
+    request_id: u64,
+    record: Box<Record>,
+) -> DhtMessage {
+    if sender.addr.ip() == remote_addr.ip() {
+        heard_from(protocol_state, lock, sender);
+    }
+
+    let config = &protocol_state.read().dht;
+    let res = lock.records.insert(
+        *record,
+        unix_now(),
+        config.max_ttl.as_secs(),
+        config.max_value_size,
+        config.max_records,
+    );
+    if let Err(StoreError::InvalidSignature) = res {
+        penalize(protocol_state, lock, remote_addr, Misbehaviour::InvalidSignature).await;
+    }
+
+    DhtMessage::Stored {
+        sender: local_contact(protocol_state),
+        request_id,
+        accepted: res.is_ok(),
+    }
+}
+
+/// Drops records that are not under the requested key or have invalid signature,
+/// penalizing the node that sent them
+pub(crate) async fn verify_records(
+    protocol_state: &ProtocolState,
+    lock: &mut ProtocolStateInnerMut,
+    addr: SocketAddr,
+    key: NodeId,
+    records: Vec<Record>,
+) -> Vec<Record> {
+    let total = records.len();
+    let valid = records
+        .into_iter()
+        .filter(|r| r.key == key && r.verify())
+        .collect::<Vec<_>>();
+
+    if valid.len() != total {
+        penalize(protocol_state, lock, addr, Misbehaviour::InvalidSignature).await;
+    }
+    valid
+}
+
+/// Stores the record at the `k` nodes closest to its key, returns how many of them accepted it
+async fn replicate(
+    protocol_state: &ProtocolState,
+    record: Record,
+) -> usize {
+    let (closest, _) = lookup(protocol_state, record.key, false).await;
+
+    let mut set = JoinSet::new();
+    for contact in closest {
+        let protocol_state = protocol_state.clone();
+        let record = record.clone();
+        set.spawn(async move {
+            let request_id = protocol_state.lock().await.state.next();
+            let answer = request(
+                &protocol_state,
+                contact.addr,
+                DhtMessage::Store {
+                    sender: local_contact(&protocol_state),
+                    request_id,
+                    record: Box::new(record),
+                },
+                true,
+            ).await;
+
+            matches!(
+                answer,
+                Ok(Some(DhtMessage::Stored { request_id: answer_id, accepted: true, .. })) if answer_id == request_id
+            )
+        });
+    }
+
+    let mut stored = 0;
+    while let Some(res) = set.join_next().await {
+        if let Ok(true) = res {
+            stored += 1;
+        }
+    }
+    stored
+}
+
+/// Stores own records again once `republish_interval` passed, and forgets expired records
+pub(crate) async fn republish(
+    protocol_state: &ProtocolState,
+) {
+    let interval = protocol_state.read().dht.republish_interval;
+    let now = unix_now();
+
+    let records = {
+        let lock = &mut *protocol_state.lock().await;
+        lock.records.remove_expired(now);
+        lock.published.retain(|_, p| !p.record.is_expired(now));
+
+        lock.published
+            .values_mut()
+            .filter(|p| p.published_at.elapsed() >= interval)
+            .map(|p| {
+                p.published_at = Instant::now();
+                p.record.clone()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    for record in records {
+        let key = record.key;
+        let stored = replicate(protocol_state, record).await;
+
+        protocol_state.emit(ProtocolEvent::RecordRepublished { key, stored });
+    }
+}
+
+impl ProtocolState {
+    /// Signs the value and stores it under the key at the nodes closest to the key.
+    /// Record is republished until `ttl` passes. Returns how many nodes accepted it.
+    pub async fn put(&self, key: NodeId, value: Vec<u8>, ttl: Duration) -> Result<usize> {
+        let config = &self.read().dht;
+        if value.len() > config.max_value_size {
+            bail!("Value is bigger than {} bytes", config.max_value_size)
+        }
+        if ttl > config.max_ttl || ttl.as_secs() == 0 {
+            bail!("Record has to live from 1 second up to {} seconds", config.max_ttl.as_secs())
+        }
+
+        let record = {
+            let lock = &mut *self.lock().await;
+            let now = unix_now();
+
+            // newer record has to win even if clock went back
+            let seq = match lock.published.get(&key) {
+                Some(p) => (p.record.seq + 1).max(now),
+                None => now,
+            };
+            let record = Record::new(&self.read().identity, key, value, seq, now + ttl.as_secs());
+
+            let _ = lock.records.insert(
+                record.clone(),
+                now,
+                config.max_ttl.as_secs(),
+                config.max_value_size,
+                config.max_records,
+            );
+            lock.published.insert(key, Published {
+                record: record.clone(),
+                published_at: Instant::now(),
+            });
+            record
+        };
+
+        Ok(replicate(self, record).await)
+    }
+
+    /// Stops republishing the record, it's still available until it expires
+    pub async fn unpublish(&self, key: NodeId) -> bool {
+        self.lock().await.published.remove(&key).is_some()
+    }
+
+    /// Latest valid records of each publisher stored under the key
+    pub async fn get(&self, key: NodeId) -> Vec<Record> {
+        let mut records = self.lock().await.records.get(&key, unix_now());
+
+        let (_, found) = lookup(self, key, true).await;
+        merge_records(&mut records, found, unix_now());
+        records
+    }
+}
@@ -0,0 +1,283 @@
+use std::net::SocketAddr;
+use std::vec::IntoIter;
+use anyhow::{bail, Context, Result};
+use crate::core::dht::record::Record;
+use crate::core::identity::{Identity, NodeId};
+use crate::utils::socket_addr_to_bytes::{socket_addr_from_bytes, socket_addr_to_bytes};
+
+const DHT_FIND_NODE:  u8 = 0;
+const DHT_NODES:      u8 = 1;
+const DHT_ROUTED:     u8 = 2;
+const DHT_STORE:      u8 = 3;
+const DHT_STORED:     u8 = 4;
+const DHT_FIND_VALUE: u8 = 5;
+const DHT_VALUE:      u8 = 6;
+const DHT_ONION:      u8 = 7;
+
+/// Node in the routing table - its id and address of its server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Contact {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+impl Contact {
+    pub const BYTES: usize = NodeId::BYTES + 6;
+
+    fn write(&self, buf: &mut Vec<u8>) -> Result<()> {
+        buf.extend(self.id.0);
+        buf.extend(socket_addr_to_bytes(self.addr)?);
+        Ok(())
+    }
+
+    fn read(iter: &mut IntoIter<u8>) -> Result<Self> {
+        let id = read_id(iter)?;
+        let addr = socket_addr_from_bytes(iter)?.context("contact requires an address")?;
+        Ok(Self { id, addr })
+    }
+}
+
+fn read_id(iter: &mut IntoIter<u8>) -> Result<NodeId> {
+    let mut id = [0; NodeId::BYTES];
+    for byte in id.iter_mut() {
+        *byte = iter.next().context("not enough bytes")?;
+    }
+    Ok(NodeId(id))
+}
+
+fn read_u64(iter: &mut IntoIter<u8>) -> Result<u64> {
+    let mut bytes = [0; 8];
+    for byte in bytes.iter_mut() {
+        *byte = iter.next().context("not enough bytes")?;
+    }
+    Ok(u64::from_be_bytes(bytes))
+}
+
+#[derive(Debug)]
+pub enum DhtMessage {
+    FindNode { // asks for the contacts closest to the target
+        sender: Contact,
+        request_id: u64,
+        target: NodeId,
+    },
+    Nodes { // answer to `FindNode`
+        sender: Contact,
+        request_id: u64,
+        nodes: Vec<Contact>,
+    },
+    Routed { // application data addressed to the specific node, forwarded closer to it on every hop
+        origin: NodeId,
+        signature: [u8; 64], // made by origin, hops can't send messages on behalf of others
+        target: NodeId,
+        id: u64,
+        hops_left: u8,
+        data: Vec<u8>,
+    },
+    Store { // asks to keep the record
+        sender: Contact,
+        request_id: u64,
+        record: Box<Record>,
+    },
+    Stored { // answer to `Store`
+        sender: Contact,
+        request_id: u64,
+        accepted: bool,
+    },
+    FindValue { // asks for records under the key
+        sender: Contact,
+        request_id: u64,
+        key: NodeId,
+    },
+    Value { // answer to `FindValue`, with contacts closest to the key to continue lookup
+        sender: Contact,
+        request_id: u64,
+        records: Vec<Record>,
+        nodes: Vec<Contact>,
+    },
+    Onion { // layer of an onion addressed to the hop, forwarded the same way as `Routed` but without origin
+        target: NodeId,
+        id: u64,
+        hops_left: u8,
+        data: Vec<u8>,
+    },
+}
+
+/// What origin of the routed message signs, hops left is changed on the way so it's not included
+pub fn routed_signed_bytes(target: &NodeId, id: u64, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(7 + NodeId::BYTES + 8 + data.len());
+    buf.extend(b"routed:");
+    buf.extend(target.0);
+    buf.extend(id.to_be_bytes());
+    buf.extend(data);
+    buf
+}
+
+impl DhtMessage {
+    /// Message from `identity` to the node with id `target`
+    pub fn routed(identity: &Identity, target: NodeId, id: u64, hops_left: u8, data: Vec<u8>) -> Self {
+        Self::Routed {
+            origin: identity.node_id(),
+            signature: identity.sign(&routed_signed_bytes(&target, id, &data)),
+            target,
+            id,
+            hops_left,
+            data,
+        }
+    }
+
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+
+        match self {
+            DhtMessage::FindNode { sender, request_id, target } => {
+                buf.push(DHT_FIND_NODE);
+                sender.write(&mut buf)?;
+                buf.extend(request_id.to_be_bytes());
+                buf.extend(target.0);
+            }
+            DhtMessage::Nodes { sender, request_id, nodes } => {
+                if nodes.len() > u8::MAX as usize {
+                    bail!("Too many nodes in one message")
+                }
+                buf.push(DHT_NODES);
+                sender.write(&mut buf)?;
+                buf.extend(request_id.to_be_bytes());
+                buf.push(nodes.len() as u8);
+                for node in nodes {
+                    node.write(&mut buf)?;
+                }
+            }
+            DhtMessage::Routed { origin, signature, target, id, hops_left, data } => {
+                buf.push(DHT_ROUTED);
+                buf.extend(origin.0);
+                buf.extend(signature);
+                buf.extend(target.0);
+                buf.extend(id.to_be_bytes());
+                buf.push(hops_left);
+                buf.extend(data);
+            }
+            DhtMessage::Store { sender, request_id, record } => {
+                buf.push(DHT_STORE);
+                sender.write(&mut buf)?;
+                buf.extend(request_id.to_be_bytes());
+                record.write(&mut buf)?;
+            }
+            DhtMessage::Stored { sender, request_id, accepted } => {
+                buf.push(DHT_STORED);
+                sender.write(&mut buf)?;
+                buf.extend(request_id.to_be_bytes());
+                buf.push(accepted as u8);
+            }
+            DhtMessage::FindValue { sender, request_id, key } => {
+                buf.push(DHT_FIND_VALUE);
+                sender.write(&mut buf)?;
+                buf.extend(request_id.to_be_bytes());
+                buf.extend(key.0);
+            }
+            DhtMessage::Value { sender, request_id, records, nodes } => {
+                if records.len() > u8::MAX as usize || nodes.len() > u8::MAX as usize {
+                    bail!("Too many entries in one message")
+                }
+                buf.push(DHT_VALUE);
+                sender.write(&mut buf)?;
+                buf.extend(request_id.to_be_bytes());
+                buf.push(records.len() as u8);
+                for record in records {
+                    record.write(&mut buf)?;
+                }
+                buf.push(nodes.len() as u8);
+                for node in nodes {
+                    node.write(&mut buf)?;
+                }
+            }
+            DhtMessage::Onion { target, id, hops_left, data } => {
+                buf.push(DHT_ONION);
+                buf.extend(target.0);
+                buf.extend(id.to_be_bytes());
+                buf.push(hops_left);
+                buf.extend(data);
+            }
+        }
+
+        Ok(buf)
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        let mut iter = bytes.into_iter();
+
+        let msg = match iter.next().context("missing dht message type")? {
+            DHT_FIND_NODE => {
+                let sender = Contact::read(&mut iter)?;
+                let request_id = read_u64(&mut iter)?;
+                let target = read_id(&mut iter)?;
+                Self::FindNode { sender, request_id, target }
+            }
+            DHT_NODES => {
+                let sender = Contact::read(&mut iter)?;
+                let request_id = read_u64(&mut iter)?;
+                let count = iter.next().context("not enough bytes")?;
+                let nodes = (0..count)
+                    .map(|_| Contact::read(&mut iter))
+                    .collect::<Result<Vec<_>>>()?;
+                Self::Nodes { sender, request_id, nodes }
+            }
+            DHT_ROUTED => {
+                let origin = read_id(&mut iter)?;
+                let mut signature = [0; 64];
+                for byte in signature.iter_mut() {
+                    *byte = iter.next().context("not enough bytes")?;
+                }
+                let target = read_id(&mut iter)?;
+                let id = read_u64(&mut iter)?;
+                let hops_left = iter.next().context("not enough bytes")?;
+                Self::Routed { origin, signature, target, id, hops_left, data: iter.by_ref().collect() }
+            }
+            DHT_STORE => {
+                let sender = Contact::read(&mut iter)?;
+                let request_id = read_u64(&mut iter)?;
+                let record = Box::new(Record::read(&mut iter)?);
+                Self::Store { sender, request_id, record }
+            }
+            DHT_STORED => {
+                let sender = Contact::read(&mut iter)?;
+                let request_id = read_u64(&mut iter)?;
+                let accepted = iter.next().context("not enough bytes")? != 0;
+                Self::Stored { sender, request_id, accepted }
+            }
+            DHT_FIND_VALUE => {
+                let sender = Contact::read(&mut iter)?;
+                let request_id = read_u64(&mut iter)?;
+                let key = read_id(&mut iter)?;
+                Self::FindValue { sender, request_id, key }
+            }
+            DHT_VALUE => {
+                let sender = Contact::read(&mut iter)?;
+                let request_id = read_u64(&mut iter)?;
+                let count = iter.next().context("not enough bytes")?;
+                let records = (0..count)
+                    .map(|_| Record::read(&mut iter))
+                    .collect::<Result<Vec<_>>>()?;
+                let count = iter.next().context("not enough bytes")?;
+                let nodes = (0..count)
+                    .map(|_| Contact::read(&mut iter))
+                    .collect::<Result<Vec<_>>>()?;
+                Self::Value { sender, request_id, records, nodes }
+            }
+            DHT_ONION => {
+                let target = read_id(&mut iter)?;
+                let id = read_u64(&mut iter)?;
+                let hops_left = iter.next().context("not enough bytes")?;
+                Self::Onion { target, id, hops_left, data: iter.by_ref().collect() }
+            }
+            _ => {
+                bail!("Unknown dht message type")
+            }
+        };
+
+        if iter.len() != 0 {
+            bail!("Unexpected bytes after dht message")
+        }
+
+        Ok(msg)
+    }
+}
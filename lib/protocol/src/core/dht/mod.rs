@@ -0,0 +1,608 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::Sender;
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::timeout;
+use crate::core::client::start_client;
+use crate::core::dht::{
+    message::{routed_signed_bytes, Contact, DhtMessage},
+    record::{merge_records, Record},
+};
+use crate::core::frames::{CloseReason, ProtocolMessage};
+use crate::core::identity::NodeId;
+use crate::core::onion;
+use crate::core::peer_store::unix_now;
+use crate::core::selection::pick_to_dial;
+use crate::core::stream::types::StreamAction;
+use crate::core::transport;
+use crate::types::{
+    state::{ProtocolState, ProtocolStateInnerMut},
+    event::{DialSource, DropReason, ProtocolEvent},
+    package::{AppPackage, RoutedPackage},
+};
+
+pub mod kv;
+pub mod message;
+pub mod record;
+pub mod routing;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DhtConfig {
+    pub k: usize, // size of the bucket and amount of nodes returned by lookup
+    pub alpha: usize, // parallel requests during lookup
+    #[serde(with = "crate::utils::duration_ms")]
+    pub refresh_interval: Duration, // bucket without lookups for that long is refreshed with a random lookup
+    #[serde(with = "crate::utils::duration_ms")]
+    pub stale_after: Duration, // oldest contact of the full bucket can be replaced after being silent that long
+    #[serde(with = "crate::utils::duration_ms")]
+    pub query_timeout: Duration,
+    pub max_hops: u8, // addressed message is dropped after that many forwards
+    #[serde(with = "crate::utils::duration_ms")]
+    pub republish_interval: Duration, // own records are stored again at the closest nodes that often
+    #[serde(with = "crate::utils::duration_ms")]
+    pub max_ttl: Duration, // records that live longer are refused
+    pub max_value_size: usize,
+    pub max_records: usize, // records kept for others
+}
+
+impl Default for DhtConfig {
+    fn default() -> Self {
+        Self {
+            k: 20,
+            alpha: 3,
+            refresh_interval: Duration::from_secs(15 * 60),
+            stale_after: Duration::from_secs(15 * 60),
+            query_timeout: Duration::from_secs(5),
+            max_hops: 16,
+            republish_interval: Duration::from_secs(60 * 60),
+            max_ttl: Duration::from_secs(24 * 60 * 60),
+            max_value_size: 1024,
+            max_records: 4096,
+        }
+    }
+}
+
+const MAINTENANCE_INTERVAL: u64 = 60;
+
+pub(crate) fn local_contact(protocol_state: &ProtocolState) -> Contact {
+    Contact {
+        id: protocol_state.read().identity.node_id(),
+        addr: protocol_state.read().server_addr,
+    }
+}
+
+/// Records the contact we've heard from directly and remembers its id for the stream, if any
+pub(crate) fn heard_from(
+    protocol_state: &ProtocolState,
+    lock: &mut ProtocolStateInnerMut,
+    contact: Contact,
+) {
+    lock.dht.insert(contact, protocol_state.read().dht.stale_after);
+    if let Some((_, metadata)) = lock.streams.get_mut(&contact.addr) {
+        metadata.node_id = Some(contact.id);
+    }
+}
+
+/// Answer to `FIND_NODE`. The sender is added to the routing table only if it claims
+/// the address on the same ip it's connected from, so it can't make us spam others.
+pub(crate) fn answer_find_node(
+    protocol_state: &ProtocolState,
+    lock: &mut ProtocolStateInnerMut,
+    remote_addr: SocketAddr,
+    sender: Contact,
+    request_id: u64,
+    target: NodeId,
+) -> DhtMessage {
+    if sender.addr.ip() == remote_addr.ip() {
+        heard_from(protocol_state, lock, sender);
+    }
+
+    DhtMessage::Nodes {
+        sender: local_contact(protocol_state),
+        request_id,
+        nodes: closest_for(protocol_state, lock, &target, &sender.id),
+    }
+}
+
+/// Contacts closest to the target to answer with, except the one who asks
+pub(crate) fn closest_for(
+    protocol_state: &ProtocolState,
+    lock: &ProtocolStateInnerMut,
+    target: &NodeId,
+    asking: &NodeId,
+) -> Vec<Contact> {
+    let k = protocol_state.read().dht.k;
+    lock
+        .dht
+        .closest(target, k + 1)
+        .into_iter()
+        .filter(|c| c.id != *asking)
+        .take(k)
+        .collect()
+}
+
+/// Message that is sent once the state lock is released, stream tasks need the lock to take theirs
+pub(crate) enum Outgoing {
+    Stream(Sender<StreamAction>, DhtMessage), // next hop is connected
+    Request(Contact, DhtMessage), // next hop is only known from the routing table
+    App(AppPackage),
+}
+
+impl Outgoing {
+    pub(crate) async fn send(self, protocol_state: &ProtocolState) {
+        match self {
+            Outgoing::Stream(channel, message) => {
+                // stream could be closing already
+                let _ = channel.send(StreamAction::Send(ProtocolMessage::Dht(message))).await;
+            }
+            Outgoing::Request(next_hop, message) => {
+                let protocol_state = protocol_state.clone();
+                tokio::spawn(async move {
+                    if request(&protocol_state, next_hop.addr, message, false).await.is_err() {
+                        protocol_state.lock().await.dht.remove(&next_hop.id);
+                    }
+                });
+            }
+            Outgoing::App(package) => protocol_state.deliver(package).await,
+        }
+    }
+}
+
+/// What is left to do after handling dht request
+pub(crate) enum Answer {
+    Reply(DhtMessage), // goes back to the party that asked
+    Pass(Outgoing), // message wasn't for us to answer
+    None,
+}
+
+/// Handles dht request and returns the answer to it, if there is any.
+/// `Err` means party sent something that is only expected as an answer.
+pub(crate) async fn answer(
+    protocol_state: &ProtocolState,
+    lock: &mut ProtocolStateInnerMut,
+    remote_addr: SocketAddr,
+    message: DhtMessage,
+) -> Result<Answer> {
+    match message {
+        DhtMessage::FindNode { sender, request_id, target } => {
+            Ok(Answer::Reply(answer_find_node(protocol_state, lock, remote_addr, sender, request_id, target)))
+        }
+        DhtMessage::FindValue { sender, request_id, key } => {
+            Ok(Answer::Reply(kv::answer_find_value(protocol_state, lock, remote_addr, sender, request_id, key)))
+        }
+        DhtMessage::Store { sender, request_id, record } => {
+            Ok(Answer::Reply(kv::answer_store(protocol_state, lock, remote_addr, sender, request_id, record).await))
+        }
+        DhtMessage::Routed { origin, signature, target, id, hops_left, data } => {
+            Ok(route(protocol_state, lock, origin, signature, target, id, hops_left, data).map_or(Answer::None, Answer::Pass))
+        }
+        DhtMessage::Onion { target, id, hops_left, data } => {
+            Ok(onion::read(protocol_state, lock, target, id, hops_left, data).map_or(Answer::None, Answer::Pass))
+        }
+        DhtMessage::Nodes { .. } | DhtMessage::Stored { .. } | DhtMessage::Value { .. } => {
+            bail!("Answer received without request")
+        }
+    }
+}
+
+/// Handles dht message that came as the first message of a connection instead of `CONN_INIT`.
+/// Returns the reply, connection is closed after it.
+pub(crate) async fn answer_query(
+    protocol_state: &ProtocolState,
+    remote_addr: SocketAddr,
+    message: DhtMessage,
+) -> Result<Option<DhtMessage>> {
+    let answer = {
+        let lock = &mut *protocol_state.lock().await;
+        answer(protocol_state, lock, remote_addr, message).await?
+    };
+    match answer {
+        Answer::Reply(reply) => Ok(Some(reply)),
+        Answer::Pass(outgoing) => {
+            outgoing.send(protocol_state).await;
+            Ok(None)
+        }
+        Answer::None => Ok(None),
+    }
+}
+
+/// Opens short-lived connection, sends a single message and waits for the answer if `expect_answer`
+pub(crate) async fn request(
+    protocol_state: &ProtocolState,
+    addr: SocketAddr,
+    message: DhtMessage,
+    expect_answer: bool,
+) -> Result<Option<DhtMessage>> {
+    let connect_timeout = protocol_state.read().timeouts.connect;
+    let query_timeout = protocol_state.read().dht.query_timeout;
+
+    timeout(query_timeout, async {
+        let mut stream = timeout(connect_timeout, transport::connect(protocol_state, addr))
+            .await
+            .context("Timed out connecting")?
+            .context("Failed to connect")?;
+        protocol_state.send_message(&mut stream, ProtocolMessage::Dht(message)).await?;
+
+        if !expect_answer {
+            let _ = stream.shutdown().await;
+            return Ok(None);
+        }
+
+        let (answer, _) = ProtocolMessage::from_stream(&mut stream)
+            .await?
+            .context("Connection closed without an answer")?;
+        let _ = stream.shutdown().await;
+
+        match answer {
+            ProtocolMessage::Dht(message) => Ok(Some(message)),
+            ProtocolMessage::ConnClosed(reason) => bail!("Connection refused - {:?}", reason),
+            _ => bail!("Unexpected answer"),
+        }
+    })
+        .await
+        .context("Timed out waiting for an answer")?
+}
+
+/// Asks a single node for the contacts closest to the target and, if `find_value`,
+/// for the records stored under it. Returns contact of that node as it is reachable,
+/// the contacts and valid records it answered with.
+async fn query_at(
+    protocol_state: &ProtocolState,
+    addr: SocketAddr,
+    target: NodeId,
+    find_value: bool,
+) -> Result<(Contact, Vec<Contact>, Vec<Record>)> {
+    let request_id = protocol_state.lock().await.state.next();
+    let sender = local_contact(protocol_state);
+
+    let message = if find_value {
+        DhtMessage::FindValue { sender, request_id, key: target }
+    } else {
+        DhtMessage::FindNode { sender, request_id, target }
+    };
+    let answer = request(protocol_state, addr, message, true).await?;
+
+    let (sender, nodes, records) = match answer {
+        Some(DhtMessage::Nodes { sender, request_id: answer_id, nodes }) if answer_id == request_id && !find_value => {
+            (sender, nodes, vec![])
+        }
+        Some(DhtMessage::Value { sender, request_id: answer_id, records, nodes }) if answer_id == request_id && find_value => {
+            (sender, nodes, records)
+        }
+        _ => bail!("Unexpected answer to the request"),
+    };
+
+    // we know for sure it is reachable at the address we've dialed
+    let contact = Contact {
+        id: sender.id,
+        addr,
+    };
+    let lock = &mut *protocol_state.lock().await;
+    heard_from(protocol_state, lock, contact);
+
+    let records = kv::verify_records(protocol_state, lock, addr, target, records).await;
+    Ok((contact, nodes, records))
+}
+
+/// Iterative lookup of the `k` nodes closest to the target.
+/// If `find_value`, also collects records stored under the target by the nodes on the way.
+pub(crate) async fn lookup(
+    protocol_state: &ProtocolState,
+    target: NodeId,
+    find_value: bool,
+) -> (Vec<Contact>, Vec<Record>) {
+    let config = &protocol_state.read().dht;
+    let local = local_contact(protocol_state);
+
+    let mut shortlist = {
+        let lock = &mut *protocol_state.lock().await;
+        lock.dht.touch(&target);
+        lock.dht.closest(&target, config.k)
+    };
+    let mut queried = HashSet::new();
+    let mut responded = vec![];
+    let mut records = vec![];
+
+    loop {
+        shortlist.sort_by(|a, b| target.cmp_distance(&a.id, &b.id));
+
+        // lookup is done once all of the closest nodes answered or failed
+        let batch = shortlist
+            .iter()
+            .take(config.k)
+            .filter(|c| !queried.contains(&c.id))
+            .take(config.alpha)
+            .cloned()
+            .collect::<Vec<_>>();
+        if batch.is_empty() {
+            break;
+        }
+
+        let mut set = JoinSet::new();
+        for contact in batch {
+            queried.insert(contact.id);
+
+            let protocol_state = protocol_state.clone();
+            set.spawn(async move {
+                let res = query_at(&protocol_state, contact.addr, target, find_value).await;
+                (contact, res)
+            });
+        }
+
+        while let Some(res) = set.join_next().await {
+            let (contact, res) = match res {
+                Ok(res) => res,
+                Err(_) => continue,
+            };
+
+            match res {
+                Ok((answered, nodes, found)) => {
+                    merge_records(&mut records, found, unix_now());
+
+                    if answered.id != contact.id {
+                        // someone else is listening on that address now
+                        protocol_state.lock().await.dht.remove(&contact.id);
+                        shortlist.retain(|c| c.id != contact.id);
+                        queried.insert(answered.id);
+                    }
+                    if !responded.contains(&answered) {
+                        responded.push(answered);
+                    }
+
+                    for node in nodes {
+                        if node.id != local.id && !shortlist.iter().any(|c| c.id == node.id) {
+                            shortlist.push(node);
+                        }
+                    }
+                }
+                Err(_) => {
+                    protocol_state.lock().await.dht.remove(&contact.id);
+                    shortlist.retain(|c| c.id != contact.id);
+                }
+            }
+        }
+    }
+
+    responded.sort_by(|a, b| target.cmp_distance(&a.id, &b.id));
+    responded.truncate(config.k);
+    (responded, records)
+}
+
+/// Iterative lookup of the `k` nodes closest to the target
+pub async fn find_node(
+    protocol_state: &ProtocolState,
+    target: NodeId,
+) -> Vec<Contact> {
+    lookup(protocol_state, target, false).await.0
+}
+
+/// Learns id of the freshly connected node and, if routing table is still small, joins the network
+/// by looking up our own id.
+pub(crate) fn introduce(
+    protocol_state: &ProtocolState,
+    addr: SocketAddr,
+) {
+    let protocol_state = protocol_state.clone();
+    tokio::spawn(async move {
+        let local = protocol_state.read().identity.node_id();
+        if query_at(&protocol_state, addr, local, false).await.is_err() {
+            return; // stays out of the routing table, stream works without it
+        }
+
+        let small = protocol_state.lock().await.dht.len() < protocol_state.read().dht.k;
+        if small {
+            find_node(&protocol_state, local).await;
+        }
+    });
+}
+
+/// Picks whether addressed message goes to the application or to the known node that is closer
+/// to the target than we are. Returns `None` if there is no such node or message is dropped.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn route(
+    protocol_state: &ProtocolState,
+    lock: &ProtocolStateInnerMut,
+    origin: NodeId,
+    signature: [u8; 64],
+    target: NodeId,
+    id: u64,
+    hops_left: u8,
+    data: Vec<u8>,
+) -> Option<Outgoing> {
+    let local = protocol_state.read().identity.node_id();
+    if target == local {
+        // any hop could have put somebody else's id as origin
+        if !origin.verify(&routed_signed_bytes(&target, id, &data), &signature) {
+            protocol_state.emit(ProtocolEvent::MessageDropped { reason: DropReason::Forged(origin) });
+            return None;
+        }
+        return Some(Outgoing::App(AppPackage::Routed(RoutedPackage {
+            from: origin,
+            msg: data,
+        })));
+    }
+    if hops_left == 0 {
+        return None;
+    }
+
+    let message = DhtMessage::Routed {
+        origin,
+        signature,
+        target,
+        id,
+        hops_left: hops_left - 1,
+        data,
+    };
+    forward(protocol_state, lock, target, message)
+}
+
+/// Picks the known node that is closer to the target than we are to send the message to.
+/// Returns `None` if there is no such node.
+pub(crate) fn forward(
+    protocol_state: &ProtocolState,
+    lock: &ProtocolStateInnerMut,
+    target: NodeId,
+    message: DhtMessage,
+) -> Option<Outgoing> {
+    let local = protocol_state.read().identity.node_id();
+
+    // connected nodes are preferred, otherwise routing table is used
+    let connected = lock
+        .streams
+        .iter()
+        .filter_map(|(addr, (_, metadata))| metadata.node_id.map(|id| Contact { id, addr: *addr }));
+    let next_hop = lock
+        .dht
+        .closest(&target, protocol_state.read().dht.k)
+        .into_iter()
+        .chain(connected)
+        .filter(|c| target.cmp_distance(&c.id, &local).is_lt())
+        .min_by(|a, b| target.cmp_distance(&a.id, &b.id));
+
+    let next_hop = match next_hop {
+        Some(c) => c,
+        None => {
+            protocol_state.emit(ProtocolEvent::MessageDropped { reason: DropReason::NoRoute(target) });
+            return None;
+        }
+    };
+
+    let stream = lock
+        .streams
+        .get(&next_hop.addr)
+        .filter(|(_, metadata)| metadata.node_id == Some(next_hop.id));
+    match stream {
+        Some((channel, _)) => Some(Outgoing::Stream(channel.clone(), message)),
+        None => Some(Outgoing::Request(next_hop, message)),
+    }
+}
+
+async fn maintain(
+    protocol_state: &ProtocolState,
+    since_self_lookup: &mut Duration,
+) {
+    let config = &protocol_state.read().dht;
+    let local = protocol_state.read().identity.node_id();
+
+    kv::republish(protocol_state).await;
+
+    let targets = {
+        let lock = &mut *protocol_state.lock().await;
+        if lock.dht.is_empty() {
+            return;
+        }
+
+        let stale = lock.dht.stale_buckets(config.refresh_interval);
+        let table = &lock.dht;
+        let state = &mut lock.state;
+        stale
+            .into_iter()
+            .map(|i| table.random_id_in_bucket(i, &mut || state.next()))
+            .collect::<Vec<_>>()
+    };
+
+    if *since_self_lookup >= config.refresh_interval {
+        *since_self_lookup = Duration::ZERO;
+        find_node(protocol_state, local).await;
+    }
+    for target in targets {
+        find_node(protocol_state, target).await;
+    }
+
+    // routing table is the source of new peers once we've lost some
+    let candidates = {
+        let lock = &mut *protocol_state.lock().await;
+
+        let contacts = lock.dht
+            .closest(&local, config.k)
+            .into_iter()
+            .filter(|c| {
+                let is_dead = lock.history.get(&c.addr).map(|h| h.dead).unwrap_or(false);
+                !is_dead && !lock.streams.contains_key(&c.addr) && !lock.scores.is_banned(&c.addr.ip())
+            })
+            .collect::<Vec<_>>();
+
+        let picked = pick_to_dial(protocol_state, lock, contacts.iter().map(|c| c.addr).collect());
+        contacts
+            .into_iter()
+            .filter(|c| picked.contains(&c.addr))
+            .collect::<Vec<_>>()
+    };
+    for contact in candidates {
+        protocol_state.emit(ProtocolEvent::Dialing {
+            addr: contact.addr,
+            source: DialSource::RoutingTable,
+        });
+
+        // routing table doesn't guarantee anything, failed contact is removed by the next lookup
+        let _ = start_client(protocol_state.clone(), contact.addr, None).await;
+    }
+}
+
+/// Refreshes buckets that had no lookups for a while, republishes own records
+/// and dials nodes from the routing table if there are not enough connections
+pub fn dht_maintenance(
+    protocol_state: ProtocolState,
+) -> [JoinHandle<()>; 1] {
+    let handle = tokio::spawn(async move {
+        let mut since_self_lookup = Duration::ZERO;
+        loop {
+            tokio::time::sleep(Duration::from_secs(MAINTENANCE_INTERVAL)).await;
+            since_self_lookup += Duration::from_secs(MAINTENANCE_INTERVAL);
+
+            maintain(&protocol_state, &mut since_self_lookup).await;
+        }
+    });
+
+    [handle]
+}
+
+impl ProtocolState {
+    pub fn node_id(&self) -> NodeId {
+        self.read().identity.node_id()
+    }
+
+    /// Looks up the nodes closest to the target in the network
+    pub async fn find_node(&self, target: NodeId) -> Vec<Contact> {
+        find_node(self, target).await
+    }
+
+    /// Sends data to the node with the given id, forwarding it through nodes closer to it.
+    /// There is no acknowledgement, `Err` only means there is no route at the moment.
+    pub async fn send_to(&self, target: NodeId, data: Vec<u8>) -> Result<()> {
+        if target == self.node_id() {
+            bail!("Can't send message to itself")
+        }
+
+        let outgoing = {
+            let lock = &mut *self.lock().await;
+            let id = lock.state.next();
+            let signature = self.read().identity.sign(&routed_signed_bytes(&target, id, &data));
+            route(self, lock, self.node_id(), signature, target, id, self.read().dht.max_hops, data)
+        };
+        match outgoing {
+            Some(outgoing) => outgoing.send(self).await,
+            None => bail!("No route to {}", target),
+        }
+        Ok(())
+    }
+}
+
+/// Closes the one-shot dht connection, telling why if it's refused
+pub(crate) async fn close_query(
+    protocol_state: &ProtocolState,
+    stream: &mut (impl AsyncWrite + Unpin),
+    answer: Option<DhtMessage>,
+) {
+    let message = match answer {
+        Some(answer) => ProtocolMessage::Dht(answer),
+        None => ProtocolMessage::ConnClosed(CloseReason::Unspecified),
+    };
+    let _ = protocol_state.send_message(stream, message).await;
+    let _ = stream.shutdown().await;
+}
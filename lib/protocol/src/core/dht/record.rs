@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+use std::vec::IntoIter;
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use crate::core::identity::{Identity, NodeId};
+
+/// Key of the record named `name`, so that apps can agree on keys without knowing them up front
+pub fn record_key(name: &[u8]) -> NodeId {
+    NodeId(Sha256::digest(name).into())
+}
+
+/// Value stored in the dht. Each key can hold one record per publisher,
+/// newer record of the same publisher replaces the older one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub key: NodeId,
+    pub value: Vec<u8>,
+    pub publisher: NodeId, // public key the record is signed with
+    pub seq: u64, // version of the record, bigger is newer
+    pub expires_at: u64, // unix seconds
+    pub signature: [u8; 64],
+}
+
+impl Record {
+    fn signed_bytes(key: &NodeId, value: &[u8], seq: u64, expires_at: u64) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(7 + NodeId::BYTES + 16 + value.len());
+        buf.extend(b"record:");
+        buf.extend(key.0);
+        buf.extend(seq.to_be_bytes());
+        buf.extend(expires_at.to_be_bytes());
+        buf.extend(value);
+        buf
+    }
+
+    pub fn new(identity: &Identity, key: NodeId, value: Vec<u8>, seq: u64, expires_at: u64) -> Self {
+        let signature = identity.sign(&Self::signed_bytes(&key, &value, seq, expires_at));
+
+        Self {
+            key,
+            value,
+            publisher: identity.node_id(),
+            seq,
+            expires_at,
+            signature,
+        }
+    }
+
+    pub fn verify(&self) -> bool {
+        self.publisher.verify(&Self::signed_bytes(&self.key, &self.value, self.seq, self.expires_at), &self.signature)
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expires_at <= now
+    }
+
+    pub(crate) fn write(&self, buf: &mut Vec<u8>) -> Result<()> {
+        if self.value.len() > u16::MAX as usize {
+            bail!("Record value is too big")
+        }
+        buf.extend(self.key.0);
+        buf.extend(self.publisher.0);
+        buf.extend(self.seq.to_be_bytes());
+        buf.extend(self.expires_at.to_be_bytes());
+        buf.extend(self.signature);
+        buf.extend((self.value.len() as u16).to_be_bytes());
+        buf.extend(&self.value);
+        Ok(())
+    }
+
+    pub(crate) fn read(iter: &mut IntoIter<u8>) -> Result<Self> {
+        let mut next = |n: usize| -> Result<Vec<u8>> {
+            let bytes = iter.by_ref().take(n).collect::<Vec<_>>();
+            if bytes.len() != n {
+                bail!("not enough bytes")
+            }
+            Ok(bytes)
+        };
+
+        let key = NodeId(next(NodeId::BYTES)?.try_into().expect("checked length"));
+        let publisher = NodeId(next(NodeId::BYTES)?.try_into().expect("checked length"));
+        let seq = u64::from_be_bytes(next(8)?.try_into().expect("checked length"));
+        let expires_at = u64::from_be_bytes(next(8)?.try_into().expect("checked length"));
+        let signature = next(64)?.try_into().expect("checked length");
+        let len = u16::from_be_bytes(next(2)?.try_into().expect("checked length"));
+        let value = next(len as usize).context("record value is cut")?;
+
+        Ok(Self {
+            key,
+            value,
+            publisher,
+            seq,
+            expires_at,
+            signature,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreError {
+    InvalidSignature,
+    Expired, // already expired or expires too far in the future
+    TooBig,
+    Full,
+    Outdated, // there is newer record from the same publisher
+}
+
+/// Records this node keeps for others
+pub struct RecordStore {
+    records: HashMap<NodeId, Vec<Record>>, // key -> one record per publisher
+    count: usize,
+}
+
+impl RecordStore {
+    pub const MAX_PUBLISHERS_PER_KEY: usize = 16;
+
+    pub fn new() -> Self {
+        Self {
+            records: HashMap::new(),
+            count: 0,
+        }
+    }
+
+    pub fn insert(
+        &mut self,
+        record: Record,
+        now: u64,
+        max_ttl: u64,
+        max_value_size: usize,
+        max_records: usize,
+    ) -> Result<(), StoreError> {
+        if record.value.len() > max_value_size {
+            return Err(StoreError::TooBig);
+        }
+        if record.is_expired(now) || record.expires_at > now.saturating_add(max_ttl) {
+            return Err(StoreError::Expired);
+        }
+        // checked last since it's the most expensive
+        if !record.verify() {
+            return Err(StoreError::InvalidSignature);
+        }
+
+        let records = self.records.get_mut(&record.key);
+        let publishers = records.as_ref().map(|r| r.len()).unwrap_or(0);
+        if let Some(existing) = records.and_then(|r| r.iter_mut().find(|r| r.publisher == record.publisher)) {
+            if existing.seq >= record.seq {
+                return if *existing == record { Ok(()) } else { Err(StoreError::Outdated) };
+            }
+            *existing = record;
+            return Ok(());
+        }
+
+        if publishers >= Self::MAX_PUBLISHERS_PER_KEY || self.count >= max_records {
+            return Err(StoreError::Full);
+        }
+        // key gets an entry only once there is a record to keep under it
+        self.records.entry(record.key).or_default().push(record);
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &NodeId, now: u64) -> Vec<Record> {
+        self.records
+            .get(key)
+            .map(|records| records.iter().filter(|r| !r.is_expired(now)).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn remove_expired(&mut self, now: u64) {
+        for records in self.records.values_mut() {
+            records.retain(|r| !r.is_expired(now));
+        }
+        self.records.retain(|_, records| !records.is_empty());
+        self.count = self.records.values().map(|r| r.len()).sum();
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Keys with at least one record under them
+    pub fn keys(&self) -> usize {
+        self.records.len()
+    }
+}
+
+impl Default for RecordStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Keeps the newest record of each publisher, dropping expired ones. Records have to be verified already
+pub fn merge_records(into: &mut Vec<Record>, records: Vec<Record>, now: u64) {
+    for record in records {
+        if record.is_expired(now) {
+            continue;
+        }
+        match into.iter_mut().find(|r| r.publisher == record.publisher) {
+            Some(existing) => {
+                if existing.seq < record.seq {
+                    *existing = record;
+                }
+            }
+            None => into.push(record),
+        }
+    }
+}
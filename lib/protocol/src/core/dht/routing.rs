@@ -0,0 +1,149 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::core::dht::message::Contact;
+use crate::core::identity::NodeId;
+
+struct Entry {
+    contact: Contact,
+    last_seen: Instant,
+}
+
+struct Bucket {
+    entries: VecDeque<Entry>, // least recently seen first
+    refreshed_at: Instant, // last time lookup touched this bucket
+}
+
+/// Kademlia routing table - up to `k` contacts per each distance range from the local node
+pub struct RoutingTable {
+    local: NodeId,
+    k: usize,
+    buckets: Vec<Bucket>,
+}
+
+impl RoutingTable {
+    pub fn new(local: NodeId, k: usize) -> Self {
+        let now = Instant::now();
+        Self {
+            local,
+            k,
+            buckets: (0..NodeId::BITS)
+                .map(|_| Bucket {
+                    entries: VecDeque::new(),
+                    refreshed_at: now,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn local(&self) -> NodeId {
+        self.local
+    }
+
+    /// Adds the contact that we've just heard from or moves it to the tail of its bucket.
+    /// If bucket is full, the new contact replaces the oldest one only if that one went silent
+    /// for longer than `stale_after`, since nodes online for a long time tend to stay online.
+    pub fn insert(&mut self, contact: Contact, stale_after: Duration) -> bool {
+        let index = match self.local.bucket_index(&contact.id) {
+            Some(i) => i,
+            None => return false, // that's us
+        };
+        let k = self.k;
+        let bucket = &mut self.buckets[index];
+
+        if let Some(pos) = bucket.entries.iter().position(|e| e.contact.id == contact.id) {
+            let mut entry = bucket.entries.remove(pos).expect("position is valid");
+            entry.contact = contact; // node could have moved
+            entry.last_seen = Instant::now();
+            bucket.entries.push_back(entry);
+            return true;
+        }
+
+        if bucket.entries.len() >= k {
+            let oldest_is_stale = bucket
+                .entries
+                .front()
+                .map(|e| e.last_seen.elapsed() > stale_after)
+                .unwrap_or(false);
+            if !oldest_is_stale {
+                return false;
+            }
+            bucket.entries.pop_front();
+        }
+
+        bucket.entries.push_back(Entry {
+            contact,
+            last_seen: Instant::now(),
+        });
+        true
+    }
+
+    pub fn remove(&mut self, id: &NodeId) {
+        if let Some(index) = self.local.bucket_index(id) {
+            self.buckets[index].entries.retain(|e| e.contact.id != *id);
+        }
+    }
+
+    pub fn get(&self, id: &NodeId) -> Option<Contact> {
+        let index = self.local.bucket_index(id)?;
+        self.buckets[index]
+            .entries
+            .iter()
+            .find(|e| e.contact.id == *id)
+            .map(|e| e.contact)
+    }
+
+    /// Up to `count` contacts closest to the target by XOR metric
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Contact> {
+        let mut all = self
+            .buckets
+            .iter()
+            .flat_map(|b| b.entries.iter().map(|e| e.contact))
+            .collect::<Vec<_>>();
+        all.sort_by(|a, b| target.cmp_distance(&a.id, &b.id));
+        all.truncate(count);
+        all
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|b| b.entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Marks bucket of the target as refreshed, should be called on every lookup
+    pub fn touch(&mut self, target: &NodeId) {
+        if let Some(index) = self.local.bucket_index(target) {
+            self.buckets[index].refreshed_at = Instant::now();
+        }
+    }
+
+    /// Indexes of non-empty buckets that weren't touched by lookups for longer than `interval`
+    pub fn stale_buckets(&self, interval: Duration) -> Vec<usize> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| !b.entries.is_empty() && b.refreshed_at.elapsed() > interval)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Id that falls into the bucket with the given index, lower bits are taken from `random`
+    pub fn random_id_in_bucket(&self, index: usize, random: &mut impl FnMut() -> u64) -> NodeId {
+        let mut id = self.local.0;
+        let byte = NodeId::BYTES - 1 - index / 8;
+        let bit = index % 8;
+
+        // flip the bit that defines the bucket and randomize everything below it
+        id[byte] ^= 1 << bit;
+        let mask = (1_u16 << bit) as u8 - 1;
+        id[byte] = (id[byte] & !mask) | (random() as u8 & mask);
+        for b in id.iter_mut().skip(byte + 1) {
+            *b = random() as u8;
+        }
+
+        NodeId(id)
+    }
+}
@@ -1,8 +1,13 @@
+use std::io::ErrorKind;
 use std::net::SocketAddr;
-use anyhow::{bail, Result};
-use tokio::net::TcpStream;
-use tokio::io::AsyncReadExt;
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use crate::core::dht::message::DhtMessage;
+use crate::core::nat::NatMessage;
 use crate::core::node_info::NodeInfo;
+use crate::core::pex::PexEntry;
+use crate::core::relay::RelayMessage;
+use crate::core::vivaldi::Coordinate;
 use crate::utils::socket_addr_to_bytes::{socket_addr_from_bytes, socket_addr_to_bytes};
 
 const PROT_OPCODE_CONTINUATION: u8 = 0b0000; // received frame is a continuation of previous unfinished frame
@@ -12,29 +17,87 @@ const PROT_OPCODE_PING:         u8 = 0b0011; // checking if connection is still
 const PROT_OPCODE_PONG:         u8 = 0b0100; // answer if connection is still alive
 const PROT_OPCODE_DATA:         u8 = 0b0101; // frame contains application data
 const PROT_OPCODE_NODE_INFO:    u8 = 0b0110; // information about other nodes client chooses to connect/disconnect/etc.
+const PROT_OPCODE_DHT:          u8 = 0b0111; // routing table lookups and messages addressed by node id
+const PROT_OPCODE_PEX:          u8 = 0b1000; // exchange of neighbour lists
+const PROT_OPCODE_NAT:          u8 = 0b1001; // observed addresses and hole punching through a relay
+const PROT_OPCODE_RELAY:        u8 = 0b1010; // circuits to unreachable nodes through a relay
 
-pub enum ProtocolBufferType {
-    ConnInit,
-    Data,
-    NodeInfo,
-    Pong,
+const PEX_REQUEST:  u8 = 0;
+const PEX_RESPONSE: u8 = 1;
+
+/// Why party closes the connection, sent as the only byte of `CONN_CLOSED` payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    Unspecified = 0,
+    HandshakeTimeout = 1, // connection didn't finish handshake in time
+    TooManyConnections = 2, // server is full or there are too many connections from the same ip or subnet
+    RateLimited = 3, // server accepts too many connections at the moment, try again later
+    Banned = 4, // party misbehaved too much and is not welcome anymore
+    ProtocolViolation = 5, // party sent something that can't be parsed or is not expected
 }
+
+impl CloseReason {
+    pub fn from_byte(byte: u8) -> Self {
+        match byte {
+            1 => Self::HandshakeTimeout,
+            2 => Self::TooManyConnections,
+            3 => Self::RateLimited,
+            4 => Self::Banned,
+            5 => Self::ProtocolViolation,
+            _ => Self::Unspecified,
+        }
+    }
+}
+
 pub enum ProtocolMessage {
     ConnInit { // maybe there will be more info
         server_addr: SocketAddr,
     },
-    ConnClosed,
+    ConnClosed(CloseReason),
     Ping,
-    Pong(Option<NodeInfo>),
+    Pong(Option<Coordinate>), // coordinate of the node answering
     Data(u64, Vec<u8>),
-    NodeStatus(NodeInfo)
+    NodeStatus(NodeInfo, Option<Coordinate>), // coordinate of the node it tells about, if known
+    Dht(DhtMessage),
+    PexRequest(u8), // max amount of entries to return
+    PexResponse(Vec<PexEntry>),
+    Nat(NatMessage),
+    Relay(RelayMessage),
 }
 
 impl ProtocolMessage {
-    pub const FRAME_SIZE: usize = 256;
-    const PAYLOAD_SIZE: usize = Self::FRAME_SIZE - 1;
+    pub const FRAME_SIZE: usize = 257; // largest possible, length of the payload is a single byte
+    pub const MIN_FRAME_SIZE: usize = 3; // header, length and a byte of payload
+    pub const MAX_MESSAGE_SIZE: usize = 1 << 20; // payload of all frames of a message together
+
+    /// Name of the message type, for diagnostics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ProtocolMessage::ConnInit { .. } => "conn_init",
+            ProtocolMessage::ConnClosed(_) => "conn_closed",
+            ProtocolMessage::Ping => "ping",
+            ProtocolMessage::Pong(_) => "pong",
+            ProtocolMessage::Data(..) => "data",
+            ProtocolMessage::NodeStatus(..) => "node_status",
+            ProtocolMessage::Dht(_) => "dht",
+            ProtocolMessage::PexRequest(_) => "pex_request",
+            ProtocolMessage::PexResponse(_) => "pex_response",
+            ProtocolMessage::Nat(_) => "nat",
+            ProtocolMessage::Relay(_) => "relay",
+        }
+    }
 
     pub fn into_frames(self) -> Result<Vec<Vec<u8>>> {
+        self.into_frames_of(Self::FRAME_SIZE)
+    }
+
+    /// Splits the message into frames no larger than `frame_size`, header included
+    pub fn into_frames_of(self, frame_size: usize) -> Result<Vec<Vec<u8>>> {
+        if !(Self::MIN_FRAME_SIZE..=Self::FRAME_SIZE).contains(&frame_size) {
+            bail!("Frame size has to be between {} and {}", Self::MIN_FRAME_SIZE, Self::FRAME_SIZE)
+        }
+        let payload_size = frame_size - 2; // header and length
+
         let mut buf = vec![];
 
         let opcode = match self {
@@ -44,24 +107,30 @@ impl ProtocolMessage {
                 );
                 PROT_OPCODE_CONN_INIT
             }
-            ProtocolMessage::ConnClosed => {
+            ProtocolMessage::ConnClosed(reason) => {
+                buf.push(reason as u8);
                 PROT_OPCODE_CONN_CLOSED
             }
             ProtocolMessage::Ping => {
                 PROT_OPCODE_PING
             }
-            ProtocolMessage::Pong(node_info) => {
-                if let Some(node_info) = node_info {
+            ProtocolMessage::Pong(coordinate) => {
+                if let Some(coordinate) = coordinate {
                     buf.extend(
-                        node_info.into_bytes()?
+                        coordinate.into_bytes()
                     );
                 }
                 PROT_OPCODE_PONG
             }
-            ProtocolMessage::NodeStatus(node_info) => {
+            ProtocolMessage::NodeStatus(node_info, coordinate) => {
                 buf.extend(
                     node_info.into_bytes()?
                 );
+                if let Some(coordinate) = coordinate {
+                    buf.extend(
+                        coordinate.into_bytes()
+                    );
+                }
                 PROT_OPCODE_NODE_INFO
             }
             ProtocolMessage::Data(id, bytes) => {
@@ -69,32 +138,73 @@ impl ProtocolMessage {
                 buf.extend(bytes);
                 PROT_OPCODE_DATA
             }
+            ProtocolMessage::Dht(message) => {
+                buf.extend(
+                    message.into_bytes()?
+                );
+                PROT_OPCODE_DHT
+            }
+            ProtocolMessage::PexRequest(max) => {
+                buf.push(PEX_REQUEST);
+                buf.push(max);
+                PROT_OPCODE_PEX
+            }
+            ProtocolMessage::PexResponse(entries) => {
+                if entries.len() > u8::MAX as usize {
+                    bail!("Too many pex entries in one message")
+                }
+                buf.push(PEX_RESPONSE);
+                buf.push(entries.len() as u8);
+                for entry in entries {
+                    buf.extend(
+                        entry.into_bytes()?
+                    );
+                }
+                PROT_OPCODE_PEX
+            }
+            ProtocolMessage::Nat(message) => {
+                buf.extend(
+                    message.into_bytes()?
+                );
+                PROT_OPCODE_NAT
+            }
+            ProtocolMessage::Relay(message) => {
+                buf.extend(
+                    message.into_bytes()?
+                );
+                PROT_OPCODE_RELAY
+            }
         };
 
+        if buf.len() > Self::MAX_MESSAGE_SIZE {
+            bail!("Message is larger than {} bytes", Self::MAX_MESSAGE_SIZE)
+        }
+
         let len = buf.len();
         let mut start = 0;
 
-        let mut result = Vec::with_capacity(len / Self::FRAME_SIZE + 1);
+        let mut result = Vec::with_capacity(len / payload_size + 1);
 
         if len == 0 {
-            result.push(vec![1 << 7 | opcode])
+            result.push(vec![1 << 7 | opcode, 0])
         } else {
-            for payload_chunk in buf.chunks(Self::PAYLOAD_SIZE) {
-                start += Self::PAYLOAD_SIZE;
+            for payload_chunk in buf.chunks(payload_size) {
+                start += payload_size;
 
                 let fin = if start < len {
                     0
                 } else {
                     1
                 };
-                let opcode = if result.len() > 0 {
+                let opcode = if !result.is_empty() {
                     PROT_OPCODE_CONTINUATION
                 } else {
                     opcode
                 };
 
-                let mut result_chunk = Vec::with_capacity(Self::FRAME_SIZE);
+                let mut result_chunk = Vec::with_capacity(frame_size);
                 result_chunk.push(fin << 7 | opcode);
+                result_chunk.push(payload_chunk.len() as u8);
                 result_chunk.extend_from_slice(payload_chunk);
 
                 result.push(result_chunk);
@@ -108,126 +218,224 @@ impl ProtocolMessage {
         Ok(result)
     }
 
+    fn from_payload(
+        opcode: u8,
+        buf: Vec<u8>,
+    ) -> Result<Self> {
+        let msg = match opcode {
+            PROT_OPCODE_CONN_INIT => {
+                let mut iter = buf.into_iter();
+                let server_addr = socket_addr_from_bytes(&mut iter)
+                    .context("---Failed to parse buffer")?
+                    .context("---Address is required for this opcode")?;
+                Self::ConnInit { server_addr }
+            }
+            PROT_OPCODE_DATA => {
+                if buf.len() < 8 {
+                    bail!("Data frame is too short to contain an id")
+                }
+                let (id, data) = buf.split_at(8);
+                let id = u64::from_be_bytes(id.try_into().expect("checked length above"));
+                Self::Data(id, data.to_vec())
+            }
+            PROT_OPCODE_NODE_INFO => {
+                if buf.len() < NodeInfo::BYTES {
+                    bail!("Node status frame is too short to contain NodeInfo")
+                }
+                let (info, coordinate) = buf.split_at(NodeInfo::BYTES);
+                let another_node = NodeInfo::from_bytes(info.to_vec())?.context("opcode requires NodeInfo")?;
+                let coordinate = match coordinate.is_empty() {
+                    true => None,
+                    false => Some(Coordinate::from_bytes(coordinate)?),
+                };
+                Self::NodeStatus(another_node, coordinate)
+            }
+            PROT_OPCODE_PONG => {
+                if buf.len() == Coordinate::BYTES {
+                    Self::Pong(Some(Coordinate::from_bytes(&buf)?))
+                } else {
+                    Self::Pong(None)
+                }
+            }
+            PROT_OPCODE_CONN_CLOSED => {
+                // reason is optional
+                Self::ConnClosed(CloseReason::from_byte(buf.first().cloned().unwrap_or(0)))
+            }
+            PROT_OPCODE_PING => {
+                Self::Ping
+            }
+            PROT_OPCODE_DHT => {
+                Self::Dht(DhtMessage::from_bytes(buf)?)
+            }
+            PROT_OPCODE_PEX => {
+                match (buf.first(), buf.get(1)) {
+                    (Some(&PEX_REQUEST), Some(&max)) if buf.len() == 2 => Self::PexRequest(max),
+                    (Some(&PEX_RESPONSE), Some(&count)) => {
+                        let entries = &buf[2..];
+                        if entries.len() != count as usize * PexEntry::BYTES {
+                            bail!("Pex response length doesn't match the count")
+                        }
+                        let entries = entries
+                            .chunks(PexEntry::BYTES)
+                            .map(|chunk| PexEntry::from_bytes(chunk.to_vec()))
+                            .collect::<Result<Vec<_>>>()?;
+                        Self::PexResponse(entries)
+                    }
+                    _ => bail!("Malformed pex message"),
+                }
+            }
+            PROT_OPCODE_NAT => {
+                Self::Nat(NatMessage::from_bytes(buf)?)
+            }
+            PROT_OPCODE_RELAY => {
+                Self::Relay(RelayMessage::from_bytes(buf)?)
+            }
+            _ => {
+                bail!("Unknown opcode")
+            }
+        };
+
+        Ok(msg)
+    }
+
+    /// Reads exactly one message, nothing past it is consumed from the stream.
+    /// Not cancel safe, use `FrameReader` where reading can be interrupted.
+    /// Returns `Err` with `std::io::Error` inside if stream is broken,
+    /// any other error means party sent something that violates protocol
     pub async fn from_stream(
-        stream: &mut TcpStream,
+        stream: &mut (impl AsyncRead + Unpin),
     ) -> Result<Option<(Self, usize)>> {
-        let mut buf = Vec::new();
-        let mut buf_type = ProtocolBufferType::Data;
-
-        let mut frame = [0; Self::FRAME_SIZE];
+        let mut partial = PartialMessage::default();
+        let mut payload = [0; Self::FRAME_SIZE - 2];
 
-        let mut frames_count = 0;
         loop {
-            frames_count += 1;
+            let mut header = [0; 2];
+            match stream.read_exact(&mut header).await {
+                Ok(_) => {}
+                // stream has ended = host disconnected
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof && partial.is_empty() => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
 
-            let n = stream.read(&mut frame).await?;
-            if n == 0 {
-                return Ok(None); // stream has ended = host disconnected
+            let payload = &mut payload[..header[1] as usize];
+            stream.read_exact(payload).await?;
+
+            if let Some((msg, frames_count, _)) = partial.push(header[0], payload)? {
+                return Ok(Some((msg, frames_count)));
             }
+        }
+    }
+}
+
+/// Message with amount of frames and bytes it took
+type Parsed = (ProtocolMessage, usize, usize);
+
+/// Message put together frame by frame, so every frame is looked at only once
+#[derive(Default)]
+struct PartialMessage {
+    opcode: Option<u8>,
+    payload: Vec<u8>,
+    frames_count: usize,
+    bytes: usize,
+}
 
-            let fin = frame[0] >> 7; // bit
-            let rsv = (frame[0] << 1) >> 5; // 3 bits
-            let opcode = (frame[0] << 1) >> 1; // 4 bits
+impl PartialMessage {
+    fn is_empty(&self) -> bool {
+        self.frames_count == 0
+    }
 
-            if rsv != 0 {
-                bail!("Unknown usage of reserved bits")
+    /// Adds complete frames from the start of `buf` until the message is complete.
+    /// Returns the message, if it is, and amount of bytes taken from `buf`.
+    fn push_frames(&mut self, buf: &[u8]) -> Result<(Option<Parsed>, usize)> {
+        let mut pos = 0;
+        while let (Some(&header), Some(&len)) = (buf.get(pos), buf.get(pos + 1)) {
+            let frame_end = pos + 2 + len as usize;
+            if buf.len() < frame_end {
+                break;
             }
+            let msg = self.push(header, &buf[pos + 2..frame_end])?;
+            pos = frame_end;
 
-            let frame = &frame[1..frame.len()];
+            if msg.is_some() {
+                return Ok((msg, pos));
+            }
+        }
+        Ok((None, pos))
+    }
 
-            match opcode {
-                PROT_OPCODE_CONN_INIT => {
-                    buf.extend_from_slice(frame);
+    /// Adds the frame, returns the message with amount of frames and bytes it took once the last frame is added
+    fn push(&mut self, header: u8, payload: &[u8]) -> Result<Option<Parsed>> {
+        let fin = header >> 7; // bit
+        let rsv = (header << 1) >> 5; // 3 bits
+        let opcode = (header << 1) >> 1; // 4 bits
 
-                    if fin == 1 {
-                        let mut iter = buf.into_iter();
-                        let server_addr = socket_addr_from_bytes(&mut iter)
-                            .expect("---Failed to parse buffer")
-                            .expect("---Address is required for this opcode");
-                        let msg = Self::ConnInit { server_addr };
-                        return Ok(Some((msg, frames_count)));
-                    } else {
-                        buf_type = ProtocolBufferType::ConnInit;
-                    }
-                }
-                PROT_OPCODE_CONTINUATION => {
-                    buf.extend_from_slice(frame);
-
-                    if fin == 1 {
-                        let msg = match buf_type {
-                            ProtocolBufferType::ConnInit => {
-                                let mut iter = buf.into_iter();
-                                let server_addr = socket_addr_from_bytes(&mut iter)
-                                    .expect("---Failed to parse buffer")
-                                    .expect("---Address is required for this opcode");
-                                Self::ConnInit { server_addr }
-                            }
-                            ProtocolBufferType::Data => {
-                                let (id, data) = buf.split_at(8);
-                                let id = u64::from_be_bytes(id.try_into().expect("invalid buf length"));
-                                Self::Data(id, data.to_vec())
-                            },
-                            ProtocolBufferType::NodeInfo => {
-                                let another_node = NodeInfo::from_bytes(buf)?.expect("opcode requires NodeINfo");
-                                Self::NodeStatus(another_node)
-                            },
-                            ProtocolBufferType::Pong => Self::Pong(NodeInfo::from_bytes(buf)?),
-                        };
-                        return Ok(Some((msg, frames_count)));
-                    }
-                }
-                PROT_OPCODE_DATA => {
-                    buf.extend_from_slice(frame);
-
-                    if fin == 1 {
-                        let (id, data) = buf.split_at(8);
-                        let id = u64::from_be_bytes(id.try_into().expect("invalid buf length"));
-                        let msg = Self::Data(id, data.to_vec());
-                        return Ok(Some((msg, frames_count)));
-                    } else {
-                        buf_type = ProtocolBufferType::Data;
-                    }
-                }
-                PROT_OPCODE_NODE_INFO => {
-                    buf.extend_from_slice(frame);
-
-                    if fin == 1 {
-                        let another_node = NodeInfo::from_bytes(buf)?.expect("opcode requires NodeINfo");
-                        let msg = Self::NodeStatus(another_node);
-                        return Ok(Some((msg, frames_count)));
-                    } else {
-                        buf_type = ProtocolBufferType::NodeInfo;
-                    }
-                }
-                PROT_OPCODE_PONG => {
-                    buf.extend_from_slice(frame);
-
-                    if fin == 1 {
-                        let msg = Self::Pong(NodeInfo::from_bytes(buf)?);
-                        return Ok(Some((msg, frames_count)));
-                    } else {
-                        buf_type = ProtocolBufferType::Pong;
-                    }
-                }
-                PROT_OPCODE_CONN_CLOSED => {
-                    if fin == 0 {
-                        bail!("Received single-frame message but fin bit is not 1")
-                    }
+        if rsv != 0 {
+            bail!("Unknown usage of reserved bits")
+        }
 
-                    let msg = Self::ConnClosed;
-                    return Ok(Some((msg, frames_count)));
-                },
-                PROT_OPCODE_PING => {
-                    if fin == 0 {
-                        bail!("Received single-frame message but fin bit is not 1")
-                    }
+        let opcode = match (opcode, self.opcode) {
+            (PROT_OPCODE_CONTINUATION, Some(opcode)) => opcode,
+            (PROT_OPCODE_CONTINUATION, None) => {
+                bail!("Received continuation frame without the message to continue")
+            }
+            (_, Some(_)) => {
+                bail!("Received new message before previous one has finished")
+            }
+            (opcode, None) => opcode,
+        };
+        self.opcode = Some(opcode);
+
+        if fin == 0 && (opcode == PROT_OPCODE_PING || opcode == PROT_OPCODE_CONN_CLOSED) {
+            bail!("Received single-frame message but fin bit is not 1")
+        }
+        // otherwise party could make us buffer as much as it sends
+        if self.payload.len() + payload.len() > ProtocolMessage::MAX_MESSAGE_SIZE {
+            bail!("Message is larger than {} bytes", ProtocolMessage::MAX_MESSAGE_SIZE)
+        }
+
+        self.payload.extend_from_slice(payload);
+        self.frames_count += 1;
+        self.bytes += 2 + payload.len();
 
-                    let msg = Self::Ping;
-                    return Ok(Some((msg, frames_count)));
-                },
-                _ => {
-                    bail!("Unknown opcode")
-                },
+        if fin == 0 {
+            return Ok(None);
+        }
+        let partial = std::mem::take(self);
+        let msg = ProtocolMessage::from_payload(opcode, partial.payload)?;
+        Ok(Some((msg, partial.frames_count, partial.bytes)))
+    }
+}
+
+/// Reads messages from the stream, keeping bytes of unfinished ones between calls,
+/// so `next` can be used in `select!` without losing data
+#[derive(Default)]
+pub struct FrameReader {
+    buf: Vec<u8>, // bytes of the frame that isn't complete yet
+    partial: PartialMessage,
+}
+
+impl FrameReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Same as `ProtocolMessage::from_stream`, but cancel safe.
+    /// Returns the message with amount of frames and bytes it took.
+    pub async fn next(
+        &mut self,
+        stream: &mut (impl AsyncRead + Unpin),
+    ) -> Result<Option<(ProtocolMessage, usize, usize)>> {
+        loop {
+            // complete frames are taken out, so the buffer never holds more than a frame and a read
+            let (msg, consumed) = self.partial.push_frames(&self.buf)?;
+            self.buf.drain(..consumed);
+            if msg.is_some() {
+                return Ok(msg);
+            }
+
+            let n = stream.read_buf(&mut self.buf).await?;
+            if n == 0 {
+                return Ok(None); // stream has ended = host disconnected
             }
         }
     }
@@ -0,0 +1,52 @@
+use std::fmt::{Display, Formatter};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HandshakeTimeouts {
+    #[serde(with = "crate::utils::duration_ms")]
+    pub connect: Duration, // establishing tcp connection to another node
+    #[serde(with = "crate::utils::duration_ms")]
+    pub conn_init: Duration, // server waiting for the first message from the client
+    #[serde(with = "crate::utils::duration_ms")]
+    pub handshake: Duration, // whole exchange from tcp connection till the stream is registered
+}
+
+impl Default for HandshakeTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            conn_init: Duration::from_secs(10),
+            handshake: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Reason the connection was closed before it became a stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeError {
+    ConnectTimeout,
+    ConnInitTimeout,
+    HandshakeTimeout,
+    Closed, // other side closed connection or it broke mid-handshake
+    Malformed, // first message couldn't be parsed or wasn't `CONN_INIT`
+    Banned, // ip the peer connects from or the one it claims in `CONN_INIT` is banned
+    AlreadyConnected, // another stream is registered for the address in `CONN_INIT`
+}
+
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::ConnectTimeout => write!(f, "tcp connection timed out"),
+            HandshakeError::ConnInitTimeout => write!(f, "didn't receive CONN_INIT in time"),
+            HandshakeError::HandshakeTimeout => write!(f, "handshake timed out"),
+            HandshakeError::Closed => write!(f, "connection closed during handshake"),
+            HandshakeError::Malformed => write!(f, "expected CONN_INIT as the first message"),
+            HandshakeError::Banned => write!(f, "peer is banned"),
+            HandshakeError::AlreadyConnected => write!(f, "peer is already connected"),
+        }
+    }
+}
+
+impl std::error::Error for HandshakeError {}
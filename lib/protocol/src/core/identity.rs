@@ -0,0 +1,106 @@
+use std::cmp::Ordering;
+use std::fmt::{Debug, Display, Formatter};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand_core::OsRng;
+
+/// Id of the node in the network, which is its public key
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NodeId(pub [u8; NodeId::BYTES]);
+
+impl NodeId {
+    pub const BYTES: usize = 32;
+    pub const BITS: usize = Self::BYTES * 8;
+
+    pub fn distance(&self, other: &NodeId) -> [u8; Self::BYTES] {
+        let mut d = [0; Self::BYTES];
+        for (i, byte) in d.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        d
+    }
+
+    /// Which of the ids is closer to `self` by XOR metric
+    pub fn cmp_distance(&self, a: &NodeId, b: &NodeId) -> Ordering {
+        self.distance(a).cmp(&self.distance(b))
+    }
+
+    /// Index of the highest bit that differs, `None` for the same id.
+    /// Ids sharing longer prefix with `self` get lower index.
+    pub fn bucket_index(&self, other: &NodeId) -> Option<usize> {
+        let d = self.distance(other);
+        for (i, byte) in d.iter().enumerate() {
+            if *byte != 0 {
+                let bit = 7 - byte.leading_zeros() as usize;
+                return Some((Self::BYTES - 1 - i) * 8 + bit);
+            }
+        }
+        None
+    }
+
+    /// Whether `signature` of `msg` was made by the owner of this id
+    pub fn verify(&self, msg: &[u8], signature: &[u8; 64]) -> bool {
+        match VerifyingKey::from_bytes(&self.0) {
+            Ok(key) => key.verify(msg, &Signature::from_bytes(signature)).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+impl Display for NodeId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // short prefix is enough to tell nodes apart in logs
+        for byte in &self.0[..6] {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for NodeId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NodeId({})", self)
+    }
+}
+
+/// Key pair of the node, its public key is used as `NodeId`
+#[derive(Clone)]
+pub struct Identity {
+    signing_key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    pub fn from_secret(secret: [u8; 32]) -> Self {
+        Self {
+            signing_key: SigningKey::from_bytes(&secret),
+        }
+    }
+
+    pub fn secret(&self) -> [u8; 32] {
+        self.signing_key.to_bytes()
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        NodeId(self.signing_key.verifying_key().to_bytes())
+    }
+
+    pub fn sign(&self, msg: &[u8]) -> [u8; 64] {
+        self.signing_key.sign(msg).to_bytes()
+    }
+
+    /// Diffie-Hellman with the owner of `other`, both keys are converted to x25519.
+    /// `None` if `other` is not a valid key.
+    pub fn agree(&self, other: &NodeId) -> Option<[u8; 32]> {
+        let public = VerifyingKey::from_bytes(&other.0).ok()?;
+        if public.is_weak() {
+            return None;
+        }
+        let shared = public.to_montgomery().mul_clamped(self.signing_key.to_scalar_bytes()).to_bytes();
+        Some(shared)
+    }
+}
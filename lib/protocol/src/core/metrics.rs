@@ -0,0 +1,214 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use crate::types::{
+    event::{DropReason, ProtocolEvent},
+    state::{ProtocolState, StreamDirection},
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+pub const MAX_SCRAPES: usize = 4; // requests answered at once, connections over it are dropped right away
+
+// seconds, vivaldi and peer selection care about tens of milliseconds
+const PING_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub addr: Option<SocketAddr>, // embedded http endpoint serving `/metrics`, not started if `None`
+}
+
+/// Counters of the node. Every node has its own registry, so several nodes can run in one process.
+pub struct Metrics {
+    registry: Registry,
+    peers: IntGaugeVec, // by direction, taken from the state on every scrape
+    connections: IntCounterVec, // by direction
+    frames_sent: IntCounterVec, // by message kind
+    frames_received: IntCounterVec,
+    bytes_sent: IntCounterVec,
+    bytes_received: IntCounterVec,
+    pub(crate) dedup_hits: IntCounter, // data messages that came again from another peer
+    dropped: IntCounterVec, // by reason
+    ping: Histogram,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("p2p".to_string()), None).expect("valid prefix");
+
+        let peers = IntGaugeVec::new(Opts::new("peers", "Connected peers"), &["direction"]).expect("valid metric");
+        let connections = IntCounterVec::new(
+            Opts::new("connections_total", "Established connections"),
+            &["direction"],
+        ).expect("valid metric");
+        let frames_sent = IntCounterVec::new(Opts::new("frames_sent_total", "Frames sent"), &["kind"]).expect("valid metric");
+        let frames_received = IntCounterVec::new(Opts::new("frames_received_total", "Frames received"), &["kind"]).expect("valid metric");
+        let bytes_sent = IntCounterVec::new(Opts::new("bytes_sent_total", "Bytes sent"), &["kind"]).expect("valid metric");
+        let bytes_received = IntCounterVec::new(Opts::new("bytes_received_total", "Bytes received"), &["kind"]).expect("valid metric");
+        let dedup_hits = IntCounter::new("dedup_hits_total", "Data messages received again").expect("valid metric");
+        let dropped = IntCounterVec::new(
+            Opts::new("messages_dropped_total", "Messages addressed to some node that were dropped"),
+            &["reason"],
+        ).expect("valid metric");
+        let ping = Histogram::with_opts(
+            HistogramOpts::new("ping_seconds", "Measured round trip time to peers").buckets(PING_BUCKETS.to_vec()),
+        ).expect("valid metric");
+
+        for collector in [
+            Box::new(peers.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(connections.clone()),
+            Box::new(frames_sent.clone()),
+            Box::new(frames_received.clone()),
+            Box::new(bytes_sent.clone()),
+            Box::new(bytes_received.clone()),
+            Box::new(dedup_hits.clone()),
+            Box::new(dropped.clone()),
+            Box::new(ping.clone()),
+        ] {
+            registry.register(collector).expect("metric names are unique");
+        }
+
+        Self {
+            registry,
+            peers,
+            connections,
+            frames_sent,
+            frames_received,
+            bytes_sent,
+            bytes_received,
+            dedup_hits,
+            dropped,
+            ping,
+        }
+    }
+
+    pub(crate) fn sent(&self, kind: &str, frames: usize, bytes: usize) {
+        self.frames_sent.with_label_values(&[kind]).inc_by(frames as u64);
+        self.bytes_sent.with_label_values(&[kind]).inc_by(bytes as u64);
+    }
+
+    pub(crate) fn received(&self, kind: &str, frames: usize, bytes: usize) {
+        self.frames_received.with_label_values(&[kind]).inc_by(frames as u64);
+        self.bytes_received.with_label_values(&[kind]).inc_by(bytes as u64);
+    }
+
+    /// Counts what the event tells about, every event goes through here
+    pub(crate) fn observe(&self, event: &ProtocolEvent) {
+        match event {
+            ProtocolEvent::PeerConnected { direction, .. } => {
+                self.connections.with_label_values(&[direction_label(*direction)]).inc();
+            }
+            ProtocolEvent::PingMeasured { rtt, .. } => self.ping.observe(rtt.as_secs_f64()),
+            ProtocolEvent::MessageDropped { reason } => {
+                let reason = match reason {
+                    DropReason::NoRoute(_) => "no_route",
+                    DropReason::BadOnion(_) => "bad_onion",
+                    DropReason::Forged(_) => "forged",
+                    DropReason::AppClosed => "app_closed",
+                };
+                self.dropped.with_label_values(&[reason]).inc();
+            }
+            _ => {}
+        }
+    }
+
+    /// Metrics in Prometheus text format
+    pub fn encode(&self) -> String {
+        let mut buf = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .expect("text encoding doesn't fail");
+        String::from_utf8(buf).expect("text encoding is utf-8")
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn direction_label(direction: StreamDirection) -> &'static str {
+    match direction {
+        StreamDirection::Inbound => "inbound",
+        StreamDirection::Outbound => "outbound",
+    }
+}
+
+impl ProtocolState {
+    /// Metrics of the node in Prometheus text format
+    pub async fn metrics(&self) -> String {
+        let metrics = &self.read().metrics;
+        {
+            let lock = self.lock().await;
+            for direction in [StreamDirection::Inbound, StreamDirection::Outbound] {
+                let count = lock.streams.values().filter(|(_, metadata)| metadata.direction == direction).count();
+                metrics.peers.with_label_values(&[direction_label(direction)]).set(count as i64);
+            }
+        }
+        metrics.encode()
+    }
+}
+
+/// Answers a single http request, only `GET /metrics` is known
+async fn answer(protocol_state: &ProtocolState, stream: &mut TcpStream) -> std::io::Result<()> {
+    let mut request = vec![];
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_SIZE {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let response = if request.starts_with(b"GET /metrics ") {
+        let body = protocol_state.metrics().await;
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body,
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+async fn running_metrics_server(protocol_state: ProtocolState, server: TcpListener) {
+    let scrapes = Arc::new(Semaphore::new(MAX_SCRAPES));
+    while let Ok((mut stream, _)) = server.accept().await {
+        // slow clients could otherwise keep a task each for the whole timeout
+        let Ok(permit) = scrapes.clone().try_acquire_owned() else { continue };
+        let protocol_state = protocol_state.clone();
+        tokio::spawn(async move {
+            // scraper that doesn't finish the request just loses the connection
+            let _ = timeout(REQUEST_TIMEOUT, answer(&protocol_state, &mut stream)).await;
+            drop(permit);
+        });
+    }
+}
+
+/// Serves `/metrics` over http at the configured address
+pub(crate) async fn start_metrics_server(protocol_state: ProtocolState) -> Option<JoinHandle<()>> {
+    let addr = protocol_state.read().metrics_config.addr?;
+    match TcpListener::bind(addr).await {
+        Ok(server) => Some(tokio::spawn(running_metrics_server(protocol_state, server))),
+        Err(e) => {
+            // node works without it
+            protocol_state.emit(ProtocolEvent::MetricsUnavailable { error: e.to_string() });
+            None
+        }
+    }
+}
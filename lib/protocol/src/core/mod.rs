@@ -1,6 +1,20 @@
+pub mod identity;
 pub mod node_info;
 pub mod frames;
+pub mod handshake;
 pub mod client;
 pub mod server;
+pub mod scoring;
+pub mod peer_store;
+pub mod pex;
+pub mod nat;
+pub mod relay;
+pub mod onion;
+pub mod metrics;
+pub mod selection;
+pub mod bootstrap;
+pub mod dht;
 pub mod commands;
 pub mod stream;
+pub mod transport;
+pub mod vivaldi;
@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use anyhow::{bail, Context, Result};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Instant};
+use crate::core::client::run_client;
+use crate::core::frames::ProtocolMessage;
+use crate::core::stream::types::StreamAction;
+use crate::core::transport::TransportKind;
+use crate::types::{
+    state::{ProtocolState, ProtocolStateInnerMut, StreamDirection},
+    event::ProtocolEvent,
+};
+use crate::utils::socket_addr_to_bytes::{socket_addr_from_bytes, socket_addr_to_bytes};
+
+const NAT_OBSERVED:      u8 = 0;
+const NAT_PUNCH_REQUEST: u8 = 1;
+const NAT_PUNCH_OFFER:   u8 = 2;
+const NAT_PUNCH_ANSWER:  u8 = 3;
+const NAT_PUNCH_READY:   u8 = 4;
+const NAT_PUNCH_FAILED:  u8 = 5;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NatConfig {
+    pub udp: bool, // udp socket at the server address, needed to punch holes and to help others do it
+    #[serde(with = "crate::utils::duration_ms")]
+    pub punch_timeout: Duration, // for each step - probe, answer from the other side and punching itself
+    #[serde(with = "crate::utils::duration_ms")]
+    pub punch_interval: Duration, // between punches sent to the other side
+    pub max_relayed: usize, // punches coordinated for other nodes at the same time
+    pub max_answered: usize, // offers of other nodes punched at the same time
+}
+
+impl Default for NatConfig {
+    fn default() -> Self {
+        Self {
+            udp: true,
+            punch_timeout: Duration::from_secs(10),
+            punch_interval: Duration::from_millis(200),
+            max_relayed: 16,
+            max_answered: 4,
+        }
+    }
+}
+
+/// Hole punching goes through a relay node both sides are connected to:
+/// A -> R `PunchRequest`, R -> B `PunchOffer`, B -> R `PunchAnswer`, R -> A `PunchReady`,
+/// then both punch at the udp addresses they got. Relay only passes messages, the link is direct.
+#[derive(Debug)]
+pub enum NatMessage {
+    Observed(SocketAddr), // address the receiver is seen at, sent by the server after handshake
+    PunchRequest { // asks relay to get `target` ready for punching
+        target: SocketAddr,
+        udp_addr: SocketAddr, // of the requesting node, as relay sees it
+    },
+    PunchOffer { // relay tells `from` wants to punch
+        from: SocketAddr,
+        udp_addr: SocketAddr,
+    },
+    PunchAnswer { // node agrees to punch with `to`
+        to: SocketAddr,
+        udp_addr: SocketAddr,
+    },
+    PunchReady { // relay tells `from` agreed and is punching already
+        from: SocketAddr,
+        udp_addr: SocketAddr,
+    },
+    PunchFailed(SocketAddr), // relay can't reach the target
+}
+
+impl NatMessage {
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        let (kind, addrs) = match self {
+            NatMessage::Observed(addr) => (NAT_OBSERVED, vec![addr]),
+            NatMessage::PunchRequest { target, udp_addr } => (NAT_PUNCH_REQUEST, vec![target, udp_addr]),
+            NatMessage::PunchOffer { from, udp_addr } => (NAT_PUNCH_OFFER, vec![from, udp_addr]),
+            NatMessage::PunchAnswer { to, udp_addr } => (NAT_PUNCH_ANSWER, vec![to, udp_addr]),
+            NatMessage::PunchReady { from, udp_addr } => (NAT_PUNCH_READY, vec![from, udp_addr]),
+            NatMessage::PunchFailed(target) => (NAT_PUNCH_FAILED, vec![target]),
+        };
+        buf.push(kind);
+        for addr in addrs {
+            buf.extend(socket_addr_to_bytes(addr)?);
+        }
+        Ok(buf)
+    }
+
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self> {
+        let mut iter = buf.into_iter();
+        let kind = iter.next().context("empty nat message")?;
+        let mut addr = || -> Result<SocketAddr> {
+            socket_addr_from_bytes(&mut iter)?.context("nat message requires an address")
+        };
+
+        let msg = match kind {
+            NAT_OBSERVED => Self::Observed(addr()?),
+            NAT_PUNCH_REQUEST => Self::PunchRequest { target: addr()?, udp_addr: addr()? },
+            NAT_PUNCH_OFFER => Self::PunchOffer { from: addr()?, udp_addr: addr()? },
+            NAT_PUNCH_ANSWER => Self::PunchAnswer { to: addr()?, udp_addr: addr()? },
+            NAT_PUNCH_READY => Self::PunchReady { from: addr()?, udp_addr: addr()? },
+            NAT_PUNCH_FAILED => Self::PunchFailed(addr()?),
+            _ => bail!("Unknown nat message"),
+        };
+        if iter.next().is_some() {
+            bail!("Nat message is too long")
+        }
+        Ok(msg)
+    }
+}
+
+pub struct NatState {
+    pending: HashMap<SocketAddr, (SocketAddr, oneshot::Sender<Option<SocketAddr>>)>, // own punches waiting for relay, by target
+    relayed: HashMap<(SocketAddr, SocketAddr), Instant>, // offers passed from requester to target, waiting for answer
+    answered: HashMap<(SocketAddr, SocketAddr), SocketAddr>, // offers we are punching for, by relay and requester
+}
+
+impl NatState {
+    pub fn new() -> Self {
+        Self {
+            pending: HashMap::new(),
+            relayed: HashMap::new(),
+            answered: HashMap::new(),
+        }
+    }
+}
+
+impl Default for NatState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handles nat message from the peer connected at `addr`. `Err` means peer sent something nobody asked for.
+pub(crate) async fn read(
+    protocol_state: &ProtocolState,
+    lock: &mut ProtocolStateInnerMut,
+    addr: SocketAddr,
+    message: NatMessage,
+) -> Result<StreamAction> {
+    let config = &protocol_state.read().nat;
+
+    match message {
+        NatMessage::Observed(observed) => {
+            match lock.streams.get_mut(&addr) {
+                // only the server knows where the connection came from
+                Some((_, metadata)) if metadata.direction == StreamDirection::Outbound => {
+                    metadata.observed = Some(observed);
+                }
+                _ => bail!("Observed address from the client"),
+            }
+        }
+        NatMessage::PunchRequest { target, udp_addr } => {
+            // otherwise target would punch at whatever address requester wants
+            let requester_ip = lock.streams.get(&addr).map(|(_, metadata)| metadata.remote_addr.ip());
+            if requester_ip != Some(udp_addr.ip()) {
+                bail!("Punch request for another ip")
+            }
+
+            let now = Instant::now();
+            lock.nat.relayed.retain(|_, at| now.duration_since(*at) < config.punch_timeout);
+
+            let can_relay = protocol_state.read().udp.is_some()
+                && target != addr
+                && lock.nat.relayed.len() < config.max_relayed
+                && !lock.nat.relayed.contains_key(&(addr, target));
+            let target_channel = lock.streams.get(&target).map(|(channel, _)| channel.clone());
+
+            match target_channel {
+                Some(channel) if can_relay => {
+                    lock.nat.relayed.insert((addr, target), now);
+                    let offer = NatMessage::PunchOffer { from: addr, udp_addr };
+                    // stream could be closing already, requester times out then
+                    let _ = channel.send(StreamAction::Send(ProtocolMessage::Nat(offer))).await;
+                }
+                _ => return Ok(StreamAction::Send(ProtocolMessage::Nat(NatMessage::PunchFailed(target)))),
+            }
+        }
+        NatMessage::PunchOffer { from, udp_addr } => {
+            // nobody would ask to punch on behalf of the relay, us or the node we are connected to
+            if from == addr || from == protocol_state.read().server_addr || lock.streams.contains_key(&from) {
+                bail!("Unrequested punch offer")
+            }
+            if lock.nat.answered.contains_key(&(addr, from)) {
+                bail!("Punch offer repeated")
+            }
+
+            // every answer sends punches to `udp_addr`, so there can't be many of them at once
+            let can_answer = protocol_state.read().udp.is_some()
+                && lock.nat.answered.len() < config.max_answered
+                && !lock.nat.answered.values().any(|a| *a == udp_addr);
+            if can_answer {
+                lock.nat.answered.insert((addr, from), udp_addr);
+                tokio::spawn(answer_punch(protocol_state.clone(), addr, from, udp_addr));
+            }
+        }
+        NatMessage::PunchAnswer { to, udp_addr } => {
+            let offered_at = lock.nat.relayed.remove(&(to, addr)).context("Punch answer without offer")?;
+            if offered_at.elapsed() >= config.punch_timeout {
+                return Ok(StreamAction::None); // requester has given up already
+            }
+            if let Some((channel, _)) = lock.streams.get(&to) {
+                let ready = NatMessage::PunchReady { from: addr, udp_addr };
+                let _ = channel.send(StreamAction::Send(ProtocolMessage::Nat(ready))).await;
+            }
+        }
+        NatMessage::PunchReady { from, udp_addr } => {
+            // late answers are fine, punch has timed out already
+            if lock.nat.pending.get(&from).map(|(relay, _)| *relay == addr).unwrap_or(false) {
+                let (_, sender) = lock.nat.pending.remove(&from).expect("checked above");
+                let _ = sender.send(Some(udp_addr));
+            }
+        }
+        NatMessage::PunchFailed(target) => {
+            if lock.nat.pending.get(&target).map(|(relay, _)| *relay == addr).unwrap_or(false) {
+                let (_, sender) = lock.nat.pending.remove(&target).expect("checked above");
+                let _ = sender.send(None);
+            }
+        }
+    }
+
+    Ok(StreamAction::None)
+}
+
+/// Other side of `ProtocolState::punch`, the link itself is accepted by the udp server
+async fn answer_punch(
+    protocol_state: ProtocolState,
+    relay: SocketAddr,
+    from: SocketAddr,
+    peer_udp: SocketAddr,
+) {
+    let res = async {
+        let config = &protocol_state.read().nat;
+        let udp = protocol_state.read().udp.as_ref().context("Udp is disabled")?;
+
+        let nonce = protocol_state.lock().await.state.next();
+        let udp_addr = udp.probe(relay, nonce, config.punch_timeout).await?;
+
+        {
+            let lock = protocol_state.lock().await;
+            let (channel, _) = lock.streams.get(&relay).context("Relay has disconnected")?;
+            let answer = NatMessage::PunchAnswer { to: from, udp_addr };
+            channel.send(StreamAction::Send(ProtocolMessage::Nat(answer))).await?;
+        }
+
+        udp.punch(peer_udp, config.punch_interval, config.punch_timeout).await
+    }.await;
+    protocol_state.lock().await.nat.answered.remove(&(relay, from));
+
+    protocol_state.emit(match res {
+        Ok(_) => ProtocolEvent::HolePunched { addr: from, udp_addr: peer_udp },
+        Err(e) => ProtocolEvent::PunchFailed { addr: from, error: e.to_string() },
+    });
+}
+
+impl ProtocolState {
+    /// Ip most of the servers we are connected to see us at, `None` if nobody told yet.
+    /// Differs from the ip of `server_addr` if the node is behind NAT.
+    pub async fn observed_ip(&self) -> Option<IpAddr> {
+        let lock = self.lock().await;
+        let mut votes: HashMap<IpAddr, usize> = HashMap::new();
+        for (_, metadata) in lock.streams.values() {
+            if let Some(observed) = metadata.observed {
+                *votes.entry(observed.ip()).or_default() += 1;
+            }
+        }
+        votes.into_iter().max_by_key(|(_, count)| *count).map(|(ip, _)| ip)
+    }
+
+    /// Connects to `target` over udp by punching holes in both NATs, `relay` is a node both are connected to.
+    /// Returns `Err` if any of the steps fails or times out.
+    pub async fn punch(
+        &self,
+        relay: SocketAddr,
+        target: SocketAddr,
+    ) -> Result<Option<JoinHandle<()>>> {
+        let config = &self.read().nat;
+        let udp = self.read().udp.as_ref().context("Udp is disabled")?;
+
+        let nonce = self.lock().await.state.next();
+        let udp_addr = udp.probe(relay, nonce, config.punch_timeout).await?;
+
+        let (sender, receiver) = oneshot::channel();
+        {
+            let lock = &mut *self.lock().await;
+            if lock.streams.contains_key(&target) {
+                bail!("Already connected to {}", target)
+            }
+            let (channel, _) = lock.streams.get(&relay).context("Not connected to relay")?;
+            let request = NatMessage::PunchRequest { target, udp_addr };
+            channel.send(StreamAction::Send(ProtocolMessage::Nat(request))).await?;
+            lock.nat.pending.insert(target, (relay, sender));
+        }
+
+        let peer_udp = timeout(config.punch_timeout, receiver).await;
+        self.lock().await.nat.pending.remove(&target);
+        let peer_udp = peer_udp
+            .context("No answer from relay")?
+            .context("Punch was dropped")?
+            .context("Relay can't reach the target")?;
+
+        udp.punch(peer_udp, config.punch_interval, config.punch_timeout).await?;
+
+        let mut stream = udp.connect(peer_udp);
+        self.lock().await.state.next();
+        self.send_message(
+            &mut stream,
+            ProtocolMessage::ConnInit {
+                server_addr: self.read().server_addr,
+            },
+        ).await?;
+
+        // ping is measured by the first pong, punching says nothing about it
+        run_client(self.clone(), target, peer_udp, TransportKind::Udp, Box::new(stream), None, None).await
+    }
+}
@@ -6,17 +6,19 @@ use crate::utils::socket_addr_to_bytes::{socket_addr_from_bytes, socket_addr_to_
 pub struct NodeInfo {
     pub addr: SocketAddr,
     pub ping: u16,
+    pub relay: Option<SocketAddr>, // node can't be connected to directly, only through a circuit at this one
 }
 
-impl<'a> NodeInfo {
+impl NodeInfo {
     pub fn new (addr: SocketAddr, ping: u16) -> Self {
         Self {
             addr,
             ping,
+            relay: None,
         }
     }
 
-    pub const BYTES: usize = 8;
+    pub const BYTES: usize = 14;
 
     pub fn into_bytes(self) -> Result<Vec<u8>> {
         let mut v = Vec::with_capacity(Self::BYTES);
@@ -29,6 +31,11 @@ impl<'a> NodeInfo {
         }
         v.extend(ping_bytes);
 
+        match self.relay {
+            Some(relay) => v.extend(socket_addr_to_bytes(relay)?),
+            None => v.extend([0; 6]),
+        }
+
         Ok(v)
     }
 
@@ -47,9 +54,12 @@ impl<'a> NodeInfo {
             iter.next().context("not enough bytes")?,
         ]);
 
+        let relay = socket_addr_from_bytes(&mut iter)?;
+
         Ok(Some(Self {
             addr,
             ping,
+            relay,
         }))
     }
 }
@@ -0,0 +1,327 @@
+use serde::{Deserialize, Serialize};
+use anyhow::{bail, Context, Result};
+use chacha20::{cipher::{KeyIvInit, StreamCipher}, ChaCha20};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit, Nonce};
+use rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use crate::core::dht::{forward, message::DhtMessage, Outgoing};
+use crate::core::identity::{Identity, NodeId};
+use crate::types::{
+    state::{ProtocolState, ProtocolStateInnerMut},
+    event::{DropReason, ProtocolEvent},
+    package::{AnonymousPackage, AppPackage},
+};
+use crate::utils::sample::sample;
+
+const ONION_FORWARD: u8 = 0;
+const ONION_DELIVER: u8 = 1;
+
+const KEY_CONTEXT: &[u8] = b"onion layer";
+
+pub const MAX_PATH: usize = 8; // nodes in the path, including the target
+const ROUTING: usize = 1 + 3 * 32; // type, next hop id, its ephemeral key and mac of its header
+const HEADER: usize = MAX_PATH * ROUTING;
+const PAYLOAD: usize = 1024; // data with its length, padded
+const TAG: usize = 16;
+pub const MAX_DATA: usize = PAYLOAD - 2;
+/// Every layer has this length, no matter where in the path it is
+pub const ONION_BYTES: usize = 32 + 32 + HEADER + PAYLOAD + TAG;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OnionConfig {
+    pub serve: bool, // whether layers of others are peeled and passed on by this node
+    pub hops: usize, // nodes between the sender and the target on a random path
+}
+
+impl Default for OnionConfig {
+    fn default() -> Self {
+        Self {
+            serve: true,
+            hops: 3,
+        }
+    }
+}
+
+/// What is inside a layer once the hop has decrypted it
+#[derive(Debug, PartialEq, Eq)]
+pub enum Layer {
+    Forward { // layer for the next hop
+        next: NodeId,
+        onion: Vec<u8>,
+    },
+    Deliver(Vec<u8>), // hop is the target
+}
+
+/// Keys of the single hop, all derived from the secret shared by its ephemeral key and the hop key
+struct HopKeys {
+    header: [u8; 32], // stream cipher over the header
+    mac: [u8; 32],
+    payload: [u8; 32], // stream cipher for hops, AEAD for the target
+}
+
+impl HopKeys {
+    fn new(shared: &[u8; 32], ephemeral: &NodeId, hop: &NodeId) -> Self {
+        let derive = |label: &[u8]| -> [u8; 32] {
+            let mut hasher = Sha256::new();
+            hasher.update(KEY_CONTEXT);
+            hasher.update(label);
+            hasher.update(shared);
+            hasher.update(ephemeral.0);
+            hasher.update(hop.0);
+            hasher.finalize().into()
+        };
+        Self {
+            header: derive(b"header"),
+            mac: derive(b"mac"),
+            payload: derive(b"payload"),
+        }
+    }
+
+    /// Inputs are of fixed length, so plain hash of key and header is enough
+    fn mac(&self, header: &[u8]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.mac);
+        hasher.update(header);
+        hasher.finalize().into()
+    }
+}
+
+/// Every key is used for a single packet, so the nonce is always zero
+fn xor_keystream(key: &[u8; 32], buf: &mut [u8]) {
+    ChaCha20::new(key.into(), &Default::default()).apply_keystream(buf);
+}
+
+fn keystream(key: &[u8; 32], len: usize) -> Vec<u8> {
+    let mut buf = vec![0; len];
+    xor_keystream(key, &mut buf);
+    buf
+}
+
+/// Decrypts the outer layer addressed to `identity`. Layer is the ephemeral key, mac of the header,
+/// the header and the payload. Hop takes its routing info from the front of the header and pads
+/// the header back with bytes only the sender could predict, so every layer has the same length.
+pub fn peel(identity: &Identity, layer: &[u8]) -> Result<Layer> {
+    if layer.len() != ONION_BYTES {
+        bail!("Onion layer has wrong length")
+    }
+    let (ephemeral, rest) = layer.split_at(32);
+    let (mac, rest) = rest.split_at(32);
+    let (header, payload) = rest.split_at(HEADER);
+
+    let ephemeral = NodeId(ephemeral.try_into().expect("checked length"));
+    let shared = identity.agree(&ephemeral).context("Invalid ephemeral key")?;
+    let keys = HopKeys::new(&shared, &ephemeral, &identity.node_id());
+    if keys.mac(header) != mac {
+        bail!("Onion layer is not for us or was tampered with")
+    }
+
+    let mut header = [header, &[0; ROUTING]].concat();
+    xor_keystream(&keys.header, &mut header);
+    let (routing, next_header) = header.split_at(ROUTING);
+
+    match routing[0] {
+        ONION_FORWARD => {
+            let mut payload = payload.to_vec();
+            xor_keystream(&keys.payload, &mut payload);
+
+            let mut onion = routing[33..].to_vec(); // ephemeral key and mac for the next hop
+            onion.extend(next_header);
+            onion.extend(payload);
+            Ok(Layer::Forward {
+                next: NodeId(routing[1..33].try_into().expect("checked length")),
+                onion,
+            })
+        }
+        ONION_DELIVER => {
+            let plaintext = ChaCha20Poly1305::new(&keys.payload.into())
+                .decrypt(&Nonce::default(), payload)
+                .map_err(|_| anyhow::anyhow!("Onion payload was tampered with"))?;
+            let len = u16::from_be_bytes([plaintext[0], plaintext[1]]) as usize;
+            let data = plaintext.get(2..2 + len).context("Onion payload is too short")?;
+            Ok(Layer::Deliver(data.to_vec()))
+        }
+        _ => bail!("Unknown onion layer type"),
+    }
+}
+
+/// Wraps `data` for the path, the last node of it is the target.
+/// Returns the onion for the first node, same as Sphinx but with own ephemeral key for every hop.
+pub fn build(path: &[NodeId], data: Vec<u8>) -> Result<Vec<u8>> {
+    if path.is_empty() || path.len() > MAX_PATH {
+        bail!("Onion path has to have from 1 to {} nodes", MAX_PATH)
+    }
+    if data.len() > MAX_DATA {
+        bail!("Onion data can't be longer than {} bytes", MAX_DATA)
+    }
+
+    let ephemerals = path.iter().map(|_| Identity::generate()).collect::<Vec<_>>();
+    let keys = path
+        .iter()
+        .zip(&ephemerals)
+        .map(|(hop, ephemeral)| {
+            let shared = ephemeral.agree(hop).context("Hop id is not a valid key")?;
+            Ok(HopKeys::new(&shared, &ephemeral.node_id(), hop))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let last = path.len() - 1;
+
+    // what hops append to the header, computed up front so that macs cover it
+    let mut filler: Vec<u8> = vec![];
+    for k in &keys[..last] {
+        filler.extend([0; ROUTING]);
+        let stream = keystream(&k.header, HEADER + ROUTING);
+        let tail = &stream[HEADER + ROUTING - filler.len()..];
+        for (f, s) in filler.iter_mut().zip(tail) {
+            *f ^= s;
+        }
+    }
+
+    let mut header = vec![0; HEADER - filler.len()];
+    header[0] = ONION_DELIVER;
+    OsRng.fill_bytes(&mut header[ROUTING..]);
+    xor_keystream(&keys[last].header, &mut header);
+    header.extend(filler);
+    let mut mac = keys[last].mac(&header);
+
+    for i in (0..last).rev() {
+        let mut routing = vec![ONION_FORWARD];
+        routing.extend(path[i + 1].0);
+        routing.extend(ephemerals[i + 1].node_id().0);
+        routing.extend(mac);
+
+        routing.extend(&header[..HEADER - ROUTING]);
+        header = routing;
+        xor_keystream(&keys[i].header, &mut header);
+        mac = keys[i].mac(&header);
+    }
+
+    let mut plaintext = (data.len() as u16).to_be_bytes().to_vec();
+    plaintext.extend(data);
+    plaintext.resize(PAYLOAD, 0);
+    let mut payload = ChaCha20Poly1305::new(&keys[last].payload.into())
+        .encrypt(&Nonce::default(), plaintext.as_slice())
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt onion payload"))?;
+    for k in keys[..last].iter().rev() {
+        xor_keystream(&k.payload, &mut payload);
+    }
+
+    let mut onion = ephemerals[0].node_id().0.to_vec();
+    onion.extend(mac);
+    onion.extend(header);
+    onion.extend(payload);
+    Ok(onion)
+}
+
+/// Handles the onion message - forwards it closer to the hop it's addressed to, or,
+/// if it's us, peels the layer and passes the rest to the next hop or to the application.
+/// Returns `None` if the message is dropped.
+pub(crate) fn read(
+    protocol_state: &ProtocolState,
+    lock: &mut ProtocolStateInnerMut,
+    target: NodeId,
+    id: u64,
+    hops_left: u8,
+    data: Vec<u8>,
+) -> Option<Outgoing> {
+    let local = protocol_state.read().identity.node_id();
+    if target != local {
+        if hops_left == 0 {
+            return None;
+        }
+        let message = DhtMessage::Onion { target, id, hops_left: hops_left - 1, data };
+        return forward(protocol_state, lock, target, message);
+    }
+
+    let mut onion = data;
+    loop {
+        let layer = match peel(&protocol_state.read().identity, &onion) {
+            Ok(layer) => layer,
+            Err(e) => {
+                protocol_state.emit(ProtocolEvent::MessageDropped { reason: DropReason::BadOnion(e.to_string()) });
+                return None;
+            }
+        };
+
+        match layer {
+            Layer::Deliver(msg) => return Some(Outgoing::App(AppPackage::Anonymous(AnonymousPackage { msg }))),
+            Layer::Forward { .. } if !protocol_state.read().onion.serve => return None,
+            Layer::Forward { next, onion: inner } if next == local => onion = inner, // path goes through us twice
+            Layer::Forward { next, onion: inner } => {
+                // fresh id, so the message can't be matched with the one that came in
+                let message = DhtMessage::Onion {
+                    target: next,
+                    id: lock.state.next(),
+                    hops_left: protocol_state.read().dht.max_hops,
+                    data: inner,
+                };
+                return forward(protocol_state, lock, next, message);
+            }
+        }
+    }
+}
+
+/// Wraps the data for the path, it's sent to the first node of it
+fn wrap(
+    protocol_state: &ProtocolState,
+    lock: &mut ProtocolStateInnerMut,
+    path: &[NodeId],
+    data: Vec<u8>,
+) -> Result<Outgoing> {
+    let onion = build(path, data)?;
+    let message = DhtMessage::Onion {
+        target: path[0],
+        id: lock.state.next(),
+        hops_left: protocol_state.read().dht.max_hops,
+        data: onion,
+    };
+    forward(protocol_state, lock, path[0], message).with_context(|| format!("No route to {}", path[0]))
+}
+
+impl ProtocolState {
+    /// Sends data through the given path, the last node of it is the target.
+    /// Every node of the path learns only the next one, target doesn't learn the sender.
+    pub async fn send_onion(&self, path: &[NodeId], data: Vec<u8>) -> Result<()> {
+        if path.is_empty() {
+            bail!("Onion path is empty")
+        }
+        if path[0] == self.node_id() {
+            bail!("Onion path can't start with this node")
+        }
+
+        let outgoing = wrap(self, &mut *self.lock().await, path, data)?;
+        outgoing.send(self).await;
+        Ok(())
+    }
+
+    /// Sends data to the target through a path of random nodes from the routing table
+    pub async fn send_anonymous(&self, target: NodeId, data: Vec<u8>) -> Result<()> {
+        let local = self.node_id();
+        if target == local {
+            bail!("Can't send message to itself")
+        }
+
+        let outgoing = {
+            let lock = &mut *self.lock().await;
+            let candidates = lock
+                .dht
+                .closest(&local, lock.dht.len())
+                .into_iter()
+                .map(|c| c.id)
+                .filter(|id| *id != target)
+                .collect::<Vec<_>>();
+
+            let hops = self.read().onion.hops;
+            if candidates.len() < hops {
+                bail!("Not enough known nodes for a path of {} hops", hops)
+            }
+            let state = &mut lock.state;
+            let mut path = sample(candidates, &mut || state.next(), hops);
+            path.push(target);
+
+            wrap(self, lock, &path, data)?
+        };
+        outgoing.send(self).await;
+        Ok(())
+    }
+}
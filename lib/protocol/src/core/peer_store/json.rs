@@ -0,0 +1,41 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use anyhow::{Context, Result};
+use crate::core::peer_store::{PeerRecord, PeerStore};
+
+/// Keeps peers in a single json file, rewritten on every save
+pub struct JsonPeerStore {
+    path: PathBuf,
+}
+
+impl JsonPeerStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+        }
+    }
+}
+
+impl PeerStore for JsonPeerStore {
+    fn load(&self) -> Result<Vec<PeerRecord>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]), // first start
+            Err(e) => return Err(e).context("Failed to read peer store"),
+        };
+
+        serde_json::from_str(&content).context("Failed to parse peer store")
+    }
+
+    fn save(&self, records: &[PeerRecord]) -> Result<()> {
+        let content = serde_json::to_string_pretty(records).context("Failed to serialize peers")?;
+
+        // write whole file aside first, so that crash mid-write doesn't lose everything
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, content).context("Failed to write peer store")?;
+        fs::rename(&tmp, &self.path).context("Failed to replace peer store")?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use crate::core::vivaldi::Coordinate;
+use crate::core::scoring::ScoringConfig;
+use crate::types::{
+    state::{ProtocolState, ProtocolStateInnerMut},
+    event::ProtocolEvent,
+};
+
+pub mod json;
+
+pub const SAVE_INTERVAL: u64 = 60; // 1 minute
+pub const SEED_CONNECTIONS: usize = 4; // how many stored peers to connect to on start
+pub const MAX_KNOWN_PEERS: usize = 1000; // addresses come from unverified `CONN_INIT`, so there has to be a limit
+
+/// Everything node remembers about another node between restarts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerRecord {
+    pub addr: SocketAddr, // server address of the node
+    pub last_seen: u64, // unix timestamp in seconds
+    pub latency: Option<u16>, // last measured ping in milliseconds
+    pub score: i32,
+    #[serde(default)] // missing in files saved by older versions
+    pub coordinate: Option<Coordinate>,
+}
+
+impl PeerRecord {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            last_seen: unix_now(),
+            latency: None,
+            score: 0,
+            coordinate: None,
+        }
+    }
+
+    pub fn seen(&mut self) {
+        self.last_seen = unix_now();
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+pub trait PeerStore: Send + Sync {
+    fn load(&self) -> Result<Vec<PeerRecord>>;
+    fn save(&self, records: &[PeerRecord]) -> Result<()>;
+}
+
+/// Picks peers worth connecting to on start - not penalized ones, most recently seen first
+pub fn seed_peers(records: &[PeerRecord], count: usize) -> Vec<SocketAddr> {
+    let mut records = records
+        .iter()
+        .filter(|r| r.score >= 0)
+        .collect::<Vec<_>>();
+    records.sort_by_key(|r| Reverse(r.last_seen));
+    records
+        .into_iter()
+        .take(count)
+        .map(|r| r.addr)
+        .collect()
+}
+
+/// Forgets the worst record to make room for another one - lowest score first, then the one seen longest ago.
+/// Records for which `keep` returns `true` are never forgotten.
+pub fn forget_worst(
+    known_peers: &mut HashMap<SocketAddr, PeerRecord>,
+    score: impl Fn(&SocketAddr) -> i32,
+    keep: impl Fn(&SocketAddr) -> bool,
+) -> Option<PeerRecord> {
+    let worst = *known_peers
+        .keys()
+        .filter(|addr| !keep(addr))
+        .min_by_key(|addr| (score(addr), known_peers[*addr].last_seen))?;
+    known_peers.remove(&worst)
+}
+
+/// Record of the connected node, created if it's new. Connected nodes are never forgotten.
+pub(crate) fn remember<'a>(
+    config: &ScoringConfig,
+    lock: &'a mut ProtocolStateInnerMut,
+    addr: SocketAddr,
+) -> &'a mut PeerRecord {
+    if !lock.known_peers.contains_key(&addr) && lock.known_peers.len() >= MAX_KNOWN_PEERS {
+        let (scores, streams) = (&lock.scores, &lock.streams);
+        forget_worst(
+            &mut lock.known_peers,
+            |a| scores.score(config, &a.ip()),
+            |a| *a == addr || streams.contains_key(a),
+        );
+    }
+    let record = lock.known_peers.entry(addr).or_insert_with(|| PeerRecord::new(addr));
+    record.seen();
+    record
+}
+
+/// Best records of the file, if it has more than `MAX_KNOWN_PEERS`
+pub(crate) fn trim_loaded(mut records: Vec<PeerRecord>) -> Vec<PeerRecord> {
+    records.sort_by_key(|r| (Reverse(r.score), Reverse(r.last_seen)));
+    records.truncate(MAX_KNOWN_PEERS);
+    records
+}
+
+/// Store does blocking io, so it runs outside of the async workers
+pub(crate) async fn load_peers(store: Arc<dyn PeerStore>) -> Result<Vec<PeerRecord>> {
+    tokio::task::spawn_blocking(move || store.load())
+        .await
+        .context("Peer store panicked")?
+}
+
+impl ProtocolState {
+    /// Writes known peers to the store right away. They are saved periodically anyway,
+    /// call it before the node is stopped to not lose the ones learned since.
+    pub async fn save_peers(&self) {
+        let store = match self.read().peer_store {
+            Some(ref store) => store.clone(),
+            None => return,
+        };
+
+        let records = {
+            let lock = &*self.lock().await;
+            lock
+                .known_peers
+                .values()
+                .map(|record| {
+                    let mut record = record.clone();
+                    record.score = lock.scores.score(&self.read().scoring, &record.addr.ip());
+                    record
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let res = tokio::task::spawn_blocking(move || store.save(&records))
+            .await
+            .context("Peer store panicked")
+            .and_then(|res| res);
+        if let Err(e) = res {
+            self.emit(ProtocolEvent::PeerStoreFailed { error: e.to_string() });
+        }
+    }
+}
+
+pub fn peer_store_saver(
+    protocol_state: ProtocolState,
+) -> [JoinHandle<()>; 1] {
+    let handle = tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(SAVE_INTERVAL)).await;
+            protocol_state.save_peers().await;
+        }
+    });
+
+    [handle]
+}
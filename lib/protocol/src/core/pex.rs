@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use anyhow::{bail, Context, Result};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use crate::core::client::{connect_indirectly, start_client};
+use crate::core::frames::ProtocolMessage;
+use crate::core::node_info::NodeInfo;
+use crate::core::peer_store::unix_now;
+use crate::core::server::limits::subnet;
+use crate::core::selection::pick_to_dial;
+use crate::core::stream::types::StreamAction;
+use crate::types::{
+    state::{ProtocolState, ProtocolStateInnerMut},
+    event::{DialSource, ProtocolEvent},
+};
+use crate::utils::sample::sample;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PexConfig {
+    #[serde(with = "crate::utils::duration_ms")]
+    pub interval: Duration, // how often to ask one of the peers for its neighbours
+    pub max_entries: usize, // in a single response, both sent and accepted
+    pub max_per_source: usize, // addresses in the table learned from the same peer
+    pub max_per_subnet: usize, // addresses in the table from the same /24 or /64
+    pub max_table: usize,
+    #[serde(with = "crate::utils::duration_ms")]
+    pub min_answer_interval: Duration, // requests from the same peer coming more often are ignored
+}
+
+impl Default for PexConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5 * 60),
+            max_entries: 32,
+            max_per_source: 32,
+            max_per_subnet: 8,
+            max_table: 1024,
+            min_answer_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Neighbour shared with PEX
+#[derive(Debug)]
+pub struct PexEntry {
+    pub info: NodeInfo, // ping is measured by the node that shares it
+    pub seen_ago: u32, // seconds since that node saw it, relative so clocks don't have to match
+}
+
+impl PexEntry {
+    pub const BYTES: usize = NodeInfo::BYTES + 4;
+
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        let mut v = self.info.into_bytes()?;
+        v.extend(self.seen_ago.to_be_bytes());
+        Ok(v)
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        if bytes.len() != Self::BYTES {
+            bail!("unexpected length of pex entry")
+        }
+        let (info, seen_ago) = bytes.split_at(NodeInfo::BYTES);
+        Ok(Self {
+            info: NodeInfo::from_bytes(info.to_vec())?.context("entry requires an address")?,
+            seen_ago: u32::from_be_bytes(seen_ago.try_into().expect("checked length")),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Candidate {
+    source: SocketAddr, // peer that told us about it
+    last_seen: u64, // unix seconds
+}
+
+/// Addresses learned from peers, to connect to once we need more connections.
+/// Limited per source and per subnet, so a single peer can't fill it with its own nodes.
+pub struct PexTable {
+    candidates: HashMap<SocketAddr, Candidate>,
+}
+
+impl PexTable {
+    pub fn new() -> Self {
+        Self {
+            candidates: HashMap::new(),
+        }
+    }
+
+    /// Returns how many of the entries were accepted
+    pub fn insert(
+        &mut self,
+        config: &PexConfig,
+        source: SocketAddr,
+        entries: Vec<(SocketAddr, u64)>,
+    ) -> usize {
+        let mut accepted = 0;
+
+        for (addr, last_seen) in entries.into_iter().take(config.max_entries) {
+            if let Some(candidate) = self.candidates.get_mut(&addr) {
+                // source stays the same, so others can't take over somebody's quota
+                candidate.last_seen = candidate.last_seen.max(last_seen);
+                continue;
+            }
+
+            let from_source = self.candidates.values().filter(|c| c.source == source).count();
+            if from_source >= config.max_per_source {
+                break;
+            }
+            let same_subnet = self.candidates.keys().filter(|a| subnet(a.ip()) == subnet(addr.ip())).count();
+            if same_subnet >= config.max_per_subnet {
+                continue;
+            }
+
+            if self.candidates.len() >= config.max_table {
+                let oldest = self
+                    .candidates
+                    .iter()
+                    .min_by_key(|(_, c)| c.last_seen)
+                    .map(|(a, c)| (*a, c.last_seen));
+                match oldest {
+                    Some((oldest, oldest_seen)) if oldest_seen < last_seen => {
+                        self.candidates.remove(&oldest);
+                    }
+                    _ => continue,
+                }
+            }
+
+            self.candidates.insert(addr, Candidate {
+                source,
+                last_seen,
+            });
+            accepted += 1;
+        }
+
+        accepted
+    }
+
+    pub fn remove(&mut self, addr: &SocketAddr) {
+        self.candidates.remove(addr);
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.candidates.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+}
+
+impl Default for PexTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Asks peer connected at `addr` for its neighbours
+pub(crate) async fn request(
+    protocol_state: &ProtocolState,
+    lock: &mut ProtocolStateInnerMut,
+    addr: SocketAddr,
+) {
+    let max = protocol_state.read().pex.max_entries.min(u8::MAX as usize) as u8;
+    if let Some((channel, metadata)) = lock.streams.get_mut(&addr) {
+        metadata.pex_requested_at = Some(Instant::now());
+        // stream could be closing already
+        let _ = channel.send(StreamAction::Send(ProtocolMessage::PexRequest(max))).await;
+    }
+}
+
+/// Random sample of connected and recently known nodes, except the one asking
+pub(crate) fn answer(
+    protocol_state: &ProtocolState,
+    lock: &mut ProtocolStateInnerMut,
+    addr: SocketAddr,
+    max: u8,
+) -> Option<Vec<PexEntry>> {
+    let config = &protocol_state.read().pex;
+
+    let (_, metadata) = lock.streams.get_mut(&addr)?;
+    if metadata.pex_answered_at.map(|at| at.elapsed() < config.min_answer_interval).unwrap_or(false) {
+        return None;
+    }
+    metadata.pex_answered_at = Some(Instant::now());
+
+    let now = unix_now();
+    let mut entries = lock
+        .streams
+        .iter()
+        .filter(|(a, _)| **a != addr)
+        .filter_map(|(a, (_, m))| Some((m.node_info(*a)?, 0))) // others would store useless addresses
+        .collect::<Vec<_>>();
+    entries.extend(
+        lock.known_peers
+            .values()
+            .filter(|r| r.addr != addr && r.score >= 0 && !lock.streams.contains_key(&r.addr))
+            .map(|r| (NodeInfo::new(r.addr, r.latency.unwrap_or(0)), now.saturating_sub(r.last_seen).min(u32::MAX as u64) as u32)),
+    );
+
+    let state = &mut lock.state;
+    let count = config.max_entries.min(max as usize);
+    Some(
+        sample(entries, &mut || state.next(), count)
+            .into_iter()
+            .map(|(info, seen_ago)| PexEntry {
+                info,
+                seen_ago,
+            })
+            .collect()
+    )
+}
+
+/// Adds addresses from the response to the table. Returns `false` if the response wasn't requested.
+pub(crate) fn accept(
+    protocol_state: &ProtocolState,
+    lock: &mut ProtocolStateInnerMut,
+    addr: SocketAddr,
+    entries: Vec<PexEntry>,
+) -> bool {
+    match lock.streams.get_mut(&addr) {
+        Some((_, metadata)) if metadata.pex_requested_at.is_some() => {
+            metadata.pex_requested_at = None;
+        }
+        _ => return false,
+    }
+
+    let now = unix_now();
+    let server_addr = protocol_state.read().server_addr;
+    for entry in &entries {
+        if let Some(relay) = entry.info.relay {
+            lock.relay.advertised(entry.info.addr, relay);
+        }
+    }
+    let entries = entries
+        .into_iter()
+        .map(|e| (e.info.addr, now.saturating_sub(e.seen_ago as u64)))
+        .filter(|(a, _)| {
+            *a != server_addr && !lock.streams.contains_key(a) && !lock.scores.is_banned(&a.ip())
+        })
+        .collect();
+
+    lock.pex.insert(&protocol_state.read().pex, addr, entries);
+    true
+}
+
+async fn exchange(
+    protocol_state: &ProtocolState,
+) {
+    let candidates = {
+        let lock = &mut *protocol_state.lock().await;
+
+        let addrs = lock.streams.keys().cloned().collect::<Vec<_>>();
+        let state = &mut lock.state;
+        if let Some(addr) = sample(addrs, &mut || state.next(), 1).pop() {
+            request(protocol_state, lock, addr).await;
+        }
+
+        let addrs = lock.pex.addrs();
+        let picked = pick_to_dial(protocol_state, lock, addrs);
+        for addr in &picked {
+            // either it becomes a stream or it's not worth trying again
+            lock.pex.remove(addr);
+        }
+        picked
+    };
+
+    for addr in candidates {
+        protocol_state.emit(ProtocolEvent::Dialing {
+            addr,
+            source: DialSource::PeerExchange,
+        });
+
+        if start_client(protocol_state.clone(), addr, None).await.is_err() {
+            let _ = connect_indirectly(protocol_state, addr, None).await;
+        }
+    }
+}
+
+/// Periodically asks a random peer for its neighbours and connects to them if there are not enough connections
+pub fn peer_exchange(
+    protocol_state: ProtocolState,
+) -> [JoinHandle<()>; 1] {
+    let handle = tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(protocol_state.read().pex.interval).await;
+            exchange(&protocol_state).await;
+        }
+    });
+
+    [handle]
+}
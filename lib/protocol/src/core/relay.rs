@@ -0,0 +1,438 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use anyhow::{bail, Context, Result};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Instant};
+use crate::core::client::{run_client, start_client};
+use crate::core::frames::ProtocolMessage;
+use crate::core::stream::types::StreamAction;
+use crate::core::transport::TransportKind;
+use crate::types::{
+    state::{ProtocolState, ProtocolStateInnerMut},
+    event::ProtocolEvent,
+};
+use crate::utils::socket_addr_to_bytes::{socket_addr_from_bytes, socket_addr_to_bytes};
+
+const RELAY_RESERVE:   u8 = 0;
+const RELAY_RESERVED:  u8 = 1;
+const RELAY_CONNECT:   u8 = 2;
+const RELAY_INCOMING:  u8 = 3;
+const RELAY_CONNECTED: u8 = 4;
+const RELAY_DATA:      u8 = 5;
+const RELAY_CLOSE:     u8 = 6;
+
+const MAX_KNOWN_RELAYS: usize = 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RelayConfig {
+    pub serve: bool, // whether other nodes can reserve circuits through this one
+    pub max_reservations: usize, // nodes reachable through this one at the same time
+    pub max_circuits: usize,
+    pub max_circuits_per_peer: usize, // opened by the same node at the same time
+    #[serde(with = "crate::utils::duration_ms")]
+    pub max_duration: Duration, // circuit is closed after that long, node has to open a new one
+    pub max_bandwidth: usize, // bytes per second in each direction of a circuit, circuit is closed if exceeded
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        Self {
+            serve: true,
+            max_reservations: 16,
+            max_circuits: 32,
+            max_circuits_per_peer: 4,
+            max_duration: Duration::from_secs(10 * 60),
+            max_bandwidth: 64 * 1024,
+        }
+    }
+}
+
+/// Unreachable node reserves a place at a relay, then others open circuits to it through that relay.
+/// Circuit ids are per stream - the one opening the circuit picks it for its stream with the relay,
+/// relay picks another one for its stream with the target.
+#[derive(Debug)]
+pub enum RelayMessage {
+    Reserve, // asks relay to accept circuits to the sender
+    Reserved(bool), // whether relay agreed
+    Connect { // asks relay for a circuit to `target`
+        target: SocketAddr,
+        circuit: u64,
+    },
+    Incoming { // relay tells `from` has opened a circuit
+        from: SocketAddr,
+        circuit: u64,
+    },
+    Connected { // answer to `Connect`
+        circuit: u64,
+        ok: bool,
+    },
+    Data {
+        circuit: u64,
+        data: Vec<u8>,
+    },
+    Close {
+        circuit: u64,
+    },
+}
+
+impl RelayMessage {
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        match self {
+            RelayMessage::Reserve => {
+                buf.push(RELAY_RESERVE);
+            }
+            RelayMessage::Reserved(ok) => {
+                buf.push(RELAY_RESERVED);
+                buf.push(ok as u8);
+            }
+            RelayMessage::Connect { target, circuit } => {
+                buf.push(RELAY_CONNECT);
+                buf.extend(socket_addr_to_bytes(target)?);
+                buf.extend(circuit.to_be_bytes());
+            }
+            RelayMessage::Incoming { from, circuit } => {
+                buf.push(RELAY_INCOMING);
+                buf.extend(socket_addr_to_bytes(from)?);
+                buf.extend(circuit.to_be_bytes());
+            }
+            RelayMessage::Connected { circuit, ok } => {
+                buf.push(RELAY_CONNECTED);
+                buf.extend(circuit.to_be_bytes());
+                buf.push(ok as u8);
+            }
+            RelayMessage::Data { circuit, data } => {
+                buf.push(RELAY_DATA);
+                buf.extend(circuit.to_be_bytes());
+                buf.extend(data);
+            }
+            RelayMessage::Close { circuit } => {
+                buf.push(RELAY_CLOSE);
+                buf.extend(circuit.to_be_bytes());
+            }
+        }
+        Ok(buf)
+    }
+
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self> {
+        let (&kind, rest) = buf.split_first().context("empty relay message")?;
+        let circuit = |bytes: &[u8]| -> Result<u64> {
+            Ok(u64::from_be_bytes(bytes.get(..8).context("not enough bytes")?.try_into().expect("checked length")))
+        };
+        let addr = |bytes: &[u8]| -> Result<SocketAddr> {
+            socket_addr_from_bytes(&mut bytes.iter().copied())?.context("relay message requires an address")
+        };
+        let flag = |bytes: &[u8]| -> Result<bool> {
+            match bytes.first() {
+                Some(0) => Ok(false),
+                Some(1) => Ok(true),
+                _ => bail!("Malformed relay flag"),
+            }
+        };
+
+        let (msg, len) = match kind {
+            RELAY_RESERVE => (Self::Reserve, 0),
+            RELAY_RESERVED => (Self::Reserved(flag(rest)?), 1),
+            RELAY_CONNECT => (Self::Connect { target: addr(rest)?, circuit: circuit(rest.get(6..).unwrap_or_default())? }, 14),
+            RELAY_INCOMING => (Self::Incoming { from: addr(rest)?, circuit: circuit(rest.get(6..).unwrap_or_default())? }, 14),
+            RELAY_CONNECTED => (Self::Connected { circuit: circuit(rest)?, ok: flag(rest.get(8..).unwrap_or_default())? }, 9),
+            RELAY_DATA => (Self::Data { circuit: circuit(rest)?, data: rest.get(8..).unwrap_or_default().to_vec() }, rest.len()),
+            RELAY_CLOSE => (Self::Close { circuit: circuit(rest)? }, 8),
+            _ => bail!("Unknown relay message"),
+        };
+        if rest.len() != len {
+            bail!("Relay message length doesn't match its type")
+        }
+        Ok(msg)
+    }
+}
+
+#[derive(Debug)]
+struct Hop {
+    to: (SocketAddr, u64), // stream and circuit id data is forwarded to
+    opener: bool, // node on this side asked for the circuit
+    opened_at: Instant,
+    window_start: Instant,
+    window_bytes: usize, // forwarded since `window_start`
+}
+
+#[derive(Default)]
+pub struct RelayState {
+    reservations: HashSet<SocketAddr>, // nodes that accept circuits through us
+    hops: HashMap<(SocketAddr, u64), Hop>, // both directions of every circuit through us
+    reserved: HashSet<SocketAddr>, // relays we are reachable through
+    reserving: HashMap<SocketAddr, oneshot::Sender<bool>>, // by relay
+    connecting: HashMap<(SocketAddr, u64), oneshot::Sender<bool>>, // own circuits waiting for relay, by relay and id
+    known: HashMap<SocketAddr, SocketAddr>, // relays other nodes are advertised with
+}
+
+impl RelayState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Relay the unreachable node was advertised with
+    pub fn relay_of(&self, addr: &SocketAddr) -> Option<SocketAddr> {
+        self.known.get(addr).cloned()
+    }
+
+    pub fn advertised(&mut self, addr: SocketAddr, relay: SocketAddr) {
+        if self.known.len() < MAX_KNOWN_RELAYS || self.known.contains_key(&addr) {
+            self.known.insert(addr, relay);
+        }
+    }
+}
+
+fn send_close(
+    lock: &ProtocolStateInnerMut,
+    (to, circuit): (SocketAddr, u64),
+) {
+    if let Some((channel, _)) = lock.streams.get(&to) {
+        let close = RelayMessage::Close { circuit };
+        let _ = channel.try_send(StreamAction::Send(ProtocolMessage::Relay(close)));
+    }
+}
+
+/// Removes both directions of the circuits and tells the other ends they are closed
+fn close_hops(
+    lock: &mut ProtocolStateInnerMut,
+    keys: Vec<(SocketAddr, u64)>,
+) {
+    for key in keys {
+        let Some(hop) = lock.relay.hops.remove(&key) else { continue };
+        lock.relay.hops.remove(&hop.to);
+        send_close(lock, hop.to);
+    }
+}
+
+/// Closes circuits that are open for too long, idle ones would hold their slots otherwise.
+/// Both ends are told, nothing is coming from either of them.
+fn close_expired(
+    config: &RelayConfig,
+    lock: &mut ProtocolStateInnerMut,
+) {
+    let expired = lock
+        .relay
+        .hops
+        .iter()
+        .filter(|(_, hop)| hop.opener && hop.opened_at.elapsed() >= config.max_duration)
+        .map(|(key, _)| *key)
+        .collect::<Vec<_>>();
+    for key in expired {
+        close_hops(lock, vec![key]);
+        send_close(lock, key);
+    }
+}
+
+/// Handles relay message from the peer connected at `addr`. `Err` means peer sent something nobody asked for.
+pub(crate) async fn read(
+    protocol_state: &ProtocolState,
+    lock: &mut ProtocolStateInnerMut,
+    addr: SocketAddr,
+    message: RelayMessage,
+) -> Result<StreamAction> {
+    let config = &protocol_state.read().relay;
+
+    let answer = match message {
+        RelayMessage::Reserve => {
+            let ok = config.serve && (
+                lock.relay.reservations.contains(&addr) || lock.relay.reservations.len() < config.max_reservations
+            );
+            if ok {
+                lock.relay.reservations.insert(addr);
+                if let Some((_, metadata)) = lock.streams.get_mut(&addr) {
+                    metadata.relay = Some(protocol_state.read().server_addr);
+                }
+            }
+            Some(RelayMessage::Reserved(ok))
+        }
+        RelayMessage::Reserved(ok) => {
+            let sender = lock.relay.reserving.remove(&addr).context("Reservation nobody asked for")?;
+            if ok {
+                lock.relay.reserved.insert(addr);
+            }
+            let _ = sender.send(ok);
+            None
+        }
+        RelayMessage::Connect { target, circuit } => {
+            close_expired(config, lock);
+
+            let target_channel = lock.streams.get(&target).map(|(channel, _)| channel.clone());
+            let opened = lock.relay.hops.iter().filter(|((a, _), hop)| *a == addr && hop.opener).count();
+            let ok = config.serve
+                && target != addr
+                && lock.relay.reservations.contains(&target)
+                && lock.relay.hops.len() / 2 < config.max_circuits
+                && opened < config.max_circuits_per_peer
+                && !lock.relay.hops.contains_key(&(addr, circuit));
+
+            match target_channel {
+                Some(channel) if ok => {
+                    let target_circuit = lock.state.next();
+                    let now = Instant::now();
+                    let hop = |to, opener| Hop { to, opener, opened_at: now, window_start: now, window_bytes: 0 };
+                    lock.relay.hops.insert((addr, circuit), hop((target, target_circuit), true));
+                    lock.relay.hops.insert((target, target_circuit), hop((addr, circuit), false));
+
+                    let incoming = RelayMessage::Incoming { from: addr, circuit: target_circuit };
+                    // goes before any data of the circuit, both are sent through the same channel
+                    let _ = channel.send(StreamAction::Send(ProtocolMessage::Relay(incoming))).await;
+                    Some(RelayMessage::Connected { circuit, ok: true })
+                }
+                _ => Some(RelayMessage::Connected { circuit, ok: false }),
+            }
+        }
+        RelayMessage::Incoming { from, circuit } => {
+            let channel = match lock.streams.get(&addr) {
+                Some((channel, _)) if lock.relay.reserved.contains(&addr) => channel.clone(),
+                _ => bail!("Circuit without reservation"),
+            };
+            protocol_state.read().circuits.accept(addr, circuit, from, channel);
+            None
+        }
+        RelayMessage::Connected { circuit, ok } => {
+            let sender = lock.relay.connecting.remove(&(addr, circuit)).context("Circuit nobody asked for")?;
+            let _ = sender.send(ok);
+            None
+        }
+        RelayMessage::Data { circuit, data } => {
+            if let Some(hop) = lock.relay.hops.get_mut(&(addr, circuit)) {
+                let now = Instant::now();
+                if now.duration_since(hop.window_start) >= Duration::from_secs(1) {
+                    hop.window_start = now;
+                    hop.window_bytes = 0;
+                }
+                hop.window_bytes += data.len();
+
+                let (to, to_circuit) = hop.to;
+                let exceeded = hop.opened_at.elapsed() >= config.max_duration || hop.window_bytes > config.max_bandwidth;
+                let forwarded = !exceeded && match lock.streams.get(&to) {
+                    // circuit is closed rather than losing data in the middle of a message
+                    Some((channel, _)) => channel
+                        .try_send(StreamAction::Send(ProtocolMessage::Relay(RelayMessage::Data { circuit: to_circuit, data })))
+                        .is_ok(),
+                    None => false,
+                };
+                if !forwarded {
+                    close_hops(lock, vec![(addr, circuit)]);
+                    return Ok(StreamAction::Send(ProtocolMessage::Relay(RelayMessage::Close { circuit })));
+                }
+            } else if !protocol_state.read().circuits.deliver(addr, circuit, data) {
+                // closed on our side or the reader can't keep up
+                protocol_state.read().circuits.close(addr, circuit);
+                return Ok(StreamAction::Send(ProtocolMessage::Relay(RelayMessage::Close { circuit })));
+            }
+            None
+        }
+        RelayMessage::Close { circuit } => {
+            if lock.relay.hops.contains_key(&(addr, circuit)) {
+                close_hops(lock, vec![(addr, circuit)]);
+            } else {
+                protocol_state.read().circuits.close(addr, circuit);
+            }
+            None
+        }
+    };
+
+    Ok(match answer {
+        Some(answer) => StreamAction::Send(ProtocolMessage::Relay(answer)),
+        None => StreamAction::None,
+    })
+}
+
+/// Drops reservation and circuits of the node whose stream is gone
+pub(crate) fn stream_removed(
+    protocol_state: &ProtocolState,
+    lock: &mut ProtocolStateInnerMut,
+    addr: SocketAddr,
+) {
+    lock.relay.reservations.remove(&addr);
+    lock.relay.reserved.remove(&addr);
+    let hops = lock.relay.hops.keys().filter(|(a, _)| *a == addr).cloned().collect();
+    close_hops(lock, hops);
+    protocol_state.read().circuits.close_relay(addr);
+}
+
+impl ProtocolState {
+    /// Asks connected node to relay circuits to this one. Others learn the relay from `NodeInfo`
+    /// and can reach this node through it while the stream with relay stays open.
+    pub async fn reserve(&self, relay: SocketAddr) -> Result<()> {
+        let (sender, receiver) = oneshot::channel();
+        {
+            let lock = &mut *self.lock().await;
+            let (channel, _) = lock.streams.get(&relay).context("Not connected to relay")?;
+            channel.send(StreamAction::Send(ProtocolMessage::Relay(RelayMessage::Reserve))).await?;
+            lock.relay.reserving.insert(relay, sender);
+        }
+
+        let res = timeout(self.read().timeouts.handshake, receiver).await;
+        self.lock().await.relay.reserving.remove(&relay);
+        match res {
+            Ok(Ok(true)) => Ok(()),
+            Ok(Ok(false)) => bail!("Relay {} refused reservation", relay),
+            _ => bail!("No answer from relay {}", relay),
+        }
+    }
+
+    /// Relays this node is reachable through
+    pub async fn reservations(&self) -> Vec<SocketAddr> {
+        self.lock().await.relay.reserved.iter().cloned().collect()
+    }
+
+    /// Connects to `target` through a circuit at `relay`, connecting to relay first if needed
+    pub async fn connect_relayed(
+        &self,
+        relay: SocketAddr,
+        target: SocketAddr,
+    ) -> Result<Option<JoinHandle<()>>> {
+        if !self.lock().await.streams.contains_key(&relay) {
+            start_client(self.clone(), relay, None).await?;
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        let (circuit, channel) = {
+            let lock = &mut *self.lock().await;
+            if lock.streams.contains_key(&target) {
+                bail!("Already connected to {}", target)
+            }
+            let (channel, _) = lock.streams.get(&relay).context("Not connected to relay")?;
+            let channel = channel.clone();
+            let circuit = lock.state.next();
+
+            let connect = RelayMessage::Connect { target, circuit };
+            channel.send(StreamAction::Send(ProtocolMessage::Relay(connect))).await?;
+            lock.relay.connecting.insert((relay, circuit), sender);
+            (circuit, channel)
+        };
+        // registered before the answer, so data right after it isn't lost
+        let mut stream = self.read().circuits.open(relay, circuit, channel);
+
+        let res = timeout(self.read().timeouts.handshake, receiver).await;
+        self.lock().await.relay.connecting.remove(&(relay, circuit));
+        match res {
+            Ok(Ok(true)) => {}
+            Ok(Ok(false)) => bail!("Relay {} can't reach {}", relay, target),
+            _ => bail!("No answer from relay {}", relay),
+        }
+
+        self.lock().await.state.next();
+        self.send_message(
+            &mut stream,
+            ProtocolMessage::ConnInit {
+                server_addr: self.read().server_addr,
+            },
+        ).await?;
+
+        self.emit(ProtocolEvent::CircuitOpened { addr: target, relay });
+
+        let handle = run_client(self.clone(), target, target, TransportKind::Relay, Box::new(stream), None, None).await?;
+        if let Some((_, metadata)) = self.lock().await.streams.get_mut(&target) {
+            metadata.relay = Some(relay);
+        }
+        Ok(handle)
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::time::Instant;
+use serde::{Deserialize, Serialize};
+use crate::types::{
+    state::{ProtocolState, ProtocolStateInnerMut},
+    event::ProtocolEvent,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehaviour {
+    ProtocolViolation, // unknown opcode, bad reserved bits, malformed payload, unexpected message
+    PingFailure, // didn't answer ping in time
+    DuplicateSpam, // sent the same message more than once
+    InvalidSignature,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScoringConfig {
+    pub protocol_violation_penalty: i32,
+    pub ping_failure_penalty: i32,
+    pub duplicate_spam_penalty: i32,
+    pub invalid_signature_penalty: i32,
+    pub ban_threshold: i32, // peer is banned once the score drops below that
+    #[serde(with = "crate::utils::duration_ms")]
+    pub ban_duration: Duration,
+    pub recovery_per_minute: i32, // score slowly goes back to 0 if peer behaves
+}
+
+impl Default for ScoringConfig {
+    fn default() -> Self {
+        Self {
+            protocol_violation_penalty: 50,
+            ping_failure_penalty: 20,
+            duplicate_spam_penalty: 5,
+            invalid_signature_penalty: 100,
+            ban_threshold: -100,
+            ban_duration: Duration::from_secs(60 * 60),
+            recovery_per_minute: 1,
+        }
+    }
+}
+
+impl ScoringConfig {
+    pub fn penalty(&self, misbehaviour: Misbehaviour) -> i32 {
+        match misbehaviour {
+            Misbehaviour::ProtocolViolation => self.protocol_violation_penalty,
+            Misbehaviour::PingFailure => self.ping_failure_penalty,
+            Misbehaviour::DuplicateSpam => self.duplicate_spam_penalty,
+            Misbehaviour::InvalidSignature => self.invalid_signature_penalty,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Score {
+    value: i32,
+    updated_at: Instant,
+}
+
+/// Scores and the bans they lead to are kept per ip. Address the peer claims in `CONN_INIT`
+/// is its own choice, so a banned peer could come back with another port.
+#[derive(Debug)]
+pub struct PeerScores {
+    scores: HashMap<IpAddr, Score>,
+    bans: HashMap<IpAddr, Instant>, // ip -> banned until
+}
+
+impl PeerScores {
+    pub fn new() -> Self {
+        Self {
+            scores: HashMap::new(),
+            bans: HashMap::new(),
+        }
+    }
+
+    pub fn score(&self, config: &ScoringConfig, ip: &IpAddr) -> i32 {
+        match self.scores.get(ip) {
+            Some(score) => {
+                let minutes = score.updated_at.elapsed().as_secs_f64() / 60.0;
+                let recovered = (minutes * config.recovery_per_minute as f64) as i32;
+                score.value.saturating_add(recovered).min(0)
+            },
+            None => 0,
+        }
+    }
+
+    /// Lowers the score and returns `true` if ip has to be banned because of that
+    pub fn penalize(&mut self, config: &ScoringConfig, ip: IpAddr, misbehaviour: Misbehaviour) -> bool {
+        let value = self.score(config, &ip).saturating_sub(config.penalty(misbehaviour));
+        self.scores.insert(ip, Score {
+            value,
+            updated_at: Instant::now(),
+        });
+
+        if value < config.ban_threshold {
+            self.ban(ip, config.ban_duration);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Sets the score remembered from previous runs, the lowest one if several peers shared the ip
+    pub fn restore(&mut self, config: &ScoringConfig, ip: IpAddr, value: i32) {
+        if value < self.score(config, &ip) {
+            self.scores.insert(ip, Score {
+                value,
+                updated_at: Instant::now(),
+            });
+        }
+    }
+
+    pub fn ban(&mut self, ip: IpAddr, duration: Duration) {
+        self.bans.insert(ip, Instant::now() + duration);
+    }
+
+    /// Lifts the ban and resets the score. Returns `false` if ip wasn't banned
+    pub fn unban(&mut self, ip: &IpAddr) -> bool {
+        self.scores.remove(ip);
+        self.bans.remove(ip).is_some_and(|until| until > Instant::now())
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.bans.get(ip).is_some_and(|until| *until > Instant::now())
+    }
+
+    /// Active bans with time left for each one
+    pub fn bans(&mut self) -> Vec<(IpAddr, Duration)> {
+        let now = Instant::now();
+        self.bans.retain(|_, until| *until > now);
+        self.bans
+            .iter()
+            .map(|(ip, until)| (*ip, until.duration_since(now)))
+            .collect()
+    }
+}
+
+impl Default for PeerScores {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Ip the peer connected at `addr` actually connects from, not the one it claims
+pub(crate) fn peer_ip(lock: &ProtocolStateInnerMut, addr: SocketAddr) -> IpAddr {
+    lock.streams.get(&addr).map_or(addr.ip(), |(_, metadata)| metadata.remote_addr.ip())
+}
+
+/// Penalizes the peer connected at `addr` and returns `true` if it got banned and has to be disconnected.
+/// `addr` can also be the remote address of a connection that isn't a stream yet.
+pub(crate) async fn penalize(
+    protocol_state: &ProtocolState,
+    lock: &mut ProtocolStateInnerMut,
+    addr: SocketAddr,
+    misbehaviour: Misbehaviour,
+) -> bool {
+    let ip = peer_ip(lock, addr);
+    let config = &protocol_state.read().scoring;
+    let banned = lock.scores.penalize(config, ip, misbehaviour);
+
+    protocol_state.emit(ProtocolEvent::PeerPenalized {
+        addr,
+        misbehaviour,
+        score: lock.scores.score(config, &ip),
+        banned,
+    });
+
+    banned
+}
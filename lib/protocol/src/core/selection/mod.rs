@@ -0,0 +1,111 @@
+use std::net::SocketAddr;
+use serde::{Deserialize, Serialize};
+use crate::core::vivaldi::Coordinate;
+use crate::types::state::{ProtocolState, ProtocolStateInnerMut};
+
+pub mod policies;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeerSelectionConfig {
+    pub min_peers: usize, // nodes from routing table and peer exchange are dialed until there are that many streams
+    pub max_peers: usize, // announced nodes are connected to until there are that many streams, then policy decides
+}
+
+impl Default for PeerSelectionConfig {
+    fn default() -> Self {
+        Self {
+            min_peers: 4,
+            max_peers: 8,
+        }
+    }
+}
+
+/// Node as policy sees it, either connected one or candidate to connect to
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    pub ping: Option<u16>, // measured for connected nodes, predicted by coordinates for candidates
+    pub coordinate: Option<Coordinate>,
+}
+
+/// Decides which nodes are worth being connected to
+pub trait PeerSelectionPolicy: Send + Sync {
+    /// Picks up to `count` of `candidates` to connect to, most preferred first
+    fn select(
+        &self,
+        connected: &[PeerInfo],
+        candidates: Vec<PeerInfo>,
+        count: usize,
+        random: &mut dyn FnMut() -> u64,
+    ) -> Vec<SocketAddr>;
+
+    /// Called when there are `max_peers` streams already and another node is announced.
+    /// Returns the connected node to drop in favor of `candidate`, `None` keeps the current ones.
+    /// Sticky peers are never in `connected`.
+    fn replace(
+        &self,
+        connected: &[PeerInfo],
+        candidate: &PeerInfo,
+        random: &mut dyn FnMut() -> u64,
+    ) -> Option<SocketAddr>;
+}
+
+/// Connected nodes, without sticky ones if they are not allowed to be dropped
+pub(crate) fn connected_peers(
+    protocol_state: &ProtocolState,
+    lock: &ProtocolStateInnerMut,
+    with_sticky: bool,
+) -> Vec<PeerInfo> {
+    lock.streams
+        .iter()
+        .filter(|(addr, _)| with_sticky || !protocol_state.read().sticky_peers.contains(addr))
+        .map(|(addr, (_, metadata))| PeerInfo {
+            addr: *addr,
+            ping: metadata.rtt.ping(), // inbound streams have none until the first pong
+            coordinate: metadata.coordinate,
+        })
+        .collect()
+}
+
+/// Node we are not connected to, with coordinate from the announcement or remembered from before
+pub(crate) fn candidate(
+    lock: &ProtocolStateInnerMut,
+    addr: SocketAddr,
+    coordinate: Option<Coordinate>,
+) -> PeerInfo {
+    let record = lock.known_peers.get(&addr);
+    let coordinate = coordinate.or_else(|| record.and_then(|r| r.coordinate));
+
+    PeerInfo {
+        addr,
+        ping: coordinate
+            .map(|c| lock.coordinate.predict(&c))
+            .or_else(|| record.and_then(|r| r.latency)),
+        coordinate,
+    }
+}
+
+/// Lets the policy pick which of `addrs` to dial so that there are at least `min_peers` streams
+pub(crate) fn pick_to_dial(
+    protocol_state: &ProtocolState,
+    lock: &mut ProtocolStateInnerMut,
+    addrs: Vec<SocketAddr>,
+) -> Vec<SocketAddr> {
+    let missing = protocol_state.read().selection.min_peers.saturating_sub(lock.streams.len());
+    if missing == 0 || addrs.is_empty() {
+        return vec![];
+    }
+
+    let connected = connected_peers(protocol_state, lock, true);
+    let candidates = addrs
+        .into_iter()
+        .map(|addr| candidate(lock, addr, None))
+        .collect();
+
+    let state = &mut lock.state;
+    protocol_state
+        .read()
+        .selection_policy
+        .select(&connected, candidates, missing, &mut || state.next())
+}
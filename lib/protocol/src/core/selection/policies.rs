@@ -0,0 +1,229 @@
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use crate::core::selection::{PeerInfo, PeerSelectionPolicy};
+use crate::core::vivaldi::Coordinate;
+use crate::utils::sample::sample;
+
+/// Prefers nodes with the smallest ping. Connected node is replaced
+/// only if the candidate is predicted to be noticeably faster.
+#[derive(Debug, Clone)]
+pub struct LowestLatency {
+    pub min_gain: f32, // fraction of the worst ping candidate has to beat it by
+}
+
+impl Default for LowestLatency {
+    fn default() -> Self {
+        Self {
+            min_gain: 0.2,
+        }
+    }
+}
+
+impl PeerSelectionPolicy for LowestLatency {
+    fn select(
+        &self,
+        _connected: &[PeerInfo],
+        candidates: Vec<PeerInfo>,
+        count: usize,
+        random: &mut dyn FnMut() -> u64,
+    ) -> Vec<SocketAddr> {
+        // shuffled first, so nodes with unknown ping are not always picked in the same order
+        let mut candidates = sample(candidates, &mut || random(), usize::MAX);
+        candidates.sort_by_key(|c| c.ping.unwrap_or(u16::MAX));
+        candidates.into_iter().take(count).map(|c| c.addr).collect()
+    }
+
+    fn replace(
+        &self,
+        connected: &[PeerInfo],
+        candidate: &PeerInfo,
+        _random: &mut dyn FnMut() -> u64,
+    ) -> Option<SocketAddr> {
+        // dropping a node for the one we know nothing about is what made connections churn
+        let ping = candidate.ping? as f32;
+        // connected node that never answered a ping is as bad as it gets
+        let worst = connected.iter().max_by_key(|p| p.ping.unwrap_or(u16::MAX))?;
+
+        let worst_ping = worst.ping.unwrap_or(u16::MAX) as f32;
+        (ping < worst_ping * (1.0 - self.min_gain)).then_some(worst.addr)
+    }
+}
+
+/// Prefers nodes far from each other by coordinates, so that
+/// connections spread over the network instead of a single region
+#[derive(Debug, Clone)]
+pub struct CoordinateDiversity {
+    pub min_gain: f32, // fraction of the distance candidate has to improve the spread by
+}
+
+impl Default for CoordinateDiversity {
+    fn default() -> Self {
+        Self {
+            min_gain: 0.2,
+        }
+    }
+}
+
+/// Distance to the closest of `others`, `None` if there is nothing to compare with
+fn nearest(coordinate: &Coordinate, others: &[Coordinate]) -> Option<f32> {
+    others
+        .iter()
+        .map(|o| coordinate.distance(o))
+        .min_by(|a, b| a.total_cmp(b))
+}
+
+impl PeerSelectionPolicy for CoordinateDiversity {
+    fn select(
+        &self,
+        connected: &[PeerInfo],
+        candidates: Vec<PeerInfo>,
+        count: usize,
+        random: &mut dyn FnMut() -> u64,
+    ) -> Vec<SocketAddr> {
+        let mut picked_coordinates = connected.iter().filter_map(|p| p.coordinate).collect::<Vec<_>>();
+        let (mut located, unknown): (Vec<_>, Vec<_>) = candidates
+            .into_iter()
+            .partition(|c| c.coordinate.is_some());
+
+        // greedily takes the candidate farthest from everything picked so far
+        let mut picked = vec![];
+        while picked.len() < count && !located.is_empty() {
+            let i = match picked_coordinates.is_empty() {
+                true => (random() % located.len() as u64) as usize,
+                false => located
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| (i, nearest(&c.coordinate.expect("partitioned"), &picked_coordinates)))
+                    .max_by(|(_, a), (_, b)| a.unwrap_or(0.0).total_cmp(&b.unwrap_or(0.0)))
+                    .map(|(i, _)| i)
+                    .expect("not empty"),
+            };
+            let c = located.swap_remove(i);
+            picked_coordinates.push(c.coordinate.expect("partitioned"));
+            picked.push(c.addr);
+        }
+
+        let missing = count - picked.len();
+        picked.extend(sample(unknown, &mut || random(), missing).into_iter().map(|c| c.addr));
+        picked
+    }
+
+    fn replace(
+        &self,
+        connected: &[PeerInfo],
+        candidate: &PeerInfo,
+        _random: &mut dyn FnMut() -> u64,
+    ) -> Option<SocketAddr> {
+        let coordinate = candidate.coordinate?;
+        let located = connected
+            .iter()
+            .filter_map(|p| p.coordinate.map(|c| (p.addr, c)))
+            .collect::<Vec<_>>();
+
+        // the most redundant node is the one closest to another connected node
+        let (redundant, spread, others) = located
+            .iter()
+            .filter_map(|(addr, c)| {
+                let others = located
+                    .iter()
+                    .filter(|(a, _)| a != addr)
+                    .map(|(_, c)| *c)
+                    .collect::<Vec<_>>();
+                nearest(c, &others).map(|d| (*addr, d, others))
+            })
+            .min_by(|(_, a, _), (_, b, _)| a.total_cmp(b))?;
+
+        let candidate_spread = nearest(&coordinate, &others)?;
+        (candidate_spread > spread * (1.0 + self.min_gain)).then_some(redundant)
+    }
+}
+
+/// Picks nodes at random and never drops connected ones for announced nodes
+#[derive(Debug, Clone, Default)]
+pub struct RandomSelection;
+
+impl PeerSelectionPolicy for RandomSelection {
+    fn select(
+        &self,
+        _connected: &[PeerInfo],
+        candidates: Vec<PeerInfo>,
+        count: usize,
+        random: &mut dyn FnMut() -> u64,
+    ) -> Vec<SocketAddr> {
+        sample(candidates, &mut || random(), count)
+            .into_iter()
+            .map(|c| c.addr)
+            .collect()
+    }
+
+    fn replace(
+        &self,
+        _connected: &[PeerInfo],
+        _candidate: &PeerInfo,
+        _random: &mut dyn FnMut() -> u64,
+    ) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Lowest latency for most of the connections, but keeps `random_links` randomly picked ones.
+/// Those long links are never replaced and keep the network from splitting into close clusters.
+pub struct Hybrid {
+    pub random_links: usize,
+    pub latency: LowestLatency,
+    long_links: Mutex<HashSet<SocketAddr>>, // picked at random, forgotten once disconnected
+}
+
+impl Hybrid {
+    pub fn new(random_links: usize) -> Self {
+        Self {
+            random_links,
+            latency: LowestLatency::default(),
+            long_links: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl Default for Hybrid {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
+
+impl PeerSelectionPolicy for Hybrid {
+    fn select(
+        &self,
+        connected: &[PeerInfo],
+        candidates: Vec<PeerInfo>,
+        count: usize,
+        random: &mut dyn FnMut() -> u64,
+    ) -> Vec<SocketAddr> {
+        let mut long_links = self.long_links.lock().expect("Long links lock is poisoned");
+        long_links.retain(|addr| connected.iter().any(|p| p.addr == *addr));
+
+        let missing = self.random_links.saturating_sub(long_links.len()).min(count);
+        let mut picked = sample(candidates, &mut || random(), usize::MAX);
+        let rest = picked.split_off(missing.min(picked.len()));
+        long_links.extend(picked.iter().map(|c| c.addr));
+
+        let picked = picked.into_iter().map(|c| c.addr).collect::<Vec<_>>();
+        let count = count - picked.len();
+        picked.into_iter().chain(self.latency.select(connected, rest, count, random)).collect()
+    }
+
+    fn replace(
+        &self,
+        connected: &[PeerInfo],
+        candidate: &PeerInfo,
+        random: &mut dyn FnMut() -> u64,
+    ) -> Option<SocketAddr> {
+        let long_links = self.long_links.lock().expect("Long links lock is poisoned");
+        let short_links = connected
+            .iter()
+            .filter(|p| !long_links.contains(&p.addr))
+            .cloned()
+            .collect::<Vec<_>>();
+        self.latency.replace(&short_links, candidate, random)
+    }
+}
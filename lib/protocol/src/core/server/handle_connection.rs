@@ -1,102 +1,189 @@
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tokio::task::JoinHandle;
+use tokio::time::timeout;
 use crate::core::{
-    frames::ProtocolMessage,
+    frames::{CloseReason, ProtocolMessage},
+    dht,
+    nat::NatMessage,
     node_info::NodeInfo,
+    handshake::HandshakeError,
+    peer_store,
+    scoring::{penalize, Misbehaviour},
 };
-use crate::core::stream::protocol_handle_stream;
+use crate::core::server::limits::ConnectionLimiter;
+use crate::core::stream::{protocol_handle_stream, types::StreamAction};
+use crate::core::transport::{
+    relay::RelayStream,
+    udp::{UdpInbound, UdpTransport},
+    BoxedStream,
+    PeerStream,
+    TransportKind,
+};
+#[cfg(feature = "sim")]
+use crate::core::transport::memory::MemoryStream;
 use crate::types::{
-    state::{ProtocolState, StreamMetadata},
-    package::{AlertPackage, AlertPackageLevel, AppPackage},
+    state::{ProtocolState, StreamDirection, StreamMetadata},
+    event::ProtocolEvent,
 };
 
-async fn handle_connection(
-    protocol_state: ProtocolState,
-    mut stream: TcpStream,
-) {
-    let addr;
-    let stream_request_receiver;
+/// Reads `CONN_INIT` and registers the stream. Returns `None` if connection was only
+/// a health check and is already closed.
+async fn handshake(
+    protocol_state: &ProtocolState,
+    stream: &mut BoxedStream,
+    remote_addr: SocketAddr,
+    transport: TransportKind,
+) -> Result<Option<(SocketAddr, Receiver<StreamAction>)>, HandshakeError> {
+    let conn_init_timeout = protocol_state.read().timeouts.conn_init;
 
-    {
-        let (first_message, _) = ProtocolMessage::from_stream(&mut stream)
-            .await
-            .expect("---Failed to read stream")
-            .expect("---Should receive at least init message");
+    let (first_message, _) = timeout(conn_init_timeout, ProtocolMessage::from_stream(stream))
+        .await
+        .map_err(|_| HandshakeError::ConnInitTimeout)?
+        .map_err(|_| HandshakeError::Malformed)?
+        .ok_or(HandshakeError::Closed)?;
 
-        addr = match first_message {
-            ProtocolMessage::ConnInit { server_addr } => server_addr,
-            ProtocolMessage::Ping => {
-                ProtocolState::send_message(
-                    &mut stream,
-                    ProtocolMessage::Pong(None),
-                ).await.expect("Failed to send message to stream");
-                ProtocolState::send_message(
-                    &mut stream,
-                    ProtocolMessage::ConnClosed,
-                ).await.expect("Failed to send message to stream");
-                stream.shutdown().await.expect("Failed to disconnect");
-                return;
-            },
-            _ => {
-                unreachable!("Unexpected first message")
-            }
-        };
+    let addr = match first_message {
+        ProtocolMessage::ConnInit { server_addr } => server_addr,
+        ProtocolMessage::Ping => {
+            // health check from bootstrap node, other side is gone right after this
+            let _ = protocol_state.send_message(
+                stream,
+                ProtocolMessage::Pong(None),
+            ).await;
+            let _ = protocol_state.send_message(
+                stream,
+                ProtocolMessage::ConnClosed(CloseReason::Unspecified),
+            ).await;
+            let _ = stream.shutdown().await;
+            return Ok(None);
+        },
+        ProtocolMessage::Dht(message) => {
+            // one-shot request from the node doing lookup or forwarding addressed message
+            let answer = dht::answer_query(protocol_state, remote_addr, message)
+                .await
+                .map_err(|_| HandshakeError::Malformed)?;
+            dht::close_query(protocol_state, stream, answer).await;
+            return Ok(None);
+        },
+        _ => {
+            return Err(HandshakeError::Malformed);
+        }
+    };
 
-        let lock = &mut *protocol_state.lock().await;
+    let lock = &mut *protocol_state.lock().await;
+    if lock.scores.is_banned(&remote_addr.ip()) || lock.scores.is_banned(&addr.ip()) {
+        return Err(HandshakeError::Banned);
+    }
+    // otherwise anyone could take over the stream of another peer by claiming its address
+    if lock.streams.contains_key(&addr) {
+        return Err(HandshakeError::AlreadyConnected);
+    }
 
-        lock.state.next();
+    lock.state.next();
 
-        protocol_state
-            .read()
-            .package_sender
-            .send(AppPackage::Alert(AlertPackage {
-                level: AlertPackageLevel::INFO,
-                msg: format!("New join from {:?}", addr),
-            }))
-            .await
-            .expect("---Failed to send app package");
+    let mut conn_metadata = StreamMetadata::new(StreamDirection::Inbound, remote_addr, transport);
+
+    // lets node behind NAT know its public address
+    protocol_state.send_message(
+        stream,
+        ProtocolMessage::Nat(NatMessage::Observed(remote_addr)),
+    )
+        .await
+        .map_err(|_| HandshakeError::Closed)?;
+
+    {
+        let state = &mut lock.state;
+        let streams = &mut lock.streams;
 
-        let mut conn_metadata = StreamMetadata::new();
+        let another_conn = streams.iter().find(|(k, _)| !k.eq(&&addr));
 
-        {
-            let package_sender = &protocol_state.read().package_sender;
-            let state = &mut lock.state;
-            let streams = &mut lock.streams;
+        if let Some((targ_addr, (_, targ_metadata))) = another_conn {
+            conn_metadata.knows_about.push(*targ_addr);
 
-            let another_conn = streams.iter().find(|(k, _)| !k.eq(&&addr));
+            // todo: use coordinates to find the closest node to the client
+            //  we don't know its coordinate yet, it comes with the first pong
+            state.next();
+            protocol_state.send_message(
+                stream,
+                ProtocolMessage::NodeStatus(
+                    // unreachable node is still worth telling about, client can punch a hole through us
+                    targ_metadata
+                        .node_info(*targ_addr)
+                        .unwrap_or_else(|| NodeInfo::new(*targ_addr, targ_metadata.rtt.ping().unwrap_or(0))),
+                    targ_metadata.coordinate,
+                ),
+            )
+                .await
+                .map_err(|_| HandshakeError::Closed)?;
+        }
+    }
+
+    // nothing is awaited past this point, so timeout can't leave the stream half-registered
+    let channels = tokio::sync::mpsc::channel(protocol_state.read().buffers.stream);
+    lock.streams.insert(addr, (channels.0, conn_metadata));
+    peer_store::remember(&protocol_state.read().scoring, lock, addr);
+    protocol_state.emit(ProtocolEvent::PeerConnected {
+        addr,
+        direction: StreamDirection::Inbound,
+        transport,
+    });
 
-            if let Some((targ_addr, (_, targ_metadata))) = another_conn {
-                package_sender
-                    .send(AppPackage::Alert(AlertPackage {
-                        level: AlertPackageLevel::DEBUG,
-                        msg: format!("Sending info about another node {}", targ_addr),
-                    }))
-                    .await
-                    .expect("---Failed to send app package");
+    Ok(Some((addr, channels.1)))
+}
 
-                conn_metadata.knows_about.push(targ_addr.clone());
+#[tracing::instrument(name = "inbound", skip(protocol_state, stream))]
+pub(crate) async fn handle_connection(
+    protocol_state: ProtocolState,
+    mut stream: BoxedStream,
+    remote_addr: SocketAddr,
+    transport: TransportKind,
+) {
+    let handshake_timeout = protocol_state.read().timeouts.handshake;
+    let res = timeout(handshake_timeout, handshake(&protocol_state, &mut stream, remote_addr, transport))
+        .await
+        .unwrap_or(Err(HandshakeError::HandshakeTimeout));
 
-                // todo: check angles and pings to find the closest node to the client
-                //  idk the ping to this new connection nor who hes connected to
-                //  i can think only of one thing - do the ping-pong first
-                state.next();
-                ProtocolState::send_message(
-                    &mut stream,
-                    ProtocolMessage::NodeStatus(
-                        NodeInfo::new(targ_addr.clone(), targ_metadata.ping)
-                    ),
-                )
-                    .await
-                    .expect("---Failed to send protocol message");
-            }
+    let (addr, stream_request_receiver) = match res {
+        Ok(Some(res)) => res,
+        Ok(None) => return,
+        Err(e) => {
+            protocol_state.emit(ProtocolEvent::HandshakeFailed {
+                addr: remote_addr,
+                direction: StreamDirection::Inbound,
+                error: e.to_string(),
+            });
+
+            let reason = match e {
+                HandshakeError::ConnInitTimeout | HandshakeError::HandshakeTimeout => CloseReason::HandshakeTimeout,
+                HandshakeError::Banned => CloseReason::Banned,
+                // lost datagram breaks framing of udp link, that's not the peer's fault
+                HandshakeError::Malformed if transport == TransportKind::Udp => CloseReason::ProtocolViolation,
+                HandshakeError::Malformed => {
+                    let lock = &mut *protocol_state.lock().await;
+                    if penalize(&protocol_state, lock, remote_addr, Misbehaviour::ProtocolViolation).await {
+                        CloseReason::Banned
+                    } else {
+                        CloseReason::ProtocolViolation
+                    }
+                },
+                _ => CloseReason::Unspecified,
+            };
+            let _ = protocol_state.send_message(
+                &mut stream,
+                ProtocolMessage::ConnClosed(reason),
+            ).await;
+            let _ = stream.shutdown().await;
+            return;
         }
+    };
 
-        let channels = tokio::sync::mpsc::channel(100);
-        stream_request_receiver = channels.1;
-        lock.streams.insert(addr.clone(), (channels.0, conn_metadata));
-    }
+    dht::introduce(&protocol_state, addr);
 
     protocol_handle_stream(
         protocol_state,
@@ -106,60 +193,183 @@ async fn handle_connection(
     ).await;
 }
 
+const MAX_REFUSING: usize = 16; // connections being told why they are refused at once, the rest are just dropped
+
+async fn refuse_connection(
+    protocol_state: ProtocolState,
+    mut stream: TcpStream,
+    reason: CloseReason,
+    _permit: OwnedSemaphorePermit,
+) {
+    // don't let slow client hold the task, it's being refused anyway
+    let _ = timeout(Duration::from_secs(1), async {
+        let _ = protocol_state.send_message(
+            &mut stream,
+            ProtocolMessage::ConnClosed(reason),
+        ).await;
+        let _ = stream.shutdown().await;
+    }).await;
+}
+
 async fn running_server(
     app_state: ProtocolState,
     server: TcpListener,
 ) {
-    let mut handles = vec![];
+    let mut handles: Vec<JoinHandle<()>> = vec![];
+    let limiter = ConnectionLimiter::new(app_state.read().inbound_limits.clone());
+    let refusing = Arc::new(Semaphore::new(MAX_REFUSING));
+
+    let refuse = |stream, reason| {
+        // flood of refused connections can't make the node spawn a task for each of them
+        let permit = refusing.clone().try_acquire_owned().ok()?;
+        Some(tokio::spawn(refuse_connection(app_state.clone(), stream, reason, permit)))
+    };
 
     loop {
         match server.accept().await {
-            Ok((stream, _addr)) => { // I don't think I need an address of local socket
+            Ok((stream, addr)) => { // that's an address of the client socket, not its server
+                handles.retain(|h| !h.is_finished());
+
+                if app_state.is_banned(addr.ip()).await {
+                    handles.extend(refuse(stream, CloseReason::Banned));
+                    continue;
+                }
+
+                let permit = match limiter.try_acquire(addr.ip()) {
+                    Ok(permit) => permit,
+                    Err(reason) => {
+                        app_state.emit(ProtocolEvent::ConnectionRefused { addr, reason });
+
+                        handles.extend(refuse(stream, reason));
+                        continue;
+                    }
+                };
+
                 let h = {
                     let app_state = app_state.clone();
-                    tokio::spawn(handle_connection(
-                        app_state,
-                        stream,
-                    ))
+                    tokio::spawn(async move {
+                        handle_connection(
+                            app_state,
+                            Box::new(stream),
+                            addr,
+                            TransportKind::Tcp,
+                        ).await;
+                        drop(permit);
+                    })
                 };
                 handles.push(h);
             },
             Err(e) => {
-                app_state
-                    .read()
-                    .package_sender
-                    .send(AppPackage::Alert(AlertPackage {
-                        level: AlertPackageLevel::ERROR,
-                        msg: format!("Failed to accept connection - {}", e),
-                    }))
-                    .await
-                    .expect("---Failed to send app package");
+                app_state.emit(ProtocolEvent::AcceptFailed { error: e.to_string() });
             }
         }
     }
 }
 
-pub async fn start_server(
+/// Server address of the node, bound by the builder so failing to bind fails the build
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(feature = "sim")]
+    Memory(Receiver<(MemoryStream, SocketAddr)>), // streams are handled as tcp ones
+}
+
+/// Accepts connections on the listener bound by the builder
+pub fn start_server(
     protocol_state: ProtocolState,
-    server_addr: SocketAddr,
-) -> Option<JoinHandle<()>> {
-    let server = {
-        let server = TcpListener::bind(server_addr).await.expect("---Failed to assign udp socket");
-
-        protocol_state
-            .read()
-            .package_sender
-            .send(AppPackage::Alert(AlertPackage {
-                level: AlertPackageLevel::INFO,
-                msg: format!("Listening on {}", server_addr),
-            }))
-            .await
-            .expect("---Failed to send app package");
+    server: Listener,
+) -> [JoinHandle<()>; 1] {
+    protocol_state.emit(ProtocolEvent::Listening { addr: protocol_state.read().server_addr });
 
-        server
+    let handle = match server {
+        Listener::Tcp(server) => tokio::spawn(running_server(protocol_state, server)),
+        #[cfg(feature = "sim")]
+        Listener::Memory(inbound) => tokio::spawn(running_inbound_server(protocol_state, inbound, TransportKind::Tcp)),
     };
+    [handle]
+}
+
+/// Accepts streams other transports hand over, udp links or relayed circuits
+async fn running_inbound_server<S: PeerStream + 'static>(
+    app_state: ProtocolState,
+    mut inbound: Receiver<(S, SocketAddr)>,
+    transport: TransportKind,
+) {
+    let mut handles: Vec<JoinHandle<()>> = vec![];
+    let limiter = ConnectionLimiter::new(app_state.read().inbound_limits.clone());
+
+    while let Some((stream, addr)) = inbound.recv().await {
+        handles.retain(|h| !h.is_finished());
 
-    Some(tokio::spawn(
-        running_server(protocol_state, server),
-    ))
+        // nothing is sent back, link is dropped together with the stream
+        if app_state.is_banned(addr.ip()).await {
+            continue;
+        }
+        let permit = match limiter.try_acquire(addr.ip()) {
+            Ok(permit) => permit,
+            Err(reason) => {
+                app_state.emit(ProtocolEvent::ConnectionRefused { addr, reason });
+
+                let mut stream: BoxedStream = Box::new(stream);
+                let _ = app_state.send_message(
+                    &mut stream,
+                    ProtocolMessage::ConnClosed(reason),
+                ).await;
+                continue;
+            }
+        };
+
+        let h = {
+            let app_state = app_state.clone();
+            tokio::spawn(async move {
+                handle_connection(
+                    app_state,
+                    Box::new(stream),
+                    addr,
+                    transport,
+                ).await;
+                drop(permit);
+            })
+        };
+        handles.push(h);
+    }
+}
+
+/// Tells nodes we are connected to the address their udp datagrams come from
+async fn answering_probes(
+    app_state: ProtocolState,
+    udp: Arc<UdpTransport>,
+    mut probes: Receiver<(SocketAddr, u64)>,
+) {
+    while let Some((from, nonce)) = probes.recv().await {
+        let connected = app_state
+            .lock()
+            .await
+            .streams
+            .values()
+            .any(|(_, metadata)| metadata.remote_addr.ip() == from.ip());
+        if connected {
+            udp.answer_probe(from, nonce).await;
+        }
+    }
+}
+
+/// Reads the udp socket and handles links punched by other nodes same way as tcp connections
+pub fn start_udp_server(
+    protocol_state: ProtocolState,
+    udp: Arc<UdpTransport>,
+    inbound: UdpInbound,
+) -> [JoinHandle<()>; 3] {
+    let receive_handle = tokio::spawn(udp.clone().receive());
+    let probes_handle = tokio::spawn(answering_probes(protocol_state.clone(), udp, inbound.probes));
+    let server_handle = tokio::spawn(running_inbound_server(protocol_state, inbound.links, TransportKind::Udp));
+
+    [receive_handle, probes_handle, server_handle]
+}
+
+/// Handles circuits other nodes open to this one through relays
+pub fn start_relay_server(
+    protocol_state: ProtocolState,
+    inbound: Receiver<(RelayStream, SocketAddr)>,
+) -> [JoinHandle<()>; 1] {
+    [tokio::spawn(running_inbound_server(protocol_state, inbound, TransportKind::Relay))]
 }
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
+use serde::{Deserialize, Serialize};
+use crate::core::frames::CloseReason;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InboundLimits {
+    pub max_inbound: usize, // total incoming connections, including ones still doing handshake
+    pub max_per_ip: usize,
+    pub max_per_subnet: usize, // /24 for IPv4 and /64 for IPv6
+    pub accept_rate: f64, // connections per second accepted on average
+    pub accept_burst: u32, // connections accepted at once before rate applies
+}
+
+impl Default for InboundLimits {
+    fn default() -> Self {
+        Self {
+            max_inbound: 64,
+            max_per_ip: 4,
+            max_per_subnet: 16,
+            accept_rate: 10.0,
+            accept_burst: 20,
+        }
+    }
+}
+
+pub(crate) fn subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let o = ip.octets();
+            IpAddr::from([o[0], o[1], o[2], 0])
+        }
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            IpAddr::from([s[0], s[1], s[2], s[3], 0, 0, 0, 0])
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+struct LimiterInner {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    per_subnet: HashMap<IpAddr, usize>,
+    bucket: TokenBucket,
+}
+
+/// Keeps track of incoming connections. Each accepted connection holds an `InboundPermit`
+/// which frees its slot once connection is done.
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    limits: InboundLimits,
+    inner: Arc<Mutex<LimiterInner>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limits: InboundLimits) -> Self {
+        let tokens = limits.accept_burst as f64;
+        Self {
+            limits,
+            inner: Arc::new(Mutex::new(LimiterInner {
+                total: 0,
+                per_ip: HashMap::new(),
+                per_subnet: HashMap::new(),
+                bucket: TokenBucket {
+                    tokens,
+                    updated_at: Instant::now(),
+                },
+            })),
+        }
+    }
+
+    /// Returns the reason to refuse connection if any of the limits is reached
+    pub fn try_acquire(&self, ip: IpAddr) -> Result<InboundPermit, CloseReason> {
+        let mut lock = self.inner.lock().expect("Failed to lock limiter");
+        let inner = &mut *lock;
+
+        let subnet = subnet(ip);
+        let ip_count = inner.per_ip.get(&ip).cloned().unwrap_or(0);
+        let subnet_count = inner.per_subnet.get(&subnet).cloned().unwrap_or(0);
+
+        if inner.total >= self.limits.max_inbound
+            || ip_count >= self.limits.max_per_ip
+            || subnet_count >= self.limits.max_per_subnet
+        {
+            return Err(CloseReason::TooManyConnections);
+        }
+
+        { // token is taken only by connections that are accepted
+            let now = Instant::now();
+            let bucket = &mut inner.bucket;
+            let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.limits.accept_rate).min(self.limits.accept_burst as f64);
+            bucket.updated_at = now;
+
+            if bucket.tokens < 1.0 {
+                return Err(CloseReason::RateLimited);
+            }
+            bucket.tokens -= 1.0;
+        }
+
+        inner.total += 1;
+        *inner.per_ip.entry(ip).or_insert(0) += 1;
+        *inner.per_subnet.entry(subnet).or_insert(0) += 1;
+
+        Ok(InboundPermit {
+            ip,
+            inner: self.inner.clone(),
+        })
+    }
+}
+
+pub struct InboundPermit {
+    ip: IpAddr,
+    inner: Arc<Mutex<LimiterInner>>,
+}
+
+impl Drop for InboundPermit {
+    fn drop(&mut self) {
+        let mut lock = self.inner.lock().expect("Failed to lock limiter");
+        let inner = &mut *lock;
+
+        inner.total -= 1;
+        for (map, key) in [(&mut inner.per_ip, self.ip), (&mut inner.per_subnet, subnet(self.ip))] {
+            if let Some(count) = map.get_mut(&key) {
+                *count -= 1;
+                if *count == 0 {
+                    map.remove(&key);
+                }
+            }
+        }
+    }
+}
@@ -1 +1,2 @@
 pub mod handle_connection;
+pub mod limits;
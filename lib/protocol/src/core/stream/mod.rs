@@ -1,80 +1,163 @@
 use std::net::SocketAddr;
 use std::time::Duration;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::select;
+use tokio::time::{sleep_until, timeout, Instant, MissedTickBehavior};
 use tokio::sync::mpsc::Receiver;
-use crate::core::frames::ProtocolMessage;
-use crate::types::state::ProtocolState;
+use crate::core::client::reconnect::schedule_reconnect;
+use crate::core::frames::{CloseReason, FrameReader, ProtocolMessage};
+use crate::core::relay;
+use crate::core::scoring::{penalize, Misbehaviour};
+use crate::core::transport::{BoxedStream, TransportKind};
+use crate::types::state::{ProtocolState, StreamDirection};
 
 pub mod read_stream;
 pub mod ping_stream;
+pub mod rtt;
 pub mod types;
 
 use types::StreamAction;
-use crate::types::package::{AlertPackage, AlertPackageLevel, AppPackage};
+use crate::types::event::{DisconnectReason, ProtocolEvent};
 
+const CLOSE_LINGER: Duration = Duration::from_secs(1); // waiting for the peer to close its side
+
+#[tracing::instrument(name = "peer", skip_all, fields(%addr))]
 pub async fn protocol_handle_stream(
     protocol_state: ProtocolState,
     addr: SocketAddr,
-    mut stream: TcpStream, // should be cloned anyway bc otherwise `&mut` at `stream.read` will block whole application
+    mut stream: BoxedStream, // should be cloned anyway bc otherwise `&mut` at `stream.read` will block whole application
     mut stream_request_sender: Receiver<StreamAction>
 ) {
-    ping_stream::ping_action(&protocol_state, addr).await; // we need to start pinging right away
+    // first tick is right away, we need to start pinging right away
+    let mut ping_interval = tokio::time::interval(protocol_state.read().ping.interval);
+    ping_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // tracked apart from the interval, so that traffic or slow peer doesn't move it
+    let mut pong_deadline: Option<Instant> = None;
+    let mut reader = FrameReader::new();
 
     loop {
         let action = select! {
             request = stream_request_sender.recv() => {
-                if request.is_none() {
-                    protocol_state
-                        .read()
-                        .package_sender
-                        .send(AppPackage::Alert(AlertPackage {
-                            level: AlertPackageLevel::WARNING,
-                            msg: format!("stream_request_sender is closed, disconnecting from stream {}", addr)
-                        }))
-                        .await
-                        .expect("Failed to send package message");
-                    StreamAction::InitiateDisconnect
-                } else {
-                    request.unwrap()
-                }
+                // sender is gone only if the stream was already removed from the state
+                request.unwrap_or(StreamAction::InitiateDisconnect(CloseReason::Unspecified))
             }
-            message = ProtocolMessage::from_stream(&mut stream) => {
-                let message = message.expect("---Failed to read stream");
-                read_stream::read_message(&protocol_state, addr, message).await
+            message = reader.next(&mut stream) => {
+                match message {
+                    Ok(message) => read_stream::read_message(&protocol_state, addr, message).await,
+                    Err(e) if e.downcast_ref::<std::io::Error>().is_some() => {
+                        protocol_state.emit(ProtocolEvent::StreamFailed { addr, error: e.to_string() });
+                        StreamAction::ConnectionLost
+                    }
+                    Err(e) => {
+                        protocol_state.emit(ProtocolEvent::InvalidMessage { addr, error: e.to_string() });
+
+                        // can't trust framing of this stream anymore, so have to disconnect anyway
+                        let lock = &mut *protocol_state.lock().await;
+                        let transport = lock.streams.get(&addr).map(|(_, metadata)| metadata.transport);
+                        if transport == Some(TransportKind::Udp) {
+                            // lost or reordered datagram breaks framing, peer did nothing wrong
+                            StreamAction::ConnectionLost
+                        } else if penalize(&protocol_state, lock, addr, Misbehaviour::ProtocolViolation).await {
+                            StreamAction::InitiateDisconnect(CloseReason::Banned)
+                        } else {
+                            StreamAction::InitiateDisconnect(CloseReason::ProtocolViolation)
+                        }
+                    }
+                }
             }
-            _ = tokio::time::sleep(Duration::from_secs(ping_stream::PING_INTERVAL)) => {
+            _ = ping_interval.tick() => {
                 ping_stream::ping_action(&protocol_state, addr).await
             }
+            _ = sleep_until(pong_deadline.unwrap_or_else(Instant::now)), if pong_deadline.is_some() => {
+                pong_deadline = None;
+                ping_stream::pong_timeout_action(&protocol_state, addr).await
+            }
         };
 
+        if let StreamAction::Send(ProtocolMessage::Ping) = action {
+            pong_deadline = Some(Instant::now() + protocol_state.read().ping.pong_timeout);
+        }
+
         match action {
             StreamAction::None => {},
-            StreamAction::InitiateDisconnect => {
-                ProtocolState::send_message(
+            StreamAction::InitiateDisconnect(reason) => {
+                // other side might be already gone, we are leaving anyway
+                let _ = protocol_state.send_message(
                     &mut stream,
-                    ProtocolMessage::ConnClosed,
-                )
-                    .await
-                    .expect("Failed to send protocol message to stream");
-                stream.shutdown().await.expect("Failed to shutdown the stream");
-                protocol_state.lock().await.streams.remove(&addr);
+                    ProtocolMessage::ConnClosed(reason),
+                ).await;
+                let _ = stream.shutdown().await;
+                remove_stream(&protocol_state, addr, DisconnectReason::Closed(reason)).await;
+
+                // closing with unread data resets the connection, and the peer could lose the reason with it
+                let _ = timeout(CLOSE_LINGER, async {
+                    let mut buf = [0; ProtocolMessage::FRAME_SIZE];
+                    while let Ok(1..) = stream.read(&mut buf).await {}
+                }).await;
                 break;
             },
-            StreamAction::AcceptDisconnect => {
-                stream.shutdown().await.expect("Failed to shutdown the stream");
-                protocol_state.lock().await.streams.remove(&addr);
+            StreamAction::AcceptDisconnect(reason) => {
+                let _ = stream.shutdown().await;
+                let direction = remove_stream(&protocol_state, addr, DisconnectReason::ClosedByPeer(reason)).await;
+
+                // other side closed connection on purpose, retry only if we were asked to stay
+                if direction == Some(StreamDirection::Outbound) && protocol_state.read().sticky_peers.contains(&addr) {
+                    schedule_reconnect(&protocol_state, addr).await;
+                }
+                break;
+            },
+            StreamAction::ConnectionLost => {
+                let _ = stream.shutdown().await;
+                let direction = remove_stream(&protocol_state, addr, DisconnectReason::Lost).await;
+
+                if direction == Some(StreamDirection::Outbound) {
+                    schedule_reconnect(&protocol_state, addr).await;
+                }
                 break;
             },
             StreamAction::Send(message) => {
-                ProtocolState::send_message(
+                let kind = message.kind();
+                tracing::trace!(kind, "sending message");
+                let res = protocol_state.send_message(
                     &mut stream,
                     message
-                )
-                    .await
-                    .expect("Failed to send protocol to stream");
+                ).await;
+
+                match res {
+                    Ok((frames, bytes)) => {
+                        protocol_state.read().metrics.sent(kind, frames, bytes);
+                        if let Some((_, metadata)) = protocol_state.lock().await.streams.get_mut(&addr) {
+                            metadata.bytes_sent += bytes as u64;
+                        }
+                    }
+                    Err(e) => {
+                        protocol_state.emit(ProtocolEvent::StreamFailed { addr, error: e.to_string() });
+
+                        let _ = stream.shutdown().await;
+                        let direction = remove_stream(&protocol_state, addr, DisconnectReason::Lost).await;
+                        if direction == Some(StreamDirection::Outbound) {
+                            schedule_reconnect(&protocol_state, addr).await;
+                        }
+                        break;
+                    }
+                }
             }
         }
     }
 }
+
+/// Removes stream from the state and returns how it was connected
+async fn remove_stream(
+    protocol_state: &ProtocolState,
+    addr: SocketAddr,
+    reason: DisconnectReason,
+) -> Option<StreamDirection> {
+    let lock = &mut *protocol_state.lock().await;
+    if let Some(record) = lock.known_peers.get_mut(&addr) {
+        record.seen();
+    }
+    relay::stream_removed(protocol_state, lock, addr);
+    let (_, metadata) = lock.streams.remove(&addr)?;
+    protocol_state.emit(ProtocolEvent::PeerDisconnected { addr, reason });
+    Some(metadata.direction)
+}
@@ -1,19 +1,45 @@
 use std::net::SocketAddr;
-use std::time::SystemTime;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
 use crate::core::frames::ProtocolMessage;
-use crate::core::stream::types::StreamAction;
+use crate::core::scoring::{penalize, Misbehaviour};
+use crate::core::stream::{rtt::MAX_RTT, types::StreamAction};
 use crate::types::{
     state::ProtocolState,
-    package::{AlertPackage, AlertPackageLevel, AppPackage},
+    event::ProtocolEvent,
 };
 
-pub const PING_INTERVAL: u64 = 2 * 60; // 2 minutes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PingConfig {
+    #[serde(with = "crate::utils::duration_ms")]
+    pub interval: Duration, // how often peers are pinged, no matter if there's other traffic
+    #[serde(with = "crate::utils::duration_ms")]
+    pub pong_timeout: Duration, // ping without pong for that long counts as missed
+    pub max_missed: u32, // peer is declared dead after missing more pongs in a row
+    #[serde(with = "crate::utils::duration_ms")]
+    pub max_rtt: Duration, // peers answering slower are disconnected, can't be above `MAX_RTT`
+}
 
+impl Default for PingConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            pong_timeout: Duration::from_secs(10),
+            max_missed: 2,
+            max_rtt: MAX_RTT,
+        }
+    }
+}
+
+/// Sends ping unless the previous one is still waiting for pong
 pub async fn ping_action(
     protocol_state: &ProtocolState,
     addr: SocketAddr,
 ) -> StreamAction {
     let lock = &mut *protocol_state.lock().await;
+
     let streams = &mut lock.streams;
     let state = &mut lock.state;
 
@@ -21,26 +47,51 @@ pub async fn ping_action(
         .get_mut(&addr)
         .expect("Unknown address");
 
-    let now = SystemTime::now();
-
     if metadata.ping_started_at.is_some() {
-        // means host did not respond to last ping = host is dead
-
-        return StreamAction::InitiateDisconnect;
+        // pong deadline decides what happens to this one
+        return StreamAction::None;
     }
 
-    metadata.ping_started_at = Some(now);
+    metadata.ping_started_at = Some(Instant::now());
     state.next();
 
-    protocol_state
-        .read()
-        .package_sender
-        .send(AppPackage::Alert(AlertPackage {
-            level: AlertPackageLevel::DEBUG,
-            msg: "Sending ping".to_string(),
-        }))
-        .await
-        .expect("---Failed to send app package");
-
-    return StreamAction::Send(ProtocolMessage::Ping);
+    StreamAction::Send(ProtocolMessage::Ping)
+}
+
+/// Called once pong deadline passes. Counts the ping as missed if it's still unanswered
+/// and pings again right away, so dead peer is detected within `(max_missed + 1) * pong_timeout`.
+pub async fn pong_timeout_action(
+    protocol_state: &ProtocolState,
+    addr: SocketAddr,
+) -> StreamAction {
+    let config = &protocol_state.read().ping;
+    let missed = {
+        let lock = &mut *protocol_state.lock().await;
+        let (_, metadata) = lock
+            .streams
+            .get_mut(&addr)
+            .expect("Unknown address");
+
+        match metadata.ping_started_at {
+            Some(started_at) if started_at.elapsed() >= config.pong_timeout => {
+                metadata.ping_started_at = None;
+                metadata.missed_pongs += 1;
+                metadata.rtt.lost();
+                metadata.missed_pongs
+            }
+            _ => return StreamAction::None, // answered in time
+        }
+    };
+
+    if missed <= config.max_missed {
+        protocol_state.emit(ProtocolEvent::PongMissed { addr, missed });
+
+        return ping_action(protocol_state, addr).await;
+    }
+
+    penalize(protocol_state, &mut *protocol_state.lock().await, addr, Misbehaviour::PingFailure).await;
+
+    protocol_state.emit(ProtocolEvent::PeerDead { addr, missed });
+
+    StreamAction::ConnectionLost
 }
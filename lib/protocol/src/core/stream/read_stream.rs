@@ -1,29 +1,140 @@
+use std::collections::HashSet;
 use std::net::SocketAddr;
-use std::time::{Duration, SystemTime};
 use crate::core::{
     commands::ProtocolCommand,
-    frames::ProtocolMessage,
-    node_info::NodeInfo,
+    dht::{self, message::DhtMessage, Answer},
+    pex,
+    nat,
+    relay,
+    frames::{CloseReason, ProtocolMessage},
+    scoring::{peer_ip, penalize, Misbehaviour},
+    selection,
 };
 use crate::core::stream::types::StreamAction;
 use crate::types::{
     state::ProtocolState,
-    package::{AlertPackage, AlertPackageLevel, AppPackage, MessagePackage},
+    event::{DialSource, ProtocolEvent},
+    package::{AppPackage, MessagePackage},
 };
-use crate::utils::sss_triangle::sss_triangle;
 
+#[tracing::instrument(name = "message", skip_all, fields(kind, bytes))]
 pub async fn read_message(
     protocol_state: &ProtocolState,
     addr: SocketAddr,
-    message: Option<(ProtocolMessage, usize)>,
+    message: Option<(ProtocolMessage, usize, usize)>,
 ) -> StreamAction {
     if message.is_none() {
         // stream has ended = host disconnected
-        return StreamAction::InitiateDisconnect;
+        return StreamAction::ConnectionLost;
     }
-    let (message, _) = message.unwrap();
+    let (message, frames, bytes) = message.unwrap();
+    let span = tracing::Span::current();
+    span.record("kind", message.kind());
+    span.record("bytes", bytes);
+    protocol_state.read().metrics.received(message.kind(), frames, bytes);
+    tracing::trace!("received message");
 
-    let lock = &mut *protocol_state.lock().await;
+    let mut guard = protocol_state.lock().await;
+    let lock = &mut *guard;
+    if let Some((_, metadata)) = lock.streams.get_mut(&addr) {
+        metadata.bytes_received += bytes as u64;
+    }
+
+    if let ProtocolMessage::ConnInit { .. } = message {
+        // handshake is already done, party doesn't follow the protocol
+        return if penalize(protocol_state, lock, addr, Misbehaviour::ProtocolViolation).await {
+            StreamAction::InitiateDisconnect(CloseReason::Banned)
+        } else {
+            StreamAction::None
+        };
+    }
+    if let ProtocolMessage::Dht(message) = message {
+        let remote_addr = match lock.streams.get(&addr) {
+            Some((_, metadata)) => metadata.remote_addr,
+            None => addr,
+        };
+
+        if let DhtMessage::Nodes { sender, .. } = message {
+            // lookups use their own connections, here it only tells us who's on the other side
+            if sender.addr == addr {
+                dht::heard_from(protocol_state, lock, sender);
+            }
+            return StreamAction::None;
+        }
+
+        let res = dht::answer(protocol_state, lock, remote_addr, message).await;
+        if lock.scores.is_banned(&peer_ip(lock, addr)) {
+            return StreamAction::InitiateDisconnect(CloseReason::Banned);
+        }
+        return match res {
+            Ok(Answer::Reply(answer)) => StreamAction::Send(ProtocolMessage::Dht(answer)),
+            Ok(Answer::Pass(outgoing)) => {
+                drop(guard);
+                outgoing.send(protocol_state).await;
+                StreamAction::None
+            }
+            Ok(Answer::None) => StreamAction::None,
+            Err(_) => {
+                if penalize(protocol_state, lock, addr, Misbehaviour::ProtocolViolation).await {
+                    StreamAction::InitiateDisconnect(CloseReason::Banned)
+                } else {
+                    StreamAction::None
+                }
+            }
+        };
+    }
+    if let ProtocolMessage::PexRequest(max) = message {
+        return match pex::answer(protocol_state, lock, addr, max) {
+            Some(entries) => StreamAction::Send(ProtocolMessage::PexResponse(entries)),
+            None => StreamAction::None, // asks too often
+        };
+    }
+    if let ProtocolMessage::PexResponse(entries) = message {
+        if pex::accept(protocol_state, lock, addr, entries) {
+            return StreamAction::None;
+        }
+        // nobody asked, might be an attempt to fill our table
+        return if penalize(protocol_state, lock, addr, Misbehaviour::ProtocolViolation).await {
+            StreamAction::InitiateDisconnect(CloseReason::Banned)
+        } else {
+            StreamAction::None
+        };
+    }
+    if let ProtocolMessage::Nat(message) = message {
+        return match nat::read(protocol_state, lock, addr, message).await {
+            Ok(action) => action,
+            Err(_) => {
+                if penalize(protocol_state, lock, addr, Misbehaviour::ProtocolViolation).await {
+                    StreamAction::InitiateDisconnect(CloseReason::Banned)
+                } else {
+                    StreamAction::None
+                }
+            }
+        };
+    }
+    if let ProtocolMessage::Relay(message) = message {
+        return match relay::read(protocol_state, lock, addr, message).await {
+            Ok(action) => action,
+            Err(_) => {
+                if penalize(protocol_state, lock, addr, Misbehaviour::ProtocolViolation).await {
+                    StreamAction::InitiateDisconnect(CloseReason::Banned)
+                } else {
+                    StreamAction::None
+                }
+            }
+        };
+    }
+    if let ProtocolMessage::Data(id, _) = message {
+        let sent_before = lock
+            .data_id_states
+            .get(&id)
+            .map(|senders| senders.contains(&addr))
+            .unwrap_or(false);
+        // honest peer never sends us the same message twice, it's either relayed from others or not
+        if sent_before && penalize(protocol_state, lock, addr, Misbehaviour::DuplicateSpam).await {
+            return StreamAction::InitiateDisconnect(CloseReason::Banned);
+        }
+    }
 
     let streams = &mut lock.streams;
     let state = &mut lock.state;
@@ -32,216 +143,154 @@ pub async fn read_message(
     state.next();
 
     match message {
-        ProtocolMessage::ConnInit { .. } => {
-            unreachable!("Unexpected protocol message")
-        }
-        ProtocolMessage::ConnClosed => {
-            return StreamAction::AcceptDisconnect;
+        ProtocolMessage::ConnInit { .. }
+        | ProtocolMessage::Dht(_)
+        | ProtocolMessage::PexRequest(_)
+        | ProtocolMessage::PexResponse(_)
+        | ProtocolMessage::Nat(_)
+        | ProtocolMessage::Relay(_) => {
+            unreachable!("Handled above")
         }
+        ProtocolMessage::ConnClosed(reason) => StreamAction::AcceptDisconnect(reason),
         ProtocolMessage::Data(id, data) => {
-            if data_id_states.contains_key(&id) {
+            if let Some(senders) = data_id_states.get_mut(&id) {
+                senders.insert(addr);
+                protocol_state.read().metrics.dedup_hits.inc();
                 return StreamAction::None;
             }
-            data_id_states.insert(id, ());
+            data_id_states.insert(id, HashSet::from([addr]));
 
             let mut biggest_ping = 0;
-            for (targ_addr, (channel, ref metadata)) in streams.iter_mut() {
+            let mut channels = vec![];
+            for (targ_addr, (channel, metadata)) in streams.iter() {
                 if targ_addr == &addr {
                     continue
                 }
-                if metadata.ping > biggest_ping {
-                    biggest_ping = metadata.ping;
+                let ping = metadata.rtt.ping().unwrap_or(0);
+                if ping > biggest_ping {
+                    biggest_ping = ping;
                 }
 
                 state.next();
-                channel
-                    .send(StreamAction::Send(ProtocolMessage::Data(id, data.clone())))
-                    .await
-                    .expect("Failed to send StreamRequest");
+                channels.push(channel.clone());
             }
 
-            { // don't need to store the id longer than the largest ping between peers
-                let app_state = protocol_state.clone();
-                tokio::spawn(async move {
-                    let ping = if biggest_ping == 0 {
-                        1
-                    } else {
-                        biggest_ping as u64 * 2 // x2 just to be sure
-                    };
-                    tokio::time::sleep(Duration::from_millis(ping)).await;
-
-                    let mut lock = app_state.lock().await;
-                    lock.data_id_states.remove(&id);
-                });
-            }
+            protocol_state.forget_data_id(id, biggest_ping);
+            // stream tasks take the lock to handle what is sent to them
+            drop(guard);
 
-            protocol_state
-                .read()
-                .package_sender
-                .send(AppPackage::Message(MessagePackage {
-                    from: addr,
-                    msg: data,
-                }))
-                .await
-                .expect("---Failed to send app package");
-            return StreamAction::None;
+            for channel in channels {
+                // stream could be closing already, others still get the message
+                let _ = channel.send(StreamAction::Send(ProtocolMessage::Data(id, data.clone()))).await;
+            }
+            protocol_state.deliver(AppPackage::Message(MessagePackage {
+                from: addr,
+                msg: data,
+            })).await;
+            StreamAction::None
         }
-        ProtocolMessage::NodeStatus(info) => {
+        ProtocolMessage::NodeStatus(info, coordinate) => {
             if streams.contains_key(&info.addr) {
                 return StreamAction::None;
             }
-            if streams.len() < 4 { // todo: move as config variable
-                protocol_state
-                    .read()
-                    .package_sender
-                    .send(AppPackage::Alert(AlertPackage {
-                        level: AlertPackageLevel::DEBUG,
-                        msg: format!("Connecting to new node {} with src_ping of {}", info.addr, info.ping),
-                    }))
-                    .await
-                    .expect("---Failed to send app package");
-
-                lock
-                    .command_sender
-                    .send(ProtocolCommand::ClientConnect {
-                        targ_addr: info.addr,
-                        src_to_targ_ping: info.ping,
-                        src_addr: addr,
-                    })
-                    .await
-                    .expect("---Failed to send NodeCommand");
-            } else {
-                let mut biggest_ping = 0;
-                let mut worst_node = None;
-                for (addr, (channel, metadata)) in streams.iter_mut() {
-                    if metadata.ping > biggest_ping {
-                        biggest_ping = metadata.ping;
-                        worst_node = Some((addr, channel));
-                    }
+            if let Some(relay) = info.relay {
+                lock.relay.advertised(info.addr, relay);
+            }
+            let candidate = selection::candidate(lock, info.addr, coordinate);
+            let policy = &protocol_state.read().selection_policy;
+            let command_sender = lock.command_sender.clone();
+            let connect = ProtocolCommand::ClientConnect {
+                targ_addr: info.addr,
+                targ_coordinate: coordinate,
+                src_addr: addr,
+            };
+
+            if lock.streams.len() < protocol_state.read().selection.max_peers {
+                let connected = selection::connected_peers(protocol_state, lock, true);
+                let state = &mut lock.state;
+                let picked = policy.select(&connected, vec![candidate.clone()], 1, &mut || state.next());
+                if picked.is_empty() {
+                    return StreamAction::None;
                 }
 
-                if let Some((r_addr, channel)) = worst_node {
-                    protocol_state
-                        .read()
-                        .package_sender
-                        .send(AppPackage::Alert(AlertPackage {
-                            level: AlertPackageLevel::DEBUG,
-                            msg: format!("Changing nodes from {} to {}", r_addr, info.addr),
-                        }))
-                        .await
-                        .expect("---Failed to send app package");
+                protocol_state.emit(ProtocolEvent::Dialing {
+                    addr: info.addr,
+                    source: DialSource::NodeStatus,
+                });
+
+                drop(guard);
+                // commands stop being processed only when the node shuts down
+                let _ = command_sender.send(connect).await;
+            } else {
+                // app asked to keep sticky ones no matter what
+                let connected = selection::connected_peers(protocol_state, lock, false);
+                let state = &mut lock.state;
+                let replaced = policy.replace(&connected, &candidate, &mut || state.next());
+
+                if let Some((r_addr, (channel, _))) = replaced.and_then(|r| lock.streams.get_key_value(&r)) {
+                    protocol_state.emit(ProtocolEvent::PeerReplaced {
+                        old: *r_addr,
+                        new: info.addr,
+                    });
+                    let channel = channel.clone();
+                    drop(guard);
 
                     // first connect in case there's unhandled problem, then disconnect
-                    lock
-                        .command_sender
-                        .send(ProtocolCommand::ClientConnect {
-                            targ_addr: info.addr,
-                            src_to_targ_ping: info.ping,
-                            src_addr: addr,
-                        })
-                        .await
-                        .expect("---Failed to send NodeCommand");
-
-                    channel
-                        .send(StreamAction::InitiateDisconnect)
-                        .await
-                        .expect("Failed to send StreamRequest");
+                    let _ = command_sender.send(connect).await;
+                    // stream could be closing already
+                    let _ = channel.send(StreamAction::InitiateDisconnect(CloseReason::Unspecified)).await;
                 }
             }
-            return StreamAction::None;
+            StreamAction::None
         }
-        ProtocolMessage::Pong(info) => {
-            let ping_info = if let Some(info) = info {
-                let src_ping = lock.streams.get(&info.addr).expect("src_addr should exist").1.ping;
-                Some((src_ping, info.ping))
-            } else {
-                None
-            };
-
+        ProtocolMessage::Pong(coordinate) => {
             let (_, metadata) = lock
                 .streams
                 .get_mut(&addr)
                 .expect("Unknown address");
 
-            if metadata.ping_started_at.is_none() {
-                // haven't requested ping => cannot measure anything
-                return StreamAction::None;
+            let rtt = match metadata.ping_started_at {
+                Some(started_at) => started_at.elapsed(),
+                None => return StreamAction::None, // haven't requested ping => cannot measure anything
+            };
+            if rtt > protocol_state.read().ping.max_rtt {
+                protocol_state.emit(ProtocolEvent::PingTooHigh { addr, rtt });
+                return StreamAction::InitiateDisconnect(CloseReason::Unspecified);
             }
+            let ping = rtt.as_millis() as u16;
 
-            let ping = SystemTime::now().duration_since(metadata.ping_started_at.unwrap()).unwrap().as_millis();
-            if ping > 60_000 { // todo: move to constant
-                protocol_state
-                    .read()
-                    .package_sender
-                    .send(AppPackage::Alert(AlertPackage {
-                        level: AlertPackageLevel::WARNING,
-                        msg: format!("Ping with host {} is too big ({}). Disconnecting", addr, ping),
-                    }))
-                    .await
-                    .expect("---Failed to send app package");
-                return StreamAction::InitiateDisconnect;
+            metadata.rtt.sample(rtt);
+            metadata.ping_started_at = None;
+            metadata.missed_pongs = 0;
+            let smoothed = metadata.rtt.ping();
+            if coordinate.is_some() {
+                metadata.coordinate = coordinate;
             }
-            let ping = ping as u16;
 
-            metadata.ping = ping;
-            metadata.ping_started_at = None;
+            if let Some(record) = lock.known_peers.get_mut(&addr) {
+                record.seen();
+                record.latency = smoothed;
+                if coordinate.is_some() {
+                    record.coordinate = coordinate;
+                }
+            }
 
-            if let Some((src_ping, src_to_targ_ping)) = ping_info {
-                let angle = sss_triangle(src_ping, ping, src_to_targ_ping);
-                metadata.topology_rad = angle;
-
-                protocol_state
-                    .read()
-                    .package_sender
-                    .send(AppPackage::Alert(AlertPackage {
-                        level: AlertPackageLevel::DEBUG,
-                        msg: format!("Calculated angle of {}", angle),
-                    }))
-                    .await
-                    .expect("---Failed to send app package");
+            let mut predicted = None;
+            if let Some(coordinate) = coordinate {
+                predicted = Some(lock.coordinate.predict(&coordinate));
+                let random = lock.state.next();
+                lock.coordinate.update(&coordinate, ping, random);
+
+                protocol_state.emit(ProtocolEvent::CoordinateUpdated { coordinate: lock.coordinate });
             }
 
-            protocol_state
-                .read()
-                .package_sender
-                .send(AppPackage::Alert(AlertPackage {
-                    level: AlertPackageLevel::DEBUG,
-                    msg: format!("Received pong, delay is {}", ping),
-                }))
-                .await
-                .expect("---Failed to send app package");
-            return StreamAction::None;
+            protocol_state.emit(ProtocolEvent::PingMeasured { addr, rtt, predicted });
+            StreamAction::None
         }
         ProtocolMessage::Ping => {
-            let (_, metadata) = lock.streams.get(&addr).expect("entry should exist");
-
-            let info = {
-                if let Some(targ_addr) = metadata.knows_about.get(0) {
-                    let (_, metadata) = lock
-                        .streams
-                        .get(&targ_addr)
-                        .expect("we should know about it bc `targ_addr` knows about us bc we connected to it");
-
-                    Some(
-                        NodeInfo::new(targ_addr.clone(), metadata.ping)
-                    )
-                } else {
-                    None
-                }
-            };
-
-            protocol_state
-                .read()
-                .package_sender
-                .send(AppPackage::Alert(AlertPackage {
-                    level: AlertPackageLevel::DEBUG,
-                    msg: format!("Received ping from {}, sending pong with info {:?}", addr, info),
-                }))
-                .await
-                .expect("---Failed to send app package");
-
+            let coordinate = lock.coordinate;
             lock.state.next();
-            return StreamAction::Send(ProtocolMessage::Pong(info));
+            StreamAction::Send(ProtocolMessage::Pong(Some(coordinate)))
         }
     }
 }
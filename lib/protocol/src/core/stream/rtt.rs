@@ -0,0 +1,71 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use crate::types::state::ProtocolState;
+
+pub const MAX_RTT: Duration = Duration::from_secs(60); // upper bound of `PingConfig::max_rtt`, keeps ping within u16 millis
+
+/// Round trip time statistics of a single peer, updated by every pong.
+/// Smoothing follows TCP retransmission timer (RFC 6298), jitter - RTP (RFC 3550).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RttStats {
+    pub srtt: Option<Duration>, // smoothed, `None` until the first sample
+    pub rttvar: Duration, // smoothed deviation
+    pub min: Option<Duration>,
+    pub max: Option<Duration>,
+    pub last: Option<Duration>,
+    pub jitter: Duration, // smoothed difference between consecutive samples
+    pub samples: u64,
+    pub lost: u64, // pings that got no pong in time
+}
+
+impl RttStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+        if let Some(last) = self.last {
+            let d = last.abs_diff(rtt);
+            self.jitter = (self.jitter * 15 + d) / 16;
+        }
+
+        self.min = Some(self.min.map_or(rtt, |m| m.min(rtt)));
+        self.max = Some(self.max.map_or(rtt, |m| m.max(rtt)));
+        self.last = Some(rtt);
+        self.samples += 1;
+    }
+
+    pub fn lost(&mut self) {
+        self.lost += 1;
+    }
+
+    /// Smoothed ping in milliseconds, as it's sent to other nodes. `None` if nothing is measured yet.
+    pub fn ping(&self) -> Option<u16> {
+        self.srtt.map(|d| d.as_millis().min(u16::MAX as u128) as u16)
+    }
+
+    /// Share of pings that got no pong
+    pub fn loss_rate(&self) -> f32 {
+        match self.samples + self.lost {
+            0 => 0.0,
+            total => self.lost as f32 / total as f32,
+        }
+    }
+}
+
+impl ProtocolState {
+    /// Round trip statistics of the connected peer, `None` if there is no stream with it
+    pub async fn rtt_stats(&self, addr: SocketAddr) -> Option<RttStats> {
+        self.lock().await.streams.get(&addr).map(|(_, m)| m.rtt)
+    }
+}
@@ -1,8 +1,9 @@
-use crate::core::frames::ProtocolMessage;
+use crate::core::frames::{CloseReason, ProtocolMessage};
 
 pub enum StreamAction {
     Send(ProtocolMessage),
-    InitiateDisconnect,
-    AcceptDisconnect,
+    InitiateDisconnect(CloseReason),
+    AcceptDisconnect(CloseReason), // party sent `ConnClosed` with the reason
+    ConnectionLost, // stream broke without `ConnClosed`, nothing can be sent to it anymore
     None,
 }
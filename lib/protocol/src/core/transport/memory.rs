@@ -0,0 +1,304 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep, sleep_until, Instant};
+use crate::core::transport::ChunkReader;
+use crate::utils::prng::{Splitmix64, Xoshiro256ss};
+
+/// Retransmission timeout of tcp doesn't go below that, lost chunk is late by at least as much
+const MIN_RTO: Duration = Duration::from_millis(200);
+
+/// How a single link between two nodes behaves, same in both directions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkConfig {
+    pub latency: Duration, // one way
+    pub jitter: Duration, // up to that much is added to latency of every chunk
+    pub loss: f64, // share of chunks that have to be retransmitted, 0 to below 1
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            latency: Duration::from_millis(20),
+            jitter: Duration::ZERO,
+            loss: 0.0,
+        }
+    }
+}
+
+impl LinkConfig {
+    /// Ping node would measure over the link on average
+    pub fn ping(&self) -> Duration {
+        self.latency * 2 + self.jitter
+    }
+}
+
+/// Which nodes can reach each other, watched by every link
+#[derive(Debug, Clone, Default)]
+struct Topology {
+    down: HashSet<IpAddr>, // nodes taken off the network, their links are closed
+    side: Option<HashSet<IpAddr>>, // nodes cut from the rest, links across wait until it heals
+}
+
+impl Topology {
+    fn is_down(&self, a: IpAddr, b: IpAddr) -> bool {
+        self.down.contains(&a) || self.down.contains(&b)
+    }
+
+    fn is_cut(&self, a: IpAddr, b: IpAddr) -> bool {
+        self.side.as_ref().is_some_and(|side| side.contains(&a) != side.contains(&b))
+    }
+}
+
+struct NetworkInner {
+    link: LinkConfig, // of every link that wasn't set explicitly
+    links: HashMap<(IpAddr, IpAddr), LinkConfig>,
+    listeners: HashMap<SocketAddr, mpsc::Sender<(MemoryStream, SocketAddr)>>,
+    rng: Xoshiro256ss,
+    next_port: u16, // of the dialing side, like ephemeral ports of tcp
+    bytes: usize,
+    retransmits: usize,
+}
+
+/// Network of nodes running in one process, streams between them behave like tcp:
+/// ordered, reliable, late by the latency of the link and by retransmissions of lost chunks.
+/// Nodes are told apart by ip, links and partitions are set between ips.
+/// Time is tokio's, so with paused time a minute of the network takes a moment.
+#[derive(Clone)]
+pub struct MemoryNetwork {
+    inner: Arc<Mutex<NetworkInner>>,
+    topology: Arc<watch::Sender<Topology>>,
+}
+
+impl MemoryNetwork {
+    pub fn new(link: LinkConfig, seed: u64) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(NetworkInner {
+                link,
+                links: HashMap::new(),
+                listeners: HashMap::new(),
+                rng: Splitmix64::new(seed).xorshift256ss(),
+                next_port: 1024,
+                bytes: 0,
+                retransmits: 0,
+            })),
+            topology: Arc::new(watch::channel(Topology::default()).0),
+        }
+    }
+
+    fn inner(&self) -> std::sync::MutexGuard<'_, NetworkInner> {
+        self.inner.lock().expect("Network lock is never held across a panic")
+    }
+
+    pub fn link(&self, a: IpAddr, b: IpAddr) -> LinkConfig {
+        let inner = self.inner();
+        inner.links.get(&(a.min(b), a.max(b))).copied().unwrap_or(inner.link)
+    }
+
+    /// Applies to streams opened before as well
+    pub fn set_link(&self, a: IpAddr, b: IpAddr, link: LinkConfig) {
+        assert!((0.0..1.0).contains(&link.loss), "Loss has to be at least 0 and below 1");
+        self.inner().links.insert((a.min(b), a.max(b)), link);
+    }
+
+    /// Nodes of `side` can't reach anyone outside of it until `heal`, replaces the previous partition
+    pub fn partition(&self, side: &[IpAddr]) {
+        self.topology.send_modify(|t| t.side = Some(side.iter().copied().collect()));
+    }
+
+    pub fn heal(&self) {
+        self.topology.send_modify(|t| t.side = None);
+    }
+
+    /// Closes every stream of the node and stops accepting connections to it, for good
+    pub fn take_down(&self, ip: IpAddr) {
+        self.inner().listeners.retain(|addr, _| addr.ip() != ip);
+        self.topology.send_modify(|t| {
+            t.down.insert(ip);
+        });
+    }
+
+    /// Bytes delivered over all links so far
+    pub fn bytes(&self) -> usize {
+        self.inner().bytes
+    }
+
+    /// Chunks that had to be sent again because the link lost them
+    pub fn retransmits(&self) -> usize {
+        self.inner().retransmits
+    }
+
+    /// Streams dialed to `addr` come out of the receiver with the address of the dialing side
+    pub fn listen(&self, addr: SocketAddr) -> io::Result<mpsc::Receiver<(MemoryStream, SocketAddr)>> {
+        let mut inner = self.inner();
+        if inner.listeners.contains_key(&addr) || self.topology.borrow().down.contains(&addr.ip()) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let (sender, receiver) = mpsc::channel(64);
+        inner.listeners.insert(addr, sender);
+        Ok(receiver)
+    }
+
+    /// Opens a stream from the node at `from` ip to the server at `to`, takes a round trip like tcp handshake.
+    /// Across a partition it waits until it heals, same as syn that gets no answer, so dialing times out.
+    pub async fn connect(&self, from: IpAddr, to: SocketAddr) -> io::Result<MemoryStream> {
+        let refused = || io::Error::from(io::ErrorKind::ConnectionRefused);
+
+        let mut topology = self.topology.subscribe();
+        if topology.wait_for(|t| !t.is_cut(from, to.ip())).await.is_err() {
+            return Err(refused());
+        }
+        let round_trip = self.delay(from, to.ip()) + self.delay(to.ip(), from);
+        sleep(round_trip).await;
+
+        if topology.borrow().is_down(from, to.ip()) {
+            return Err(refused());
+        }
+        let (listener, local_addr) = {
+            let mut inner = self.inner();
+            let listener = inner.listeners.get(&to).cloned().ok_or_else(refused)?;
+            inner.next_port = inner.next_port.checked_add(1).unwrap_or(1024);
+            (listener, SocketAddr::new(from, inner.next_port))
+        };
+
+        let (ours, theirs) = self.pair(from, to.ip());
+        listener.send((theirs, local_addr)).await.map_err(|_| refused())?;
+        Ok(ours)
+    }
+
+    /// Both ends of a new stream between `a` and `b`
+    fn pair(&self, a: IpAddr, b: IpAddr) -> (MemoryStream, MemoryStream) {
+        let (a_writer, a_chunks) = mpsc::unbounded_channel();
+        let (b_writer, b_chunks) = mpsc::unbounded_channel();
+        let (a_out, a_incoming) = mpsc::channel(64);
+        let (b_out, b_incoming) = mpsc::channel(64);
+
+        tokio::spawn(self.clone().forward(a, b, a_chunks, b_out));
+        tokio::spawn(self.clone().forward(b, a, b_chunks, a_out));
+
+        (MemoryStream::new(a_incoming, a_writer), MemoryStream::new(b_incoming, b_writer))
+    }
+
+    /// When the chunk sent now from `from` arrives at `to`, counted from sending
+    fn delay(&self, from: IpAddr, to: IpAddr) -> Duration {
+        let link = self.link(from, to);
+        let mut inner = self.inner();
+        let mut delay = link.latency + link.jitter.mul_f64(uniform(&mut inner.rng));
+        while uniform(&mut inner.rng) < link.loss {
+            delay += link.ping().max(MIN_RTO);
+            inner.retransmits += 1;
+        }
+        delay
+    }
+
+    /// Carries chunks one way, keeping their order
+    async fn forward(
+        self,
+        from: IpAddr,
+        to: IpAddr,
+        mut chunks: mpsc::UnboundedReceiver<(Instant, Vec<u8>)>,
+        out: mpsc::Sender<Vec<u8>>,
+    ) {
+        let mut topology = self.topology.subscribe();
+        let mut arrives = Instant::now();
+
+        loop {
+            let (sent_at, chunk) = tokio::select! {
+                chunk = chunks.recv() => match chunk {
+                    Some(chunk) => chunk,
+                    None => return, // other side shut the stream down
+                },
+                _ = went_down(&mut topology, from, to) => return,
+            };
+
+            // tcp keeps retransmitting while the link is cut, chunk goes through once it heals
+            let mut sent_at = sent_at;
+            loop {
+                let (down, cut) = {
+                    let current = topology.borrow_and_update();
+                    (current.is_down(from, to), current.is_cut(from, to))
+                };
+                if down {
+                    return;
+                }
+                if !cut {
+                    break;
+                }
+                if topology.changed().await.is_err() {
+                    return;
+                }
+                sent_at = Instant::now();
+            }
+            arrives = arrives.max(sent_at + self.delay(from, to));
+
+            tokio::select! {
+                _ = sleep_until(arrives) => {},
+                _ = went_down(&mut topology, from, to) => return,
+            }
+            self.inner().bytes += chunk.len();
+            if out.send(chunk).await.is_err() {
+                return; // stream was dropped
+            }
+        }
+    }
+}
+
+async fn went_down(topology: &mut watch::Receiver<Topology>, a: IpAddr, b: IpAddr) {
+    if topology.wait_for(|t| t.is_down(a, b)).await.is_err() {
+        // network is gone, nothing can go down anymore
+        std::future::pending::<()>().await;
+    }
+}
+
+/// In `[0, 1)`
+fn uniform(rng: &mut Xoshiro256ss) -> f64 {
+    (rng.next() >> 11) as f64 / (1_u64 << 53) as f64
+}
+
+/// End of a stream over `MemoryNetwork`
+pub struct MemoryStream {
+    reader: ChunkReader,
+    writer: Option<mpsc::UnboundedSender<(Instant, Vec<u8>)>>, // `None` once shut down
+}
+
+impl MemoryStream {
+    fn new(incoming: mpsc::Receiver<Vec<u8>>, writer: mpsc::UnboundedSender<(Instant, Vec<u8>)>) -> Self {
+        Self {
+            reader: ChunkReader::new(incoming),
+            writer: Some(writer),
+        }
+    }
+}
+
+impl AsyncRead for MemoryStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        self.reader.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MemoryStream {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let sent = self.writer
+            .as_ref()
+            .is_some_and(|writer| writer.send((Instant::now(), buf.to_vec())).is_ok());
+        match sent {
+            true => Poll::Ready(Ok(buf.len())),
+            false => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.writer = None;
+        Poll::Ready(Ok(()))
+    }
+}
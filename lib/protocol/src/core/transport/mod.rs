@@ -0,0 +1,68 @@
+use std::io;
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use crate::types::state::ProtocolState;
+
+pub mod udp;
+pub mod sim_nat;
+pub mod relay;
+#[cfg(feature = "sim")]
+pub mod memory;
+
+/// Anything the protocol can run over, tcp connection, udp link or relayed circuit
+pub trait PeerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> PeerStream for T {}
+
+pub type BoxedStream = Box<dyn PeerStream>;
+
+/// Dials the server of the node at `addr`, over the memory network if node was built with one
+#[cfg_attr(not(feature = "sim"), allow(unused_variables))]
+pub(crate) async fn connect(protocol_state: &ProtocolState, addr: SocketAddr) -> io::Result<BoxedStream> {
+    #[cfg(feature = "sim")]
+    if let Some(network) = &protocol_state.read().memory {
+        let stream = network.connect(protocol_state.read().server_addr.ip(), addr).await?;
+        return Ok(Box::new(stream));
+    }
+    Ok(Box::new(TcpStream::connect(addr).await?))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Tcp, // streams over the memory network of simulations count as tcp
+    Udp, // link made by hole punching
+    Relay, // circuit through another node
+}
+
+/// Read half of streams that get their bytes in chunks from a channel
+pub struct ChunkReader {
+    incoming: mpsc::Receiver<Vec<u8>>,
+    pending: Vec<u8>, // rest of the chunk that didn't fit into the read buffer
+}
+
+impl ChunkReader {
+    pub fn new(incoming: mpsc::Receiver<Vec<u8>>) -> Self {
+        Self {
+            incoming,
+            pending: vec![],
+        }
+    }
+
+    pub fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if self.pending.is_empty() {
+            match self.incoming.poll_recv(cx) {
+                Poll::Ready(Some(chunk)) => self.pending = chunk,
+                Poll::Ready(None) => return Poll::Ready(Ok(())), // sender is dropped = stream has ended
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let n = buf.remaining().min(self.pending.len());
+        buf.put_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc::{self, error::SendError, OwnedPermit, Sender};
+use crate::core::frames::ProtocolMessage;
+use crate::core::relay::RelayMessage;
+use crate::core::stream::types::StreamAction;
+use crate::core::transport::ChunkReader;
+
+const CIRCUIT_BUFFER: usize = 100; // chunks waiting to be read, circuit is closed if reader is that much behind
+const INBOUND_BUFFER: usize = 10;
+
+type Links = HashMap<(SocketAddr, u64), Sender<Vec<u8>>>; // by relay address and circuit id
+type Reserve = Pin<Box<dyn Future<Output = Result<OwnedPermit<StreamAction>, SendError<()>>> + Send>>;
+
+/// Ends of circuits this node has through relays
+pub struct Circuits {
+    links: Mutex<Links>,
+    inbound: Sender<(RelayStream, SocketAddr)>,
+}
+
+impl Circuits {
+    pub fn new() -> (Arc<Self>, mpsc::Receiver<(RelayStream, SocketAddr)>) {
+        let (inbound, inbound_receiver) = mpsc::channel(INBOUND_BUFFER);
+        let circuits = Arc::new(Self {
+            links: Mutex::new(HashMap::new()),
+            inbound,
+        });
+        (circuits, inbound_receiver)
+    }
+
+    /// `channel` is the request channel of the stream with the relay
+    pub fn open(
+        self: &Arc<Self>,
+        relay: SocketAddr,
+        circuit: u64,
+        channel: Sender<StreamAction>,
+    ) -> RelayStream {
+        let (sender, receiver) = mpsc::channel(CIRCUIT_BUFFER);
+        self.links.lock().expect("Circuits lock is poisoned").insert((relay, circuit), sender);
+
+        RelayStream {
+            circuits: self.clone(),
+            relay,
+            circuit,
+            channel,
+            reader: ChunkReader::new(receiver),
+            reserve: None,
+        }
+    }
+
+    /// Circuit `from` opened to us, handed to the server
+    pub fn accept(
+        self: &Arc<Self>,
+        relay: SocketAddr,
+        circuit: u64,
+        from: SocketAddr,
+        channel: Sender<StreamAction>,
+    ) {
+        let stream = self.open(relay, circuit, channel);
+        // server is busy, dropping the stream closes the circuit
+        let _ = self.inbound.try_send((stream, from));
+    }
+
+    /// Passes data relay forwarded to the reader. Returns `false` if circuit is unknown or reader is too slow.
+    pub fn deliver(&self, relay: SocketAddr, circuit: u64, data: Vec<u8>) -> bool {
+        let links = self.links.lock().expect("Circuits lock is poisoned");
+        match links.get(&(relay, circuit)) {
+            Some(link) => link.try_send(data).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Ends the circuit, its reader sees the end of the stream
+    pub fn close(&self, relay: SocketAddr, circuit: u64) {
+        self.links.lock().expect("Circuits lock is poisoned").remove(&(relay, circuit));
+    }
+
+    /// Ends every circuit going through the relay, once stream with it is gone
+    pub fn close_relay(&self, relay: SocketAddr) {
+        self.links.lock().expect("Circuits lock is poisoned").retain(|(r, _), _| *r != relay);
+    }
+}
+
+/// Circuit to a peer through a relay, looks like a byte stream to the protocol.
+/// Each write goes to the relay as a single `DATA` of the circuit.
+pub struct RelayStream {
+    circuits: Arc<Circuits>,
+    relay: SocketAddr,
+    circuit: u64,
+    channel: Sender<StreamAction>,
+    reader: ChunkReader,
+    reserve: Option<Reserve>, // place in the relay stream channel being waited for
+}
+
+impl RelayStream {
+    pub fn relay(&self) -> SocketAddr {
+        self.relay
+    }
+}
+
+impl AsyncRead for RelayStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        self.reader.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for RelayStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let channel = self.channel.clone();
+        let reserve = self.reserve.get_or_insert_with(|| Box::pin(channel.reserve_owned()));
+
+        match reserve.as_mut().poll(cx) {
+            Poll::Ready(Ok(permit)) => {
+                self.reserve = None;
+                let data = RelayMessage::Data {
+                    circuit: self.circuit,
+                    data: buf.to_vec(),
+                };
+                permit.send(StreamAction::Send(ProtocolMessage::Relay(data)));
+                Poll::Ready(Ok(buf.len()))
+            }
+            Poll::Ready(Err(_)) => {
+                self.reserve = None;
+                Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())) // stream with the relay is gone
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(())) // relay is told once the stream is dropped
+    }
+}
+
+impl Drop for RelayStream {
+    fn drop(&mut self) {
+        self.circuits.close(self.relay, self.circuit);
+        // relay could be gone already, then circuit is closed anyway
+        let close = RelayMessage::Close { circuit: self.circuit };
+        let _ = self.channel.try_send(StreamAction::Send(ProtocolMessage::Relay(close)));
+    }
+}
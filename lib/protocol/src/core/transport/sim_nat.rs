@@ -0,0 +1,51 @@
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use crate::core::transport::udp::DatagramSocket;
+
+/// Port restricted cone NAT in front of a udp socket, lets hole punching be tested on a single machine.
+/// Node behind it is seen at the address of the socket, but gets datagrams only
+/// from addresses it has sent something to, everything else is dropped like a real NAT does.
+pub struct SimulatedNat {
+    socket: UdpSocket,
+    mappings: Mutex<HashSet<SocketAddr>>, // addresses the inside node has sent to
+}
+
+impl SimulatedNat {
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self {
+            socket: UdpSocket::bind(addr).await?,
+            mappings: Mutex::new(HashSet::new()),
+        })
+    }
+}
+
+impl DatagramSocket for SimulatedNat {
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>> {
+        self.mappings.lock().expect("Nat mappings lock is poisoned").insert(target);
+        self.socket.poll_send_to(cx, buf, target)
+    }
+
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<SocketAddr>> {
+        loop {
+            let filled = buf.filled().len();
+            match self.socket.poll_recv_from(cx, buf) {
+                Poll::Ready(Ok(from)) => {
+                    if self.mappings.lock().expect("Nat mappings lock is poisoned").contains(&from) {
+                        return Poll::Ready(Ok(from));
+                    }
+                    buf.set_filled(filled); // unsolicited, dropped
+                }
+                other => return other,
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
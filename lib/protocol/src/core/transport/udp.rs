@@ -0,0 +1,286 @@
+use std::collections::HashMap;
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::Instant;
+use anyhow::{bail, Result};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use crate::core::transport::ChunkReader;
+use crate::utils::socket_addr_to_bytes::{socket_addr_from_bytes, socket_addr_to_bytes};
+
+// control datagrams start with a byte that is never a frame header, it has reserved bits set
+const CONTROL_PROBE:     u8 = 0x71; // asks for the address datagram came from, 8 bytes of nonce
+const CONTROL_OBSERVED:  u8 = 0x72; // answer to probe - nonce and 6 bytes of address
+const CONTROL_PUNCH:     u8 = 0x73; // opens NAT mapping towards the peer
+const CONTROL_PUNCH_ACK: u8 = 0x74; // punch went through
+
+const LINK_BUFFER: usize = 100; // datagrams waiting to be read, newer ones are dropped
+const INBOUND_BUFFER: usize = 10;
+const PROBE_BUFFER: usize = 16; // probes waiting for the node to check who sent them, the rest are dropped
+const MAX_DATAGRAM: usize = 2048;
+
+/// Socket udp links go through, real one or `SimulatedNat` in tests
+pub trait DatagramSocket: Send + Sync {
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>>;
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<SocketAddr>>;
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl DatagramSocket for UdpSocket {
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>> {
+        UdpSocket::poll_send_to(self, cx, buf, target)
+    }
+
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<SocketAddr>> {
+        UdpSocket::poll_recv_from(self, cx, buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+/// What udp socket hands over to the node
+pub struct UdpInbound {
+    pub links: mpsc::Receiver<(UdpStream, SocketAddr)>, // opened by peers we are punching with
+    pub probes: mpsc::Receiver<(SocketAddr, u64)>, // answered with `answer_probe` if the node knows the sender
+}
+
+/// Single udp socket shared by all udp links of the node. Datagrams are sorted by the address
+/// they came from, frame from the peer we are punching with means new inbound link.
+/// Links have no retransmission, lost datagram is a lost frame and breaks the link.
+pub struct UdpTransport {
+    socket: Box<dyn DatagramSocket>,
+    links: Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>,
+    probes: Mutex<HashMap<u64, oneshot::Sender<SocketAddr>>>,
+    punches: Mutex<HashMap<SocketAddr, (Instant, bool)>>, // punch deadline and whether peer got through
+    inbound: mpsc::Sender<(UdpStream, SocketAddr)>,
+    inbound_probes: mpsc::Sender<(SocketAddr, u64)>,
+}
+
+impl UdpTransport {
+    pub fn new(socket: Box<dyn DatagramSocket>) -> (Arc<Self>, UdpInbound) {
+        let (inbound, links) = mpsc::channel(INBOUND_BUFFER);
+        let (inbound_probes, probes) = mpsc::channel(PROBE_BUFFER);
+        let transport = Arc::new(Self {
+            socket,
+            links: Mutex::new(HashMap::new()),
+            probes: Mutex::new(HashMap::new()),
+            punches: Mutex::new(HashMap::new()),
+            inbound,
+            inbound_probes,
+        });
+        (transport, UdpInbound { links, probes })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<()> {
+        poll_fn(|cx| self.socket.poll_send_to(cx, buf, target)).await.map(|_| ())
+    }
+
+    /// Link to the peer at `remote`. Datagrams from it go to this link from now on.
+    pub fn connect(self: &Arc<Self>, remote: SocketAddr) -> UdpStream {
+        let (sender, receiver) = mpsc::channel(LINK_BUFFER);
+        self.links.lock().expect("Udp links lock is poisoned").insert(remote, sender);
+        UdpStream::new(self.clone(), remote, receiver)
+    }
+
+    /// Asks node listening at `server` for the address it sees this socket at
+    pub async fn probe(&self, server: SocketAddr, nonce: u64, timeout: Duration) -> Result<SocketAddr> {
+        let (sender, mut receiver) = oneshot::channel();
+        self.probes.lock().expect("Udp probes lock is poisoned").insert(nonce, sender);
+
+        let mut datagram = vec![CONTROL_PROBE];
+        datagram.extend(nonce.to_be_bytes());
+
+        let deadline = Instant::now() + timeout;
+        let res = loop {
+            // probe itself can get lost, so it's repeated
+            self.send_to(&datagram, server).await?;
+            let retry = (deadline - Instant::now()).min(timeout / 4);
+            match tokio::time::timeout(retry, &mut receiver).await {
+                Ok(Ok(observed)) => break Ok(observed),
+                Ok(Err(_)) => break Err(anyhow::anyhow!("Probe was dropped")),
+                Err(_) if Instant::now() >= deadline => break Err(anyhow::anyhow!("No answer to probe from {}", server)),
+                Err(_) => continue,
+            }
+        };
+
+        self.probes.lock().expect("Udp probes lock is poisoned").remove(&nonce);
+        res
+    }
+
+    /// Tells the sender of the probe which address it came from
+    pub async fn answer_probe(&self, from: SocketAddr, nonce: u64) {
+        let Ok(addr) = socket_addr_to_bytes(from) else { return };
+        let mut answer = vec![CONTROL_OBSERVED];
+        answer.extend(nonce.to_be_bytes());
+        answer.extend(addr);
+        let _ = self.send_to(&answer, from).await;
+    }
+
+    /// Whether we are punching with `peer` now, only such peers can open links
+    fn is_punching(&self, peer: &SocketAddr) -> bool {
+        self.punches
+            .lock()
+            .expect("Udp punches lock is poisoned")
+            .get(peer)
+            .map(|(until, _)| *until > Instant::now())
+            .unwrap_or(false)
+    }
+
+    /// Sends punches to `peer` until one of its punches gets through or `timeout` passes.
+    /// Peer has to do the same at the same time, so that both NATs let the other one in.
+    pub async fn punch(&self, peer: SocketAddr, interval: Duration, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        {
+            let mut punches = self.punches.lock().expect("Udp punches lock is poisoned");
+            let now = Instant::now();
+            punches.retain(|_, (until, _)| *until > now);
+            // kept till the deadline even after success, so late punches of the peer are still answered
+            punches.insert(peer, (deadline, false));
+        }
+
+        while Instant::now() < deadline {
+            let through = self
+                .punches
+                .lock()
+                .expect("Udp punches lock is poisoned")
+                .get(&peer)
+                .map(|(_, through)| *through)
+                .unwrap_or(false);
+            if through {
+                return Ok(());
+            }
+
+            self.send_to(&[CONTROL_PUNCH], peer).await?;
+            tokio::time::sleep(interval).await;
+        }
+        bail!("Punch to {} didn't go through", peer)
+    }
+
+    /// Reads the socket and sorts datagrams, runs as long as the node does
+    pub async fn receive(self: Arc<Self>) {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        loop {
+            let mut read_buf = ReadBuf::new(&mut buf);
+            let from = match poll_fn(|cx| self.socket.poll_recv_from(cx, &mut read_buf)).await {
+                Ok(from) => from,
+                Err(_) => continue, // some platforms report icmp errors of previous sends here
+            };
+            let datagram = read_buf.filled();
+
+            match datagram.first() {
+                Some(&CONTROL_PROBE) if datagram.len() == 9 => {
+                    // source could be spoofed, so node answers only the ones it's connected to
+                    let nonce = u64::from_be_bytes(datagram[1..].try_into().expect("checked length"));
+                    let _ = self.inbound_probes.try_send((from, nonce));
+                }
+                Some(&CONTROL_OBSERVED) if datagram.len() == 15 => {
+                    let nonce = u64::from_be_bytes(datagram[1..9].try_into().expect("checked length"));
+                    let Ok(Some(observed)) = socket_addr_from_bytes(&mut datagram[9..].iter().copied()) else { continue };
+                    if let Some(sender) = self.probes.lock().expect("Udp probes lock is poisoned").remove(&nonce) {
+                        let _ = sender.send(observed);
+                    }
+                }
+                Some(&kind @ (CONTROL_PUNCH | CONTROL_PUNCH_ACK)) => {
+                    let expected = match self.punches.lock().expect("Udp punches lock is poisoned").get_mut(&from) {
+                        Some((until, through)) if *until > Instant::now() => {
+                            *through = true;
+                            true
+                        }
+                        _ => false, // nobody asked, answering would make us a reflector
+                    };
+                    if expected && kind == CONTROL_PUNCH {
+                        let _ = self.send_to(&[CONTROL_PUNCH_ACK], from).await;
+                    }
+                }
+                Some(_) => self.deliver(from, datagram.to_vec()),
+                None => {}
+            }
+        }
+    }
+
+    fn deliver(self: &Arc<Self>, from: SocketAddr, datagram: Vec<u8>) {
+        let link = self.links.lock().expect("Udp links lock is poisoned").get(&from).cloned();
+        match link {
+            Some(link) => {
+                let _ = link.try_send(datagram); // reader is behind, same as if datagram was lost
+            }
+            None => {
+                // anybody else could spoof the source and make us answer the victim
+                if !self.is_punching(&from) {
+                    return;
+                }
+                let stream = self.connect(from);
+                let link = self.links.lock().expect("Udp links lock is poisoned").get(&from).cloned();
+                if let Some(link) = link {
+                    let _ = link.try_send(datagram);
+                }
+                // server is busy, dropping the stream removes the link
+                let _ = self.inbound.try_send((stream, from));
+            }
+        }
+    }
+
+    fn remove_link(&self, remote: &SocketAddr) {
+        self.links.lock().expect("Udp links lock is poisoned").remove(remote);
+    }
+}
+
+/// Udp link to a single peer, looks like a byte stream to the protocol
+pub struct UdpStream {
+    transport: Arc<UdpTransport>,
+    remote: SocketAddr,
+    reader: ChunkReader,
+}
+
+impl UdpStream {
+    fn new(transport: Arc<UdpTransport>, remote: SocketAddr, incoming: mpsc::Receiver<Vec<u8>>) -> Self {
+        Self {
+            transport,
+            remote,
+            reader: ChunkReader::new(incoming),
+        }
+    }
+
+    pub fn remote_addr(&self) -> SocketAddr {
+        self.remote
+    }
+}
+
+impl AsyncRead for UdpStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        self.reader.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UdpStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.transport.socket.poll_send_to(cx, buf, self.remote)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.transport.remove_link(&self.remote);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UdpStream {
+    fn drop(&mut self) {
+        self.transport.remove_link(&self.remote);
+    }
+}
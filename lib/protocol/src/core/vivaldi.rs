@@ -0,0 +1,136 @@
+use std::net::SocketAddr;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use crate::types::state::ProtocolState;
+
+const CE: f32 = 0.25; // how fast error estimate adapts
+const CC: f32 = 0.25; // how far coordinate moves on a single sample
+const MIN_HEIGHT: f32 = 0.01;
+const MIN_ERROR: f32 = 0.01; // never fully confident, also keeps weights from dividing by zero
+const MAX_ERROR: f32 = 1.5;
+const MAX_VALUE: f32 = 60_000.0; // same as the biggest accepted ping
+
+/// Synthetic Vivaldi coordinate - 2d position plus height, which stands for the latency
+/// of the node's access link. Distance between two coordinates predicts RTT in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Coordinate {
+    pub pos: [f32; 2],
+    pub height: f32,
+    pub error: f32, // relative error of predictions, starts at the maximum
+}
+
+impl Coordinate {
+    pub const BYTES: usize = 16;
+
+    pub fn new() -> Self {
+        Self {
+            pos: [0.0; 2],
+            height: MIN_HEIGHT,
+            error: MAX_ERROR,
+        }
+    }
+
+    /// Predicted RTT in milliseconds
+    pub fn distance(&self, other: &Coordinate) -> f32 {
+        let dx = self.pos[0] - other.pos[0];
+        let dy = self.pos[1] - other.pos[1];
+        (dx * dx + dy * dy).sqrt() + self.height + other.height
+    }
+
+    pub fn predict(&self, other: &Coordinate) -> u16 {
+        self.distance(other).round().min(u16::MAX as f32) as u16
+    }
+
+    /// Moves coordinate by a measured `rtt` to the node at `remote`.
+    /// `random` picks the direction when both nodes are at the same position.
+    pub fn update(&mut self, remote: &Coordinate, rtt: u16, random: u64) {
+        let rtt = (rtt as f32).max(1.0); // ping of 0 happens on localhost
+
+        // trust the sample more if remote is more confident than we are
+        let weight = self.error / (self.error + remote.error);
+        let dist = self.distance(remote);
+        let sample_error = (dist - rtt).abs() / rtt;
+        self.error = (sample_error * CE * weight + self.error * (1.0 - CE * weight)).clamp(MIN_ERROR, MAX_ERROR);
+
+        let force = CC * weight * (rtt - dist);
+
+        let mut dir = [self.pos[0] - remote.pos[0], self.pos[1] - remote.pos[1]];
+        let mut len = (dir[0] * dir[0] + dir[1] * dir[1]).sqrt();
+        if len < f32::EPSILON {
+            let angle = (random % 360) as f32 * std::f32::consts::PI / 180.0;
+            dir = [angle.cos(), angle.sin()];
+            len = 1.0;
+        }
+        let total = len + self.height + remote.height;
+
+        self.pos[0] = (self.pos[0] + dir[0] / len * force).clamp(-MAX_VALUE, MAX_VALUE);
+        self.pos[1] = (self.pos[1] + dir[1] / len * force).clamp(-MAX_VALUE, MAX_VALUE);
+        // height takes its share of the force, as if it was one more dimension
+        self.height = (self.height + (self.height + remote.height) / total * force).clamp(MIN_HEIGHT, MAX_VALUE);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(Self::BYTES);
+        v.extend(self.pos[0].to_be_bytes());
+        v.extend(self.pos[1].to_be_bytes());
+        v.extend(self.height.to_be_bytes());
+        v.extend(self.error.to_be_bytes());
+        v
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != Self::BYTES {
+            bail!("unexpected length of coordinate")
+        }
+        let mut values = bytes
+            .chunks(4)
+            .map(|chunk| f32::from_be_bytes(chunk.try_into().expect("checked length")));
+        let mut next = || values.next().expect("checked length");
+
+        let coordinate = Self {
+            pos: [next(), next()],
+            height: next(),
+            error: next(),
+        };
+        if !coordinate.is_valid() {
+            bail!("coordinate is out of range")
+        }
+        Ok(coordinate)
+    }
+
+    /// Remote can send anything, this keeps it from breaking our own coordinate
+    fn is_valid(&self) -> bool {
+        let in_range = |v: f32| v.is_finite() && v.abs() <= MAX_VALUE;
+        in_range(self.pos[0])
+            && in_range(self.pos[1])
+            && in_range(self.height)
+            && self.height >= 0.0
+            && self.error.is_finite()
+            && self.error >= MIN_ERROR
+            && self.error <= MAX_ERROR
+    }
+}
+
+impl Default for Coordinate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProtocolState {
+    pub async fn coordinate(&self) -> Coordinate {
+        self.lock().await.coordinate
+    }
+
+    /// Ping to the node predicted by coordinates, works for nodes we are not connected to
+    /// as long as their coordinate was learned before. `None` if it's unknown.
+    pub async fn predicted_ping(&self, addr: SocketAddr) -> Option<u16> {
+        let lock = self.lock().await;
+        let remote = lock
+            .streams
+            .get(&addr)
+            .and_then(|(_, m)| m.coordinate)
+            .or_else(|| lock.known_peers.get(&addr).and_then(|r| r.coordinate))?;
+        Some(lock.coordinate.predict(&remote))
+    }
+}
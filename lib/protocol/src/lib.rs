@@ -1,3 +1,5 @@
 pub mod core;
 pub mod types;
 pub mod utils;
+#[cfg(feature = "sim")]
+pub mod sim;
@@ -0,0 +1,300 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::Result;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc::{channel, Receiver};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use crate::core::identity::Identity;
+use crate::core::transport::memory::{LinkConfig, MemoryNetwork};
+use crate::sim::report::{MessageReport, SimReport};
+use crate::types::{
+    builder::ProtocolBuilder,
+    config::ProtocolConfig,
+    event::ProtocolEvent,
+    package::AppPackage,
+    state::ProtocolState,
+};
+use crate::utils::prng::{Splitmix64, Xoshiro256ss};
+
+pub mod report;
+
+const PORT: u16 = 7000; // of every node, they differ by ip
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub seed: u64, // of the network and of every node
+    pub link: LinkConfig, // of every link that isn't set with `Simulation::set_link`
+    pub protocol: ProtocolConfig, // of every node, udp and metrics server are turned off
+    pub join_interval: Duration, // between nodes started by `add_nodes`, nodes refuse connections coming too fast
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            link: LinkConfig::default(),
+            protocol: ProtocolConfig::default(),
+            join_interval: Duration::from_millis(500),
+        }
+    }
+}
+
+struct Tracked {
+    origin: usize,
+    sent_at: Duration,
+    expected: BTreeSet<usize>, // nodes that were up when it was sent, origin excluded
+    received: BTreeMap<usize, Duration>, // first delivery to every node
+    duplicates: usize,
+}
+
+struct SimNode {
+    addr: SocketAddr,
+    state: ProtocolState,
+    handles: Vec<JoinHandle<()>>,
+    events: Arc<Mutex<Vec<ProtocolEvent>>>,
+    up: bool,
+}
+
+/// Runs real nodes over `MemoryNetwork` in one process, each with its own `ProtocolState`, streams and tasks.
+/// Meant for tokio's paused time (`#[tokio::test(start_paused = true)]`), then waiting is free and
+/// hundreds of nodes run minutes of the network in seconds.
+/// Links, node ids and rngs of the nodes come from `SimConfig::seed`. Order in which tasks run and
+/// hash maps of the nodes don't, so runs with the same seed agree on the outcome, not on every detail.
+pub struct Simulation {
+    config: SimConfig,
+    network: MemoryNetwork,
+    seeds: Xoshiro256ss,
+    started_at: Instant,
+    nodes: Vec<SimNode>,
+    indexes: HashMap<SocketAddr, usize>,
+    tracked: Arc<Mutex<Vec<Tracked>>>, // in the order messages were broadcast, index is in the payload
+}
+
+impl Simulation {
+    pub fn new(config: SimConfig) -> Self {
+        let mut seeds = Splitmix64::new(config.seed).xorshift256ss();
+        let network = MemoryNetwork::new(config.link, seeds.next());
+
+        Self {
+            config,
+            network,
+            seeds,
+            started_at: Instant::now(),
+            nodes: vec![],
+            indexes: HashMap::new(),
+            tracked: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Server address of the node, every node has its own /24
+    pub fn addr(node: usize) -> SocketAddr {
+        let [_, _, high, low] = (node as u32).to_be_bytes();
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, high, low, 1)), PORT)
+    }
+
+    /// Starts a node that dials `peers`, returns its index, the other methods take it
+    pub async fn add_node(&mut self, peers: &[usize]) -> Result<usize> {
+        self.add_node_with(peers, |_| {}).await
+    }
+
+    /// Same as `add_node`, `configure` can change the builder before the node starts
+    pub async fn add_node_with(&mut self, peers: &[usize], configure: impl FnOnce(&mut ProtocolBuilder)) -> Result<usize> {
+        let index = self.nodes.len();
+        let addr = Self::addr(index);
+
+        let (package_sender, packages) = channel(1000);
+        let mut builder = ProtocolBuilder::new(addr, package_sender);
+        let mut config = self.config.protocol.clone();
+        config.nat.udp = false;
+        config.metrics.addr = None;
+        builder.set_config(config);
+        builder.set_memory_network(self.network.clone());
+        builder.set_rng_seed(self.seeds.next());
+        builder.set_identity(Identity::from_secret(self.secret()));
+        for peer in peers {
+            builder.set_client(Self::addr(*peer));
+        }
+        configure(&mut builder);
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut handles = vec![
+            tokio::spawn(record_events(builder.subscribe(), events.clone())),
+            tokio::spawn(track_deliveries(index, packages, self.tracked.clone(), self.started_at)),
+        ];
+        let (state, node_handles) = builder.build().await?;
+        handles.extend(node_handles);
+
+        self.nodes.push(SimNode { addr, state, handles, events, up: true });
+        self.indexes.insert(addr, index);
+        Ok(index)
+    }
+
+    /// Nodes join one by one, every one dials one of the nodes that are up, so the network starts connected
+    pub async fn add_nodes(&mut self, count: usize) -> Result<Vec<usize>> {
+        let mut added = vec![];
+        for _ in 0..count {
+            let up = (0..self.nodes.len()).filter(|n| self.nodes[*n].up).collect::<Vec<_>>();
+            let peers = match up.len() {
+                0 => vec![],
+                len => {
+                    tokio::time::sleep(self.config.join_interval).await;
+                    vec![up[(self.seeds.next() % len as u64) as usize]]
+                }
+            };
+            added.push(self.add_node(&peers).await?);
+        }
+        Ok(added)
+    }
+
+    fn secret(&mut self) -> [u8; 32] {
+        let mut secret = [0; 32];
+        for chunk in secret.chunks_mut(8) {
+            chunk.copy_from_slice(&self.seeds.next().to_le_bytes());
+        }
+        secret
+    }
+
+    /// Node leaves for good: its streams are closed, nobody can dial it and its tasks are stopped
+    pub fn stop(&mut self, node: usize) {
+        let node = &mut self.nodes[node];
+        self.network.take_down(node.addr.ip());
+        for handle in &node.handles {
+            handle.abort();
+        }
+        node.up = false;
+    }
+
+    pub fn set_link(&self, a: usize, b: usize, link: LinkConfig) {
+        self.network.set_link(Self::addr(a).ip(), Self::addr(b).ip(), link);
+    }
+
+    /// Nodes of `side` can't reach the others until `heal`
+    pub fn partition(&self, side: &[usize]) {
+        let side = side.iter().map(|node| Self::addr(*node).ip()).collect::<Vec<_>>();
+        self.network.partition(&side);
+    }
+
+    pub fn heal(&self) {
+        self.network.heal();
+    }
+
+    pub fn state(&self, node: usize) -> &ProtocolState {
+        &self.nodes[node].state
+    }
+
+    /// Nodes it has streams with
+    pub async fn peers(&self, node: usize) -> Vec<usize> {
+        let mut peers = self.nodes[node]
+            .state
+            .peers()
+            .await
+            .into_iter()
+            .filter_map(|peer| self.indexes.get(&peer.addr).copied())
+            .collect::<Vec<_>>();
+        peers.sort();
+        peers
+    }
+
+    /// Everything the node emitted so far
+    pub fn events(&self, node: usize) -> Vec<ProtocolEvent> {
+        self.nodes[node].events.lock().expect("Event log lock is never held across a panic").clone()
+    }
+
+    /// Node broadcasts `data`, it's tracked in the report
+    pub async fn broadcast(&mut self, node: usize, data: &[u8]) -> Result<()> {
+        let index = {
+            let mut tracked = self.tracked.lock().expect("Tracking lock is never held across a panic");
+            tracked.push(Tracked {
+                origin: node,
+                sent_at: self.now(),
+                expected: (0..self.nodes.len()).filter(|n| *n != node && self.nodes[*n].up).collect(),
+                received: BTreeMap::new(),
+                duplicates: 0,
+            });
+            tracked.len() - 1
+        };
+
+        let mut payload = (index as u64).to_le_bytes().to_vec();
+        payload.extend_from_slice(data);
+        self.nodes[node].state.broadcast_data(payload).await
+    }
+
+    /// Lets the network run, in paused time it's over as soon as every task is waiting
+    pub async fn run_for(&self, duration: Duration) {
+        tokio::time::sleep(duration).await;
+    }
+
+    /// Since the simulation was created
+    pub fn now(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    pub fn report(&self) -> SimReport {
+        let tracked = self.tracked.lock().expect("Tracking lock is never held across a panic");
+        let messages = tracked
+            .iter()
+            .map(|t| {
+                let delivered = t.expected.iter().filter(|n| t.received.contains_key(n)).count();
+                MessageReport {
+                    origin: t.origin,
+                    sent_at: t.sent_at,
+                    expected: t.expected.len(),
+                    delivered,
+                    duplicates: t.duplicates,
+                    converged_in: (delivered == t.expected.len()).then(|| {
+                        t.received.values().max().map_or(Duration::ZERO, |last| *last - t.sent_at)
+                    }),
+                }
+            })
+            .collect();
+
+        SimReport {
+            nodes: self.nodes.len(),
+            up: self.nodes.iter().filter(|n| n.up).count(),
+            messages,
+            bytes: self.network.bytes(),
+            retransmits: self.network.retransmits(),
+            elapsed: self.now(),
+        }
+    }
+}
+
+async fn record_events(mut events: tokio::sync::broadcast::Receiver<ProtocolEvent>, log: Arc<Mutex<Vec<ProtocolEvent>>>) {
+    loop {
+        match events.recv().await {
+            Ok(event) => log.lock().expect("Event log lock is never held across a panic").push(event),
+            Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Application of the node, notes when every tracked message got to it
+async fn track_deliveries(
+    node: usize,
+    mut packages: Receiver<AppPackage>,
+    tracked: Arc<Mutex<Vec<Tracked>>>,
+    started_at: Instant,
+) {
+    while let Some(package) = packages.recv().await {
+        let AppPackage::Message(message) = package else {
+            continue;
+        };
+        let Some(index) = message.msg.get(..8).map(|b| u64::from_le_bytes(b.try_into().expect("8 bytes"))) else {
+            continue;
+        };
+
+        let mut tracked = tracked.lock().expect("Tracking lock is never held across a panic");
+        let Some(t) = tracked.get_mut(index as usize) else {
+            continue;
+        };
+        if node == t.origin || t.received.contains_key(&node) {
+            t.duplicates += 1;
+        } else {
+            t.received.insert(node, started_at.elapsed());
+        }
+    }
+}
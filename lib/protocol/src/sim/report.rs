@@ -0,0 +1,45 @@
+use std::time::Duration;
+
+/// What happened to a single broadcast message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageReport {
+    pub origin: usize,
+    pub sent_at: Duration,
+    pub expected: usize, // nodes other than origin that were up when it was sent
+    pub delivered: usize, // of the expected ones, passed it to the application
+    pub duplicates: usize, // deliveries to the application past the first one, origin included
+    pub converged_in: Option<Duration>, // since sending until the last expected node got it, `None` if some didn't
+}
+
+/// Outcome of the simulation so far
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimReport {
+    pub nodes: usize, // ever started
+    pub up: usize, // not stopped
+    pub messages: Vec<MessageReport>, // in the order they were broadcast
+    pub bytes: usize, // delivered over all links, every kind of message
+    pub retransmits: usize, // chunks the links lost and sent again
+    pub elapsed: Duration, // virtual time since the simulation was created
+}
+
+impl SimReport {
+    /// Share of the expected deliveries that happened
+    pub fn delivery_ratio(&self) -> f64 {
+        let expected = self.messages.iter().map(|m| m.expected).sum::<usize>();
+        if expected == 0 {
+            return 1.0;
+        }
+        self.messages.iter().map(|m| m.delivered).sum::<usize>() as f64 / expected as f64
+    }
+
+    pub fn duplicates(&self) -> usize {
+        self.messages.iter().map(|m| m.duplicates).sum()
+    }
+
+    /// Time the slowest message took to reach every node, `None` if some message didn't
+    pub fn convergence_time(&self) -> Option<Duration> {
+        self.messages
+            .iter()
+            .try_fold(Duration::ZERO, |slowest, m| Some(slowest.max(m.converged_in?)))
+    }
+}
@@ -1,62 +1,467 @@
+use std::collections::HashSet;
 use std::net::SocketAddr;
-use tokio::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use anyhow::{Context, Result};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{channel, Sender};
 use tokio::task::JoinHandle;
-use crate::core::client::start_client;
-use crate::core::commands::{command_processor, ProtocolCommand};
-use crate::core::server::handle_connection::start_server;
+use crate::core::bootstrap::{bootstrap, BootstrapConfig};
+use crate::core::client::{
+    reconnect::{schedule_reconnect, ReconnectConfig},
+    start_client,
+};
+use crate::core::commands::command_processor;
+use crate::core::dht::{dht_maintenance, DhtConfig};
+use crate::core::handshake::HandshakeTimeouts;
+use crate::core::identity::Identity;
+use crate::core::peer_store::{load_peers, peer_store_saver, seed_peers, trim_loaded, PeerStore, SEED_CONNECTIONS};
+use crate::core::nat::NatConfig;
+use crate::core::pex::{peer_exchange, PexConfig};
+use crate::core::relay::RelayConfig;
+use crate::core::onion::OnionConfig;
+use crate::core::metrics::{start_metrics_server, Metrics, MetricsConfig};
+use crate::core::stream::ping_stream::PingConfig;
+use crate::core::selection::{policies::LowestLatency, PeerSelectionConfig, PeerSelectionPolicy};
+use crate::core::scoring::ScoringConfig;
+use crate::core::transport::{
+    relay::Circuits,
+    udp::{DatagramSocket, UdpTransport},
+};
+#[cfg(feature = "sim")]
+use crate::core::transport::memory::MemoryNetwork;
+use crate::core::server::{
+    handle_connection::{start_relay_server, start_server, start_udp_server, Listener},
+    limits::InboundLimits,
+};
 use crate::types::{
-    state::ProtocolState,
+    state::{ProtocolState, ProtocolStateInnerRead},
+    config::{BufferConfig, ProtocolConfig},
+    event::{ProtocolEvent, EVENT_BUFFER},
     package::AppPackage,
 };
+use crate::utils::prng::SecureRng;
 
 pub struct ProtocolBuilder {
-    state: ProtocolState,
-    command_receiver: Receiver<ProtocolCommand>,
-    handles: Vec<JoinHandle<()>>,
+    server_addr: SocketAddr,
+    package_sender: Sender<AppPackage>,
+    events: broadcast::Sender<ProtocolEvent>,
+    rng_seed: Option<u64>,
+    buffers: BufferConfig,
+    reconnect: ReconnectConfig,
+    timeouts: HandshakeTimeouts,
+    inbound_limits: InboundLimits,
+    scoring: ScoringConfig,
+    clients: Vec<SocketAddr>,
+    sticky_peers: HashSet<SocketAddr>,
+    peer_store: Option<Arc<dyn PeerStore>>,
+    bootstrap: Option<BootstrapConfig>,
+    identity: Option<Identity>,
+    dht: DhtConfig,
+    pex: PexConfig,
+    ping: PingConfig,
+    selection: PeerSelectionConfig,
+    selection_policy: Box<dyn PeerSelectionPolicy>,
+    nat: NatConfig,
+    udp_socket: Option<Box<dyn DatagramSocket>>,
+    relay: RelayConfig,
+    onion: OnionConfig,
+    metrics: MetricsConfig,
+    #[cfg(feature = "sim")]
+    memory: Option<MemoryNetwork>,
 }
 
 impl ProtocolBuilder {
     pub fn new(
         server_addr: SocketAddr,
         package_sender: Sender<AppPackage>,
-        rng_seed: u64,
     ) -> Self {
-        let (command_sender, command_receiver) = channel(100);
-
-        let state = ProtocolState::new(
+        Self {
             server_addr,
-            command_sender,
             package_sender,
-            rng_seed,
-        );
+            events: broadcast::channel(EVENT_BUFFER).0,
+            rng_seed: None,
+            buffers: BufferConfig::default(),
+            reconnect: ReconnectConfig::default(),
+            timeouts: HandshakeTimeouts::default(),
+            inbound_limits: InboundLimits::default(),
+            scoring: ScoringConfig::default(),
+            clients: vec![],
+            sticky_peers: HashSet::new(),
+            peer_store: None,
+            bootstrap: None,
+            identity: None,
+            dht: DhtConfig::default(),
+            pex: PexConfig::default(),
+            ping: PingConfig::default(),
+            selection: PeerSelectionConfig::default(),
+            selection_policy: Box::new(LowestLatency::default()),
+            nat: NatConfig::default(),
+            udp_socket: None,
+            relay: RelayConfig::default(),
+            onion: OnionConfig::default(),
+            metrics: MetricsConfig::default(),
+            #[cfg(feature = "sim")]
+            memory: None,
+        }
+    }
 
-        Self {
-            state,
-            command_receiver,
-            handles: vec![],
+    /// Events of the node, including the ones emitted while it starts
+    pub fn subscribe(&self) -> broadcast::Receiver<ProtocolEvent> {
+        self.events.subscribe()
+    }
+
+    /// Makes ids, nonces and every random choice of the node repeat between runs. Only for tests,
+    /// peers could predict the ids and drop the node's messages. Without it OS entropy is used.
+    pub fn set_rng_seed(
+        &mut self,
+        seed: u64,
+    ) {
+        self.rng_seed = Some(seed);
+    }
+
+    /// Sets every tunable at once, e.g. loaded with `ProtocolConfig::from_file`
+    pub fn set_config(
+        &mut self,
+        config: ProtocolConfig,
+    ) {
+        self.buffers = config.buffers;
+        self.reconnect = config.reconnect;
+        self.timeouts = config.timeouts;
+        self.inbound_limits = config.inbound_limits;
+        self.scoring = config.scoring;
+        self.dht = config.dht;
+        self.pex = config.pex;
+        self.ping = config.ping;
+        self.selection = config.selection;
+        self.nat = config.nat;
+        self.relay = config.relay;
+        self.onion = config.onion;
+        self.metrics = config.metrics;
+        if config.bootstrap.is_some() {
+            self.bootstrap = config.bootstrap;
         }
     }
 
-    pub async fn set_client(
+    pub fn set_buffers(
+        &mut self,
+        buffers: BufferConfig,
+    ) {
+        self.buffers = buffers;
+    }
+
+    /// Node to connect to on start. If connection fails, it is retried with a backoff.
+    pub fn set_client(
+        &mut self,
+        client_addr: SocketAddr,
+    ) {
+        self.clients.push(client_addr);
+    }
+
+    /// Same as `set_client` but connection to that node is always restored
+    /// no matter how many times it failed or why it was closed.
+    pub fn set_sticky_client(
         &mut self,
         client_addr: SocketAddr,
     ) {
-        let protocol_state = self.state.clone();
-        let handle = start_client(protocol_state, client_addr, None).await;
-        self.handles.extend(handle);
+        self.clients.push(client_addr);
+        self.sticky_peers.insert(client_addr);
+    }
+
+    pub fn set_reconnect(
+        &mut self,
+        reconnect: ReconnectConfig,
+    ) {
+        self.reconnect = reconnect;
     }
 
-    pub async fn build(mut self) -> (ProtocolState, Vec<JoinHandle<()>>) {
-        self.handles.extend(command_processor(
-            self.state.clone(),
-            self.command_receiver, // this is a bridge from application to protocol
+    pub fn set_timeouts(
+        &mut self,
+        timeouts: HandshakeTimeouts,
+    ) {
+        self.timeouts = timeouts;
+    }
+
+    pub fn set_inbound_limits(
+        &mut self,
+        inbound_limits: InboundLimits,
+    ) {
+        self.inbound_limits = inbound_limits;
+    }
+
+    pub fn set_scoring(
+        &mut self,
+        scoring: ScoringConfig,
+    ) {
+        self.scoring = scoring;
+    }
+
+    /// Remembers known nodes between restarts and connects to them on start
+    pub fn set_peer_store(
+        &mut self,
+        peer_store: impl PeerStore + 'static,
+    ) {
+        self.peer_store = Some(Arc::new(peer_store));
+    }
+
+    /// Lists the node at bootstrap nodes and connects to random listed nodes on start
+    pub fn with_bootstrap(
+        &mut self,
+        urls: Vec<String>,
+    ) {
+        self.bootstrap = Some(BootstrapConfig::new(urls));
+    }
+
+    pub fn set_bootstrap(
+        &mut self,
+        bootstrap: BootstrapConfig,
+    ) {
+        self.bootstrap = Some(bootstrap);
+    }
+
+    /// Key pair the node id is derived from, new one is generated if not set
+    pub fn set_identity(
+        &mut self,
+        identity: Identity,
+    ) {
+        self.identity = Some(identity);
+    }
+
+    pub fn set_dht(
+        &mut self,
+        dht: DhtConfig,
+    ) {
+        self.dht = dht;
+    }
+
+    pub fn set_pex(
+        &mut self,
+        pex: PexConfig,
+    ) {
+        self.pex = pex;
+    }
+
+    pub fn set_ping(
+        &mut self,
+        ping: PingConfig,
+    ) {
+        self.ping = ping;
+    }
+
+    pub fn set_peer_selection(
+        &mut self,
+        selection: PeerSelectionConfig,
+    ) {
+        self.selection = selection;
+    }
+
+    /// Decides which nodes to connect to and which to drop for better ones, see `selection::policies`
+    pub fn set_selection_policy(
+        &mut self,
+        policy: impl PeerSelectionPolicy + 'static,
+    ) {
+        self.selection_policy = Box::new(policy);
+    }
+
+    pub fn set_nat(
+        &mut self,
+        nat: NatConfig,
+    ) {
+        self.nat = nat;
+    }
+
+    /// Socket for udp links instead of the one bound at the server address, e.g. `SimulatedNat` in tests
+    pub fn set_udp_socket(
+        &mut self,
+        socket: impl DatagramSocket + 'static,
+    ) {
+        self.udp_socket = Some(Box::new(socket));
+    }
+
+    /// Runs the node over `MemoryNetwork` instead of tcp, for simulations. Udp isn't part of it,
+    /// turn it off with `set_nat` or give a socket with `set_udp_socket`.
+    #[cfg(feature = "sim")]
+    pub fn set_memory_network(
+        &mut self,
+        network: MemoryNetwork,
+    ) {
+        self.memory = Some(network);
+    }
+
+    pub fn set_relay(
+        &mut self,
+        relay: RelayConfig,
+    ) {
+        self.relay = relay;
+    }
+
+    pub fn set_onion(
+        &mut self,
+        onion: OnionConfig,
+    ) {
+        self.onion = onion;
+    }
+
+    /// Serves metrics over http, they are available with `ProtocolState::metrics` either way
+    pub fn set_metrics(
+        &mut self,
+        metrics: MetricsConfig,
+    ) {
+        self.metrics = metrics;
+    }
+
+    /// Config put together from the setters, checked before the node starts
+    fn config(&self) -> ProtocolConfig {
+        ProtocolConfig {
+            buffers: self.buffers.clone(),
+            reconnect: self.reconnect.clone(),
+            timeouts: self.timeouts.clone(),
+            inbound_limits: self.inbound_limits.clone(),
+            scoring: self.scoring.clone(),
+            dht: self.dht.clone(),
+            pex: self.pex.clone(),
+            ping: self.ping.clone(),
+            selection: self.selection.clone(),
+            nat: self.nat.clone(),
+            relay: self.relay.clone(),
+            onion: self.onion.clone(),
+            metrics: self.metrics.clone(),
+            bootstrap: self.bootstrap.clone(),
+        }
+    }
+
+    async fn listen(&self) -> Result<Listener> {
+        #[cfg(feature = "sim")]
+        if let Some(network) = &self.memory {
+            let inbound = network
+                .listen(self.server_addr)
+                .with_context(|| format!("Failed to listen on {} in memory network", self.server_addr))?;
+            return Ok(Listener::Memory(inbound));
+        }
+
+        let server = TcpListener::bind(self.server_addr)
+            .await
+            .with_context(|| format!("Failed to bind tcp listener to {}", self.server_addr))?;
+        Ok(Listener::Tcp(server))
+    }
+
+    /// Starts the node. Fails without starting anything if the config has values the node can't work with
+    /// or the server address can't be bound.
+    pub async fn build(self) -> Result<(ProtocolState, Vec<JoinHandle<()>>)> {
+        self.config().validate()?;
+        // bound before anything is started, taken port fails the build
+        let server = self.listen().await?;
+
+        let (command_sender, command_receiver) = channel(self.buffers.commands);
+
+        let udp_socket = match (self.nat.udp, self.udp_socket) {
+            (false, _) => None,
+            (true, Some(socket)) => Some(socket),
+            (true, None) => match UdpSocket::bind(self.server_addr).await {
+                Ok(socket) => Some(Box::new(socket) as Box<dyn DatagramSocket>),
+                Err(e) => {
+                    // node still works, it just can't punch holes
+                    let _ = self.events.send(ProtocolEvent::UdpUnavailable { error: e.to_string() });
+                    None
+                }
+            },
+        };
+        let (udp, udp_inbound) = match udp_socket {
+            Some(socket) => {
+                let (udp, inbound) = UdpTransport::new(socket);
+                (Some(udp), Some(inbound))
+            }
+            None => (None, None),
+        };
+        let (circuits, circuits_inbound) = Circuits::new();
+
+        let state = ProtocolState::new(
+            ProtocolStateInnerRead {
+                server_addr: self.server_addr,
+                package_sender: self.package_sender,
+                events: self.events,
+                buffers: self.buffers,
+                reconnect: self.reconnect,
+                timeouts: self.timeouts,
+                inbound_limits: self.inbound_limits,
+                scoring: self.scoring,
+                sticky_peers: self.sticky_peers,
+                peer_store: self.peer_store,
+                bootstrap: self.bootstrap,
+                identity: self.identity.unwrap_or_else(Identity::generate),
+                dht: self.dht,
+                pex: self.pex,
+                ping: self.ping,
+                selection: self.selection,
+                selection_policy: self.selection_policy,
+                nat: self.nat,
+                udp: udp.clone(),
+                relay: self.relay,
+                circuits: circuits.clone(),
+                onion: self.onion,
+                metrics: Metrics::new(),
+                metrics_config: self.metrics,
+                #[cfg(feature = "sim")]
+                memory: self.memory,
+            },
+            command_sender,
+            self.rng_seed.map_or_else(SecureRng::from_entropy, SecureRng::from_seed),
+        );
+
+        let mut handles = vec![];
+
+        handles.extend(command_processor(
+            state.clone(),
+            command_receiver, // this is a bridge from application to protocol
         ));
 
-        let protocol_state = self.state.clone();
-        if let Some(handle) = start_server(protocol_state, self.state.read().server_addr).await {
-            self.handles.push(handle);
+        handles.extend(start_server(state.clone(), server));
+        if let (Some(udp), Some(inbound)) = (udp, udp_inbound) {
+            handles.extend(start_udp_server(state.clone(), udp, inbound));
+        }
+        handles.extend(start_relay_server(state.clone(), circuits_inbound));
+        handles.extend(start_metrics_server(state.clone()).await);
+
+        let mut clients = self.clients;
+
+        if let Some(ref store) = state.read().peer_store {
+            match load_peers(store.clone()).await {
+                Ok(records) => {
+                    let records = trim_loaded(records);
+                    for addr in seed_peers(&records, SEED_CONNECTIONS) {
+                        if !clients.contains(&addr) && addr != state.read().server_addr {
+                            clients.push(addr);
+                        }
+                    }
+
+                    let lock = &mut *state.lock().await;
+                    for record in records {
+                        lock.scores.restore(&state.read().scoring, record.addr.ip(), record.score);
+                        lock.known_peers.insert(record.addr, record);
+                    }
+                }
+                Err(e) => {
+                    state.emit(ProtocolEvent::PeerStoreFailed { error: e.to_string() });
+                }
+            }
+
+            handles.extend(peer_store_saver(state.clone()));
+        }
+
+        for client_addr in clients {
+            match start_client(state.clone(), client_addr, None).await {
+                Ok(handle) => handles.extend(handle),
+                Err(_) => schedule_reconnect(&state, client_addr).await,
+            }
+        }
+
+        handles.extend(dht_maintenance(state.clone()));
+        handles.extend(peer_exchange(state.clone()));
+
+        if state.read().bootstrap.is_some() {
+            handles.extend(bootstrap(state.clone()));
         }
 
-        (self.state, self.handles)
+        Ok((state, handles))
     }
 }
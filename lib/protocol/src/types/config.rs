@@ -0,0 +1,158 @@
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use crate::core::{
+    bootstrap::BootstrapConfig,
+    client::reconnect::ReconnectConfig,
+    dht::DhtConfig,
+    frames::ProtocolMessage,
+    handshake::HandshakeTimeouts,
+    metrics::MetricsConfig,
+    nat::NatConfig,
+    onion::OnionConfig,
+    pex::PexConfig,
+    relay::RelayConfig,
+    scoring::ScoringConfig,
+    selection::PeerSelectionConfig,
+    server::limits::InboundLimits,
+    stream::{ping_stream::PingConfig, rtt::MAX_RTT},
+};
+
+pub const ENV_PREFIX: &str = "P2P";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BufferConfig {
+    pub commands: usize, // commands from the application waiting to be processed
+    pub stream: usize, // actions waiting to be done on a single stream
+    pub frame_size: usize, // largest frame sent, header included. Received ones can be up to `ProtocolMessage::FRAME_SIZE`
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        Self {
+            commands: 100,
+            stream: 100,
+            frame_size: ProtocolMessage::FRAME_SIZE,
+        }
+    }
+}
+
+/// Every tunable of the node, see `ProtocolBuilder::set_config`.
+/// In files and env vars durations are in milliseconds.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProtocolConfig {
+    pub buffers: BufferConfig,
+    pub reconnect: ReconnectConfig,
+    pub timeouts: HandshakeTimeouts,
+    pub inbound_limits: InboundLimits,
+    pub scoring: ScoringConfig,
+    pub dht: DhtConfig,
+    pub pex: PexConfig,
+    pub ping: PingConfig,
+    pub selection: PeerSelectionConfig,
+    pub nat: NatConfig,
+    pub relay: RelayConfig,
+    pub onion: OnionConfig,
+    pub metrics: MetricsConfig,
+    pub bootstrap: Option<BootstrapConfig>, // not used if `None`
+}
+
+impl ProtocolConfig {
+    /// Missing fields keep their defaults
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let config: Self = toml::from_str(text).context("Failed to parse config")?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let text = std::fs::read_to_string(path).context("Failed to read config")?;
+        Self::from_toml(&text)
+    }
+
+    /// Overrides fields set in env vars named `P2P_<SECTION>__<FIELD>`, e.g. `P2P_PING__INTERVAL=10000`
+    pub fn with_env(self) -> Result<Self> {
+        self.with_vars(ENV_PREFIX, std::env::vars())
+    }
+
+    /// Same as `with_env`, but with any prefix and variables. Value is parsed as TOML,
+    /// if it isn't valid TOML it is taken as a string, so `P2P_BOOTSTRAP__URLS=["http://a"]` works too.
+    pub fn with_vars(self, prefix: &str, vars: impl IntoIterator<Item = (String, String)>) -> Result<Self> {
+        let mut config = toml::Table::try_from(&self).context("Failed to serialize config")?;
+        let prefix = format!("{}_", prefix);
+
+        for (name, value) in vars {
+            let Some(path) = name.strip_prefix(&prefix) else {
+                continue;
+            };
+            let path = path.to_lowercase();
+            let mut keys = path.split("__").collect::<Vec<_>>();
+            let field = keys.pop().expect("split returns at least one part");
+
+            let mut table = &mut config;
+            for key in keys {
+                table = table
+                    .entry(key)
+                    .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                    .as_table_mut()
+                    .with_context(|| format!("{} is not a section", name))?;
+            }
+            table.insert(field.to_string(), parse_value(&value));
+        }
+
+        let config: Self = config.try_into().context("Invalid config in env vars")?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Catches values the node can't work with, instead of panicking later
+    pub fn validate(&self) -> Result<()> {
+        if self.buffers.commands == 0 || self.buffers.stream == 0 {
+            bail!("Buffer sizes have to be positive")
+        }
+        if !(ProtocolMessage::MIN_FRAME_SIZE..=ProtocolMessage::FRAME_SIZE).contains(&self.buffers.frame_size) {
+            bail!(
+                "Frame size has to be between {} and {}",
+                ProtocolMessage::MIN_FRAME_SIZE,
+                ProtocolMessage::FRAME_SIZE,
+            )
+        }
+        if self.ping.max_rtt > MAX_RTT {
+            bail!("Max rtt can't be above {:?}", MAX_RTT)
+        }
+        if self.ping.interval.is_zero() || self.ping.pong_timeout.is_zero() {
+            bail!("Ping interval and pong timeout have to be positive")
+        }
+        if self.pex.interval.is_zero() {
+            bail!("Pex interval has to be positive")
+        }
+        if self.selection.min_peers > self.selection.max_peers {
+            bail!("Min peers can't be above max peers")
+        }
+        if self.dht.k == 0 || self.dht.alpha == 0 {
+            bail!("Dht k and alpha have to be positive")
+        }
+        let dht_durations = [
+            self.dht.refresh_interval,
+            self.dht.stale_after,
+            self.dht.query_timeout,
+            self.dht.republish_interval,
+            self.dht.max_ttl,
+        ];
+        if dht_durations.iter().any(Duration::is_zero) {
+            bail!("Dht durations have to be positive")
+        }
+        Ok(())
+    }
+}
+
+fn parse_value(value: &str) -> toml::Value {
+    format!("value = {}", value)
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
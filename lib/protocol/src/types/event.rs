@@ -0,0 +1,233 @@
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use crate::core::frames::CloseReason;
+use crate::core::identity::NodeId;
+use crate::core::scoring::Misbehaviour;
+use crate::core::transport::TransportKind;
+use crate::core::vivaldi::Coordinate;
+use crate::types::state::{ProtocolState, StreamDirection};
+
+pub const EVENT_BUFFER: usize = 1000; // subscriber that falls behind that much misses the oldest events
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EventLevel {
+    Debug,
+    Info,
+    Warning,
+    Error,
+}
+
+/// Why the stream has ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    Closed(CloseReason), // this node closed it
+    ClosedByPeer(CloseReason),
+    Lost, // stream broke or peer stopped answering pings
+}
+
+/// Where the node being dialed was learned from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DialSource {
+    NodeStatus,
+    PeerExchange,
+    RoutingTable,
+    Bootstrap,
+}
+
+/// Why the message addressed to some node was dropped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DropReason {
+    NoRoute(NodeId), // no known node is closer to the target
+    BadOnion(String), // onion layer couldn't be peeled
+    Forged(NodeId), // routed message isn't signed by the node it claims to come from
+    AppClosed, // message is for us, but the application stopped receiving packages
+}
+
+/// Why the bootstrap node is suspected of censoring the network
+#[derive(Debug, Clone, PartialEq)]
+pub enum SuspectReason {
+    NotListed, // node registered but isn't in the listings
+    Disjoint { overlap: f64 }, // share of its listings confirmed by other bootstrap nodes
+}
+
+impl Display for SuspectReason {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SuspectReason::NotListed => write!(f, "this node is missing from its listings"),
+            SuspectReason::Disjoint { overlap } => write!(f, "only {:.0}% of its listings are confirmed by others", overlap * 100.0),
+        }
+    }
+}
+
+/// What is happening inside the protocol, for the application to observe
+#[derive(Debug, Clone)]
+pub enum ProtocolEvent {
+    Listening {
+        addr: SocketAddr,
+    },
+    PeerConnected {
+        addr: SocketAddr,
+        direction: StreamDirection,
+        transport: TransportKind,
+    },
+    PeerDisconnected {
+        addr: SocketAddr,
+        reason: DisconnectReason,
+    },
+    PeerReplaced { // connection to `old` is dropped in favor of `new`
+        old: SocketAddr,
+        new: SocketAddr,
+    },
+    PingMeasured {
+        addr: SocketAddr,
+        rtt: Duration,
+        predicted: Option<u16>, // ping the coordinates predicted, if the peer's coordinate is known
+    },
+    PingTooHigh { // peer is dropped, it's too far away
+        addr: SocketAddr,
+        rtt: Duration,
+    },
+    PongMissed {
+        addr: SocketAddr,
+        missed: u32,
+    },
+    PeerDead { // peer stopped answering pings and its connection is dropped
+        addr: SocketAddr,
+        missed: u32,
+    },
+    CoordinateUpdated {
+        coordinate: Coordinate,
+    },
+    HandshakeFailed {
+        addr: SocketAddr, // server address for outbound connections, address of the socket for inbound ones
+        direction: StreamDirection,
+        error: String,
+    },
+    ConnectionRefused { // inbound connection refused before the handshake
+        addr: SocketAddr,
+        reason: CloseReason,
+    },
+    Dialing {
+        addr: SocketAddr,
+        source: DialSource,
+    },
+    Reconnecting {
+        addr: SocketAddr,
+        delay: Duration,
+    },
+    ReconnectGaveUp { // failed too many times, node is marked as dead
+        addr: SocketAddr,
+    },
+    InvalidMessage { // peer is penalized and disconnected
+        addr: SocketAddr,
+        error: String,
+    },
+    StreamFailed { // reading or writing the stream failed, it's dropped
+        addr: SocketAddr,
+        error: String,
+    },
+    PeerPenalized {
+        addr: SocketAddr,
+        misbehaviour: Misbehaviour,
+        score: i32,
+        banned: bool,
+    },
+    MessageDropped {
+        reason: DropReason,
+    },
+    RecordRepublished {
+        key: NodeId,
+        stored: usize, // nodes that accepted the record
+    },
+    HolePunched {
+        addr: SocketAddr,
+        udp_addr: SocketAddr,
+    },
+    PunchFailed {
+        addr: SocketAddr,
+        error: String,
+    },
+    CircuitOpened {
+        addr: SocketAddr,
+        relay: SocketAddr,
+    },
+    BootstrapFailed {
+        url: String,
+        error: String,
+    },
+    SuspectBootstrap { // bootstrap node might be censoring the network, reported once until it behaves
+        url: String,
+        reason: SuspectReason,
+    },
+    AcceptFailed { // server couldn't accept the connection
+        error: String,
+    },
+    UdpUnavailable { // udp socket couldn't be bound, node can't punch holes
+        error: String,
+    },
+    PeerStoreFailed { // loading or saving known peers failed
+        error: String,
+    },
+    MetricsUnavailable { // metrics endpoint couldn't be bound
+        error: String,
+    },
+}
+
+impl ProtocolEvent {
+    /// How much the application should care, to filter out the noise
+    pub fn level(&self) -> EventLevel {
+        match self {
+            ProtocolEvent::Listening { .. }
+            | ProtocolEvent::PeerConnected { .. }
+            | ProtocolEvent::PeerDisconnected { .. }
+            | ProtocolEvent::PeerDead { .. } => EventLevel::Info,
+            ProtocolEvent::PeerReplaced { .. }
+            | ProtocolEvent::PingMeasured { .. }
+            | ProtocolEvent::PongMissed { .. }
+            | ProtocolEvent::CoordinateUpdated { .. }
+            | ProtocolEvent::Dialing { .. }
+            | ProtocolEvent::Reconnecting { .. }
+            | ProtocolEvent::MessageDropped { .. }
+            | ProtocolEvent::HolePunched { .. }
+            | ProtocolEvent::CircuitOpened { .. } => EventLevel::Debug,
+            ProtocolEvent::PeerPenalized { banned, .. } => if *banned { EventLevel::Warning } else { EventLevel::Debug },
+            ProtocolEvent::RecordRepublished { stored, .. } => if *stored == 0 { EventLevel::Warning } else { EventLevel::Debug },
+            ProtocolEvent::PingTooHigh { .. }
+            | ProtocolEvent::HandshakeFailed { .. }
+            | ProtocolEvent::ConnectionRefused { .. }
+            | ProtocolEvent::ReconnectGaveUp { .. }
+            | ProtocolEvent::InvalidMessage { .. }
+            | ProtocolEvent::PunchFailed { .. }
+            | ProtocolEvent::BootstrapFailed { .. }
+            | ProtocolEvent::SuspectBootstrap { .. } => EventLevel::Warning,
+            ProtocolEvent::StreamFailed { .. }
+            | ProtocolEvent::AcceptFailed { .. }
+            | ProtocolEvent::UdpUnavailable { .. }
+            | ProtocolEvent::PeerStoreFailed { .. }
+            | ProtocolEvent::MetricsUnavailable { .. } => EventLevel::Error,
+        }
+    }
+}
+
+impl ProtocolState {
+    /// Events from now on. Subscriber that doesn't keep up gets `Lagged` and misses the oldest ones.
+    pub fn subscribe(&self) -> broadcast::Receiver<ProtocolEvent> {
+        self.read().events.subscribe()
+    }
+
+    /// Sends the event to subscribers, logs it with `tracing` and counts it in metrics,
+    /// none of them can block the caller
+    pub(crate) fn emit(&self, event: ProtocolEvent) {
+        self.read().metrics.observe(&event);
+        match event.level() {
+            EventLevel::Debug => tracing::debug!(?event),
+            EventLevel::Info => tracing::info!(?event),
+            EventLevel::Warning => tracing::warn!(?event),
+            EventLevel::Error => tracing::error!(?event),
+        }
+        // nobody might be listening, that's fine
+        let _ = self.read().events.send(event);
+    }
+}
@@ -1,3 +1,6 @@
 pub mod package;
+pub mod event;
+pub mod peer;
 pub mod builder;
+pub mod config;
 pub mod state;
@@ -1,10 +1,11 @@
-use std::fmt::{Display, Formatter};
 use std::net::SocketAddr;
+use crate::core::identity::NodeId;
 
 #[derive(Debug)]
 pub enum AppPackage {
     Message(MessagePackage),
-    Alert(AlertPackage),
+    Routed(RoutedPackage),
+    Anonymous(AnonymousPackage),
 }
 
 #[derive(Debug)]
@@ -13,22 +14,15 @@ pub struct MessagePackage {
     pub msg: Vec<u8>,
 }
 
+/// Message addressed to this node by its id
 #[derive(Debug)]
-pub enum AlertPackageLevel {
-    DEBUG,
-    ERROR,
-    INFO,
-    WARNING,
-}
-
-impl Display for AlertPackageLevel {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
-    }
+pub struct RoutedPackage {
+    pub from: NodeId, // signature of the sender is checked before the message is delivered
+    pub msg: Vec<u8>,
 }
 
+/// Message that came through an onion path, sender is unknown
 #[derive(Debug)]
-pub struct AlertPackage {
-    pub level: AlertPackageLevel,
-    pub msg: String,
+pub struct AnonymousPackage {
+    pub msg: Vec<u8>,
 }
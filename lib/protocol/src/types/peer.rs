@@ -0,0 +1,118 @@
+use std::net::SocketAddr;
+use std::sync::{Arc, Weak};
+use std::time::Instant;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use crate::core::identity::NodeId;
+use crate::core::stream::rtt::RttStats;
+use crate::core::transport::TransportKind;
+use crate::core::vivaldi::Coordinate;
+use crate::types::{
+    event::{DisconnectReason, ProtocolEvent},
+    state::{ProtocolState, ProtocolStateInner, StreamDirection, StreamMetadata},
+};
+
+/// Snapshot of a connected peer
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerInfo {
+    pub addr: SocketAddr, // server address of the peer
+    pub remote_addr: SocketAddr, // actual address of the socket, for inbound streams it's not the server address
+    pub node_id: Option<NodeId>, // known once node answered dht request
+    pub direction: StreamDirection,
+    pub transport: TransportKind,
+    pub connected_since: Instant,
+    pub rtt: RttStats,
+    pub coordinate: Option<Coordinate>, // last one node sent in pong
+    pub relay: Option<SocketAddr>, // node can be reached through it
+    pub knows_about: Vec<SocketAddr>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl PeerInfo {
+    fn new(addr: SocketAddr, metadata: &StreamMetadata) -> Self {
+        Self {
+            addr,
+            remote_addr: metadata.remote_addr,
+            node_id: metadata.node_id,
+            direction: metadata.direction,
+            transport: metadata.transport,
+            connected_since: metadata.connected_at.into_std(),
+            rtt: metadata.rtt,
+            coordinate: metadata.coordinate,
+            relay: metadata.relay,
+            knows_about: metadata.knows_about.clone(),
+            bytes_sent: metadata.bytes_sent,
+            bytes_received: metadata.bytes_received,
+        }
+    }
+}
+
+/// Change of the peer set
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerChange {
+    Connected(Box<PeerInfo>),
+    Disconnected {
+        addr: SocketAddr,
+        reason: DisconnectReason,
+    },
+    Resync(Vec<PeerInfo>), // watcher fell behind and missed some changes, this is the whole set again
+}
+
+/// Changes of the peer set, see `ProtocolState::watch_peers`
+pub struct PeerWatch {
+    protocol_state: Weak<ProtocolStateInner>, // watching doesn't keep the node alive
+    events: Receiver<ProtocolEvent>,
+}
+
+impl PeerWatch {
+    /// Next change, `None` once the node is gone
+    pub async fn next(&mut self) -> Option<PeerChange> {
+        loop {
+            let event = self.events.recv().await;
+            let protocol_state = ProtocolState(self.protocol_state.upgrade()?);
+            match event {
+                Ok(ProtocolEvent::PeerConnected { addr, .. }) => {
+                    // peer could be gone already, then its disconnect comes next
+                    if let Some(peer) = protocol_state.peer(addr).await {
+                        return Some(PeerChange::Connected(Box::new(peer)));
+                    }
+                }
+                Ok(ProtocolEvent::PeerDisconnected { addr, reason }) => {
+                    return Some(PeerChange::Disconnected { addr, reason });
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(_)) => return Some(PeerChange::Resync(protocol_state.peers().await)),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl ProtocolState {
+    /// Snapshot of every connected peer
+    pub async fn peers(&self) -> Vec<PeerInfo> {
+        self.lock()
+            .await
+            .streams
+            .iter()
+            .map(|(addr, (_, metadata))| PeerInfo::new(*addr, metadata))
+            .collect()
+    }
+
+    /// Snapshot of the connected peer, `None` if there is no stream with it
+    pub async fn peer(&self, addr: SocketAddr) -> Option<PeerInfo> {
+        self.lock()
+            .await
+            .streams
+            .get(&addr)
+            .map(|(_, metadata)| PeerInfo::new(addr, metadata))
+    }
+
+    /// Current peers and the changes after them. Change may repeat what the snapshot already has.
+    pub async fn watch_peers(&self) -> (Vec<PeerInfo>, PeerWatch) {
+        // subscribed first, so nothing happens between the snapshot and the changes
+        let events = self.subscribe();
+        let peers = self.peers().await;
+        (peers, PeerWatch { protocol_state: Arc::downgrade(&self.0), events })
+    }
+}
@@ -1,50 +1,157 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::Duration;
 use tokio::sync::mpsc::Sender;
 use anyhow::{anyhow, Result};
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use tokio::sync::{Mutex, MutexGuard};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, Mutex, MutexGuard};
+use tokio::time::Instant;
 use crate::core::{
+    bootstrap::BootstrapConfig,
+    dht::{kv::Published, record::RecordStore, routing::RoutingTable, DhtConfig},
+    client::reconnect::{ConnectionHistory, ReconnectConfig},
     commands::ProtocolCommand,
     frames::ProtocolMessage,
+    frames::CloseReason,
+    handshake::HandshakeTimeouts,
+    identity::{Identity, NodeId},
+    nat::{NatConfig, NatState},
+    node_info::NodeInfo,
+    peer_store::{PeerRecord, PeerStore},
+    pex::{PexConfig, PexTable},
+    relay::{RelayConfig, RelayState},
+    onion::OnionConfig,
+    metrics::{Metrics, MetricsConfig},
+    selection::{PeerSelectionConfig, PeerSelectionPolicy},
+    vivaldi::Coordinate,
+    scoring::{peer_ip, PeerScores, ScoringConfig},
+    server::limits::InboundLimits,
+    transport::{relay::Circuits, udp::UdpTransport, TransportKind},
 };
-use crate::core::stream::types::StreamAction;
-use crate::types::package::AppPackage;
-use crate::utils::prng::{Splitmix64, Xoshiro256ss};
+#[cfg(feature = "sim")]
+use crate::core::transport::memory::MemoryNetwork;
+use crate::core::stream::{ping_stream::PingConfig, rtt::RttStats, types::StreamAction};
+use crate::types::{
+    config::BufferConfig,
+    event::{DropReason, ProtocolEvent},
+    package::AppPackage,
+};
+use crate::utils::prng::SecureRng;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamDirection {
+    Inbound, // they connected to our server
+    Outbound, // we connected to their server
+}
 
 #[derive(Debug)]
 pub(crate) struct StreamMetadata {
-    pub ping: u16, // in milliseconds but we check that ping is less than 60000, so it can fit
-    pub ping_started_at: Option<SystemTime>,
-    pub topology_rad: f32, // angel relative to the first connection, used to determine who's closer to another user
+    pub direction: StreamDirection,
+    pub remote_addr: SocketAddr, // actual address of the socket, for inbound streams it's not the server address
+    pub transport: TransportKind,
+    pub node_id: Option<NodeId>, // known once node answered dht request
+    pub connected_at: Instant,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub pex_requested_at: Option<Instant>, // response is expected only while this is set
+    pub pex_answered_at: Option<Instant>,
+    pub rtt: RttStats,
+    pub ping_started_at: Option<Instant>,
+    pub missed_pongs: u32, // in a row
+    pub coordinate: Option<Coordinate>, // last one node sent in pong
+    pub observed: Option<SocketAddr>, // address the server sees us at, only for outbound streams
+    pub relay: Option<SocketAddr>, // node can be reached through it, it's us if node reserved a place here
     // vec of address this node knows about for any cross-referencing
-    // (like for topology_rad or to find the path to specific node)
+    // (like to find the path to specific node)
     pub knows_about: Vec<SocketAddr>,
 }
 
 impl StreamMetadata {
-    pub fn new() -> Self {
+    pub fn new(direction: StreamDirection, remote_addr: SocketAddr, transport: TransportKind) -> Self {
         Self {
-            ping: 0,
+            direction,
+            remote_addr,
+            transport,
+            node_id: None,
+            connected_at: Instant::now(),
+            bytes_sent: 0,
+            bytes_received: 0,
+            pex_requested_at: None,
+            pex_answered_at: None,
+            rtt: RttStats::new(),
             ping_started_at: None,
-            topology_rad: 0_f32,
+            missed_pongs: 0,
+            coordinate: None,
+            observed: None,
+            relay: None,
             knows_about: vec![],
         }
     }
+
+    /// Whether others can connect to the node at `addr`, its server address. For inbound tcp streams
+    /// from another ip it's only claimed - node behind NAT often sends its private address.
+    pub fn is_dialable(&self, addr: &SocketAddr) -> bool {
+        match (self.transport, self.direction) {
+            (TransportKind::Udp | TransportKind::Relay, _) => false,
+            (TransportKind::Tcp, StreamDirection::Outbound) => true,
+            (TransportKind::Tcp, StreamDirection::Inbound) => self.remote_addr.ip() == addr.ip(),
+        }
+    }
+
+    /// How the node at `addr` is shared with others, `None` if they can't reach it
+    pub fn node_info(&self, addr: SocketAddr) -> Option<NodeInfo> {
+        let mut info = NodeInfo::new(addr, self.rtt.ping().unwrap_or(0));
+        if !self.is_dialable(&addr) {
+            info.relay = Some(self.relay?);
+        }
+        Some(info)
+    }
 }
 
 pub struct ProtocolStateInnerRead {
     pub server_addr: SocketAddr,
     pub package_sender: Sender<AppPackage>,
+    pub events: broadcast::Sender<ProtocolEvent>,
+    pub buffers: BufferConfig,
+    pub reconnect: ReconnectConfig,
+    pub timeouts: HandshakeTimeouts,
+    pub inbound_limits: InboundLimits,
+    pub scoring: ScoringConfig,
+    pub sticky_peers: HashSet<SocketAddr>, // always reconnected to and never dropped in favor of others
+    pub peer_store: Option<Arc<dyn PeerStore>>,
+    pub bootstrap: Option<BootstrapConfig>,
+    pub identity: Identity,
+    pub dht: DhtConfig,
+    pub pex: PexConfig,
+    pub ping: PingConfig,
+    pub selection: PeerSelectionConfig,
+    pub selection_policy: Box<dyn PeerSelectionPolicy>,
+    pub nat: NatConfig,
+    pub udp: Option<Arc<UdpTransport>>, // `None` if udp is disabled or socket couldn't be bound
+    pub relay: RelayConfig,
+    pub circuits: Arc<Circuits>, // own ends of relayed circuits
+    pub onion: OnionConfig,
+    pub metrics: Metrics,
+    pub metrics_config: MetricsConfig,
+    #[cfg(feature = "sim")]
+    pub memory: Option<MemoryNetwork>, // dialed instead of tcp
 }
 pub(crate) struct ProtocolStateInnerMut {
     pub command_sender: Sender<ProtocolCommand>,
     pub streams: HashMap<SocketAddr, (Sender<StreamAction>, StreamMetadata)>,
-    pub state: Xoshiro256ss,
-    pub data_id_states: HashMap<u64, ()>,
+    pub state: SecureRng,
+    pub data_id_states: HashMap<u64, HashSet<SocketAddr>>, // message id -> streams that sent it
+    pub history: HashMap<SocketAddr, ConnectionHistory>, // outbound connection attempts, used for backoff
+    pub scores: PeerScores,
+    pub known_peers: HashMap<SocketAddr, PeerRecord>, // every node we've been connected to, persisted with `PeerStore`
+    pub dht: RoutingTable,
+    pub records: RecordStore, // kept for other nodes
+    pub published: HashMap<NodeId, Published>, // own records, by key
+    pub pex: PexTable, // candidates learned from peers
+    pub coordinate: Coordinate, // own, moved by every measured ping
+    pub nat: NatState,
+    pub relay: RelayState,
 }
 pub struct ProtocolStateInner {
     r: ProtocolStateInnerRead,
@@ -55,21 +162,28 @@ pub struct ProtocolState(pub Arc<ProtocolStateInner>);
 
 impl ProtocolState {
     pub fn new(
-        server_addr: SocketAddr,
+        r: ProtocolStateInnerRead,
         command_sender: Sender<ProtocolCommand>,
-        package_sender: Sender<AppPackage>,
-        seed: u64,
+        rng: SecureRng,
     ) -> Self {
+        let dht = RoutingTable::new(r.identity.node_id(), r.dht.k);
         Self(Arc::new(ProtocolStateInner {
-            r: ProtocolStateInnerRead {
-                server_addr,
-                package_sender,
-            },
+            r,
             m: Mutex::new(ProtocolStateInnerMut {
                 command_sender,
                 streams: HashMap::new(),
-                state: Splitmix64::new(seed).xorshift256ss(),
+                state: rng,
                 data_id_states: HashMap::new(),
+                history: HashMap::new(),
+                scores: PeerScores::new(),
+                known_peers: HashMap::new(),
+                dht,
+                records: RecordStore::new(),
+                published: HashMap::new(),
+                pex: PexTable::new(),
+                coordinate: Coordinate::new(),
+                nat: NatState::new(),
+                relay: RelayState::new(),
             }),
         }))
     }
@@ -78,37 +192,119 @@ impl ProtocolState {
         &self.0.r
     }
 
-    pub(crate) async fn lock(&self) -> MutexGuard<ProtocolStateInnerMut> {
+    pub(crate) async fn lock(&self) -> MutexGuard<'_, ProtocolStateInnerMut> {
         self.0.m.lock().await
     }
 
+    /// Writes the message in frames of the configured size, returns amount of frames and bytes it took
     pub async fn send_message(
-        stream: &mut TcpStream,
+        &self,
+        stream: &mut (impl AsyncWrite + Unpin),
         message: ProtocolMessage,
-    ) -> Result<()> {
-        for chunk in message.into_frames()? {
-            stream.write(&chunk).await.map_err(|e| anyhow!("---Failed to write to stream: {}", e.to_string()))?;
+    ) -> Result<(usize, usize)> {
+        let frames = message.into_frames_of(self.read().buffers.frame_size)?;
+        let frames_count = frames.len();
+        let mut bytes = 0;
+        for chunk in frames {
+            // frame has to be written in one piece, udp link sends each write as a datagram
+            stream.write_all(&chunk).await.map_err(|e| anyhow!("---Failed to write to stream: {}", e.to_string()))?;
+            bytes += chunk.len();
         }
 
-        Ok(())
+        Ok((frames_count, bytes))
+    }
+
+    /// Passes the package to the application, it's dropped if the application stopped receiving them
+    pub(crate) async fn deliver(&self, package: AppPackage) {
+        if self.read().package_sender.send(package).await.is_err() {
+            self.emit(ProtocolEvent::MessageDropped { reason: DropReason::AppClosed });
+        }
     }
 
     pub async fn broadcast_data(&self, data: Vec<u8>) -> Result<()> {
-        let lock = &mut *self.lock().await;
-        let streams = &mut lock.streams;
-        let state = &mut lock.state;
+        let (id, channels) = {
+            let lock = &mut *self.lock().await;
+            let id = lock.state.next();
+            // peers relay it back to us, it's a duplicate by then
+            lock.data_id_states.insert(id, HashSet::new());
+
+            let biggest_ping = lock
+                .streams
+                .values()
+                .filter_map(|(_, metadata)| metadata.rtt.ping())
+                .max()
+                .unwrap_or(0);
+            self.forget_data_id(id, biggest_ping);
 
-        let id = state.next();
+            let channels = lock.streams.values().map(|(channel, _)| channel.clone()).collect::<Vec<_>>();
+            (id, channels)
+        };
 
-        for (_, (ref mut channel, _)) in streams.iter_mut() {
-            channel
-                .send(StreamAction::Send(ProtocolMessage::Data(id, data.clone())))
-                .await
-                .expect("Failed to send data to stream request channel")
+        for channel in channels {
+            // stream could be closing already, others still get the message
+            let _ = channel.send(StreamAction::Send(ProtocolMessage::Data(id, data.clone()))).await;
         }
 
         Ok(())
     }
+
+    /// Forgets the message id once the message can't come back, that is after the largest ping between peers
+    pub(crate) fn forget_data_id(&self, id: u64, biggest_ping: u16) {
+        let protocol_state = self.clone();
+        tokio::spawn(async move {
+            let ping = if biggest_ping == 0 {
+                1
+            } else {
+                biggest_ping as u64 * 2 // x2 just to be sure
+            };
+            tokio::time::sleep(Duration::from_millis(ping)).await;
+
+            protocol_state.lock().await.data_id_states.remove(&id);
+        });
+    }
+}
+
+impl ProtocolState {
+    /// Bans ip for the given duration and drops all streams connected from it
+    pub async fn ban(&self, ip: IpAddr, duration: Duration) {
+        let channels = {
+            let lock = &mut *self.lock().await;
+            lock.scores.ban(ip, duration);
+            lock.streams
+                .iter()
+                .filter(|(addr, _)| peer_ip(lock, **addr) == ip)
+                .map(|(_, (channel, _))| channel.clone())
+                .collect::<Vec<_>>()
+        };
+        disconnect_banned(channels).await;
+    }
+
+    /// Lifts the ban and resets the score. Returns `false` if ip wasn't banned
+    pub async fn unban(&self, ip: IpAddr) -> bool {
+        self.lock().await.scores.unban(&ip)
+    }
+
+    /// Currently banned ips, by hand or for misbehaving, with the time left for each ban
+    pub async fn bans(&self) -> Vec<(IpAddr, Duration)> {
+        self.lock().await.scores.bans()
+    }
+
+    pub async fn is_banned(&self, ip: IpAddr) -> bool {
+        self.lock().await.scores.is_banned(&ip)
+    }
+
+    /// Score of the ip, starts at 0 and goes down with every misbehaviour of peers connecting from it
+    pub async fn score(&self, ip: IpAddr) -> i32 {
+        self.lock().await.scores.score(&self.read().scoring, &ip)
+    }
+}
+
+/// Sent without the state lock, stream task takes it to handle other actions
+async fn disconnect_banned(channels: impl IntoIterator<Item = Sender<StreamAction>>) {
+    for channel in channels {
+        // stream could be closing already
+        let _ = channel.send(StreamAction::InitiateDisconnect(CloseReason::Banned)).await;
+    }
 }
 
 impl Clone for ProtocolState {
@@ -1,3 +1,4 @@
-pub mod sss_triangle;
 pub mod socket_addr_to_bytes;
 pub mod prng;
+pub mod sample;
+pub mod duration_ms;
@@ -1,12 +1,25 @@
+use rand_chacha::ChaCha20Rng;
+use rand_core::{OsRng, RngCore, SeedableRng};
+
+/// [xoshiro256**](https://prng.di.unimi.it/xoshiro256starstar.c), fast but predictable,
+/// fine for simulations and tests, not for anything peers see - see `SecureRng`.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Xoshiro256ss([u64; 4]);
 
 impl Xoshiro256ss {
-    fn rol64(x: u64, k: u64) -> u64 {
-        (x << k) | (x >> (64_u64.overflowing_sub(k).0))
+    /// State must not be all zeros, generator would give only zeros
+    pub fn new(state: [u64; 4]) -> Self {
+        assert!(state != [0; 4], "Xoshiro256** state can't be all zeros");
+        Self(state)
+    }
+
+    pub fn state(&self) -> [u64; 4] {
+        self.0
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> u64 {
-        let result = Self::rol64(self.0[1].overflowing_mul(5).0, 7).overflowing_mul(9).0;
+        let result = self.0[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
         let t = self.0[1] << 17;
 
         self.0[2] ^= self.0[0];
@@ -15,47 +28,45 @@ impl Xoshiro256ss {
         self.0[0] ^= self.0[3];
 
         self.0[2] ^= t;
-        self.0[3] = Self::rol64(self.0[3], 45);
+        self.0[3] = self.0[3].rotate_left(45);
 
         result
     }
 
     fn jump_inner(&mut self, jump_const: [u64; 4]) {
-        let mut s0: u64 = 0;
-        let mut s1: u64 = 0;
-        let mut s2: u64 = 0;
-        let mut s3: u64 = 0;
+        let mut s = [0; 4];
         for j in jump_const {
             for b in 0..u64::BITS {
-                if (j & 1_u64 << b) == 1 {
-                    s0 ^= self.0[0];
-                    s1 ^= self.0[1];
-                    s2 ^= self.0[2];
-                    s3 ^= self.0[3];
+                if j & (1 << b) != 0 {
+                    for (s, x) in s.iter_mut().zip(self.0) {
+                        *s ^= x;
+                    }
                 }
                 self.next();
             }
         }
-
-        self.0[0] = s0;
-        self.0[1] = s1;
-        self.0[2] = s2;
-        self.0[3] = s3;
+        self.0 = s;
     }
 
-    // suited for parallel execution
-    #[allow(unused)]
+    /// Same as 2^128 calls to `next`, gives 2^128 non-overlapping sequences
     pub fn jump(&mut self) {
         const JUMP: [u64; 4] = [0x180ec6d33cfd0aba, 0xd5a61266f0c9392c, 0xa9582618e03fc9aa, 0x39abdc4529b1661c];
         self.jump_inner(JUMP)
     }
 
-    // suited for distributed parallel execution
-    #[allow(unused)]
+    /// Same as 2^192 calls to `next`, each of 2^64 starting points can then be split with `jump`
     pub fn long_jump(&mut self) {
         const LONG_JUMP: [u64; 4] = [0x76e15d3efefdcbbf, 0xc5004e441c522fb3, 0x77710069854ee241, 0x39109bb02acbe635];
         self.jump_inner(LONG_JUMP)
     }
+
+    /// Independent stream for another task: returns the current sequence and jumps ahead,
+    /// so streams split one after another never overlap
+    pub fn split(&mut self) -> Self {
+        let stream = self.clone();
+        self.jump();
+        stream
+    }
 }
 
 pub struct Splitmix64(u64);
@@ -65,7 +76,7 @@ impl Splitmix64 {
         Self(seed)
     }
 
-    fn splitmix64(&mut self) -> u64 {
+    pub fn splitmix64(&mut self) -> u64 {
         self.0 = self.0.overflowing_add(0x9e3779b97f4a7c15).0;
         let mut z = self.0;
         z = (z ^ (z >> 30)).overflowing_mul(0xbf58476d1ce4e5b9).0;
@@ -83,3 +94,24 @@ impl Splitmix64 {
     }
 }
 
+
+/// Cryptographically secure generator for everything peers must not predict: message ids,
+/// request ids, nonces and circuit ids. Knowing a few outputs of `Xoshiro256ss` is enough to
+/// recover its state, then a peer could send its own message with the id of someone else's next one.
+pub struct SecureRng(ChaCha20Rng);
+
+impl SecureRng {
+    pub fn from_entropy() -> Self {
+        Self(ChaCha20Rng::from_rng(OsRng).expect("OS entropy is available"))
+    }
+
+    /// Same sequence for the same seed, only for tests - outputs are predictable
+    pub fn from_seed(seed: u64) -> Self {
+        Self(ChaCha20Rng::seed_from_u64(seed))
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+}
@@ -0,0 +1,9 @@
+/// Picks up to `count` random items without repeats
+pub fn sample<T>(mut items: Vec<T>, random: &mut impl FnMut() -> u64, count: usize) -> Vec<T> {
+    let mut picked = Vec::with_capacity(count.min(items.len()));
+    while picked.len() < count && !items.is_empty() {
+        let i = (random() % items.len() as u64) as usize;
+        picked.push(items.swap_remove(i));
+    }
+    picked
+}
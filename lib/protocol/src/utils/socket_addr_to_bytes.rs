@@ -1,5 +1,4 @@
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::vec::IntoIter;
 use anyhow::{bail, Context, Result};
 
 pub fn socket_addr_to_bytes(addr: SocketAddr) -> Result<[u8; 6]> {
@@ -29,7 +28,7 @@ pub fn socket_addr_to_bytes(addr: SocketAddr) -> Result<[u8; 6]> {
     Ok(v)
 }
 
-pub fn socket_addr_from_bytes(bytes: &mut IntoIter<u8>) -> Result<Option<SocketAddr>> {
+pub fn socket_addr_from_bytes(bytes: &mut impl Iterator<Item = u8>) -> Result<Option<SocketAddr>> {
     let ip = Ipv4Addr::new(
         bytes.next().context("not enough bytes")?,
         bytes.next().context("not enough bytes")?,
@@ -0,0 +1,109 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use protocol::core::bootstrap::{cross_check::Listings, weighted_subset};
+use protocol::types::event::SuspectReason;
+use protocol::utils::prng::Splitmix64;
+
+mod common;
+use common::localhost;
+
+#[test]
+fn subset_has_no_repeats() {
+    let addrs = (0..10).map(|i| (localhost(1000 + i), 1 + i as usize)).collect::<Vec<_>>();
+    let mut rng = Splitmix64::new(7);
+
+    for count in [0, 1, 5, 10] {
+        let picked = weighted_subset(addrs.clone(), &mut || rng.splitmix64(), count);
+        assert_eq!(picked.len(), count);
+        assert_eq!(picked.iter().collect::<HashSet<_>>().len(), count);
+        assert!(picked.iter().all(|addr| addrs.iter().any(|(a, _)| a == addr)));
+    }
+}
+
+#[test]
+fn subset_is_capped_by_addresses() {
+    let addrs = vec![(localhost(1000), 1), (localhost(1001), 3)];
+    let mut rng = Splitmix64::new(7);
+
+    let picked = weighted_subset(addrs, &mut || rng.splitmix64(), 5);
+    assert_eq!(picked.iter().collect::<HashSet<_>>(), [localhost(1000), localhost(1001)].iter().collect());
+    assert!(weighted_subset(vec![], &mut || rng.splitmix64(), 5).is_empty());
+}
+
+#[test]
+fn confirmed_addresses_are_picked_more_often() {
+    let (confirmed, single) = (localhost(1000), localhost(1001));
+    let addrs = vec![(confirmed, 3), (single, 1)];
+
+    // every random value once, so the share is exact
+    let mut r = 0;
+    let mut picks = 0;
+    for _ in 0..400 {
+        let picked = weighted_subset(addrs.clone(), &mut || { r += 1; r - 1 }, 1);
+        if picked == [confirmed] {
+            picks += 1;
+        }
+    }
+    assert_eq!(picks, 300);
+}
+
+fn listings(sources: &[(&str, &[u16])]) -> Listings {
+    let mut listings = Listings::new();
+    for (url, ports) in sources {
+        listings.add(url.to_string(), ports.iter().map(|port| localhost(*port)).collect());
+    }
+    listings
+}
+
+fn confirmations(counts: &[(u16, usize)]) -> HashMap<SocketAddr, usize> {
+    counts.iter().map(|(port, count)| (localhost(*port), *count)).collect()
+}
+
+#[test]
+fn agreeing_bootstraps_confirm_each_other() {
+    let me = localhost(1000);
+    let listings = listings(&[
+        ("a", &[1000, 1001, 1002]),
+        ("b", &[1000, 1001, 1002]),
+        ("c", &[1000, 1002]),
+    ]);
+
+    assert!(listings.suspects(me, 0.5).is_empty());
+    assert_eq!(listings.confirmations(&HashSet::new()), confirmations(&[(1000, 3), (1001, 2), (1002, 3)]));
+}
+
+#[test]
+fn disagreeing_bootstraps_are_suspected() {
+    let me = localhost(1000);
+    let listings = listings(&[
+        ("a", &[1000, 1001, 1002]),
+        ("b", &[1000, 1001, 1002]),
+        ("c", &[1000, 1002, 1003, 1004, 1005]), // only a quarter is confirmed
+        ("d", &[1001, 1002]), // hides this node
+    ]);
+
+    let suspects = listings.suspects(me, 0.5);
+    assert_eq!(suspects, [
+        ("d".to_string(), SuspectReason::NotListed),
+        ("c".to_string(), SuspectReason::Disjoint { overlap: 0.25 }),
+    ]);
+
+    // addresses only the suspects know about are left out
+    let excluded = suspects.into_iter().map(|(url, _)| url).collect();
+    assert_eq!(listings.confirmations(&excluded), confirmations(&[(1000, 2), (1001, 2), (1002, 2)]));
+}
+
+#[test]
+fn unreachable_bootstrap_is_left_out() {
+    let me = localhost(1000);
+    // bootstrap node that didn't respond never makes it to the listings
+    let listings = listings(&[("a", &[1000, 1001])]);
+
+    // a single listing has nothing to be compared with
+    assert!(listings.suspects(me, 0.5).is_empty());
+    assert_eq!(listings.confirmations(&HashSet::new()), confirmations(&[(1000, 1), (1001, 1)]));
+
+    // if every bootstrap node that responded is suspected, its listings are still used
+    let excluded = HashSet::from(["a".to_string()]);
+    assert_eq!(listings.confirmations(&excluded), confirmations(&[(1000, 1), (1001, 1)]));
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+use tokio::net::TcpStream;
+use protocol::core::frames::ProtocolMessage;
+use protocol::types::{
+    builder::ProtocolBuilder,
+    event::{DropReason, ProtocolEvent},
+};
+
+mod common;
+use common::localhost;
+
+#[tokio::test]
+async fn own_message_coming_back_is_a_duplicate() {
+    let (addr, peer_addr) = (localhost(17552), localhost(17553));
+    let (sender, mut packages) = tokio::sync::mpsc::channel(1000);
+    let mut builder = ProtocolBuilder::new(addr, sender);
+    builder.set_rng_seed(0);
+    let mut events = builder.subscribe();
+    let node = builder.build().await.unwrap().0;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    node.send_message(&mut stream, ProtocolMessage::ConnInit { server_addr: peer_addr }).await.unwrap();
+    while !matches!(events.recv().await.unwrap(), ProtocolEvent::PeerConnected { .. }) {}
+
+    node.broadcast_data(b"hello".to_vec()).await.unwrap();
+    // peer relays it back, as it would if we were connected through someone else too
+    let relayed = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some((message @ ProtocolMessage::Data(..), _)) = ProtocolMessage::from_stream(&mut stream).await.unwrap() {
+                return message;
+            }
+        }
+    })
+        .await
+        .expect("message wasn't broadcast");
+    node.send_message(&mut stream, relayed).await.unwrap();
+
+    let delivered = tokio::time::timeout(Duration::from_millis(500), packages.recv()).await;
+    assert!(delivered.is_err(), "own message was delivered back to the application");
+}
+
+#[tokio::test]
+async fn data_for_closed_application_keeps_the_stream() {
+    let (addr, peer_addr) = (localhost(17554), localhost(17555));
+    let (sender, packages) = tokio::sync::mpsc::channel(1000);
+    drop(packages);
+    let mut builder = ProtocolBuilder::new(addr, sender);
+    builder.set_rng_seed(0);
+    let mut events = builder.subscribe();
+    let node = builder.build().await.unwrap().0;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    node.send_message(&mut stream, ProtocolMessage::ConnInit { server_addr: peer_addr }).await.unwrap();
+    while !matches!(events.recv().await.unwrap(), ProtocolEvent::PeerConnected { .. }) {}
+
+    node.send_message(&mut stream, ProtocolMessage::Data(7, b"hello".to_vec())).await.unwrap();
+    node.send_message(&mut stream, ProtocolMessage::Ping).await.unwrap();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match events.recv().await.unwrap() {
+                ProtocolEvent::MessageDropped { reason: DropReason::AppClosed } => break,
+                ProtocolEvent::PeerDisconnected { .. } => panic!("stream was dropped"),
+                _ => {},
+            }
+        }
+        // stream task is still there to answer
+        loop {
+            if let Some((ProtocolMessage::Pong(_), _)) = ProtocolMessage::from_stream(&mut stream).await.unwrap() {
+                break;
+            }
+        }
+    })
+        .await
+        .expect("message wasn't dropped or ping wasn't answered");
+}
@@ -0,0 +1,59 @@
+#![allow(dead_code)] // every test file uses its own part
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::sync::mpsc::Receiver;
+use protocol::core::selection::{policies::RandomSelection, PeerSelectionConfig};
+use protocol::types::{
+    builder::ProtocolBuilder,
+    package::AppPackage,
+    state::ProtocolState,
+};
+
+pub fn localhost(port: u16) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+}
+
+pub fn drain(mut receiver: Receiver<AppPackage>) {
+    tokio::spawn(async move { while receiver.recv().await.is_some() {} });
+}
+
+pub fn builder(port: u16, seed: u64) -> ProtocolBuilder {
+    let (sender, receiver) = tokio::sync::mpsc::channel(1000);
+    drain(receiver);
+    let mut builder = ProtocolBuilder::new(localhost(port), sender);
+    builder.set_rng_seed(seed);
+    builder
+}
+
+pub async fn node(port: u16, seed: u64) -> ProtocolState {
+    builder(port, seed).build().await.unwrap().0
+}
+
+/// Node connected only to `peer` and not looking for other nodes, so tests decide how it connects further
+pub fn isolated(port: u16, peer: SocketAddr, seed: u64) -> ProtocolBuilder {
+    let mut builder = builder(port, seed);
+    builder.set_client(peer);
+    builder.set_peer_selection(PeerSelectionConfig {
+        min_peers: 1,
+        max_peers: 1,
+    });
+    builder.set_selection_policy(RandomSelection);
+    builder
+}
+
+pub async fn wait_for(mut condition: impl AsyncFnMut() -> bool) -> bool {
+    for _ in 0..100 {
+        if condition().await {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    false
+}
+
+/// Whether pings went over the stream in both directions
+pub async fn linked(a: &ProtocolState, a_addr: SocketAddr, b: &ProtocolState, b_addr: SocketAddr) -> bool {
+    let pinged = async |s: &ProtocolState, addr| s.rtt_stats(addr).await.map(|s| s.samples > 0).unwrap_or(false);
+    wait_for(async || pinged(a, b_addr).await && pinged(b, a_addr).await).await
+}
@@ -0,0 +1,121 @@
+use std::time::Duration;
+use protocol::core::frames::ProtocolMessage;
+use protocol::core::stream::{ping_stream::PingConfig, rtt::MAX_RTT};
+use protocol::types::config::BufferConfig;
+use protocol::types::config::ProtocolConfig;
+
+mod common;
+use common::{builder, linked, localhost};
+
+fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+    pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+#[test]
+fn toml_keeps_defaults_of_missing_fields() {
+    let config = ProtocolConfig::from_toml(r#"
+        [ping]
+        interval = 5000
+
+        [buffers]
+        frame_size = 64
+
+        [bootstrap]
+        urls = ["http://127.0.0.1:6000"]
+    "#).unwrap();
+
+    assert_eq!(config.ping.interval, Duration::from_secs(5));
+    assert_eq!(config.ping.pong_timeout, ProtocolConfig::default().ping.pong_timeout);
+    assert_eq!(config.buffers.frame_size, 64);
+    assert_eq!(config.buffers.commands, 100);
+    assert_eq!(config.bootstrap.unwrap().urls, vec!["http://127.0.0.1:6000".to_string()]);
+    assert!(ProtocolConfig::default().bootstrap.is_none());
+}
+
+#[test]
+fn env_vars_override_fields() {
+    let config = ProtocolConfig::default().with_vars("P2P", vars(&[
+        ("P2P_PING__MAX_MISSED", "5"),
+        ("P2P_SELECTION__MAX_PEERS", "12"),
+        ("P2P_METRICS__ADDR", "127.0.0.1:9000"),
+        ("P2P_NAT__UDP", "false"),
+        ("OTHER_PING__MAX_MISSED", "7"),
+    ])).unwrap();
+
+    assert_eq!(config.ping.max_missed, 5);
+    assert_eq!(config.selection.max_peers, 12);
+    assert_eq!(config.metrics.addr, Some(localhost(9000)));
+    assert!(!config.nat.udp);
+
+    let config = ProtocolConfig::default()
+        .with_vars("P2P", vars(&[("P2P_BOOTSTRAP__URLS", r#"["http://a", "http://b"]"#)]))
+        .unwrap();
+    assert_eq!(config.bootstrap.unwrap().urls.len(), 2);
+}
+
+#[test]
+fn invalid_values_are_refused() {
+    assert!(ProtocolConfig::from_toml("[buffers]\nframe_size = 300").is_err());
+    assert!(ProtocolConfig::from_toml("[buffers]\nstream = 0").is_err());
+    assert!(ProtocolConfig::from_toml("[ping]\nmax_rtt = 70000").is_err());
+    assert!(ProtocolConfig::from_toml("[selection]\nmin_peers = 9\nmax_peers = 8").is_err());
+    assert!(ProtocolConfig::from_toml("[pex]\ninterval = 0").is_err());
+    assert!(ProtocolConfig::from_toml("[ping]\npong_timeout = 0").is_err());
+    assert!(ProtocolConfig::from_toml("[dht]\nrefresh_interval = 0").is_err());
+    assert!(ProtocolConfig::from_toml("[dht]\nquery_timeout = 0").is_err());
+    assert!(ProtocolConfig::default().with_vars("P2P", vars(&[("P2P_PING__INTERVAL", "soon")])).is_err());
+}
+
+#[tokio::test]
+async fn builder_refuses_invalid_values() {
+    let mut zero_buffer = builder(17538, 0);
+    zero_buffer.set_buffers(BufferConfig {
+        stream: 0,
+        ..BufferConfig::default()
+    });
+    assert!(zero_buffer.build().await.is_err());
+
+    let mut high_rtt = builder(17538, 0);
+    high_rtt.set_ping(PingConfig {
+        max_rtt: MAX_RTT + Duration::from_millis(1),
+        ..PingConfig::default()
+    });
+    assert!(high_rtt.build().await.is_err());
+
+    // nothing was bound by the refused ones
+    let _node = builder(17538, 0).build().await.unwrap();
+
+    // port is taken by the node above
+    let error = builder(17538, 1).build().await.err().expect("port is taken");
+    assert!(error.to_string().contains("Failed to bind"), "{}", error);
+}
+
+#[tokio::test]
+async fn small_frames_are_read_back() {
+    let data = (0..200).collect::<Vec<u8>>();
+    let frames = ProtocolMessage::Data(7, data.clone()).into_frames_of(16).unwrap();
+    assert!(frames.len() > 1 && frames.iter().all(|f| f.len() <= 16));
+
+    let bytes = frames.concat();
+    match ProtocolMessage::from_stream(&mut bytes.as_slice()).await.unwrap() {
+        Some((ProtocolMessage::Data(7, read), _)) => assert_eq!(read, data),
+        _ => panic!("message wasn't read back"),
+    }
+}
+
+#[tokio::test]
+async fn nodes_with_small_frames_link() {
+    let (a_addr, b_addr) = (localhost(17500), localhost(17501));
+    let config = ProtocolConfig::from_toml("[buffers]\nframe_size = 8").unwrap();
+
+    let mut a_builder = builder(a_addr.port(), 0);
+    a_builder.set_config(config.clone());
+    let a = a_builder.build().await.unwrap().0;
+
+    let mut b_builder = builder(b_addr.port(), 1);
+    b_builder.set_config(config);
+    b_builder.set_client(a_addr);
+    let b = b_builder.build().await.unwrap().0;
+
+    assert!(linked(&a, a_addr, &b, b_addr).await);
+}
@@ -0,0 +1,69 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use tokio::sync::broadcast::Receiver;
+use protocol::core::frames::CloseReason;
+use protocol::core::transport::TransportKind;
+use protocol::types::event::{DisconnectReason, ProtocolEvent};
+use protocol::types::state::StreamDirection;
+
+mod common;
+use common::{builder, localhost};
+
+/// First event the filter picks, skipping the rest
+async fn next_matching<T>(events: &mut Receiver<ProtocolEvent>, mut filter: impl FnMut(ProtocolEvent) -> Option<T>) -> T {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(picked) = filter(events.recv().await.unwrap()) {
+                return picked;
+            }
+        }
+    })
+        .await
+        .expect("event didn't come in time")
+}
+
+#[tokio::test]
+async fn connection_lifecycle_events() {
+    let (a_addr, b_addr) = (localhost(17470), localhost(17471));
+
+    let a_builder = builder(a_addr.port(), 0);
+    let mut a_events = a_builder.subscribe();
+    let a = a_builder.build().await.unwrap().0;
+
+    let mut b_builder = builder(b_addr.port(), 1);
+    b_builder.set_client(a_addr);
+    let mut b_events = b_builder.subscribe();
+    let _b = b_builder.build().await.unwrap().0;
+
+    let listening = next_matching(&mut a_events, |e| match e {
+        ProtocolEvent::Listening { addr } => Some(addr),
+        _ => None,
+    }).await;
+    assert_eq!(listening, a_addr);
+
+    let (addr, direction, transport) = next_matching(&mut a_events, |e| match e {
+        ProtocolEvent::PeerConnected { addr, direction, transport } => Some((addr, direction, transport)),
+        _ => None,
+    }).await;
+    assert_eq!((addr, direction, transport), (b_addr, StreamDirection::Inbound, TransportKind::Tcp));
+
+    let (addr, direction) = next_matching(&mut b_events, |e| match e {
+        ProtocolEvent::PeerConnected { addr, direction, .. } => Some((addr, direction)),
+        _ => None,
+    }).await;
+    assert_eq!((addr, direction), (a_addr, StreamDirection::Outbound));
+
+    a.ban(IpAddr::V4(Ipv4Addr::LOCALHOST), Duration::from_secs(60)).await;
+
+    let (addr, reason) = next_matching(&mut a_events, |e| match e {
+        ProtocolEvent::PeerDisconnected { addr, reason } => Some((addr, reason)),
+        _ => None,
+    }).await;
+    assert_eq!((addr, reason), (b_addr, DisconnectReason::Closed(CloseReason::Banned)));
+
+    let reason = next_matching(&mut b_events, |e| match e {
+        ProtocolEvent::PeerDisconnected { reason, .. } => Some(reason),
+        _ => None,
+    }).await;
+    assert_eq!(reason, DisconnectReason::ClosedByPeer(CloseReason::Banned));
+}
@@ -0,0 +1,65 @@
+use tokio::io::AsyncWriteExt;
+use protocol::core::frames::{FrameReader, ProtocolMessage};
+
+/// Data message of `len` payload bytes in full frames, sent as is regardless of the cap
+fn oversized(len: usize) -> Vec<u8> {
+    let mut bytes = vec![];
+    let chunks = len.div_ceil(255);
+    for i in 0..chunks {
+        let fin = if i + 1 == chunks { 1 << 7 } else { 0 };
+        let opcode = if i == 0 { 0b0101 } else { 0 };
+        let size = if i + 1 == chunks { len - i * 255 } else { 255 };
+        bytes.push(fin | opcode);
+        bytes.push(size as u8);
+        bytes.extend(std::iter::repeat_n(0, size));
+    }
+    bytes
+}
+
+#[test]
+fn message_above_cap_is_not_sent() {
+    let data = vec![0; ProtocolMessage::MAX_MESSAGE_SIZE];
+    assert!(ProtocolMessage::Data(7, data).into_frames().is_err());
+}
+
+#[tokio::test]
+async fn message_above_cap_is_rejected() {
+    let bytes = oversized(ProtocolMessage::MAX_MESSAGE_SIZE + 1);
+
+    assert!(ProtocolMessage::from_stream(&mut bytes.as_slice()).await.is_err());
+    assert!(FrameReader::new().next(&mut bytes.as_slice()).await.is_err());
+
+    // just fits, id takes 8 bytes of the payload
+    let bytes = oversized(ProtocolMessage::MAX_MESSAGE_SIZE);
+    match FrameReader::new().next(&mut bytes.as_slice()).await.unwrap() {
+        Some((ProtocolMessage::Data(_, data), _, consumed)) => {
+            assert_eq!(data.len(), ProtocolMessage::MAX_MESSAGE_SIZE - 8);
+            assert_eq!(consumed, bytes.len());
+        }
+        _ => panic!("message wasn't read"),
+    }
+}
+
+#[tokio::test]
+async fn reader_puts_together_frames_split_across_reads() {
+    let bytes = (0..5u8)
+        .flat_map(|i| ProtocolMessage::Data(i as u64, vec![i; 100 * i as usize]).into_frames_of(64).unwrap())
+        .flatten()
+        .collect::<Vec<_>>();
+
+    // small pipe, so frames come in pieces and several frames in one read
+    let (mut writer, mut reader) = tokio::io::duplex(7);
+    tokio::spawn(async move { writer.write_all(&bytes).await.unwrap() });
+
+    let mut frames = FrameReader::new();
+    for i in 0..5u8 {
+        match frames.next(&mut reader).await.unwrap() {
+            Some((ProtocolMessage::Data(id, data), _, _)) => {
+                assert_eq!(id, i as u64);
+                assert_eq!(data, vec![i; 100 * i as usize]);
+            }
+            _ => panic!("message {} wasn't read", i),
+        }
+    }
+    assert!(frames.next(&mut reader).await.unwrap().is_none());
+}
@@ -0,0 +1,139 @@
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::sync::broadcast::Receiver;
+use tokio::time::Instant;
+use protocol::core::frames::{CloseReason, ProtocolMessage};
+use protocol::core::handshake::HandshakeTimeouts;
+use protocol::types::{event::ProtocolEvent, state::StreamDirection};
+
+mod common;
+use common::{builder, isolated, linked, localhost, node};
+
+fn timeouts(connect: u64, conn_init: u64, handshake: u64) -> HandshakeTimeouts {
+    HandshakeTimeouts {
+        connect: Duration::from_secs(connect),
+        conn_init: Duration::from_secs(conn_init),
+        handshake: Duration::from_secs(handshake),
+    }
+}
+
+async fn handshake_failed(events: &mut Receiver<ProtocolEvent>, expected: StreamDirection) -> String {
+    loop {
+        if let ProtocolEvent::HandshakeFailed { direction, error, .. } = events.recv().await.unwrap() {
+            if direction == expected {
+                return error;
+            }
+        }
+    }
+}
+
+/// Connects and sends nothing, returns the reason the node closed the connection with and when
+async fn silent_client(port: u16) -> (CloseReason, Instant) {
+    let mut stream = TcpStream::connect(localhost(port)).await.unwrap();
+    let reason = match ProtocolMessage::from_stream(&mut stream).await.unwrap() {
+        Some((ProtocolMessage::ConnClosed(reason), _)) => reason,
+        _ => panic!("expected CONN_CLOSED"),
+    };
+    let closed_at = Instant::now();
+    // handshake task is done with the connection
+    assert!(ProtocolMessage::from_stream(&mut stream).await.unwrap().is_none());
+    (reason, closed_at)
+}
+
+#[tokio::test(start_paused = true)]
+async fn silent_client_misses_conn_init() {
+    let mut node = builder(17539, 0);
+    node.set_timeouts(timeouts(10, 2, 30));
+    let mut events = node.subscribe();
+    let _node = node.build().await.unwrap().0;
+
+    let started = Instant::now();
+    let (reason, closed_at) = silent_client(17539).await;
+    assert_eq!(reason, CloseReason::HandshakeTimeout);
+    assert_eq!((closed_at - started).as_secs(), 2);
+    assert_eq!(handshake_failed(&mut events, StreamDirection::Inbound).await, "didn't receive CONN_INIT in time");
+}
+
+#[tokio::test(start_paused = true)]
+async fn handshake_timeout_bounds_conn_init() {
+    let mut node = builder(17540, 0);
+    node.set_timeouts(timeouts(10, 30, 3));
+    let mut events = node.subscribe();
+    let _node = node.build().await.unwrap().0;
+
+    let started = Instant::now();
+    let (reason, closed_at) = silent_client(17540).await;
+    assert_eq!(reason, CloseReason::HandshakeTimeout);
+    assert_eq!((closed_at - started).as_secs(), 3);
+    assert_eq!(handshake_failed(&mut events, StreamDirection::Inbound).await, "handshake timed out");
+}
+
+#[tokio::test(start_paused = true)]
+async fn unanswered_connect_times_out() {
+    // listener that never accepts, once its backlog is full further connections hang
+    let socket = TcpSocket::new_v4().unwrap();
+    socket.bind(localhost(17541)).unwrap();
+    let _listener = socket.listen(1).unwrap();
+    let mut backlog = vec![];
+    while let Ok(Ok(stream)) = tokio::time::timeout(Duration::from_millis(100), TcpStream::connect(localhost(17541))).await {
+        backlog.push(stream);
+    }
+
+    let mut node = builder(17542, 0);
+    node.set_timeouts(timeouts(4, 10, 30));
+    node.set_client(localhost(17541));
+    let mut events = node.subscribe();
+    let started = Instant::now();
+    let _node = node.build().await.unwrap().0;
+
+    assert_eq!(handshake_failed(&mut events, StreamDirection::Outbound).await, "tcp connection timed out");
+    assert_eq!(started.elapsed().as_secs(), 4);
+}
+
+#[tokio::test]
+async fn conn_init_for_connected_address_is_refused() {
+    let (a_addr, b_addr) = (localhost(17543), localhost(17544));
+    let a_builder = builder(a_addr.port(), 0);
+    let mut events = a_builder.subscribe();
+    let a = a_builder.build().await.unwrap().0;
+    let b = isolated(b_addr.port(), a_addr, 1).build().await.unwrap().0;
+    assert!(linked(&a, a_addr, &b, b_addr).await);
+
+    let mut stream = TcpStream::connect(a_addr).await.unwrap();
+    a.send_message(&mut stream, ProtocolMessage::ConnInit { server_addr: b_addr }).await.unwrap();
+    match ProtocolMessage::from_stream(&mut stream).await.unwrap() {
+        Some((ProtocolMessage::ConnClosed(reason), _)) => assert_eq!(reason, CloseReason::Unspecified),
+        _ => panic!("expected CONN_CLOSED"),
+    }
+    assert_eq!(handshake_failed(&mut events, StreamDirection::Inbound).await, "peer is already connected");
+
+    // stream of the real peer is left in place
+    assert!(a.rtt_stats(b_addr).await.is_some_and(|stats| stats.samples > 0));
+}
+
+#[tokio::test]
+async fn malformed_handshakes_add_up_to_ip_ban() {
+    let a_addr = localhost(17545);
+    let a = node(a_addr.port(), 0).await;
+
+    // every attempt comes from another port, penalties still hit the same ip
+    let mut reasons = vec![];
+    for _ in 0..3 {
+        let mut stream = TcpStream::connect(a_addr).await.unwrap();
+        a.send_message(&mut stream, ProtocolMessage::Pong(None)).await.unwrap();
+        match ProtocolMessage::from_stream(&mut stream).await.unwrap() {
+            Some((ProtocolMessage::ConnClosed(reason), _)) => reasons.push(reason),
+            _ => panic!("expected CONN_CLOSED"),
+        }
+    }
+    assert_eq!(reasons, [CloseReason::ProtocolViolation, CloseReason::ProtocolViolation, CloseReason::Banned]);
+    assert!(a.is_banned(a_addr.ip()).await);
+
+    // claiming another server address doesn't help
+    let mut stream = TcpStream::connect(a_addr).await.unwrap();
+    let _ = a.send_message(&mut stream, ProtocolMessage::ConnInit { server_addr: localhost(17546) }).await;
+    match ProtocolMessage::from_stream(&mut stream).await.unwrap() {
+        Some((ProtocolMessage::ConnClosed(reason), _)) => assert_eq!(reason, CloseReason::Banned),
+        _ => panic!("expected CONN_CLOSED"),
+    }
+}
@@ -0,0 +1,62 @@
+use std::net::{IpAddr, Ipv4Addr};
+use protocol::core::frames::CloseReason;
+use protocol::core::server::limits::{ConnectionLimiter, InboundLimits};
+
+fn ip(a: u8, b: u8) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(10, 0, a, b))
+}
+
+fn limits() -> InboundLimits {
+    InboundLimits {
+        max_inbound: 6,
+        max_per_ip: 2,
+        max_per_subnet: 3,
+        accept_rate: 0.0, // nothing comes back within a test
+        accept_burst: 100,
+    }
+}
+
+#[test]
+fn counts_are_limited_and_freed() {
+    let limiter = ConnectionLimiter::new(limits());
+
+    let first = limiter.try_acquire(ip(0, 1)).unwrap();
+    let _second = limiter.try_acquire(ip(0, 1)).unwrap();
+    assert_eq!(limiter.try_acquire(ip(0, 1)).err(), Some(CloseReason::TooManyConnections));
+
+    let _third = limiter.try_acquire(ip(0, 2)).unwrap();
+    assert_eq!(limiter.try_acquire(ip(0, 3)).err(), Some(CloseReason::TooManyConnections)); // subnet is full
+
+    let _others = (1..=3).map(|i| limiter.try_acquire(ip(i, 1)).unwrap()).collect::<Vec<_>>();
+    assert_eq!(limiter.try_acquire(ip(9, 1)).err(), Some(CloseReason::TooManyConnections)); // node is full
+
+    drop(first);
+    assert!(limiter.try_acquire(ip(0, 1)).is_ok());
+}
+
+#[test]
+fn burst_is_rate_limited() {
+    let limiter = ConnectionLimiter::new(InboundLimits {
+        max_per_ip: 100,
+        max_per_subnet: 100,
+        max_inbound: 100,
+        accept_burst: 3,
+        ..limits()
+    });
+
+    for _ in 0..3 {
+        drop(limiter.try_acquire(ip(0, 1)).unwrap());
+    }
+    assert_eq!(limiter.try_acquire(ip(0, 1)).err(), Some(CloseReason::RateLimited));
+}
+
+#[test]
+fn refused_connections_dont_take_tokens() {
+    let limiter = ConnectionLimiter::new(InboundLimits { accept_burst: 3, ..limits() });
+
+    let _held = (0..2).map(|_| limiter.try_acquire(ip(0, 1)).unwrap()).collect::<Vec<_>>();
+    for _ in 0..10 {
+        assert_eq!(limiter.try_acquire(ip(0, 1)).err(), Some(CloseReason::TooManyConnections));
+    }
+    assert!(limiter.try_acquire(ip(1, 1)).is_ok());
+}
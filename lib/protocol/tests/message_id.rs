@@ -0,0 +1,10 @@
+use protocol::utils::prng::SecureRng;
+
+#[test]
+fn seeded_rng_repeats_and_entropy_does_not() {
+    let (mut a, mut b) = (SecureRng::from_seed(1), SecureRng::from_seed(1));
+    assert_eq!((a.next(), a.next()), (b.next(), b.next()));
+
+    let (mut a, mut b) = (SecureRng::from_entropy(), SecureRng::from_entropy());
+    assert_ne!(a.next(), b.next());
+}
@@ -0,0 +1,70 @@
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use protocol::core::metrics::{MetricsConfig, MAX_SCRAPES};
+
+mod common;
+use common::{builder, linked, localhost, node};
+
+/// Value of the sample with exactly this name and labels
+fn sample(metrics: &str, name: &str) -> Option<f64> {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' ')?.parse().ok())
+}
+
+#[tokio::test]
+async fn metrics_endpoint_serves_node_counters() {
+    let (a_addr, b_addr, metrics_addr) = (localhost(17480), localhost(17481), localhost(17482));
+
+    let a = node(a_addr.port(), 0).await;
+    let mut b_builder = builder(b_addr.port(), 1);
+    b_builder.set_client(a_addr);
+    b_builder.set_metrics(MetricsConfig { addr: Some(metrics_addr) });
+    let b = b_builder.build().await.unwrap().0;
+    assert!(linked(&a, a_addr, &b, b_addr).await);
+
+    let mut stream = TcpStream::connect(metrics_addr).await.unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+    assert_eq!(sample(body, "p2p_peers{direction=\"outbound\"}"), Some(1.0));
+    assert_eq!(sample(body, "p2p_connections_total{direction=\"outbound\"}"), Some(1.0));
+    assert!(sample(body, "p2p_frames_sent_total{kind=\"ping\"}").unwrap() >= 1.0);
+    assert!(sample(body, "p2p_bytes_received_total{kind=\"pong\"}").unwrap() > 0.0);
+    assert!(sample(body, "p2p_ping_seconds_count").unwrap() >= 1.0);
+
+    // same text is available without the endpoint
+    assert!(a.metrics().await.contains("p2p_peers{direction=\"inbound\"} 1"));
+}
+
+#[tokio::test]
+async fn concurrent_scrapes_are_capped() {
+    let (a_addr, metrics_addr) = (localhost(17536), localhost(17537));
+
+    let mut a_builder = builder(a_addr.port(), 0);
+    a_builder.set_metrics(MetricsConfig { addr: Some(metrics_addr) });
+    let _a = a_builder.build().await.unwrap().0;
+
+    // connections that never send the request hold their slots till the timeout
+    let mut idle = vec![];
+    for _ in 0..MAX_SCRAPES {
+        idle.push(TcpStream::connect(metrics_addr).await.unwrap());
+    }
+
+    let mut stream = TcpStream::connect(metrics_addr).await.unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    let read = tokio::time::timeout(Duration::from_secs(1), stream.read_to_string(&mut response)).await;
+    assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))), "connection over the cap should be dropped without an answer");
+
+    drop(idle);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut stream = TcpStream::connect(metrics_addr).await.unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+}
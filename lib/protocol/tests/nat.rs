@@ -0,0 +1,154 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use protocol::core::frames::ProtocolMessage;
+use protocol::core::nat::NatMessage;
+use protocol::core::scoring::Misbehaviour;
+use protocol::core::transport::{sim_nat::SimulatedNat, udp::UdpTransport};
+use protocol::types::{event::ProtocolEvent, state::ProtocolState};
+
+mod common;
+use common::{builder, isolated, linked, localhost, node, wait_for};
+
+/// Node behind simulated NAT, connected only to `relay`
+async fn natted_node(port: u16, relay: SocketAddr, seed: u64) -> ProtocolState {
+    let mut builder = isolated(port, relay, seed);
+    builder.set_udp_socket(SimulatedNat::bind(localhost(0)).await.unwrap());
+    builder.build().await.unwrap().0
+}
+
+#[tokio::test]
+async fn simulated_nat_drops_unsolicited_datagrams() {
+    let (a, _) = UdpTransport::new(Box::new(SimulatedNat::bind(localhost(0)).await.unwrap()));
+    let (b, mut b_inbound) = UdpTransport::new(Box::new(SimulatedNat::bind(localhost(0)).await.unwrap()));
+    tokio::spawn(a.clone().receive());
+    tokio::spawn(b.clone().receive());
+    let (a_addr, b_addr) = (a.local_addr().unwrap(), b.local_addr().unwrap());
+
+    let mut a_stream = a.connect(b_addr);
+    a_stream.write_all(b"before").await.unwrap();
+    let res = tokio::time::timeout(Duration::from_millis(300), b_inbound.links.recv()).await;
+    assert!(res.is_err(), "b's NAT let the datagram in without a mapping");
+
+    let interval = Duration::from_millis(50);
+    let timeout = Duration::from_secs(5);
+    let (a_res, b_res) = tokio::join!(a.punch(b_addr, interval, timeout), b.punch(a_addr, interval, timeout));
+    a_res.unwrap();
+    b_res.unwrap();
+
+    a_stream.write_all(b"after").await.unwrap();
+    let (mut b_stream, from) = tokio::time::timeout(timeout, b_inbound.links.recv()).await.unwrap().unwrap();
+    assert_eq!(from, a_addr);
+
+    let mut buf = [0; 5];
+    b_stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"after");
+}
+
+#[tokio::test]
+async fn punch_through_relay() {
+    let relay_addr = localhost(17410);
+    let (a_addr, b_addr) = (localhost(17411), localhost(17412));
+
+    let relay = node(relay_addr.port(), 0).await;
+    let a = natted_node(a_addr.port(), relay_addr, 1).await;
+    let b = natted_node(b_addr.port(), relay_addr, 2).await;
+    assert!(wait_for(async || relay.rtt_stats(a_addr).await.is_some() && relay.rtt_stats(b_addr).await.is_some()).await);
+
+    // relay tells the address it sees the connection from
+    assert!(wait_for(async || a.observed_ip().await.is_some()).await);
+    assert_eq!(a.observed_ip().await, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+
+    assert!(a.rtt_stats(b_addr).await.is_none());
+    a.punch(relay_addr, b_addr).await.unwrap();
+
+    // both sides have the stream and pings go over the punched link
+    assert!(linked(&a, a_addr, &b, b_addr).await);
+}
+
+#[tokio::test]
+async fn punch_fails_for_unknown_target() {
+    let relay_addr = localhost(17420);
+    let a_addr = localhost(17421);
+
+    let relay = node(relay_addr.port(), 0).await;
+    let a = natted_node(a_addr.port(), relay_addr, 1).await;
+    assert!(wait_for(async || relay.rtt_stats(a_addr).await.is_some()).await);
+
+    assert!(a.punch(relay_addr, localhost(17429)).await.is_err());
+}
+
+#[tokio::test]
+async fn unpunched_address_opens_no_link() {
+    let (transport, mut inbound) = UdpTransport::new(Box::new(UdpSocket::bind(localhost(0)).await.unwrap()));
+    tokio::spawn(transport.clone().receive());
+    let addr = transport.local_addr().unwrap();
+
+    let spoofer = UdpSocket::bind(localhost(0)).await.unwrap();
+    let conn_init = ProtocolMessage::ConnInit { server_addr: localhost(17535) }.into_frames_of(256).unwrap();
+    spoofer.send_to(&conn_init[0], addr).await.unwrap();
+    let res = tokio::time::timeout(Duration::from_millis(300), inbound.links.recv()).await;
+    assert!(res.is_err(), "datagram from unknown address opened a link");
+
+    // probe is handed over to the node instead of being answered right away
+    let mut probe = vec![0x71];
+    probe.extend(7u64.to_be_bytes());
+    spoofer.send_to(&probe, addr).await.unwrap();
+    let (from, nonce) = tokio::time::timeout(Duration::from_secs(1), inbound.probes.recv()).await.unwrap().unwrap();
+    assert_eq!((from, nonce), (spoofer.local_addr().unwrap(), 7));
+    let mut buf = [0; 64];
+    assert!(tokio::time::timeout(Duration::from_millis(300), spoofer.recv_from(&mut buf)).await.is_err());
+}
+
+#[tokio::test]
+async fn probes_are_answered_only_for_peers() {
+    let (a_addr, b_addr) = (localhost(17529), localhost(17530));
+    let a = node(a_addr.port(), 0).await;
+
+    let prober = UdpSocket::bind(localhost(0)).await.unwrap();
+    let mut probe = vec![0x71];
+    probe.extend(7u64.to_be_bytes());
+    let mut buf = [0; 64];
+
+    prober.send_to(&probe, a_addr).await.unwrap();
+    assert!(tokio::time::timeout(Duration::from_millis(300), prober.recv_from(&mut buf)).await.is_err());
+
+    // stream from the same ip makes it a peer
+    let b = isolated(b_addr.port(), a_addr, 1).build().await.unwrap().0;
+    assert!(linked(&a, a_addr, &b, b_addr).await);
+    prober.send_to(&probe, a_addr).await.unwrap();
+    let (len, _) = tokio::time::timeout(Duration::from_secs(1), prober.recv_from(&mut buf)).await.unwrap().unwrap();
+    assert_eq!(buf[0], 0x72);
+    assert_eq!(len, 15);
+}
+
+#[tokio::test]
+async fn unrequested_punch_offers_are_penalized() {
+    let (a_addr, fake_addr) = (localhost(17531), localhost(17532));
+    let a_builder = builder(a_addr.port(), 0);
+    let mut events = a_builder.subscribe();
+    let a = a_builder.build().await.unwrap().0;
+
+    let mut stream = TcpStream::connect(a_addr).await.unwrap();
+    a.send_message(&mut stream, ProtocolMessage::ConnInit { server_addr: fake_addr }).await.unwrap();
+    let offer = |from| ProtocolMessage::Nat(NatMessage::PunchOffer { from, udp_addr: localhost(17533) });
+
+    // first one is answered, it waits for the probe of the fake relay to time out
+    a.send_message(&mut stream, offer(localhost(17534))).await.unwrap();
+    a.send_message(&mut stream, offer(localhost(17534))).await.unwrap();
+    a.send_message(&mut stream, offer(fake_addr)).await.unwrap();
+
+    let mut penalized = 0;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while penalized < 2 {
+            if let ProtocolEvent::PeerPenalized { addr, misbehaviour, .. } = events.recv().await.unwrap() {
+                assert_eq!(addr, fake_addr);
+                assert_eq!(misbehaviour, Misbehaviour::ProtocolViolation);
+                penalized += 1;
+            }
+        }
+    })
+        .await
+        .expect("offers weren't penalized");
+}
@@ -0,0 +1,86 @@
+use std::time::Duration;
+use protocol::core::identity::Identity;
+use protocol::core::onion::{build, peel, Layer, MAX_DATA, MAX_PATH, ONION_BYTES};
+use protocol::types::{builder::ProtocolBuilder, package::AppPackage};
+
+mod common;
+use common::{builder, isolated, localhost, wait_for};
+
+#[test]
+fn every_hop_peels_own_layer() {
+    let (a, b, other) = (Identity::generate(), Identity::generate(), Identity::generate());
+    let onion = build(&[a.node_id(), b.node_id()], b"hello".to_vec()).unwrap();
+
+    assert!(peel(&other, &onion).is_err());
+    let Layer::Forward { next, onion } = peel(&a, &onion).unwrap() else { panic!("first hop should forward") };
+    assert_eq!(next, b.node_id());
+    assert!(peel(&a, &onion).is_err());
+    assert_eq!(peel(&b, &onion).unwrap(), Layer::Deliver(b"hello".to_vec()));
+
+    let mut tampered = build(&[a.node_id()], b"hello".to_vec()).unwrap();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(peel(&a, &tampered).is_err());
+}
+
+#[test]
+fn all_layers_have_the_same_length() {
+    for len in [1, MAX_PATH] {
+        let hops = (0..len).map(|_| Identity::generate()).collect::<Vec<_>>();
+        let path = hops.iter().map(|hop| hop.node_id()).collect::<Vec<_>>();
+
+        let mut onion = build(&path, b"hello".to_vec()).unwrap();
+        let mut delivered = None;
+        for hop in &hops {
+            assert_eq!(onion.len(), ONION_BYTES);
+            match peel(hop, &onion).unwrap() {
+                Layer::Forward { onion: inner, .. } => onion = inner,
+                Layer::Deliver(data) => delivered = Some(data),
+            }
+        }
+        assert_eq!(delivered.unwrap(), b"hello");
+    }
+
+    let path = [Identity::generate().node_id()];
+    assert_eq!(build(&path, vec![0; MAX_DATA]).unwrap().len(), ONION_BYTES);
+    assert!(build(&path, vec![0; MAX_DATA + 1]).is_err());
+    assert!(build(&vec![path[0]; MAX_PATH + 1], vec![]).is_err());
+}
+
+#[tokio::test]
+async fn onion_reaches_target() {
+    let hub_addr = localhost(17460);
+    let hub = builder(hub_addr.port(), 0).build().await.unwrap().0;
+    let a = isolated(17461, hub_addr, 1).build().await.unwrap().0;
+    let b = isolated(17462, hub_addr, 2).build().await.unwrap().0;
+
+    let (sender, mut packages) = tokio::sync::mpsc::channel(1000);
+    let mut target_builder = ProtocolBuilder::new(localhost(17463), sender);
+    target_builder.set_rng_seed(3);
+    target_builder.set_client(hub_addr);
+    let target = target_builder.build().await.unwrap().0;
+
+    // leaves are connected only to the hub, they reach each other through routing table
+    let nodes = [&hub, &a, &b, &target];
+    for node in nodes {
+        for other in nodes {
+            if node.node_id() != other.node_id() {
+                let id = other.node_id();
+                assert!(wait_for(async || node.find_node(id).await.iter().any(|c| c.id == id)).await);
+            }
+        }
+    }
+
+    let path = [b.node_id(), hub.node_id(), target.node_id()];
+    a.send_onion(&path, b"secret".to_vec()).await.unwrap();
+
+    let msg = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AppPackage::Anonymous(package)) = packages.recv().await {
+                return package.msg;
+            }
+        }
+    })
+        .await
+        .unwrap();
+    assert_eq!(msg, b"secret");
+}
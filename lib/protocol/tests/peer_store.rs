@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use protocol::core::peer_store::{forget_worst, json::JsonPeerStore, PeerRecord, PeerStore};
+
+mod common;
+use common::{builder, linked, localhost};
+
+fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("p2p-peers-{}-{}.json", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+fn record(port: u16, score: i32, last_seen: u64) -> PeerRecord {
+    PeerRecord {
+        score,
+        last_seen,
+        ..PeerRecord::new(localhost(port))
+    }
+}
+
+#[test]
+fn json_store_round_trip() {
+    let path = temp_path("round-trip");
+    let store = JsonPeerStore::new(&path);
+    assert!(store.load().unwrap().is_empty()); // first start
+
+    let mut records = vec![record(1000, 0, 10), record(1001, -20, 5)];
+    records[0].latency = Some(42);
+    store.save(&records).unwrap();
+
+    let loaded = store.load().unwrap();
+    assert_eq!(loaded.len(), 2);
+    for (saved, loaded) in records.iter().zip(&loaded) {
+        assert_eq!(
+            (saved.addr, saved.last_seen, saved.latency, saved.score),
+            (loaded.addr, loaded.last_seen, loaded.latency, loaded.score),
+        );
+    }
+    assert!(!path.with_extension("tmp").exists());
+
+    std::fs::write(&path, "not json").unwrap();
+    assert!(store.load().is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn worst_record_is_forgotten() {
+    let mut known = [record(1000, 0, 1), record(1001, -10, 5), record(1002, -10, 3), record(1003, -50, 9)]
+        .into_iter()
+        .map(|r| (r.addr, r))
+        .collect::<HashMap<_, _>>();
+    let scores = known.iter().map(|(addr, r)| (*addr, r.score)).collect::<HashMap<_, _>>();
+    let score = |addr: &_| scores[addr];
+
+    // lowest score goes first unless it has to be kept, then the one seen longest ago
+    let forgotten = forget_worst(&mut known, score, |addr| *addr == localhost(1003)).unwrap();
+    assert_eq!(forgotten.addr, localhost(1002));
+    let forgotten = forget_worst(&mut known, score, |_| false).unwrap();
+    assert_eq!(forgotten.addr, localhost(1003));
+    assert!(forget_worst(&mut known, score, |_| true).is_none());
+}
+
+#[tokio::test]
+async fn connected_peers_are_saved() {
+    let (a_addr, b_addr) = (localhost(17522), localhost(17523));
+    let path = temp_path("saved");
+
+    let mut a_builder = builder(a_addr.port(), 0);
+    a_builder.set_peer_store(JsonPeerStore::new(&path));
+    let a = a_builder.build().await.unwrap().0;
+
+    let mut b_builder = builder(b_addr.port(), 1);
+    b_builder.set_client(a_addr);
+    let b = b_builder.build().await.unwrap().0;
+    assert!(linked(&a, a_addr, &b, b_addr).await);
+
+    a.save_peers().await;
+    let saved = JsonPeerStore::new(&path).load().unwrap();
+    assert_eq!(saved.iter().map(|r| r.addr).collect::<Vec<_>>(), vec![b_addr]);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,47 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use protocol::core::frames::CloseReason;
+use protocol::types::event::DisconnectReason;
+use protocol::types::peer::PeerChange;
+use protocol::types::state::StreamDirection;
+
+mod common;
+use common::{builder, linked, localhost, node};
+
+#[tokio::test]
+async fn peers_snapshot_and_changes() {
+    let (a_addr, b_addr) = (localhost(17490), localhost(17491));
+
+    let a = node(a_addr.port(), 0).await;
+    let (peers, mut watch) = a.watch_peers().await;
+    assert!(peers.is_empty());
+
+    let mut b_builder = builder(b_addr.port(), 1);
+    b_builder.set_client(a_addr);
+    let b = b_builder.build().await.unwrap().0;
+
+    let change = tokio::time::timeout(Duration::from_secs(5), watch.next()).await.unwrap();
+    match change {
+        Some(PeerChange::Connected(peer)) => {
+            assert_eq!(peer.addr, b_addr);
+            assert_eq!(peer.direction, StreamDirection::Inbound);
+        }
+        change => panic!("unexpected change {:?}", change),
+    }
+
+    assert!(linked(&a, a_addr, &b, b_addr).await);
+    let peers = b.peers().await;
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].addr, a_addr);
+    assert_eq!(peers[0].direction, StreamDirection::Outbound);
+    assert!(peers[0].rtt.samples > 0);
+    assert!(peers[0].bytes_sent > 0 && peers[0].bytes_received > 0);
+
+    a.ban(IpAddr::V4(Ipv4Addr::LOCALHOST), Duration::from_secs(60)).await;
+    let change = tokio::time::timeout(Duration::from_secs(5), watch.next()).await.unwrap();
+    assert_eq!(change, Some(PeerChange::Disconnected {
+        addr: b_addr,
+        reason: DisconnectReason::Closed(CloseReason::Banned),
+    }));
+    assert!(a.peers().await.is_empty());
+}
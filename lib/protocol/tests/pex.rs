@@ -0,0 +1,69 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use protocol::core::pex::{PexConfig, PexTable};
+
+mod common;
+use common::localhost;
+
+fn addr(a: u8, b: u8, c: u8) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, a, b, c)), 4000)
+}
+
+fn config() -> PexConfig {
+    PexConfig {
+        max_entries: 8,
+        max_per_source: 4,
+        max_per_subnet: 2,
+        max_table: 6,
+        ..PexConfig::default()
+    }
+}
+
+#[test]
+fn response_is_cut_to_max_entries() {
+    let mut table = PexTable::new();
+    let config = PexConfig { max_per_source: 100, max_table: 100, ..config() };
+
+    let entries = (0..20).map(|i| (addr(i, 0, 1), 0)).collect();
+    assert_eq!(table.insert(&config, localhost(1), entries), 8);
+    assert_eq!(table.len(), 8);
+}
+
+#[test]
+fn source_and_subnet_are_capped() {
+    let mut table = PexTable::new();
+
+    // same /24, only two of them fit
+    let entries = (1..=3).map(|i| (addr(0, 0, i), 0)).collect();
+    assert_eq!(table.insert(&config(), localhost(1), entries), 2);
+
+    let entries = (1..=5).map(|i| (addr(i, 0, 1), 0)).collect();
+    assert_eq!(table.insert(&config(), localhost(1), entries), 2);
+    assert_eq!(table.insert(&config(), localhost(2), vec![(addr(9, 0, 1), 0)]), 1);
+    assert_eq!(table.len(), 5);
+}
+
+#[test]
+fn known_address_keeps_its_source() {
+    let mut table = PexTable::new();
+    let entries = (1..=4).map(|i| (addr(i, 0, 1), 0)).collect::<Vec<_>>();
+    assert_eq!(table.insert(&config(), localhost(1), entries.clone()), 4);
+
+    // other source repeating them doesn't use up its own quota
+    assert_eq!(table.insert(&config(), localhost(2), entries), 0);
+    let entries = (5..=8).map(|i| (addr(i, 0, 1), 0)).collect();
+    assert_eq!(table.insert(&config(), localhost(2), entries), 2);
+}
+
+#[test]
+fn full_table_replaces_only_older_entries() {
+    let mut table = PexTable::new();
+    let config = PexConfig { max_table: 2, ..config() };
+
+    table.insert(&config, localhost(1), vec![(addr(1, 0, 1), 10), (addr(2, 0, 1), 20)]);
+    assert_eq!(table.insert(&config, localhost(2), vec![(addr(3, 0, 1), 5)]), 0);
+    assert_eq!(table.insert(&config, localhost(2), vec![(addr(3, 0, 1), 15)]), 1);
+
+    let mut addrs = table.addrs();
+    addrs.sort();
+    assert_eq!(addrs, vec![addr(2, 0, 1), addr(3, 0, 1)]);
+}
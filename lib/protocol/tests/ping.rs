@@ -0,0 +1,83 @@
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use protocol::core::stream::ping_stream::PingConfig;
+use protocol::types::event::{DisconnectReason, ProtocolEvent};
+
+mod common;
+use common::{builder, linked, localhost};
+
+fn config() -> PingConfig {
+    PingConfig {
+        interval: Duration::from_millis(100),
+        pong_timeout: Duration::from_millis(100),
+        max_missed: 2,
+        ..PingConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn silent_peer_is_dropped_after_missed_pongs() {
+    let (a_addr, silent_addr) = (localhost(17525), localhost(17526));
+
+    // takes everything the node sends and never answers
+    let server = TcpListener::bind(silent_addr).await.unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = server.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                while stream.read(&mut buf).await.map(|n| n > 0).unwrap_or(false) {}
+            });
+        }
+    });
+
+    let mut a_builder = builder(a_addr.port(), 0);
+    a_builder.set_ping(config());
+    a_builder.set_client(silent_addr);
+    let mut events = a_builder.subscribe();
+    let _a = a_builder.build().await.unwrap().0;
+
+    let (mut missed, mut dead) = (vec![], None);
+    let started = Instant::now();
+    let reason = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            match events.recv().await.unwrap() {
+                ProtocolEvent::PongMissed { addr, missed: count } if addr == silent_addr => missed.push(count),
+                ProtocolEvent::PeerDead { addr, missed: count } if addr == silent_addr => dead = Some(count),
+                ProtocolEvent::PeerDisconnected { addr, reason } if addr == silent_addr => return reason,
+                _ => {}
+            }
+        }
+    })
+        .await
+        .expect("silent peer wasn't dropped");
+
+    assert_eq!(missed, vec![1, 2]);
+    assert_eq!(dead, Some(3));
+    assert!(matches!(reason, DisconnectReason::Lost));
+    // every missed pong is followed by another ping right away, not after the interval
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn answered_pings_are_not_missed() {
+    let (a_addr, b_addr) = (localhost(17527), localhost(17528));
+
+    let mut a_builder = builder(a_addr.port(), 0);
+    a_builder.set_ping(config());
+    let mut events = a_builder.subscribe();
+    let a = a_builder.build().await.unwrap().0;
+
+    let mut b_builder = builder(b_addr.port(), 1);
+    b_builder.set_ping(config());
+    b_builder.set_client(a_addr);
+    let b = b_builder.build().await.unwrap().0;
+
+    assert!(linked(&a, a_addr, &b, b_addr).await);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    while let Ok(event) = events.try_recv() {
+        assert!(!matches!(event, ProtocolEvent::PongMissed { .. } | ProtocolEvent::PeerDisconnected { .. }), "{:?}", event);
+    }
+    assert!(a.rtt_stats(b_addr).await.unwrap().samples >= 3);
+}
@@ -0,0 +1,51 @@
+use protocol::utils::prng::{Splitmix64, Xoshiro256ss};
+
+// from the reference C implementations, https://prng.di.unimi.it
+const STATE: [u64; 4] = [1, 2, 3, 4];
+
+fn take(rng: &mut Xoshiro256ss) -> [u64; 4] {
+    [rng.next(), rng.next(), rng.next(), rng.next()]
+}
+
+#[test]
+fn next_matches_reference() {
+    let mut rng = Xoshiro256ss::new(STATE);
+    assert_eq!(take(&mut rng), [0x0000000000002d00, 0x0000000000000000, 0x000000005a007080, 0x10e0000000009d80]);
+}
+
+#[test]
+fn jump_matches_reference() {
+    let mut rng = Xoshiro256ss::new(STATE);
+    rng.jump();
+    assert_eq!(rng.state(), [0x8c7a153956b5f3d1, 0x701f1a713401d85e, 0x6527f66a65469085, 0x8386b786c4408050]);
+    assert_eq!(take(&mut rng), [0xbbd2f312298443d8, 0x62e57db2d5706577, 0x34d1890374a6d72b, 0xa0425028ca8b66a0]);
+}
+
+#[test]
+fn long_jump_matches_reference() {
+    let mut rng = Xoshiro256ss::new(STATE);
+    rng.long_jump();
+    assert_eq!(rng.state(), [0x096a8eb71295a400, 0xdbf84991e50f4516, 0x534ee745810d2a0e, 0x31655ca1a2215bf1]);
+    assert_eq!(take(&mut rng), [0x527752a1d792704d, 0xd8d8bdec57599e64, 0x601cb926727eb003, 0xe0cd980a84253102]);
+}
+
+#[test]
+fn splitmix_matches_reference() {
+    let mut rng = Splitmix64::new(0);
+    let outputs = [rng.splitmix64(), rng.splitmix64(), rng.splitmix64(), rng.splitmix64()];
+    assert_eq!(outputs, [0xe220a8397b1dcdaf, 0x6e789e6aa1b965f4, 0x06c45d188009454f, 0xf88bb8a8724c81ec]);
+}
+
+#[test]
+fn split_streams_follow_each_other() {
+    let mut rng = Xoshiro256ss::new(STATE);
+    let first = rng.split();
+    let second = rng.split();
+
+    let mut jumped = Xoshiro256ss::new(STATE);
+    assert_eq!(first, jumped);
+    jumped.jump();
+    assert_eq!(second, jumped);
+    jumped.jump();
+    assert_eq!(rng, jumped);
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use protocol::core::client::reconnect::ReconnectConfig;
+use protocol::types::event::ProtocolEvent;
+
+mod common;
+use common::{builder, localhost};
+
+fn config() -> ReconnectConfig {
+    ReconnectConfig {
+        base_delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(1),
+        ..ReconnectConfig::default()
+    }
+}
+
+#[test]
+fn delay_doubles_with_jitter_up_to_half() {
+    let config = config();
+    for failures in 0..4 {
+        let full = Duration::from_millis(100 << failures);
+        assert_eq!(config.delay(failures, 0), full / 2);
+        for random in [1, 7, 12345, u64::MAX / 3] {
+            let delay = config.delay(failures, random);
+            assert!(delay >= full / 2 && delay < full, "{:?} for {} failures", delay, failures);
+        }
+    }
+}
+
+#[test]
+fn delay_is_capped() {
+    let config = config();
+    for failures in [4, 10, 16, 100, u32::MAX] {
+        let delay = config.delay(failures, 12345);
+        assert!(delay >= Duration::from_millis(500) && delay < Duration::from_secs(1));
+    }
+
+    let zero = ReconnectConfig { base_delay: Duration::ZERO, ..config };
+    assert_eq!(zero.delay(3, 12345), Duration::ZERO);
+}
+
+#[tokio::test]
+async fn flapping_peer_is_backed_off() {
+    let (a_addr, flapping_addr) = (localhost(17520), localhost(17521));
+
+    // accepts the connection and drops it right after `CONN_INIT`
+    let server = TcpListener::bind(flapping_addr).await.unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = server.accept().await {
+            let mut buf = [0; 64];
+            let _ = stream.read(&mut buf).await;
+        }
+    });
+
+    let mut a_builder = builder(a_addr.port(), 0);
+    a_builder.set_reconnect(config());
+    a_builder.set_client(flapping_addr);
+    let mut events = a_builder.subscribe();
+    let _a = a_builder.build().await.unwrap().0;
+
+    let mut delays = vec![];
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while delays.len() < 3 {
+            if let ProtocolEvent::Reconnecting { addr, delay } = events.recv().await.unwrap() {
+                assert_eq!(addr, flapping_addr);
+                delays.push(delay);
+            }
+        }
+    }).await.expect("node didn't reconnect");
+
+    // [50, 100), [100, 200), [200, 400) - failures weren't reset by every accepted connection
+    assert!(delays[0] < Duration::from_millis(100));
+    assert!(delays[2] >= Duration::from_millis(200));
+}
@@ -0,0 +1,44 @@
+use protocol::core::dht::record::{record_key, Record, RecordStore, StoreError};
+use protocol::core::identity::Identity;
+
+const NOW: u64 = 1_000_000;
+const MAX_TTL: u64 = 3600;
+const MAX_VALUE: usize = 1024;
+
+fn record(identity: &Identity, name: &[u8], seq: u64) -> Record {
+    Record::new(identity, record_key(name), b"value".to_vec(), seq, NOW + 60)
+}
+
+#[test]
+fn newer_record_of_publisher_replaces_older() {
+    let mut store = RecordStore::new();
+    let publisher = Identity::generate();
+
+    assert_eq!(store.insert(record(&publisher, b"a", 2), NOW, MAX_TTL, MAX_VALUE, 10), Ok(()));
+    assert_eq!(store.insert(record(&publisher, b"a", 2), NOW, MAX_TTL, MAX_VALUE, 10), Ok(()));
+    assert_eq!(store.insert(record(&publisher, b"a", 1), NOW, MAX_TTL, MAX_VALUE, 10), Err(StoreError::Outdated));
+    assert_eq!(store.insert(record(&publisher, b"a", 3), NOW, MAX_TTL, MAX_VALUE, 10), Ok(()));
+
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get(&record_key(b"a"), NOW)[0].seq, 3);
+}
+
+#[test]
+fn refused_records_leave_no_keys() {
+    let mut store = RecordStore::new();
+    let publisher = Identity::generate();
+    assert_eq!(store.insert(record(&publisher, b"a", 1), NOW, MAX_TTL, MAX_VALUE, 1), Ok(()));
+
+    let other = Identity::generate();
+    assert_eq!(store.insert(record(&other, b"b", 1), NOW, MAX_TTL, MAX_VALUE, 1), Err(StoreError::Full));
+
+    let mut forged = record(&other, b"c", 1);
+    forged.seq = 2;
+    assert_eq!(store.insert(forged, NOW, MAX_TTL, MAX_VALUE, 10), Err(StoreError::InvalidSignature));
+
+    let expired = Record::new(&other, record_key(b"d"), vec![], 1, NOW);
+    assert_eq!(store.insert(expired, NOW, MAX_TTL, MAX_VALUE, 10), Err(StoreError::Expired));
+
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.keys(), 1);
+}
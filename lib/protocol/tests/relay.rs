@@ -0,0 +1,110 @@
+use std::time::Duration;
+use protocol::core::relay::RelayConfig;
+
+mod common;
+use common::{builder, isolated, linked, localhost, node, wait_for};
+
+#[tokio::test]
+async fn relayed_connection() {
+    let relay_addr = localhost(17430);
+    let (a_addr, b_addr) = (localhost(17431), localhost(17432));
+
+    let relay = node(relay_addr.port(), 0).await;
+    let a = isolated(a_addr.port(), relay_addr, 1).build().await.unwrap().0;
+    let b = isolated(b_addr.port(), relay_addr, 2).build().await.unwrap().0;
+    assert!(wait_for(async || relay.rtt_stats(a_addr).await.is_some() && relay.rtt_stats(b_addr).await.is_some()).await);
+
+    b.reserve(relay_addr).await.unwrap();
+    assert_eq!(b.reservations().await, vec![relay_addr]);
+
+    assert!(a.rtt_stats(b_addr).await.is_none());
+    a.connect_relayed(relay_addr, b_addr).await.unwrap();
+
+    // looks like any other stream, pings go through the circuit both ways
+    assert!(linked(&a, a_addr, &b, b_addr).await);
+}
+
+#[tokio::test]
+async fn circuit_requires_reservation() {
+    let relay_addr = localhost(17440);
+    let (a_addr, b_addr) = (localhost(17441), localhost(17442));
+
+    let relay = node(relay_addr.port(), 0).await;
+    let a = isolated(a_addr.port(), relay_addr, 1).build().await.unwrap().0;
+    let _b = isolated(b_addr.port(), relay_addr, 2).build().await.unwrap().0;
+    assert!(wait_for(async || relay.rtt_stats(a_addr).await.is_some() && relay.rtt_stats(b_addr).await.is_some()).await);
+
+    assert!(a.connect_relayed(relay_addr, b_addr).await.is_err());
+    assert!(a.rtt_stats(b_addr).await.is_none());
+}
+
+#[tokio::test]
+async fn relay_limits() {
+    let relay_addr = localhost(17450);
+    let (a_addr, b_addr) = (localhost(17451), localhost(17452));
+
+    let mut relay = builder(relay_addr.port(), 0);
+    relay.set_relay(RelayConfig {
+        max_circuits: 0,
+        ..RelayConfig::default()
+    });
+    let relay = relay.build().await.unwrap().0;
+    let a = isolated(a_addr.port(), relay_addr, 1).build().await.unwrap().0;
+    let b = isolated(b_addr.port(), relay_addr, 2).build().await.unwrap().0;
+    assert!(wait_for(async || relay.rtt_stats(a_addr).await.is_some() && relay.rtt_stats(b_addr).await.is_some()).await);
+
+    b.reserve(relay_addr).await.unwrap();
+    assert!(a.connect_relayed(relay_addr, b_addr).await.is_err());
+
+    let closed_addr = localhost(17453);
+    let mut closed = builder(closed_addr.port(), 3);
+    closed.set_relay(RelayConfig {
+        serve: false,
+        ..RelayConfig::default()
+    });
+    closed.set_client(relay_addr);
+    let _closed = closed.build().await.unwrap().0;
+    let c = isolated(17454, closed_addr, 4).build().await.unwrap().0;
+    assert!(wait_for(async || c.rtt_stats(closed_addr).await.is_some()).await);
+    assert!(c.reserve(closed_addr).await.is_err());
+}
+
+#[tokio::test]
+async fn idle_circuits_expire_and_peers_are_capped() {
+    let relay_addr = localhost(17455);
+    let (a_addr, b_addr, c_addr, d_addr) = (localhost(17456), localhost(17457), localhost(17458), localhost(17459));
+
+    let mut relay = builder(relay_addr.port(), 0);
+    relay.set_relay(RelayConfig {
+        max_circuits: 2,
+        max_circuits_per_peer: 1,
+        max_duration: Duration::from_millis(500),
+        ..RelayConfig::default()
+    });
+    let relay = relay.build().await.unwrap().0;
+    let a = isolated(a_addr.port(), relay_addr, 1).build().await.unwrap().0;
+    let b = isolated(b_addr.port(), relay_addr, 2).build().await.unwrap().0;
+    let c = isolated(c_addr.port(), relay_addr, 3).build().await.unwrap().0;
+    let d = isolated(d_addr.port(), relay_addr, 4).build().await.unwrap().0;
+    let peers = [a_addr, b_addr, c_addr, d_addr];
+    assert!(wait_for(async || {
+        for addr in peers {
+            if relay.rtt_stats(addr).await.is_none() {
+                return false;
+            }
+        }
+        true
+    }).await);
+    b.reserve(relay_addr).await.unwrap();
+    d.reserve(relay_addr).await.unwrap();
+
+    a.connect_relayed(relay_addr, b_addr).await.unwrap();
+    assert!(a.connect_relayed(relay_addr, d_addr).await.is_err(), "second circuit of the same peer");
+    c.connect_relayed(relay_addr, d_addr).await.unwrap();
+    assert!(b.connect_relayed(relay_addr, d_addr).await.is_err(), "no circuits left");
+
+    // circuits are idle after the first pings, they don't hold the slots past max duration
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    b.connect_relayed(relay_addr, d_addr).await.unwrap();
+    assert!(wait_for(async || a.rtt_stats(b_addr).await.is_none()).await);
+}
@@ -0,0 +1,97 @@
+use std::time::Duration;
+use tokio::net::TcpStream;
+use protocol::core::dht::message::{routed_signed_bytes, DhtMessage};
+use protocol::core::frames::ProtocolMessage;
+use protocol::core::identity::Identity;
+use protocol::types::{
+    builder::ProtocolBuilder,
+    event::{DropReason, ProtocolEvent},
+    package::AppPackage,
+};
+
+mod common;
+use common::localhost;
+
+#[test]
+fn routed_message_is_signed_by_origin() {
+    let (origin, target) = (Identity::generate(), Identity::generate());
+    let message = DhtMessage::routed(&origin, target.node_id(), 7, 3, b"hello".to_vec());
+
+    let DhtMessage::Routed { origin: id, signature, target: to, data, .. } = DhtMessage::from_bytes(message.into_bytes().unwrap()).unwrap() else {
+        panic!("should stay routed message");
+    };
+    assert_eq!(id, origin.node_id());
+    assert!(id.verify(&routed_signed_bytes(&to, 7, &data), &signature));
+    assert!(!id.verify(&routed_signed_bytes(&to, 8, &data), &signature));
+    assert!(!target.node_id().verify(&routed_signed_bytes(&to, 7, &data), &signature));
+}
+
+#[tokio::test]
+async fn forged_origin_is_not_delivered() {
+    let addr = localhost(17524);
+    let (sender, mut packages) = tokio::sync::mpsc::channel(1000);
+    let mut builder = ProtocolBuilder::new(addr, sender);
+    builder.set_rng_seed(0);
+    let mut events = builder.subscribe();
+    let target = builder.build().await.unwrap().0;
+
+    let (sender, victim) = (Identity::generate(), Identity::generate());
+    let send = async |message| {
+        // one-shot dht connection, same as a hop forwarding it would open
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        target.send_message(&mut stream, ProtocolMessage::Dht(message)).await.unwrap();
+    };
+
+    let DhtMessage::Routed { signature, target: to, id, hops_left, data, .. } = DhtMessage::routed(&sender, target.node_id(), 1, 3, b"forged".to_vec()) else {
+        unreachable!()
+    };
+    send(DhtMessage::Routed { origin: victim.node_id(), signature, target: to, id, hops_left, data }).await;
+    let dropped = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let ProtocolEvent::MessageDropped { reason: DropReason::Forged(origin) } = events.recv().await.unwrap() {
+                return origin;
+            }
+        }
+    })
+        .await
+        .unwrap();
+    assert_eq!(dropped, victim.node_id());
+
+    send(DhtMessage::routed(&sender, target.node_id(), 2, 3, b"genuine".to_vec())).await;
+    let package = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(AppPackage::Routed(package)) = packages.recv().await {
+                return package;
+            }
+        }
+    })
+        .await
+        .unwrap();
+    assert_eq!(package.from, sender.node_id());
+    assert_eq!(package.msg, b"genuine");
+}
+
+#[tokio::test]
+async fn closed_application_drops_the_message() {
+    let addr = localhost(17551);
+    let (sender, packages) = tokio::sync::mpsc::channel(1000);
+    drop(packages);
+    let mut builder = ProtocolBuilder::new(addr, sender);
+    builder.set_rng_seed(0);
+    let mut events = builder.subscribe();
+    let target = builder.build().await.unwrap().0;
+
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let message = DhtMessage::routed(&Identity::generate(), target.node_id(), 1, 3, b"hello".to_vec());
+    target.send_message(&mut stream, ProtocolMessage::Dht(message)).await.unwrap();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let ProtocolEvent::MessageDropped { reason: DropReason::AppClosed } = events.recv().await.unwrap() {
+                return;
+            }
+        }
+    })
+        .await
+        .expect("message wasn't dropped");
+}
@@ -0,0 +1,69 @@
+use std::time::Duration;
+use protocol::core::stream::rtt::RttStats;
+
+fn ms(v: u64) -> Duration {
+    Duration::from_millis(v)
+}
+
+#[test]
+fn first_sample_initializes_smoothing() {
+    let mut stats = RttStats::new();
+    assert_eq!(stats.ping(), None);
+
+    stats.sample(ms(100));
+    assert_eq!(stats.srtt, Some(ms(100)));
+    assert_eq!(stats.rttvar, ms(50));
+    assert_eq!(stats.jitter, Duration::ZERO);
+    assert_eq!(stats.ping(), Some(100));
+}
+
+#[test]
+fn smoothing_follows_rfc_6298() {
+    let mut stats = RttStats::new();
+    stats.sample(ms(100));
+    stats.sample(ms(200));
+
+    // rttvar = 3/4 * 50 + 1/4 * |100 - 200|, srtt = 7/8 * 100 + 1/8 * 200
+    assert_eq!(stats.rttvar, ms(62) + Duration::from_micros(500));
+    assert_eq!(stats.srtt, Some(ms(112) + Duration::from_micros(500)));
+    // jitter = 1/16 * |100 - 200|
+    assert_eq!(stats.jitter, ms(6) + Duration::from_micros(250));
+
+    stats.sample(ms(50));
+    assert_eq!(stats.min, Some(ms(50)));
+    assert_eq!(stats.max, Some(ms(200)));
+    assert_eq!(stats.last, Some(ms(50)));
+    assert_eq!(stats.samples, 3);
+}
+
+#[test]
+fn steady_rtt_settles() {
+    let mut stats = RttStats::new();
+    stats.sample(ms(500));
+    for _ in 0..100 {
+        stats.sample(ms(40));
+    }
+    assert_eq!(stats.ping(), Some(40));
+    assert!(stats.rttvar < ms(1));
+    assert!(stats.jitter < ms(1));
+}
+
+#[test]
+fn loss_rate_counts_missed_pongs() {
+    let mut stats = RttStats::new();
+    assert_eq!(stats.loss_rate(), 0.0);
+
+    stats.lost();
+    assert_eq!(stats.loss_rate(), 1.0);
+    for _ in 0..3 {
+        stats.sample(ms(10));
+    }
+    assert_eq!(stats.loss_rate(), 0.25);
+}
+
+#[test]
+fn ping_saturates_at_u16() {
+    let mut stats = RttStats::new();
+    stats.sample(Duration::from_secs(100));
+    assert_eq!(stats.ping(), Some(u16::MAX));
+}
@@ -0,0 +1,77 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use protocol::core::scoring::{Misbehaviour, PeerScores, ScoringConfig};
+
+mod common;
+use common::localhost;
+
+fn config() -> ScoringConfig {
+    ScoringConfig {
+        protocol_violation_penalty: 50,
+        ban_threshold: -60,
+        ban_duration: Duration::from_millis(300),
+        recovery_per_minute: 60 * 20, // 20 a second
+        ..ScoringConfig::default()
+    }
+}
+
+#[test]
+fn score_recovers_over_time() {
+    let config = config();
+    let mut scores = PeerScores::new();
+    let ip = localhost(1000).ip();
+
+    assert!(!scores.penalize(&config, ip, Misbehaviour::ProtocolViolation));
+    let score = scores.score(&config, &ip);
+    assert!((-50..-45).contains(&score), "{}", score);
+
+    std::thread::sleep(Duration::from_millis(1000));
+    let score = scores.score(&config, &ip);
+    assert!((-35..-25).contains(&score), "{}", score);
+
+    std::thread::sleep(Duration::from_millis(2000));
+    assert_eq!(scores.score(&config, &ip), 0); // never goes above 0
+}
+
+#[test]
+fn ban_hits_only_the_ip_and_expires() {
+    let config = config();
+    let mut scores = PeerScores::new();
+    let (ip, neighbour) = (localhost(1000).ip(), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)));
+
+    assert!(!scores.penalize(&config, ip, Misbehaviour::ProtocolViolation));
+    assert!(scores.penalize(&config, ip, Misbehaviour::ProtocolViolation));
+    assert!(scores.is_banned(&ip));
+    assert!(!scores.is_banned(&neighbour));
+    assert_eq!(scores.score(&config, &neighbour), 0);
+    assert_eq!(scores.bans().len(), 1);
+
+    std::thread::sleep(Duration::from_millis(400));
+    assert!(!scores.is_banned(&ip));
+    assert!(scores.bans().is_empty());
+}
+
+#[test]
+fn unban_resets_the_score() {
+    let config = config();
+    let mut scores = PeerScores::new();
+    let ip = localhost(1000).ip();
+
+    scores.penalize(&config, ip, Misbehaviour::ProtocolViolation);
+    scores.ban(ip, Duration::from_secs(60));
+    assert!(scores.unban(&ip));
+    assert_eq!(scores.score(&config, &ip), 0);
+    assert!(!scores.unban(&ip));
+}
+
+#[test]
+fn restore_keeps_the_lowest_score() {
+    let config = config();
+    let mut scores = PeerScores::new();
+    let ip = localhost(1000).ip();
+
+    scores.restore(&config, ip, -40);
+    scores.restore(&config, ip, -10);
+    let score = scores.score(&config, &ip);
+    assert!((-40..-35).contains(&score), "{}", score);
+}
@@ -0,0 +1,111 @@
+use std::net::SocketAddr;
+use protocol::core::selection::{
+    policies::{CoordinateDiversity, Hybrid, LowestLatency, RandomSelection},
+    PeerInfo,
+    PeerSelectionPolicy,
+};
+use protocol::core::vivaldi::Coordinate;
+
+mod common;
+use common::localhost;
+
+fn counter() -> impl FnMut() -> u64 {
+    let mut n = 0;
+    move || {
+        n += 7;
+        n
+    }
+}
+
+fn pinged(port: u16, ping: Option<u16>) -> PeerInfo {
+    PeerInfo { addr: localhost(port), ping, coordinate: None }
+}
+
+fn located(port: u16, x: f32) -> PeerInfo {
+    PeerInfo {
+        addr: localhost(port),
+        ping: None,
+        coordinate: Some(Coordinate { pos: [x, 0.0], height: 0.0, error: 0.5 }),
+    }
+}
+
+fn ports(addrs: Vec<SocketAddr>) -> Vec<u16> {
+    addrs.into_iter().map(|a| a.port()).collect()
+}
+
+#[test]
+fn lowest_latency_picks_fastest_and_unknown_last() {
+    let candidates = vec![pinged(1, None), pinged(2, Some(80)), pinged(3, Some(20)), pinged(4, Some(50))];
+    let picked = LowestLatency::default().select(&[], candidates, 3, &mut counter());
+    assert_eq!(ports(picked), vec![3, 4, 2]);
+}
+
+#[test]
+fn lowest_latency_replaces_only_with_enough_gain() {
+    let policy = LowestLatency::default();
+    let connected = [pinged(1, Some(40)), pinged(2, Some(100))];
+
+    assert_eq!(policy.replace(&connected, &pinged(3, Some(90)), &mut counter()), None);
+    assert_eq!(policy.replace(&connected, &pinged(3, Some(70)), &mut counter()), Some(localhost(2)));
+    assert_eq!(policy.replace(&connected, &pinged(3, None), &mut counter()), None);
+
+    // never answered a ping, so it's worse than any measured candidate
+    let connected = [pinged(1, Some(40)), pinged(2, None)];
+    assert_eq!(policy.replace(&connected, &pinged(3, Some(500)), &mut counter()), Some(localhost(2)));
+}
+
+#[test]
+fn coordinate_diversity_spreads_connections() {
+    let policy = CoordinateDiversity::default();
+    let connected = [located(1, 0.0)];
+    let candidates = vec![located(2, 1.0), located(3, 100.0), located(4, 50.0), pinged(5, None)];
+
+    let picked = policy.select(&connected, candidates.clone(), 2, &mut counter());
+    assert_eq!(ports(picked), vec![3, 4]);
+
+    // nodes without coordinate only fill what's left
+    let picked = policy.select(&connected, candidates, 4, &mut counter());
+    assert_eq!(ports(picked), vec![3, 4, 2, 5]);
+}
+
+#[test]
+fn coordinate_diversity_replaces_redundant_node() {
+    let policy = CoordinateDiversity::default();
+    let connected = [located(1, 0.0), located(2, 1.0), located(3, 100.0)];
+
+    let replaced = policy.replace(&connected, &located(4, 50.0), &mut counter());
+    assert!(matches!(replaced.map(|a| a.port()), Some(1 | 2)));
+    assert_eq!(policy.replace(&connected, &located(4, 100.5), &mut counter()), None);
+    assert_eq!(policy.replace(&connected, &pinged(4, Some(1)), &mut counter()), None);
+}
+
+#[test]
+fn random_selection_never_replaces() {
+    let candidates = (1..=10).map(|p| pinged(p, Some(p))).collect::<Vec<_>>();
+    let mut picked = ports(RandomSelection.select(&[], candidates, 4, &mut counter()));
+    picked.sort();
+    picked.dedup();
+    assert_eq!(picked.len(), 4);
+
+    let connected = [pinged(1, Some(1000))];
+    assert_eq!(RandomSelection.replace(&connected, &pinged(2, Some(1)), &mut counter()), None);
+}
+
+#[test]
+fn hybrid_keeps_long_link() {
+    let policy = Hybrid::new(1);
+    let candidates = (1..=5).map(|p| pinged(p, Some(p * 100))).collect::<Vec<_>>();
+    let picked = ports(policy.select(&[], candidates, 3, &mut counter()));
+    assert_eq!(picked.len(), 3);
+
+    // first one is random, the rest are the fastest of the remaining
+    let long_link = picked[0];
+    let mut expected = (1..=5).filter(|p| *p != long_link).collect::<Vec<_>>();
+    expected.truncate(2);
+    assert_eq!(picked[1..], expected);
+
+    // long link is slow but stays, the slowest short link goes instead
+    let connected = picked.iter().map(|p| pinged(*p, Some(if *p == long_link { 10_000 } else { *p * 100 }))).collect::<Vec<_>>();
+    let replaced = policy.replace(&connected, &pinged(9, Some(1)), &mut counter());
+    assert_eq!(replaced, Some(localhost(expected[1])));
+}
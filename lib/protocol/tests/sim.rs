@@ -0,0 +1,134 @@
+use std::time::Duration;
+use protocol::core::transport::memory::LinkConfig;
+use protocol::sim::{SimConfig, Simulation};
+use protocol::types::event::{DialSource, ProtocolEvent};
+
+fn secs(s: u64) -> Duration {
+    Duration::from_secs(s)
+}
+
+/// While its routing table is small node looks itself up on every new stream, in a network
+/// that is just starting that's more connections than default `accept_rate` lets in
+fn config(seed: u64) -> SimConfig {
+    let mut config = SimConfig { seed, ..SimConfig::default() };
+    config.protocol.inbound_limits.accept_rate = 1000.0;
+    config.protocol.inbound_limits.accept_burst = 1000;
+    config
+}
+
+#[tokio::test(start_paused = true)]
+async fn flood_reaches_every_node_once() {
+    let mut sim = Simulation::new(config(0));
+    let nodes = sim.add_nodes(100).await.unwrap();
+    sim.run_for(secs(30)).await;
+
+    for node in nodes.iter().step_by(20) {
+        sim.broadcast(*node, &[7; 300]).await.unwrap();
+        sim.run_for(Duration::from_millis(100)).await;
+    }
+    sim.run_for(secs(5)).await;
+
+    let report = sim.report();
+    assert_eq!(report.messages.len(), 5);
+    assert_eq!(report.delivery_ratio(), 1.0);
+    assert_eq!(report.duplicates(), 0);
+    assert!(report.convergence_time().unwrap() < secs(1));
+}
+
+#[tokio::test(start_paused = true)]
+async fn node_status_brings_more_peers() {
+    let mut sim = Simulation::new(config(0));
+    let hub = sim.add_node(&[]).await.unwrap();
+    let mut joined = vec![];
+    for _ in 0..4 {
+        joined.push(sim.add_node(&[hub]).await.unwrap());
+        sim.run_for(secs(1)).await;
+    }
+    sim.run_for(secs(5)).await;
+
+    // every node but the first was told about someone already connected to the hub
+    for node in &joined[1..] {
+        let told = sim.events(*node).iter().any(|event| matches!(
+            event,
+            ProtocolEvent::Dialing { source: DialSource::NodeStatus, .. },
+        ));
+        assert!(told, "node {} didn't dial anyone from node status", node);
+        assert!(sim.peers(*node).await.len() > 1);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn churn_leaves_the_rest_connected() {
+    let mut sim = Simulation::new(config(3));
+    let nodes = sim.add_nodes(50).await.unwrap();
+    sim.run_for(secs(30)).await;
+
+    let stopped = nodes.iter().copied().skip(1).step_by(5).collect::<Vec<_>>();
+    for node in &stopped {
+        sim.stop(*node);
+    }
+    sim.add_nodes(10).await.unwrap();
+    sim.run_for(secs(60)).await;
+
+    for peer in (0..sim.report().nodes).filter(|n| !stopped.contains(n)) {
+        let peers = sim.peers(peer).await;
+        assert!(!peers.is_empty(), "{} is left without peers", peer);
+        assert!(stopped.iter().all(|node| !peers.contains(node)), "{} is still connected to a stopped node", peer);
+    }
+
+    sim.broadcast(0, b"after churn").await.unwrap();
+    sim.run_for(secs(5)).await;
+    let report = sim.report();
+    assert_eq!(report.up, 50);
+    assert_eq!(report.messages[0].expected, 49);
+    assert_eq!(report.delivery_ratio(), 1.0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn partition_holds_messages_until_it_heals() {
+    let mut sim = Simulation::new(config(0));
+    let (a, b) = (sim.add_node(&[]).await.unwrap(), 1);
+    sim.add_node(&[a]).await.unwrap();
+    sim.run_for(secs(5)).await;
+
+    // shorter than pong timeout, stream survives and tcp delivers once it heals
+    sim.partition(&[b]);
+    sim.broadcast(a, b"during").await.unwrap();
+    sim.run_for(secs(1)).await;
+    assert_eq!(sim.report().messages[0].delivered, 0);
+
+    sim.heal();
+    sim.run_for(secs(1)).await;
+    let converged_in = sim.report().messages[0].converged_in.unwrap();
+    assert!(converged_in >= secs(1) && converged_in < secs(2));
+}
+
+#[tokio::test(start_paused = true)]
+async fn latency_and_loss_are_per_link() {
+    let mut sim = Simulation::new(config(0));
+    let a = sim.add_node(&[]).await.unwrap();
+    let b = sim.add_node(&[a]).await.unwrap();
+    let c = sim.add_node(&[b]).await.unwrap();
+    sim.set_link(a, b, LinkConfig { latency: Duration::from_millis(300), ..LinkConfig::default() });
+    sim.set_link(b, c, LinkConfig { loss: 0.5, ..LinkConfig::default() });
+    sim.run_for(secs(5)).await;
+
+    sim.broadcast(a, b"hi").await.unwrap();
+    sim.run_for(secs(5)).await;
+    let report = sim.report();
+    assert_eq!(report.delivery_ratio(), 1.0);
+    assert!(report.messages[0].converged_in.unwrap() >= Duration::from_millis(300));
+    assert!(report.retransmits > 0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn same_seed_gives_same_nodes() {
+    let ids = |seed| async move {
+        let mut sim = Simulation::new(SimConfig { seed, ..SimConfig::default() });
+        sim.add_nodes(3).await.unwrap();
+        (0..3).map(|node| sim.state(node).read().identity.node_id()).collect::<Vec<_>>()
+    };
+
+    assert_eq!(ids(1).await, ids(1).await);
+    assert_ne!(ids(1).await, ids(2).await);
+}
@@ -0,0 +1,94 @@
+use protocol::core::vivaldi::Coordinate;
+
+fn close(a: f32, b: f32) -> bool {
+    (a - b).abs() < 1e-3
+}
+
+#[test]
+fn distance_adds_heights() {
+    let a = Coordinate { pos: [0.0, 0.0], height: 1.0, error: 1.0 };
+    let b = Coordinate { pos: [3.0, 4.0], height: 2.0, error: 1.0 };
+    assert!(close(a.distance(&b), 8.0));
+    assert_eq!(b.predict(&a), 8);
+}
+
+#[test]
+fn single_update_moves_by_weighted_force() {
+    let mut local = Coordinate::new();
+    let remote = Coordinate::new();
+    local.update(&remote, 100, 0); // direction is picked at 0 degrees
+
+    // both are equally unsure, so weight is 0.5 and force is 0.25 * 0.5 * (100 - 0.02)
+    let force = 0.125 * 99.98;
+    assert!(close(local.pos[0], force));
+    assert!(close(local.pos[1], 0.0));
+    assert!(close(local.height, 0.01 + 0.02 / 1.02 * force));
+    assert!(close(local.error, 0.125 * 0.9998 + 0.875 * 1.5));
+}
+
+#[test]
+fn coordinates_converge_to_measured_rtts() {
+    // nodes on a plane, rtt is the distance between them plus 5ms of access link on each side
+    let truth = [[0.0f32, 0.0], [30.0, 0.0], [0.0, 40.0], [60.0, 80.0]];
+    let rtt = |a: usize, b: usize| {
+        let (dx, dy) = (truth[a][0] - truth[b][0], truth[a][1] - truth[b][1]);
+        ((dx * dx + dy * dy).sqrt() + 10.0).round() as u16
+    };
+
+    let mut coordinates = [Coordinate::new(); 4];
+    let mut random = 0;
+    for _ in 0..500 {
+        for a in 0..4 {
+            for b in 0..4 {
+                if a != b {
+                    random += 97;
+                    let remote = coordinates[b];
+                    coordinates[a].update(&remote, rtt(a, b), random);
+                }
+            }
+        }
+    }
+
+    for a in 0..4 {
+        assert!(coordinates[a].error < 0.2, "error of {} stays at {}", a, coordinates[a].error);
+        for b in 0..4 {
+            if a != b {
+                let predicted = coordinates[a].distance(&coordinates[b]);
+                let measured = rtt(a, b) as f32;
+                assert!((predicted - measured).abs() / measured < 0.15, "{} to {}: {} instead of {}", a, b, predicted, measured);
+            }
+        }
+    }
+}
+
+#[test]
+fn error_is_kept_in_range() {
+    let mut local = Coordinate::new();
+    let remote = Coordinate { pos: [50.0, 0.0], height: 0.01, error: 0.01 };
+    for _ in 0..1000 {
+        local.update(&remote, 50, 0);
+    }
+    assert!(local.error >= 0.01);
+    assert!((local.distance(&remote) - 50.0).abs() < 1.0);
+
+    let mut far = Coordinate::new();
+    far.update(&remote, u16::MAX, 0);
+    assert!(far.error <= 1.5);
+}
+
+#[test]
+fn invalid_coordinates_are_refused() {
+    let valid = Coordinate { pos: [1.0, -2.0], height: 3.0, error: 0.5 };
+    assert_eq!(Coordinate::from_bytes(&valid.into_bytes()).unwrap(), valid);
+
+    for invalid in [
+        Coordinate { pos: [f32::NAN, 0.0], ..valid },
+        Coordinate { pos: [0.0, 1e9], ..valid },
+        Coordinate { height: -1.0, ..valid },
+        Coordinate { error: 0.0, ..valid },
+        Coordinate { error: f32::INFINITY, ..valid },
+    ] {
+        assert!(Coordinate::from_bytes(&invalid.into_bytes()).is_err());
+    }
+    assert!(Coordinate::from_bytes(&[0; 15]).is_err());
+}